@@ -77,7 +77,6 @@ entry:
 	mov [drive], dl
 	mov ax, 0
 	int 13h
-	mov ax, 0x07e0
 	call read_disk
 	
 
@@ -113,13 +112,31 @@ entry:
 	jmp GDT_CODE32:protected_mode
 
 
+; Read the rest of the image, [sectors] sectors starting from the second,
+; into memory at 0x7E00. BIOSes only guarantee 127 sectors per extended
+; read, so it is done in chunks of at most that many
 read_disk:	
-	mov si, packet		; address of "disk address packet"
-	mov ah, 0x42		; extended read
-	mov dl, [drive]		; drive number 0 (OR the drive # with 0x80)
-	int 0x13
-	jc .error
-	ret
+	mov cx, [sectors]	; sectors left to read
+	jcxz .done
+	cmp cx, 127
+	jbe .read
+	mov cx, 127
+
+	.read:
+		mov [packet.count], cx
+		mov si, packet		; address of "disk address packet"
+		mov ah, 0x42		; extended read
+		mov dl, [drive]		; drive number 0 (OR the drive # with 0x80)
+		int 0x13
+		jc .error
+		mov cx, [packet.count]
+		sub [sectors], cx
+		add [packet.lba], cx
+		shl cx, 5			; 512 byte sectors are 32 paragraphs
+		add [packet.dest + 2], cx
+		jmp read_disk
+	.done:
+		ret
 	.error:
 		hlt
 
@@ -128,7 +145,7 @@ packet:
 	db	0x10	; packet size (16 bytes)
 	db	0		; always 0
 .count:		
-	dw	127		; number of sectors to transfer
+	dw	0		; number of sectors to transfer
 .dest:		
 	dw	0		; destination offset (0:7c00)
 	dw	0x7e0	; destination segment
@@ -247,7 +264,6 @@ paging:
 	.pt 	dd 0x003F4000	; Page table, 0x00000000-0x00200000
 	.pt2	dd 0x003F5000
 
-msg_long 		db "Succesfully made it to long mode!", 0
 msg_nolong 		db "Error: processor is not x86_64 enabled!", 0
 
 ;;; GLOBAL DESCRIPTOR TABLE
@@ -294,7 +310,8 @@ gdt_desc:					; The GDT descriptor
 	dd gdt_null 			; Address of the GDT
 
 
-times 510-($-$$) db 0 		; Fill up the file with zeros
+times 508-($-$$) db 0 		; Fill up the file with zeros
+sectors dw 0 				; Sectors after this one to read, patched in by the builder
 dw 0xAA55 					; Last 2 bytes = Boot sector identifyer
 
 %include "stage2.asm"
@@ -189,6 +189,16 @@ load_elf:
 	mov [elf_ptr], rdi
 	mov r10, [rdi + elf64_ehdr.ehsize]
 	mov [elf_len], r10
+	; the builder appends the kernel symbol table to the ELF file, just
+	; past the section headers and aligned to 8 bytes
+	movzx rax, word [rdi + elf64_ehdr.shentsize]
+	movzx rcx, word [rdi + elf64_ehdr.shnum]
+	mul rcx
+	add rax, [rdi + elf64_ehdr.shoff]
+	add rax, rdi
+	add rax, 7
+	and rax, ~7
+	mov [ksyms_ptr], rax
 	; clear rax, load it with the size of the memory mapping table
 	; that we saved at 0x6FF0 earlier.
 	; TODO - save this value directly to the memory location of
@@ -221,3 +231,4 @@ boot_struct:
 	mmap_len: dq 0	
 	elf_ptr: dq 0
 	elf_len: dq 0
+	ksyms_ptr: dq 0
@@ -1,6 +1,30 @@
 use std::fs::File;
 use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
-use std::process::{Command, Stdio};
+use std::process::Command;
+
+#[allow(dead_code)]
+#[path = "../../kernel/src/ksyms.rs"]
+mod ksyms;
+mod symbols;
+
+/// Offset of the kernel in the disk image
+const KERNEL_OFFSET: u64 = 0x400;
+
+/// Offset in the boot sector of the number of sectors after it that the
+/// first stage bootloader reads
+const SECTOR_COUNT_OFFSET: u64 = 508;
+const SECTOR_SIZE: u64 = 512;
+
+/// The image is read into conventional memory at 0x7C00, up to the real mode
+/// stack at 0x8C000, so it has to end within this many bytes
+const LOADED_LIMIT: u64 = 0x8C000 - 0x7C00;
+
+/// User programs in `user/`, which the kernel embeds
+const USER_PROGRAMS: &[&str] = &["init", "hello"];
+
+/// User programs in `user/` written in C, which are linked statically
+/// against musl
+const USER_C_PROGRAMS: &[&str] = &["musl-hello"];
 
 fn create_block(output: &str, blocks: usize) -> io::Result<BufWriter<File>> {
     let mut handle = BufWriter::new(File::create(output)?);
@@ -11,6 +35,12 @@ fn create_block(output: &str, blocks: usize) -> io::Result<BufWriter<File>> {
     Ok(handle)
 }
 
+/// Number of sectors after the boot sector needed to load an image of
+/// `len` bytes
+fn loaded_sectors(len: u64) -> u16 {
+    ((len - 1) / SECTOR_SIZE) as u16
+}
+
 fn copy_to_file<W: Write + Seek, R: Read>(
     output: &mut W,
     input: &mut R,
@@ -25,16 +55,83 @@ fn copy_to_file<W: Write + Seek, R: Read>(
             Ok(n) => n,
             Err(e) => return Err(e),
         };
-        output.write_all(&mut buffer[..n])?;
+        output.write_all(&buffer[..n])?;
         written += n;
     }
     Ok(written)
 }
 
+/// Assemble and statically link `user/<name>.asm` into `build/user/<name>`
+fn build_user(name: &str) -> io::Result<()> {
+    let source = format!("./user/{}.asm", name);
+    let object = format!("./build/user/{}.o", name);
+    let nasm = Command::new("nasm")
+        .args(["-f", "elf64", &source, "-o", &object].iter())
+        .spawn()?
+        .wait()?
+        .success();
+    if !nasm {
+        panic!("Error assembling user program {}", name);
+    }
+
+    let ld = Command::new("ld")
+        .args(
+            [
+                "-static",
+                "-z",
+                "max-page-size=0x1000",
+                "-o",
+                &format!("./build/user/{}", name),
+                &object,
+            ]
+            .iter(),
+        )
+        .spawn()?
+        .wait()?
+        .success();
+    if !ld {
+        panic!("Error linking user program {}", name);
+    }
+    Ok(())
+}
+
+/// Compile `user/<name>.c` into a static executable at `build/user/<name>`
+/// with musl-gcc
+fn build_user_c(name: &str) -> io::Result<()> {
+    let source = format!("./user/{}.c", name);
+    let output = format!("./build/user/{}", name);
+    let mut cc = match Command::new("musl-gcc")
+        .args(["-static", "-no-pie", "-Os", "-s", "-o", &output, &source].iter())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            panic!("musl-gcc is needed to compile user program {}", name)
+        }
+        Err(err) => return Err(err),
+    };
+    if !cc.wait()?.success() {
+        panic!("Error compiling user program {}", name);
+    }
+    Ok(())
+}
+
 fn main() -> io::Result<()> {
+    // The kernel embeds the user programs, so they are built first
+    std::fs::create_dir_all("./build/user")?;
+    for name in USER_PROGRAMS {
+        build_user(name)?;
+    }
+    for name in USER_C_PROGRAMS {
+        build_user_c(name)?;
+    }
+
+    // Any arguments, such as `--features contention-test`, are passed on
+    // to the kernel build
     let build = Command::new("cargo")
         .current_dir("kernel")
-        .args(&["xbuild", "--target", "target.json", "--release"])
+        .args(["xbuild", "--target", "target.json", "--release"].iter())
+        .args(std::env::args().skip(1))
         .spawn()?
         .wait()?
         .success();
@@ -43,22 +140,29 @@ fn main() -> io::Result<()> {
     }
 
     let ld = Command::new("ld")
-        .args(&[
-            "--gc-sections",
-            "-z",
-            "max-page-size=0x1000",
-            "-o",
-            "./build/kernel.elf",
-            "-T",
-            "linker.ld",
-            "./target/target/release/librust_os.a",
-        ])
+        .args(
+            [
+                "--gc-sections",
+                "-z",
+                "max-page-size=0x1000",
+                "-o",
+                "./build/kernel.elf",
+                "-T",
+                "linker.ld",
+                "./target/target/release/librust_os.a",
+            ]
+            .iter(),
+        )
         .spawn()?
-        .wait()?;
+        .wait()?
+        .success();
+    if !ld {
+        panic!("Error linking kernel");
+    }
 
     let nasm = Command::new("nasm")
         .current_dir("bootloader")
-        .args(&["-f", "bin", "stage1.asm", "-o", "../build/bootstrap.bin"])
+        .args(["-f", "bin", "stage1.asm", "-o", "../build/bootstrap.bin"].iter())
         .spawn()?
         .wait()?
         .success();
@@ -66,9 +170,13 @@ fn main() -> io::Result<()> {
         panic!("Error executing assembler commands");
     }
 
+    // The symbol table must be extracted before it is stripped
+    let elf = std::fs::read("./build/kernel.elf")?;
+    let ksyms = symbols::encode(symbols::read(&elf)?);
+
     Command::new("strip")
         .current_dir("build")
-        .args(&["kernel.elf"])
+        .args(["kernel.elf"].iter())
         .spawn()?
         .wait()?;
 
@@ -81,7 +189,53 @@ fn main() -> io::Result<()> {
     let data = kernel.metadata()?.len() as usize;
 
     copy_to_file(&mut handle, &mut bootloader, 0)?;
-    assert_eq!(data, copy_to_file(&mut handle, &mut kernel, 0x400)?);
+    assert_eq!(data, copy_to_file(&mut handle, &mut kernel, KERNEL_OFFSET)?);
+
+    // The bootloader passes the kernel the address just past the section
+    // headers, rounded up to 8 bytes, as the location of the symbol table
+    let stripped = std::fs::read("./build/kernel.elf")?;
+    let ksyms_offset = (symbols::section_headers_end(&stripped)? + 7) & !7;
+    assert!(
+        ksyms_offset >= data,
+        "section headers are not at the end of kernel.elf"
+    );
+    let end = KERNEL_OFFSET + (ksyms_offset + ksyms.len()) as u64;
+    assert!(
+        end <= LOADED_LIMIT,
+        "kernel image ends at {:#X}, but the bootloader only reads up to {:#X}",
+        end,
+        LOADED_LIMIT
+    );
+    copy_to_file(
+        &mut handle,
+        &mut &ksyms[..],
+        KERNEL_OFFSET + ksyms_offset as u64,
+    )?;
+    let sectors = loaded_sectors(end);
+    copy_to_file(
+        &mut handle,
+        &mut &sectors.to_le_bytes()[..],
+        SECTOR_COUNT_OFFSET,
+    )?;
+    println!(
+        "Kernel symbol table: {} bytes at offset {:#X}",
+        ksyms.len(),
+        ksyms_offset
+    );
+    println!("Bootloader reads {} sectors", sectors);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sectors() {
+        assert_eq!(loaded_sectors(KERNEL_OFFSET + 1), 2);
+        assert_eq!(loaded_sectors(2 * SECTOR_SIZE), 1);
+        assert_eq!(loaded_sectors(2 * SECTOR_SIZE + 1), 2);
+        assert_eq!(loaded_sectors(LOADED_LIMIT), 1057);
+    }
+}
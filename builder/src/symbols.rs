@@ -0,0 +1,288 @@
+//! Extract the kernel's function symbols into the compact table described
+//! in `kernel/src/ksyms.rs`
+use crate::ksyms;
+use std::io;
+
+/// Section type of the ELF symbol table
+const SHT_SYMTAB: u32 = 2;
+/// Symbol type of functions
+const STT_FUNC: u8 = 2;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub address: u64,
+    pub size: u32,
+    pub name: String,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn bytes(data: &[u8], offset: usize, len: usize) -> io::Result<&[u8]> {
+    data.get(offset..offset + len)
+        .ok_or_else(|| invalid("ELF file is truncated"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    buf.copy_from_slice(bytes(data, offset, 2)?);
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes(data, offset, 4)?);
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes(data, offset, 8)?);
+    Ok(u64::from_le_bytes(buf))
+}
+
+struct Section {
+    ty: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+fn sections(elf: &[u8]) -> io::Result<Vec<Section>> {
+    if bytes(elf, 0, 4)? != b"\x7FELF" || elf[4] != 2 {
+        return Err(invalid("not a 64-bit ELF file"));
+    }
+    let shoff = read_u64(elf, 0x28)? as usize;
+    let shnum = read_u16(elf, 0x3C)? as usize;
+    (0..shnum)
+        .map(|i| {
+            let header = shoff + i * SECTION_HEADER_SIZE;
+            Ok(Section {
+                ty: read_u32(elf, header + 4)?,
+                offset: read_u64(elf, header + 0x18)? as usize,
+                size: read_u64(elf, header + 0x20)? as usize,
+                link: read_u32(elf, header + 0x28)? as usize,
+            })
+        })
+        .collect()
+}
+
+/// Return the offset just past the section header table of `elf`, which
+/// the bootloader expects the symbol table to follow
+pub fn section_headers_end(elf: &[u8]) -> io::Result<usize> {
+    let shoff = read_u64(elf, 0x28)? as usize;
+    let shentsize = read_u16(elf, 0x3A)? as usize;
+    let shnum = read_u16(elf, 0x3C)? as usize;
+    Ok(shoff + shentsize * shnum)
+}
+
+/// Return every function with a non-zero size in the symbol table of
+/// `elf`, with demangled names
+pub fn read(elf: &[u8]) -> io::Result<Vec<Symbol>> {
+    let sections = sections(elf)?;
+    let symtab = sections
+        .iter()
+        .find(|s| s.ty == SHT_SYMTAB)
+        .ok_or_else(|| invalid("ELF file has no symbol table"))?;
+    let strtab = sections
+        .get(symtab.link)
+        .ok_or_else(|| invalid("symbol table has no string table"))?;
+    let strings = bytes(elf, strtab.offset, strtab.size)?;
+
+    let mut symbols = Vec::new();
+    for i in 0..symtab.size / SYMBOL_SIZE {
+        let symbol = bytes(elf, symtab.offset + i * SYMBOL_SIZE, SYMBOL_SIZE)?;
+        let size = read_u64(symbol, 16)?;
+        if symbol[4] & 0xF != STT_FUNC || size == 0 {
+            continue;
+        }
+        let name = strings
+            .get(read_u32(symbol, 0)? as usize..)
+            .ok_or_else(|| invalid("symbol name is out of bounds"))?;
+        let len = name
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| invalid("unterminated symbol name"))?;
+        symbols.push(Symbol {
+            address: read_u64(symbol, 8)?,
+            size: size as u32,
+            name: demangle(&String::from_utf8_lossy(&name[..len])),
+        });
+    }
+    Ok(symbols)
+}
+
+/// Demangle a Rust symbol in the legacy `_ZN...E` scheme, dropping the
+/// trailing hash. Other names are returned unchanged
+pub fn demangle(name: &str) -> String {
+    if !name.starts_with("_ZN") {
+        return name.to_string();
+    }
+    let mut rest = &name[3..];
+
+    let mut path: Vec<String> = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let component = match rest[..digits].parse::<usize>() {
+            Ok(len) => rest.get(digits..digits + len),
+            Err(_) => None,
+        };
+        match component {
+            Some(component) => {
+                path.push(unescape(component));
+                rest = &rest[digits + component.len()..];
+            }
+            None => return name.to_string(),
+        }
+    }
+
+    let is_hash = |s: &String| {
+        s.len() == 17 && s.starts_with('h') && s[1..].bytes().all(|b| b.is_ascii_hexdigit())
+    };
+    if path.last().map(is_hash) == Some(true) {
+        path.pop();
+    }
+    path.join("::")
+}
+
+/// Replace the escape sequences used in legacy mangled path components
+fn unescape(component: &str) -> String {
+    const ESCAPES: &[(&str, &str)] = &[
+        ("$SP$", "@"),
+        ("$BP$", "*"),
+        ("$RF$", "&"),
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u22$", "\""),
+        ("$u27$", "'"),
+        ("$u2b$", "+"),
+        ("$u3b$", ";"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+    ];
+    // Components that would start with `$` are prefixed with `_`
+    let component = if component.starts_with("_$") {
+        &component[1..]
+    } else {
+        component
+    };
+    let mut out = component.to_string();
+    for (from, to) in ESCAPES {
+        out = out.replace(from, to);
+    }
+    out.replace("..", "::")
+}
+
+/// Encode `symbols` as a table that the kernel can parse with
+/// `ksyms::Table`. Symbols are sorted by address, and only the first of
+/// several symbols at the same address is kept
+pub fn encode(mut symbols: Vec<Symbol>) -> Vec<u8> {
+    symbols.sort_by_key(|s| s.address);
+    symbols.dedup_by_key(|s| s.address);
+
+    let mut entries = Vec::with_capacity(symbols.len() * ksyms::ENTRY_SIZE);
+    let mut strings = Vec::new();
+    for symbol in &symbols {
+        entries.extend_from_slice(&symbol.address.to_le_bytes());
+        entries.extend_from_slice(&symbol.size.to_le_bytes());
+        entries.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        strings.extend_from_slice(symbol.name.as_bytes());
+        strings.push(0);
+    }
+
+    let mut table = Vec::with_capacity(ksyms::HEADER_SIZE + entries.len() + strings.len());
+    table.extend_from_slice(&ksyms::MAGIC);
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    table.extend_from_slice(&entries);
+    table.extend_from_slice(&strings);
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ksyms::Table;
+
+    fn symbol(address: u64, size: u32, name: &str) -> Symbol {
+        Symbol {
+            address,
+            size,
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let symbols = vec![
+            symbol(0xFFFF_FFFF_8010_2000, 0x40, "rust_os::_start"),
+            symbol(0xFFFF_FFFF_8010_1000, 0x100, "rust_os::panic::panic"),
+            symbol(0xFFFF_FFFF_8010_1000, 0x100, "alias"),
+            symbol(0xFFFF_FFFF_8010_1100, 0x8, "core::ptr::drop_in_place"),
+        ];
+        let data = encode(symbols);
+        let table = Table::parse(&data).unwrap();
+        assert_eq!(Table::size(&data), Ok(data.len()));
+
+        let names: Vec<&str> = table.iter().map(|s| s.name).collect();
+        assert_eq!(
+            names,
+            [
+                "rust_os::panic::panic",
+                "core::ptr::drop_in_place",
+                "rust_os::_start"
+            ]
+        );
+
+        let (symbol, offset) = table.lookup(0xFFFF_FFFF_8010_2010).unwrap();
+        assert_eq!((symbol.name, offset), ("rust_os::_start", 0x10));
+        let (symbol, offset) = table.lookup(0xFFFF_FFFF_8010_1107).unwrap();
+        assert_eq!((symbol.name, offset), ("core::ptr::drop_in_place", 7));
+        assert_eq!(table.lookup(0xFFFF_FFFF_8010_1108), None);
+        assert_eq!(table.lookup(0xFFFF_FFFF_8010_2040), None);
+
+        let empty = encode(Vec::new());
+        assert!(Table::parse(&empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn demangling() {
+        assert_eq!(
+            demangle("_ZN7rust_os5panic5panic17h2b9d9a2b1e8c5d33E"),
+            "rust_os::panic::panic"
+        );
+        assert_eq!(
+            demangle("_ZN4core3ptr18real_drop_in_place17h0123456789abcdefE"),
+            "core::ptr::real_drop_in_place"
+        );
+        assert_eq!(
+            demangle("_ZN56_$LT$rust_os..io..Serial$u20$as$u20$core..fmt..Write$GT$9write_str17h0123456789abcdefE"),
+            "<rust_os::io::Serial as core::fmt::Write>::write_str"
+        );
+        assert_eq!(demangle("_start"), "_start");
+        assert_eq!(demangle("_ZN99tooshortE"), "_ZN99tooshortE");
+    }
+
+    #[test]
+    fn read_own_symbols() {
+        // Test binaries keep their symbol tables, so use this one as input
+        let elf = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let symbols = read(&elf).unwrap();
+        assert!(symbols
+            .iter()
+            .any(|s| s.name.ends_with("symbols::test::read_own_symbols")));
+        let data = encode(symbols);
+        assert!(!Table::parse(&data).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+//! Fixed ACPI Description Table
+use super::{read_u16, read_u32, read_u64, AcpiError, GenericAddress, Sdt};
+
+/// Length of the ACPI 1.0 table, the shortest that can be parsed
+const MIN_LEN: usize = 116;
+
+/// Boot architecture flags
+pub const BOOT_LEGACY_DEVICES: u16 = 1 << 0;
+pub const BOOT_8042: u16 = 1 << 1;
+pub const BOOT_NO_VGA: u16 = 1 << 2;
+pub const BOOT_NO_MSI: u16 = 1 << 3;
+pub const BOOT_NO_CMOS_RTC: u16 = 1 << 5;
+
+/// Fixed feature flags
+pub const FLAG_WBINVD: u32 = 1 << 0;
+pub const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+pub const FLAG_HW_REDUCED_ACPI: u32 = 1 << 20;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Fadt {
+    sdt: Sdt,
+}
+
+impl Fadt {
+    pub fn new(sdt: Sdt) -> Result<Fadt, AcpiError> {
+        sdt.require(MIN_LEN)?;
+        Ok(Fadt { sdt })
+    }
+
+    fn bytes(&self) -> &'static [u8] {
+        self.sdt.bytes()
+    }
+
+    /// Returns true if the table is long enough to contain the field at
+    /// `offset` of `len` bytes, which was added in a later revision
+    fn has(&self, offset: usize, len: usize) -> bool {
+        self.sdt.len() >= offset + len
+    }
+
+    /// Physical address of the Firmware ACPI Control Structure
+    pub fn facs(&self) -> u64 {
+        if self.has(132, 8) && read_u64(self.bytes(), 132) != 0 {
+            read_u64(self.bytes(), 132)
+        } else {
+            read_u32(self.bytes(), 36) as u64
+        }
+    }
+
+    /// Physical address of the Differentiated System Description Table
+    pub fn dsdt(&self) -> u64 {
+        if self.has(140, 8) && read_u64(self.bytes(), 140) != 0 {
+            read_u64(self.bytes(), 140)
+        } else {
+            read_u32(self.bytes(), 40) as u64
+        }
+    }
+
+    pub fn preferred_pm_profile(&self) -> u8 {
+        self.bytes()[45]
+    }
+
+    /// The ISA IRQ that the system control interrupt is wired to
+    pub fn sci_interrupt(&self) -> u16 {
+        read_u16(self.bytes(), 46)
+    }
+
+    /// I/O port that `acpi_enable` and `acpi_disable` are written to, or 0
+    /// if the system is always in ACPI mode
+    pub fn smi_command(&self) -> u32 {
+        read_u32(self.bytes(), 48)
+    }
+
+    pub fn acpi_enable(&self) -> u8 {
+        self.bytes()[52]
+    }
+
+    pub fn acpi_disable(&self) -> u8 {
+        self.bytes()[53]
+    }
+
+    pub fn pm1a_event_block(&self) -> u32 {
+        read_u32(self.bytes(), 56)
+    }
+
+    pub fn pm1a_control_block(&self) -> u32 {
+        read_u32(self.bytes(), 64)
+    }
+
+    /// I/O port of the ACPI power management timer, or 0 if there is none
+    pub fn pm_timer_block(&self) -> u32 {
+        read_u32(self.bytes(), 76)
+    }
+
+    /// The power management timer is 32 bits wide, rather than 24
+    pub fn pm_timer_32bit(&self) -> bool {
+        self.flags() & (1 << 8) != 0
+    }
+
+    /// Index of the century register in CMOS RAM, or 0 if there is none
+    pub fn century(&self) -> u8 {
+        self.bytes()[108]
+    }
+
+    /// IA-PC boot architecture flags, which are 0 before ACPI 2.0
+    pub fn boot_flags(&self) -> u16 {
+        if self.sdt.revision() >= 2 {
+            read_u16(self.bytes(), 109)
+        } else {
+            0
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        read_u32(self.bytes(), 112)
+    }
+
+    /// Register to write [`Fadt::reset_value`] to in order to reset the
+    /// system, if supported
+    pub fn reset_register(&self) -> Option<GenericAddress> {
+        if self.has(116, 13) && self.flags() & FLAG_RESET_REG_SUP != 0 {
+            Some(GenericAddress::parse(self.bytes(), 116))
+        } else {
+            None
+        }
+    }
+
+    pub fn reset_value(&self) -> u8 {
+        if self.has(128, 1) {
+            self.bytes()[128]
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::{q35, Q35_TABLES};
+    use super::super::Tables;
+    use super::*;
+
+    #[test]
+    fn q35_fadt() {
+        let tables = Tables::new(&q35()).unwrap();
+        let fadt = tables.fadt().unwrap();
+        assert_eq!(fadt.facs(), Q35_TABLES as u64);
+        assert_eq!(fadt.dsdt(), Q35_TABLES as u64 + 0x40);
+        assert_eq!(fadt.sci_interrupt(), 9);
+        assert_eq!(fadt.smi_command(), 0xB2);
+        assert_eq!((fadt.acpi_enable(), fadt.acpi_disable()), (2, 3));
+        assert_eq!(fadt.pm_timer_block(), 0x608);
+        assert!(!fadt.pm_timer_32bit());
+        assert_eq!(fadt.century(), 0x32);
+        assert_eq!(fadt.boot_flags(), BOOT_8042);
+
+        let reset = fadt.reset_register().unwrap();
+        assert_eq!(reset.address_space, GenericAddress::SYSTEM_IO);
+        assert_eq!(reset.address, 0xCF9);
+        assert_eq!(fadt.reset_value(), 0x0F);
+    }
+}
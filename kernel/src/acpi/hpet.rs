@@ -0,0 +1,67 @@
+//! High Precision Event Timer description table
+use super::{read_u16, read_u32, AcpiError, GenericAddress, Sdt, HEADER_SIZE};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Hpet {
+    sdt: Sdt,
+}
+
+impl Hpet {
+    pub fn new(sdt: Sdt) -> Result<Hpet, AcpiError> {
+        sdt.require(HEADER_SIZE + 20)?;
+        Ok(Hpet { sdt })
+    }
+
+    fn block_id(&self) -> u32 {
+        read_u32(self.sdt.bytes(), HEADER_SIZE)
+    }
+
+    /// PCI vendor ID of the timer block
+    pub fn vendor_id(&self) -> u16 {
+        (self.block_id() >> 16) as u16
+    }
+
+    /// Number of comparators in the timer block
+    pub fn comparators(&self) -> u8 {
+        ((self.block_id() >> 8) & 0x1F) as u8 + 1
+    }
+
+    /// The main counter is 64 bits wide
+    pub fn counter_64bit(&self) -> bool {
+        self.block_id() & (1 << 13) != 0
+    }
+
+    /// Location of the timer block registers
+    pub fn base(&self) -> GenericAddress {
+        GenericAddress::parse(self.sdt.bytes(), HEADER_SIZE + 4)
+    }
+
+    /// Sequence number of this timer block
+    pub fn number(&self) -> u8 {
+        self.sdt.bytes()[HEADER_SIZE + 16]
+    }
+
+    /// Minimum main counter period, in ticks, for periodic mode
+    pub fn minimum_tick(&self) -> u16 {
+        read_u16(self.sdt.bytes(), HEADER_SIZE + 17)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::q35;
+    use super::super::Tables;
+    use super::*;
+
+    #[test]
+    fn q35_hpet() {
+        let tables = Tables::new(&q35()).unwrap();
+        let hpet = tables.hpet().unwrap();
+        assert_eq!(hpet.vendor_id(), 0x8086);
+        assert_eq!(hpet.comparators(), 3);
+        assert!(hpet.counter_64bit());
+        assert_eq!(hpet.base().address_space, GenericAddress::SYSTEM_MEMORY);
+        assert_eq!(hpet.base().address, 0xFED0_0000);
+        assert_eq!(hpet.number(), 0);
+    }
+}
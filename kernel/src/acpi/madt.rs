@@ -0,0 +1,303 @@
+//! Multiple APIC Description Table
+use super::{read_u16, read_u32, read_u64, AcpiError, Sdt, HEADER_SIZE};
+
+/// Offset of the first interrupt controller structure
+const ENTRIES: usize = HEADER_SIZE + 8;
+
+/// Polarity of an interrupt input, from the MPS INTI flags
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Polarity {
+    /// Conforms to the specification of the bus
+    Conforming,
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an interrupt input, from the MPS INTI flags
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Trigger {
+    /// Conforms to the specification of the bus
+    Conforming,
+    Edge,
+    Level,
+}
+
+fn polarity(flags: u16) -> Polarity {
+    match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::Conforming,
+    }
+}
+
+fn trigger(flags: u16) -> Trigger {
+    match (flags >> 2) & 0b11 {
+        0b01 => Trigger::Edge,
+        0b11 => Trigger::Level,
+        _ => Trigger::Conforming,
+    }
+}
+
+/// A processor, described by either a local APIC or a local x2APIC entry
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Processor {
+    /// ACPI processor UID
+    pub uid: u32,
+    pub apic_id: u32,
+    flags: u32,
+}
+
+impl Processor {
+    /// The processor is ready to use
+    pub fn enabled(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    /// The processor is disabled, but can be brought online at runtime
+    pub fn online_capable(&self) -> bool {
+        self.flags & 2 != 0
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct IoApic {
+    pub id: u8,
+    /// Physical address of the I/O APIC registers
+    pub address: u32,
+    /// First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// Describes an ISA IRQ that is connected to a different global system
+/// interrupt, or that uses non-standard signalling
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn polarity(&self) -> Polarity {
+        polarity(self.flags)
+    }
+
+    pub fn trigger(&self) -> Trigger {
+        trigger(self.flags)
+    }
+}
+
+/// A local APIC LINT input that is connected to NMI
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LocalApicNmi {
+    /// ACPI processor UID, or 0xFF for every processor
+    pub uid: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+impl LocalApicNmi {
+    pub fn polarity(&self) -> Polarity {
+        polarity(self.flags)
+    }
+
+    pub fn trigger(&self) -> Trigger {
+        trigger(self.flags)
+    }
+}
+
+/// An interrupt controller structure from the MADT
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Entry {
+    Processor(Processor),
+    IoApic(IoApic),
+    InterruptOverride(InterruptOverride),
+    LocalApicNmi(LocalApicNmi),
+    /// 64-bit local APIC address, replacing the one in the table header
+    LocalApicAddress(u64),
+    /// A structure type that is not parsed
+    Unknown(u8),
+}
+
+/// Iterator over the interrupt controller structures of a [`Madt`]
+pub struct Entries {
+    bytes: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let b = self.bytes.get(self.offset..)?;
+        if b.len() < 2 {
+            return None;
+        }
+        let (ty, len) = (b[0], b[1] as usize);
+        if len < 2 || len > b.len() {
+            // A malformed length would send us off into the weeds
+            return None;
+        }
+        self.offset += len;
+
+        let entry = match (ty, len) {
+            (0, 8) => Entry::Processor(Processor {
+                uid: b[2] as u32,
+                apic_id: b[3] as u32,
+                flags: read_u32(b, 4),
+            }),
+            (1, 12) => Entry::IoApic(IoApic {
+                id: b[2],
+                address: read_u32(b, 4),
+                gsi_base: read_u32(b, 8),
+            }),
+            (2, 10) => Entry::InterruptOverride(InterruptOverride {
+                bus: b[2],
+                source: b[3],
+                gsi: read_u32(b, 4),
+                flags: read_u16(b, 8),
+            }),
+            (4, 6) => Entry::LocalApicNmi(LocalApicNmi {
+                uid: b[2],
+                flags: read_u16(b, 3),
+                lint: b[5],
+            }),
+            (5, 12) => Entry::LocalApicAddress(read_u64(b, 4)),
+            (9, 16) => Entry::Processor(Processor {
+                uid: read_u32(b, 12),
+                apic_id: read_u32(b, 4),
+                flags: read_u32(b, 8),
+            }),
+            _ => Entry::Unknown(ty),
+        };
+        Some(entry)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Madt {
+    sdt: Sdt,
+}
+
+impl Madt {
+    pub fn new(sdt: Sdt) -> Result<Madt, AcpiError> {
+        sdt.require(ENTRIES)?;
+        Ok(Madt { sdt })
+    }
+
+    /// Physical address of the local APIC registers
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .filter_map(|e| match e {
+                Entry::LocalApicAddress(addr) => Some(addr),
+                _ => None,
+            })
+            .next()
+            .unwrap_or(read_u32(self.sdt.bytes(), HEADER_SIZE) as u64)
+    }
+
+    /// The system also has dual 8259s, which must be masked when using
+    /// the APICs
+    pub fn pcat_compatible(&self) -> bool {
+        read_u32(self.sdt.bytes(), HEADER_SIZE + 4) & 1 != 0
+    }
+
+    pub fn entries(&self) -> Entries {
+        Entries {
+            bytes: self.sdt.bytes(),
+            offset: ENTRIES,
+        }
+    }
+
+    /// Every processor that is enabled or can be brought online
+    pub fn processors(&self) -> impl Iterator<Item = Processor> {
+        self.entries().filter_map(|e| match e {
+            Entry::Processor(p) if p.enabled() || p.online_capable() => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApic> {
+        self.entries().filter_map(|e| match e {
+            Entry::IoApic(io) => Some(io),
+            _ => None,
+        })
+    }
+
+    pub fn overrides(&self) -> impl Iterator<Item = InterruptOverride> {
+        self.entries().filter_map(|e| match e {
+            Entry::InterruptOverride(o) => Some(o),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::q35;
+    use super::super::Tables;
+    use super::*;
+
+    #[test]
+    fn q35_madt() {
+        let tables = Tables::new(&q35()).unwrap();
+        let madt = tables.madt().unwrap();
+        assert_eq!(madt.local_apic_address(), 0xFEE0_0000);
+        assert!(madt.pcat_compatible());
+
+        let cpus = madt.processors().collect::<Vec<_>>();
+        assert_eq!(cpus.len(), 2);
+        assert_eq!((cpus[1].uid, cpus[1].apic_id), (1, 1));
+        assert!(cpus.iter().all(|p| p.enabled()));
+
+        let io = madt.io_apics().collect::<Vec<_>>();
+        assert_eq!(
+            io,
+            [IoApic {
+                id: 0,
+                address: 0xFEC0_0000,
+                gsi_base: 0
+            }]
+        );
+
+        let overrides = madt.overrides().collect::<Vec<_>>();
+        assert_eq!(overrides.len(), 5);
+        assert_eq!((overrides[0].source, overrides[0].gsi), (0, 2));
+        assert_eq!(overrides[0].polarity(), Polarity::Conforming);
+        assert_eq!(overrides[1].source, 5);
+        assert_eq!(overrides[1].polarity(), Polarity::ActiveHigh);
+        assert_eq!(overrides[1].trigger(), Trigger::Level);
+
+        let nmi = madt
+            .entries()
+            .filter_map(|e| match e {
+                Entry::LocalApicNmi(nmi) => Some(nmi),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        assert_eq!((nmi.uid, nmi.lint), (0xFF, 1));
+    }
+
+    #[test]
+    fn malformed_entries() {
+        let mut table = vec![0u8; ENTRIES];
+        table[..4].copy_from_slice(b"APIC");
+        // x2APIC, an unknown type, then an entry with a bad length
+        table.extend_from_slice(&[9, 16, 0, 0, 4, 1, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0]);
+        table.extend_from_slice(&[0x7F, 3, 0]);
+        table.extend_from_slice(&[1, 40, 0, 0]);
+        let sdt = Sdt {
+            bytes: Box::leak(table.into_boxed_slice()),
+        };
+
+        let entries = Madt::new(sdt).unwrap().entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        match entries[0] {
+            Entry::Processor(p) => assert_eq!((p.uid, p.apic_id), (7, 0x104)),
+            e => panic!("unexpected {:?}", e),
+        }
+        assert_eq!(entries[1], Entry::Unknown(0x7F));
+    }
+}
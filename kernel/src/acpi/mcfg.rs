@@ -0,0 +1,75 @@
+//! PCI Express memory mapped configuration space table
+use super::{read_u16, read_u64, AcpiError, Sdt, HEADER_SIZE};
+
+/// Offset of the first allocation entry, after 8 reserved bytes
+const ENTRIES: usize = HEADER_SIZE + 8;
+const ENTRY_SIZE: usize = 16;
+
+/// Enhanced configuration space for a range of buses in one PCI segment
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Allocation {
+    /// Physical address of the configuration space of bus 0, even if
+    /// `start_bus` is greater than 0
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Allocation {
+    /// Physical address of the 4 KiB configuration space for a function
+    pub fn address(&self, bus: u8, device: u8, function: u8) -> Option<u64> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = (bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12;
+        Some(self.base + offset)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Mcfg {
+    sdt: Sdt,
+}
+
+impl Mcfg {
+    pub fn new(sdt: Sdt) -> Result<Mcfg, AcpiError> {
+        sdt.require(ENTRIES)?;
+        Ok(Mcfg { sdt })
+    }
+
+    pub fn allocations(&self) -> impl Iterator<Item = Allocation> {
+        self.sdt.bytes()[ENTRIES..]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|b| Allocation {
+                base: read_u64(b, 0),
+                segment: read_u16(b, 8),
+                start_bus: b[10],
+                end_bus: b[11],
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::q35;
+    use super::super::Tables;
+    use super::*;
+
+    #[test]
+    fn q35_mcfg() {
+        let tables = Tables::new(&q35()).unwrap();
+        let alloc = tables.mcfg().unwrap().allocations().collect::<Vec<_>>();
+        assert_eq!(
+            alloc,
+            [Allocation {
+                base: 0xB000_0000,
+                segment: 0,
+                start_bus: 0,
+                end_bus: 0xFF
+            }]
+        );
+        assert_eq!(alloc[0].address(0, 0x1F, 3), Some(0xB00F_B000));
+        assert_eq!(alloc[0].address(1, 32, 0), None);
+    }
+}
@@ -0,0 +1,535 @@
+//! ACPI table discovery and parsing
+//!
+//! Firmware memory is only ever reached through a [`PhysicalMemory`]
+//! implementation, so that the parser can run on the host against dumps of
+//! the tables as well as in the kernel.
+use alloc::vec::Vec;
+use core::fmt;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::Madt;
+pub use mcfg::Mcfg;
+
+/// Size, in bytes, of the header shared by every system description table
+pub const HEADER_SIZE: usize = 36;
+
+/// Physical address of the BIOS data area word holding the EBDA segment
+const EBDA_POINTER: usize = 0x40E;
+/// Physical range of the BIOS read-only memory area searched for the RSDP
+const BIOS_AREA: (usize, usize) = (0xE0000, 0x20000);
+
+/// Read access to physical memory
+pub trait PhysicalMemory {
+    /// Return the `len` bytes of physical memory starting at `phys`, or
+    /// `None` if they cannot be accessed
+    fn map(&self, phys: usize, len: usize) -> Option<&'static [u8]>;
+}
+
+/// Errors that can occur while reading the ACPI tables
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AcpiError {
+    /// No valid RSDP was found in the EBDA or the BIOS area
+    NoRsdp,
+    /// The bytes of a table do not sum to zero
+    Checksum(Signature),
+    /// A table is too short to hold its header or fixed fields
+    Truncated(Signature),
+    /// The memory at a table's address could not be accessed
+    Unmapped(usize),
+}
+
+/// Four character table signature
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Signature(pub [u8; 4]);
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", core::str::from_utf8(&self.0).unwrap_or("????"))
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+/// Returns true if `bytes` sum to zero, modulo 256
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Root System Description Pointer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// Physical address of the RSDT
+    pub rsdt: u32,
+    /// Physical address of the XSDT, for ACPI 2.0 and later
+    pub xsdt: Option<u64>,
+}
+
+impl Rsdp {
+    /// Parse and validate an RSDP at the start of `bytes`
+    pub fn parse(bytes: &[u8]) -> Option<Rsdp> {
+        if bytes.len() < 20 || &bytes[..8] != b"RSD PTR " || !checksum(&bytes[..20]) {
+            return None;
+        }
+        let mut oem_id = [0; 6];
+        oem_id.copy_from_slice(&bytes[9..15]);
+        let revision = bytes[15];
+
+        let xsdt = if revision >= 2 {
+            if bytes.len() < 36 || !checksum(&bytes[..36]) {
+                return None;
+            }
+            Some(read_u64(bytes, 24)).filter(|&addr| addr != 0)
+        } else {
+            None
+        };
+
+        Some(Rsdp {
+            revision,
+            oem_id,
+            rsdt: read_u32(bytes, 16),
+            xsdt,
+        })
+    }
+
+    /// Search the first KiB of the extended BIOS data area, and then the
+    /// BIOS area from 0xE0000 to 0xFFFFF, for the RSDP. It is always on a
+    /// 16 byte boundary
+    pub fn search<M: PhysicalMemory>(mem: &M) -> Result<Rsdp, AcpiError> {
+        let ebda = mem
+            .map(EBDA_POINTER, 2)
+            .map(|ptr| (read_u16(ptr, 0) as usize) << 4)
+            .filter(|&ebda| ebda != 0)
+            .map(|ebda| (ebda, 0x400));
+
+        for &(start, len) in [ebda, Some(BIOS_AREA)].iter().flatten() {
+            let area = match mem.map(start, len) {
+                Some(area) => area,
+                None => continue,
+            };
+            let found = (0..area.len().saturating_sub(19))
+                .step_by(16)
+                .filter_map(|offset| Rsdp::parse(&area[offset..]))
+                .next();
+            if let Some(rsdp) = found {
+                return Ok(rsdp);
+            }
+        }
+        Err(AcpiError::NoRsdp)
+    }
+}
+
+/// A system description table, including its header
+#[derive(Copy, Clone)]
+pub struct Sdt {
+    bytes: &'static [u8],
+}
+
+impl Sdt {
+    /// Read and validate the table at `phys`
+    pub fn load<M: PhysicalMemory>(mem: &M, phys: usize) -> Result<Sdt, AcpiError> {
+        let header = mem
+            .map(phys, HEADER_SIZE)
+            .ok_or(AcpiError::Unmapped(phys))?;
+        let sdt = Sdt { bytes: header };
+        let len = read_u32(header, 4) as usize;
+        if len < HEADER_SIZE {
+            return Err(AcpiError::Truncated(sdt.signature()));
+        }
+
+        let bytes = mem.map(phys, len).ok_or(AcpiError::Unmapped(phys))?;
+        if !checksum(bytes) {
+            return Err(AcpiError::Checksum(sdt.signature()));
+        }
+        Ok(Sdt { bytes })
+    }
+
+    pub fn signature(&self) -> Signature {
+        let mut sig = [0; 4];
+        sig.copy_from_slice(&self.bytes[..4]);
+        Signature(sig)
+    }
+
+    /// Length of the table in bytes, including the header
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    pub fn oem_id(&self) -> &'static [u8] {
+        &self.bytes[10..16]
+    }
+
+    pub fn oem_table_id(&self) -> &'static [u8] {
+        &self.bytes[16..24]
+    }
+
+    /// The table contents following the header
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes[HEADER_SIZE..]
+    }
+
+    /// The whole table, including the header
+    pub fn bytes(&self) -> &'static [u8] {
+        self.bytes
+    }
+
+    /// Return an error unless the table holds at least `len` bytes
+    fn require(&self, len: usize) -> Result<(), AcpiError> {
+        if self.len() < len {
+            Err(AcpiError::Truncated(self.signature()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Debug for Sdt {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{:?} rev {} len {} oem {}",
+            self.signature(),
+            self.revision(),
+            self.len(),
+            core::str::from_utf8(self.oem_id()).unwrap_or("?")
+        )
+    }
+}
+
+/// Generic Address Structure, describing a register in memory, I/O or
+/// PCI configuration space
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+
+    fn parse(bytes: &[u8], offset: usize) -> GenericAddress {
+        GenericAddress {
+            address_space: bytes[offset],
+            bit_width: bytes[offset + 1],
+            bit_offset: bytes[offset + 2],
+            access_size: bytes[offset + 3],
+            address: read_u64(bytes, offset + 4),
+        }
+    }
+}
+
+/// Every valid table referenced by the RSDT or XSDT
+pub struct Tables {
+    pub rsdp: Rsdp,
+    tables: Vec<Sdt>,
+    errors: Vec<AcpiError>,
+}
+
+impl Tables {
+    /// Find the RSDP and load every table that the root table points to.
+    /// Tables that fail validation are skipped, and recorded in
+    /// [`Tables::errors`]
+    pub fn new<M: PhysicalMemory>(mem: &M) -> Result<Tables, AcpiError> {
+        let rsdp = Rsdp::search(mem)?;
+        let (root, entry_size) = match rsdp.xsdt {
+            Some(xsdt) => (Sdt::load(mem, xsdt as usize)?, 8),
+            None => (Sdt::load(mem, rsdp.rsdt as usize)?, 4),
+        };
+
+        let mut tables = Vec::new();
+        let mut errors = Vec::new();
+        for entry in root.data().chunks_exact(entry_size) {
+            let phys = if entry_size == 8 {
+                read_u64(entry, 0) as usize
+            } else {
+                read_u32(entry, 0) as usize
+            };
+            match Sdt::load(mem, phys) {
+                Ok(table) => tables.push(table),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        Ok(Tables {
+            rsdp,
+            tables,
+            errors,
+        })
+    }
+
+    /// Return the first table with signature `sig`
+    pub fn find(&self, sig: &[u8; 4]) -> Option<Sdt> {
+        self.tables.iter().find(|t| &t.signature().0 == sig).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Sdt> {
+        self.tables.iter()
+    }
+
+    /// Tables that were referenced by the root table but failed validation
+    pub fn errors(&self) -> &[AcpiError] {
+        &self.errors
+    }
+
+    pub fn madt(&self) -> Option<Madt> {
+        self.find(b"APIC").and_then(|t| Madt::new(t).ok())
+    }
+
+    pub fn fadt(&self) -> Option<Fadt> {
+        self.find(b"FACP").and_then(|t| Fadt::new(t).ok())
+    }
+
+    pub fn hpet(&self) -> Option<Hpet> {
+        self.find(b"HPET").and_then(|t| Hpet::new(t).ok())
+    }
+
+    pub fn mcfg(&self) -> Option<Mcfg> {
+        self.find(b"MCFG").and_then(|t| Mcfg::new(t).ok())
+    }
+}
+
+impl fmt::Display for Tables {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "ACPI {}.0 ({}):",
+            if self.rsdp.revision >= 2 { 2 } else { 1 },
+            core::str::from_utf8(&self.rsdp.oem_id).unwrap_or("?")
+        )?;
+        for table in &self.tables {
+            write!(fmt, " {:?}", table.signature())?;
+        }
+        for err in &self.errors {
+            write!(fmt, " ({:?})", err)?;
+        }
+        Ok(())
+    }
+}
+
+/// Firmware memory as seen by the kernel. The first 4 MiB are reached
+/// through the boot mapping, and anything above is mapped into the MMIO
+/// window the first time it is read. MMIO mappings are never removed, so
+/// the pages mapped are remembered and reused for later reads
+#[cfg(not(test))]
+#[derive(Default)]
+pub struct Firmware {
+    /// Page aligned physical ranges, as `(start, end, virtual start)`
+    mapped: core::cell::RefCell<Vec<(usize, usize, usize)>>,
+}
+
+#[cfg(not(test))]
+impl PhysicalMemory for Firmware {
+    fn map(&self, phys: usize, len: usize) -> Option<&'static [u8]> {
+        use crate::memory::{mmio, KERNEL_VIRT};
+        use crate::paging::{Physical, PAGE_SIZE};
+
+        let end = phys.checked_add(len)?;
+        let addr = if end <= 0x40_0000 {
+            KERNEL_VIRT + phys
+        } else {
+            let mut mapped = self.mapped.borrow_mut();
+            let cached = mapped
+                .iter()
+                .find(|&&(start, stop, _)| phys >= start && end <= stop)
+                .map(|&(start, _, virt)| virt + phys - start);
+            match cached {
+                Some(addr) => addr,
+                None => {
+                    let start = phys & !(PAGE_SIZE - 1);
+                    let stop = end.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+                    let virt = mmio::map(Physical::new(start), stop - start).ok()?;
+                    mapped.push((start, stop, virt.as_usize()));
+                    virt.as_usize() + phys - start
+                }
+            }
+        };
+        Some(unsafe { core::slice::from_raw_parts(addr as *const u8, len) })
+    }
+}
+
+static TABLES: crate::sync::Once<Tables> = crate::sync::Once::new();
+
+/// Read the firmware's ACPI tables
+#[cfg(not(test))]
+pub fn init() -> Result<&'static Tables, AcpiError> {
+    let tables = Tables::new(&Firmware::default())?;
+    Ok(TABLES.call_once(|| tables))
+}
+
+/// Return the ACPI tables, if [`init`] found them
+pub fn tables() -> Option<&'static Tables> {
+    TABLES.try_get()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Physical memory made up of a few fixed regions
+    pub struct Image {
+        regions: Vec<(usize, &'static [u8])>,
+    }
+
+    impl Image {
+        pub fn new() -> Image {
+            Image {
+                regions: Vec::new(),
+            }
+        }
+
+        pub fn add(&mut self, phys: usize, bytes: &[u8]) {
+            let bytes = Box::leak(bytes.to_vec().into_boxed_slice());
+            self.regions.push((phys, bytes));
+        }
+    }
+
+    impl PhysicalMemory for Image {
+        fn map(&self, phys: usize, len: usize) -> Option<&'static [u8]> {
+            self.regions.iter().find_map(|&(base, bytes)| {
+                if phys >= base && phys + len <= base + bytes.len() {
+                    Some(&bytes[phys - base..phys - base + len])
+                } else {
+                    None
+                }
+            })
+        }
+    }
+
+    /// Tables laid out as QEMU builds them for `-machine q35 -smp 2`, with
+    /// the RSDP in the BIOS area and the tables near the top of 128 MiB
+    pub const Q35_RSDP: usize = 0xF5A10;
+    pub const Q35_TABLES: usize = 0x07FE_0000;
+
+    pub fn q35() -> Image {
+        let mut bios = vec![0u8; BIOS_AREA.1];
+        let rsdp = include_bytes!("testdata/q35-rsdp.bin");
+        let offset = Q35_RSDP - BIOS_AREA.0;
+        bios[offset..offset + rsdp.len()].copy_from_slice(rsdp);
+
+        let mut image = Image::new();
+        image.add(BIOS_AREA.0, &bios);
+        image.add(Q35_TABLES, include_bytes!("testdata/q35-tables.bin"));
+        image
+    }
+
+    #[test]
+    fn discover() {
+        let tables = Tables::new(&q35()).unwrap();
+        assert_eq!(tables.rsdp.revision, 0);
+        assert_eq!(&tables.rsdp.oem_id, b"BOCHS ");
+        assert_eq!(tables.rsdp.xsdt, None);
+
+        let sigs = tables.iter().map(|t| t.signature()).collect::<Vec<_>>();
+        let expected = [b"FACP", b"APIC", b"HPET", b"MCFG", b"WAET"];
+        assert_eq!(sigs.len(), expected.len());
+        for (sig, exp) in sigs.iter().zip(expected.iter()) {
+            assert_eq!(&sig.0, *exp);
+        }
+        assert!(tables.errors().is_empty());
+        assert_eq!(tables.find(b"APIC").unwrap().oem_table_id(), b"BXPC    ");
+        assert_eq!(
+            format!("{}", tables),
+            "ACPI 1.0 (BOCHS ): FACP APIC HPET MCFG WAET"
+        );
+    }
+
+    #[test]
+    fn search_ebda() {
+        let mut image = q35();
+        let rsdp = include_bytes!("testdata/q35-rsdp.bin");
+        // EBDA at 0x9FC00, which takes priority over the BIOS area
+        image.add(EBDA_POINTER, &[0xC0, 0x9F]);
+        let mut ebda = vec![0u8; 0x400];
+        ebda[0x40..0x40 + rsdp.len()].copy_from_slice(rsdp);
+        ebda[0x40 + 9] = b'E';
+        ebda[0x40 + 8] = ebda[0x40 + 8].wrapping_sub(b'E' - b'B');
+        image.add(0x9FC00, &ebda);
+
+        let rsdp = Rsdp::search(&image).unwrap();
+        assert_eq!(&rsdp.oem_id, b"EOCHS ");
+        assert_eq!(Rsdp::search(&Image::new()), Err(AcpiError::NoRsdp));
+    }
+
+    #[test]
+    fn checksums() {
+        let rsdp = include_bytes!("testdata/q35-rsdp.bin");
+        let mut bad = rsdp.to_vec();
+        bad[12] ^= 1;
+        assert!(Rsdp::parse(rsdp).is_some());
+        assert!(Rsdp::parse(&bad).is_none());
+
+        // Corrupt the HPET, which should be skipped but not fail the rest
+        let mut image = q35();
+        let mut tables = include_bytes!("testdata/q35-tables.bin").to_vec();
+        tables[0x200 + HEADER_SIZE] ^= 0xFF;
+        image.regions[1] = (Q35_TABLES, Box::leak(tables.into_boxed_slice()));
+
+        let tables = Tables::new(&image).unwrap();
+        assert_eq!(tables.errors(), &[AcpiError::Checksum(Signature(*b"HPET"))]);
+        assert!(tables.hpet().is_none());
+        assert!(tables.madt().is_some());
+    }
+
+    #[test]
+    fn xsdt() {
+        // An ACPI 2.0 RSDP pointing at an XSDT with the same tables
+        let rsdt = Sdt::load(&q35(), 0x07FE_02C0).unwrap();
+        let mut xsdt = b"XSDT".to_vec();
+        xsdt.extend_from_slice(&(HEADER_SIZE as u32 + 5 * 8).to_le_bytes());
+        xsdt.extend_from_slice(&rsdt.bytes()[8..HEADER_SIZE]);
+        for entry in rsdt.data().chunks(4) {
+            xsdt.extend_from_slice(&(read_u32(entry, 0) as u64).to_le_bytes());
+        }
+        xsdt[9] = 0;
+        xsdt[9] = 0u8.wrapping_sub(xsdt.iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+
+        let mut rsdp = include_bytes!("testdata/q35-rsdp.bin").to_vec();
+        rsdp[15] = 2;
+        rsdp.extend_from_slice(&36u32.to_le_bytes());
+        rsdp.extend_from_slice(&0x1000u64.to_le_bytes());
+        rsdp.extend_from_slice(&[0; 4]);
+        rsdp[8] = 0;
+        rsdp[8] = 0u8.wrapping_sub(rsdp[..20].iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+        rsdp[32] = 0u8.wrapping_sub(rsdp[..36].iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+
+        let mut image = q35();
+        let mut bios = vec![0u8; BIOS_AREA.1];
+        bios[0x100..0x100 + rsdp.len()].copy_from_slice(&rsdp);
+        image.regions[0] = (BIOS_AREA.0, Box::leak(bios.into_boxed_slice()));
+        image.add(0x1000, &xsdt);
+
+        let tables = Tables::new(&image).unwrap();
+        assert_eq!(tables.rsdp.xsdt, Some(0x1000));
+        assert_eq!(tables.iter().count(), 5);
+        assert!(tables.fadt().is_some());
+    }
+}
@@ -0,0 +1,149 @@
+//! Switching between kernel threads
+//!
+//! A thread that is not running keeps its callee-saved registers and stack
+//! pointer in a [`Context`]. Everything else, including the instruction
+//! pointer, is on its stack: [`switch`] is an ordinary function call that
+//! returns in whichever thread is switched back to.
+//!
+//! The FS base is switched too, since user code keeps its thread pointer
+//! there, and so are the x87 and SSE registers. The kernel is built without
+//! floating point, so only user code uses those. Each thread starts out
+//! with an FS base of 0 and the registers in their initial state.
+use super::instructions;
+use super::interrupts::Preserved;
+
+const IA32_FS_BASE: u32 = 0xC000_0100;
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+
+global_asm!(
+    r#"
+.global context_switch
+context_switch:
+    mov %r15, 0x00(%rdi)
+    mov %r14, 0x08(%rdi)
+    mov %r13, 0x10(%rdi)
+    mov %r12, 0x18(%rdi)
+    mov %rbp, 0x20(%rdi)
+    mov %rbx, 0x28(%rdi)
+    mov %rsp, 0x30(%rdi)
+
+    mov 0x00(%rsi), %r15
+    mov 0x08(%rsi), %r14
+    mov 0x10(%rsi), %r13
+    mov 0x18(%rsi), %r12
+    mov 0x20(%rsi), %rbp
+    mov 0x28(%rsi), %rbx
+    mov 0x30(%rsi), %rsp
+    ret
+"#
+);
+
+extern "C" {
+    fn context_switch(prev: *mut Context, next: *const Context);
+}
+
+/// The x87, MMX and SSE registers, as `fxsave` stores them
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl Default for FpuState {
+    /// The state after `fninit`, with every exception masked
+    fn default() -> FpuState {
+        let mut area = [0u8; 512];
+        // x87 control word, then MXCSR
+        area[0..2].copy_from_slice(&0x037Fu16.to_ne_bytes());
+        area[24..28].copy_from_slice(&0x1F80u32.to_ne_bytes());
+        FpuState(area)
+    }
+}
+
+impl core::fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("FpuState").finish()
+    }
+}
+
+/// Registers of a thread that is not running
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct Context {
+    pub preserved: Preserved,
+    pub rsp: usize,
+    pub fs_base: usize,
+    pub fpu: FpuState,
+}
+
+impl Context {
+    /// Set up `stack` so that switching to the context calls `entry`, which
+    /// must never return. The stack is used from its highest address
+    pub fn new(stack: &mut [u8], entry: extern "C" fn() -> !) -> Context {
+        let top = (stack.as_mut_ptr() as usize + stack.len()) & !0xF;
+        // `switch` returns into `entry` as if it had been called, with a
+        // null return address to end backtraces
+        let rsp = top - 16;
+        unsafe {
+            *((top - 8) as *mut usize) = 0;
+            *(rsp as *mut usize) = entry as usize;
+        }
+        Context {
+            preserved: Preserved::default(),
+            rsp,
+            fs_base: 0,
+            fpu: FpuState::default(),
+        }
+    }
+}
+
+/// Set the stack that the processor switches to when an interrupt or
+/// system call arrives from ring 3. Each thread that runs user code needs
+/// its own
+pub fn set_kernel_stack(top: usize) {
+    super::percpu::current_cpu().set_kernel_stack(top)
+}
+
+/// Save the calling thread's registers in `prev`, and continue the thread
+/// described by `next`. Returns when another thread switches back to `prev`
+///
+/// # Safety
+///
+/// `next` must have been saved by a previous `switch`, or created by
+/// [`Context::new`] with a stack that is still allocated. Interrupts
+/// should be disabled, and no locks held
+pub unsafe fn switch(prev: *mut Context, next: *const Context) {
+    (*prev).fs_base = fs_base();
+    set_fs_base((*next).fs_base);
+    instructions::fxsave((*prev).fpu.0.as_mut_ptr());
+    instructions::fxrstor((*next).fpu.0.as_ptr());
+    context_switch(prev, next)
+}
+
+/// Enable the x87 FPU and SSE on the calling processor, for user code
+pub fn init() {
+    unsafe {
+        let cr0 = instructions::cr0() & !CR0_EM;
+        instructions::set_cr0(cr0 | CR0_MP);
+        let cr4 = instructions::cr4();
+        instructions::set_cr4(cr4 | CR4_OSFXSR | CR4_OSXMMEXCPT);
+    }
+}
+
+/// Put the calling thread's x87 and SSE registers in their initial state
+pub fn reset_fpu() {
+    let initial = FpuState::default();
+    unsafe { instructions::fxrstor(initial.0.as_ptr()) }
+}
+
+/// Return the calling thread's FS base
+pub fn fs_base() -> usize {
+    unsafe { instructions::rdmsr(IA32_FS_BASE) as usize }
+}
+
+/// Set the calling thread's FS base, which is kept across context switches
+pub fn set_fs_base(base: usize) {
+    unsafe { instructions::wrmsr(IA32_FS_BASE, base as u64) }
+}
@@ -0,0 +1,351 @@
+//! Hardware watchpoints, using the debug registers
+//!
+//! DR0-DR3 hold the addresses of up to four watchpoints, which DR7 enables
+//! and configures. When one fires, the processor raises a debug exception
+//! and records which in DR6. Every processor has its own debug registers,
+//! so changes are programmed on the calling processor and sent to the
+//! others, and application processors load the watchpoints as they start.
+use super::devices::{self, apic, Mode};
+use super::interrupts::InterruptStack;
+use super::{instructions, percpu};
+use crate::prelude::*;
+use alloc::boxed::Box;
+
+/// Number of address registers
+pub const WATCHPOINTS: usize = 4;
+
+/// DR6: the debug exception was raised by single stepping
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+/// DR7: exact breakpoint enable, recommended whenever watchpoints are used
+const DR7_LOCAL_EXACT: u64 = 1 << 8;
+/// RFLAGS: resume flag, which suppresses instruction breakpoints for one
+/// instruction
+const RFLAGS_RF: usize = 1 << 16;
+
+/// Access that triggers a watchpoint
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Condition {
+    /// Executing the instruction at the address
+    Execute = 0b00,
+    Write = 0b01,
+    /// Reading or writing, but not executing
+    ReadWrite = 0b11,
+}
+
+/// Number of bytes covered by a watchpoint, from its address
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Length {
+    Byte = 0b00,
+    Word = 0b01,
+    Qword = 0b10,
+    Dword = 0b11,
+}
+
+impl Length {
+    pub fn bytes(self) -> usize {
+        match self {
+            Length::Byte => 1,
+            Length::Word => 2,
+            Length::Dword => 4,
+            Length::Qword => 8,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub address: usize,
+    pub length: Length,
+    pub condition: Condition,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WatchError {
+    /// All four debug registers are in use
+    Full,
+    /// The address is not aligned to the length
+    Unaligned,
+    /// Instruction watchpoints must have a length of one byte
+    InvalidLength,
+}
+
+impl Watchpoint {
+    pub fn new(address: usize, length: Length, condition: Condition) -> Watchpoint {
+        Watchpoint {
+            address,
+            length,
+            condition,
+        }
+    }
+
+    fn validate(&self) -> Result<(), WatchError> {
+        if self.condition == Condition::Execute && self.length != Length::Byte {
+            return Err(WatchError::InvalidLength);
+        }
+        if self.address % self.length.bytes() != 0 {
+            return Err(WatchError::Unaligned);
+        }
+        Ok(())
+    }
+
+    /// Return `dr7` with watchpoint `index` enabled and configured as `self`
+    fn enable(&self, dr7: u64, index: usize) -> u64 {
+        let shift = 16 + index * 4;
+        let config = (self.length as u64) << 2 | self.condition as u64;
+        let dr7 = dr7 & !(0xF << shift) | config << shift;
+        dr7 | 1 << (index * 2) | DR7_LOCAL_EXACT
+    }
+}
+
+/// Return `dr7` with watchpoint `index` disabled
+fn disable(dr7: u64, index: usize) -> u64 {
+    dr7 & !(0b11 << (index * 2)) & !(0xF << (16 + index * 4))
+}
+
+/// Return the watchpoints that fired, according to `dr6`
+fn triggered(dr6: u64) -> impl Iterator<Item = usize> {
+    (0..WATCHPOINTS).filter(move |&index| dr6 & (1 << index) != 0)
+}
+
+/// Called with the index and description of a watchpoint when it fires.
+/// Data watchpoints fire after the access, and execute watchpoints before
+/// the instruction runs
+pub type Handler = Box<dyn FnMut(usize, &Watchpoint, &mut InterruptStack) + Send>;
+
+struct Slot {
+    watch: Watchpoint,
+    handler: Option<Handler>,
+    /// The handler has been taken out to run
+    running: bool,
+}
+
+global!(Watchpoints);
+
+#[derive(Default)]
+pub struct Watchpoints {
+    slots: [Option<Slot>; WATCHPOINTS],
+}
+
+impl Watchpoints {
+    /// Claim a free slot for `watch`, returning its index
+    fn add(&mut self, watch: Watchpoint, handler: Option<Handler>) -> Result<usize, WatchError> {
+        watch.validate()?;
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(WatchError::Full)?;
+        self.slots[index] = Some(Slot {
+            watch,
+            handler,
+            running: false,
+        });
+        Ok(index)
+    }
+
+    /// Compute DR7 for the watchpoints in use
+    fn dr7(&self) -> u64 {
+        self.slots
+            .iter()
+            .enumerate()
+            .fold(0, |dr7, (index, slot)| match slot {
+                Some(slot) => slot.watch.enable(dr7, index),
+                None => disable(dr7, index),
+            })
+    }
+
+    /// Program the debug registers of the calling processor
+    fn load(&self) {
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(slot) = slot {
+                unsafe { instructions::set_dr(index, slot.watch.address) };
+            }
+        }
+        unsafe { instructions::set_dr7(self.dr7()) };
+    }
+}
+
+/// Have every other online processor load the watchpoints again. Without
+/// local APICs, there are no other processors
+fn broadcast() {
+    if devices::mode() != Mode::Apic {
+        return;
+    }
+    let this = percpu::current_cpu().id;
+    let others = (0..percpu::count())
+        .filter_map(percpu::cpu)
+        .filter(|cpu| cpu.id != this && cpu.online());
+    for cpu in others {
+        apic::local().send_fixed(cpu.apic_id, apic::DEBUG_VECTOR);
+    }
+}
+
+/// Set a watchpoint, calling `handler` when it fires. Returns the index of
+/// the debug register it uses
+pub fn watch<F>(watch: Watchpoint, handler: F) -> Result<usize, WatchError>
+where
+    F: FnMut(usize, &Watchpoint, &mut InterruptStack) + Send + 'static,
+{
+    let index = {
+        let mut watchpoints = Watchpoints::global().critical();
+        let index = watchpoints.add(watch, Some(Box::new(handler)))?;
+        watchpoints.load();
+        index
+    };
+    broadcast();
+    Ok(index)
+}
+
+/// Set a watchpoint that reports when it fires, without a handler
+pub fn watch_and_report(watch: Watchpoint) -> Result<usize, WatchError> {
+    let index = {
+        let mut watchpoints = Watchpoints::global().critical();
+        let index = watchpoints.add(watch, None)?;
+        watchpoints.load();
+        index
+    };
+    broadcast();
+    Ok(index)
+}
+
+/// Remove watchpoint `index`, returning false if it was not set. May be
+/// called from a handler, including the watchpoint's own
+pub fn clear(index: usize) -> bool {
+    {
+        let mut watchpoints = Watchpoints::global().critical();
+        match watchpoints.slots.get_mut(index) {
+            Some(slot) if slot.is_some() => *slot = None,
+            _ => return false,
+        }
+        watchpoints.load();
+    }
+    broadcast();
+    true
+}
+
+/// Load the watchpoints on an application processor, once it is online
+pub fn init_ap() {
+    Watchpoints::global().lock().load();
+}
+
+// Sent by another processor that changed the watchpoints
+interrupt!(reload, {
+    Watchpoints::global().critical().load();
+    apic::local().eoi();
+});
+
+/// Handle a debug exception, running the handlers of any watchpoints that
+/// fired. Returns true if the exception was caused by single stepping, or
+/// by nothing that was recognized
+pub fn handle(stack: &mut InterruptStack) -> bool {
+    let dr6 = instructions::dr6();
+    // The processor never clears DR6 itself
+    unsafe { instructions::set_dr6(0) };
+
+    let mut handled = false;
+    for index in triggered(dr6) {
+        // The interrupted code may hold the lock, if it was changing the
+        // watchpoints
+        let (watch, handler) = match Watchpoints::global().try_lock() {
+            // If another processor is running the handler, this hit is
+            // only reported
+            Some(mut watchpoints) => match watchpoints.slots[index].as_mut() {
+                Some(slot) => {
+                    let handler = slot.handler.take();
+                    slot.running |= handler.is_some();
+                    (slot.watch, handler)
+                }
+                None => continue,
+            },
+            None => break,
+        };
+        handled = true;
+
+        // Handlers run without the lock, so that they can change the
+        // watchpoints themselves
+        match handler {
+            Some(mut handler) => {
+                handler(index, &watch, stack);
+                // Put it back, unless the handler cleared its watchpoint
+                let mut watchpoints = Watchpoints::global().critical();
+                if let Some(slot) = watchpoints.slots[index].as_mut() {
+                    if slot.running {
+                        slot.handler = Some(handler);
+                        slot.running = false;
+                    }
+                }
+            }
+            None => println!("watchpoint {} hit: {:?} at {:#X}", index, watch, stack.rip),
+        }
+        if watch.condition == Condition::Execute {
+            // Otherwise the instruction would fire the watchpoint again
+            stack.rflags |= RFLAGS_RF;
+        }
+    }
+    !handled || dr6 & DR6_SINGLE_STEP != 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dr7() {
+        let write = Watchpoint::new(0x1000, Length::Qword, Condition::Write);
+        assert_eq!(write.enable(0, 0), 0x0009_0101);
+
+        let exec = Watchpoint::new(0x1001, Length::Byte, Condition::Execute);
+        let dr7 = exec.enable(write.enable(0, 0), 3);
+        assert_eq!(dr7, 0x0009_0141);
+
+        let read = Watchpoint::new(0x1002, Length::Word, Condition::ReadWrite);
+        let dr7 = read.enable(dr7, 0);
+        assert_eq!(dr7, 0x0007_0141);
+        assert_eq!(disable(dr7, 0), 0x0000_0140);
+        assert_eq!(disable(disable(dr7, 0), 3), 0x0000_0100);
+
+        let dword = Watchpoint::new(0x1004, Length::Dword, Condition::ReadWrite);
+        assert_eq!(dword.enable(0, 1), 0x00F0_0104);
+    }
+
+    #[test]
+    fn dr6() {
+        assert_eq!(triggered(0xFFFF_0FF0).count(), 0);
+        let fired: Vec<usize> = triggered(0xFFFF_0FF5).collect();
+        assert_eq!(fired, [0, 2]);
+    }
+
+    #[test]
+    fn slots() {
+        let mut watchpoints = Watchpoints::default();
+        let watch = Watchpoint::new(0x2000, Length::Dword, Condition::Write);
+        for index in 0..WATCHPOINTS {
+            assert_eq!(watchpoints.add(watch, None), Ok(index));
+        }
+        assert_eq!(watchpoints.add(watch, None), Err(WatchError::Full));
+        assert_eq!(watchpoints.dr7(), 0xDDDD_0155);
+
+        watchpoints.slots[1] = None;
+        assert_eq!(watchpoints.dr7(), 0xDD0D_0151);
+        assert_eq!(watchpoints.add(watch, None), Ok(1));
+
+        let mut watchpoints = Watchpoints::default();
+        assert_eq!(
+            watchpoints.add(
+                Watchpoint::new(0x2002, Length::Dword, Condition::Write),
+                None
+            ),
+            Err(WatchError::Unaligned)
+        );
+        assert_eq!(
+            watchpoints.add(
+                Watchpoint::new(0x2000, Length::Word, Condition::Execute),
+                None
+            ),
+            Err(WatchError::InvalidLength)
+        );
+        assert_eq!(watchpoints.dr7(), 0);
+    }
+}
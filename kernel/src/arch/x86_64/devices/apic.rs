@@ -0,0 +1,174 @@
+//! Local Advanced Programmable Interrupt Controller
+//!
+//! Every processor has its own local APIC, which receives interrupts from
+//! the I/O APICs and other processors. All of them sit at the same
+//! physical address, and each processor sees only its own registers there
+use crate::arch::instructions;
+use crate::memory::mmio;
+use crate::paging::Physical;
+use crate::prelude::*;
+use crate::sync::Once;
+use core::ptr;
+use core::sync::atomic::spin_loop_hint;
+
+/// Model specific register holding the local APIC base address
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Vector for interrupts that were withdrawn before they could be
+/// delivered. These must not be acknowledged
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Vector for internal APIC errors
+pub const ERROR_VECTOR: u8 = 0xFE;
+/// Vector for asking a processor to load the watchpoints again
+pub const DEBUG_VECTOR: u8 = 0xFD;
+
+/// Register offsets from the local APIC base
+mod reg {
+    pub const ID: usize = 0x20;
+    pub const VERSION: usize = 0x30;
+    pub const TPR: usize = 0x80;
+    pub const EOI: usize = 0xB0;
+    pub const SVR: usize = 0xF0;
+    pub const ESR: usize = 0x280;
+    pub const ICR_LOW: usize = 0x300;
+    pub const ICR_HIGH: usize = 0x310;
+    pub const LVT_TIMER: usize = 0x320;
+    pub const LVT_LINT0: usize = 0x350;
+    pub const LVT_LINT1: usize = 0x360;
+    pub const LVT_ERROR: usize = 0x370;
+}
+
+/// Spurious vector register: software enable
+const SVR_ENABLE: u32 = 1 << 8;
+/// Local vector table entry: masked
+const LVT_MASKED: u32 = 1 << 16;
+/// Local vector table entry: deliver as an NMI
+const LVT_NMI: u32 = 0b100 << 8;
+/// Interrupt command register: delivery modes and status
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+static LOCAL: Once<LocalApic> = Once::new();
+
+/// Returns true if the processor has a local APIC
+pub fn supported() -> bool {
+    instructions::cpuid(1).edx.get_bit(9)
+}
+
+pub struct LocalApic {
+    base: usize,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, reg: usize) -> u32 {
+        ptr::read_volatile((self.base + reg) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: usize, value: u32) {
+        ptr::write_volatile((self.base + reg) as *mut u32, value)
+    }
+
+    /// APIC ID of the calling processor
+    pub fn id(&self) -> u8 {
+        unsafe { (self.read(reg::ID) >> 24) as u8 }
+    }
+
+    pub fn version(&self) -> u8 {
+        unsafe { self.read(reg::VERSION) as u8 }
+    }
+
+    /// Signal the end of the interrupt currently being serviced
+    pub fn eoi(&self) {
+        unsafe { self.write(reg::EOI, 0) }
+    }
+
+    /// Read and clear the error status register
+    pub fn error_status(&self) -> u32 {
+        unsafe {
+            // The register must be written before reading to latch errors
+            self.write(reg::ESR, 0);
+            self.read(reg::ESR)
+        }
+    }
+
+    /// Send an inter-processor interrupt described by the low half of the
+    /// interrupt command register `icr` to the processor with APIC ID
+    /// `dest`, waiting until it has been accepted
+    pub fn send_ipi(&self, dest: u8, icr: u32) {
+        unsafe {
+            self.write(reg::ICR_HIGH, (dest as u32) << 24);
+            self.write(reg::ICR_LOW, icr);
+            while self.read(reg::ICR_LOW) & ICR_PENDING != 0 {
+                spin_loop_hint();
+            }
+        }
+    }
+
+    /// Send an interrupt with `vector` to the processor `dest`
+    pub fn send_fixed(&self, dest: u8, vector: u8) {
+        self.send_ipi(dest, ICR_ASSERT | vector as u32);
+    }
+
+    /// Send an INIT IPI, resetting the processor `dest` into the
+    /// wait-for-SIPI state
+    pub fn send_init(&self, dest: u8) {
+        self.send_ipi(dest, ICR_INIT | ICR_ASSERT);
+    }
+
+    /// Send a startup IPI, starting `dest` in real mode at physical address
+    /// `page << 12`
+    pub fn send_startup(&self, dest: u8, page: u8) {
+        self.send_ipi(dest, ICR_STARTUP | page as u32);
+    }
+
+    /// Software enable the local APIC of the calling processor, masking
+    /// the timer and LINT0, and routing LINT1 as an NMI
+    pub fn enable(&self) {
+        unsafe {
+            self.write(reg::TPR, 0);
+            self.write(reg::LVT_TIMER, LVT_MASKED);
+            self.write(reg::LVT_LINT0, LVT_MASKED);
+            self.write(reg::LVT_LINT1, LVT_NMI);
+            self.write(reg::LVT_ERROR, ERROR_VECTOR as u32);
+            self.error_status();
+            self.write(reg::SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+        }
+        self.eoi();
+    }
+}
+
+/// Map and enable the local APIC of the bootstrap processor
+pub fn init() -> &'static LocalApic {
+    let apic = LOCAL.call_once(|| unsafe {
+        let msr = instructions::rdmsr(IA32_APIC_BASE);
+        instructions::wrmsr(IA32_APIC_BASE, msr | APIC_BASE_ENABLE);
+
+        let phys = Physical::new(msr as usize & 0xF_FFFF_F000);
+        let base = mmio::map(phys, 0x1000).expect("failed to map local APIC");
+        LocalApic {
+            base: base.as_usize(),
+        }
+    });
+    apic.enable();
+    apic
+}
+
+/// Return the local APIC
+///
+/// # Panics
+///
+/// Panics if [`init`] has not been called
+pub fn local() -> &'static LocalApic {
+    LOCAL.try_get().expect("local APIC is not initialized")
+}
+
+interrupt!(spurious, {});
+
+interrupt!(error, {
+    let apic = local();
+    println!("local APIC {} error: {:#X}", apic.id(), apic.error_status());
+    apic.eoi();
+});
@@ -0,0 +1,267 @@
+//! I/O Advanced Programmable Interrupt Controller
+//!
+//! Each I/O APIC has a redirection table with one entry per input pin,
+//! describing which vector and processor the pin is delivered to. Pins are
+//! numbered globally across all I/O APICs as global system interrupts
+//! (GSIs). ISA IRQs map onto the GSI with the same number, unless firmware
+//! provides an [`InterruptOverride`] for them.
+use crate::memory::mmio;
+use crate::paging::{MapError, Physical};
+use crate::prelude::*;
+use alloc::vec::Vec;
+use core::ptr;
+
+/// Physical address of the first I/O APIC on a standard PC
+pub const DEFAULT_BASE: usize = 0xFEC0_0000;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+global!(IoApics);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// Describes an ISA IRQ that is not connected to the GSI of the same
+/// number, or that does not use the ISA default of active high, edge
+/// triggered signalling
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// An entry in the I/O APIC redirection table
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Redirection(u64);
+
+impl Redirection {
+    /// A masked entry delivering `vector` to the processor with APIC ID
+    /// `dest`, using fixed delivery and physical destination mode
+    pub fn new(vector: u8, dest: u8, polarity: Polarity, trigger: Trigger) -> Redirection {
+        let mut entry = 0u64;
+        entry.set_bits(0..8, vector as u64);
+        entry.set_bit(13, polarity == Polarity::ActiveLow);
+        entry.set_bit(15, trigger == Trigger::Level);
+        entry.set_bit(16, true);
+        entry.set_bits(56..64, dest as u64);
+        Redirection(entry)
+    }
+
+    pub fn vector(&self) -> u8 {
+        self.0 as u8
+    }
+
+    pub fn masked(&self) -> bool {
+        self.0.get_bit(16)
+    }
+
+    pub fn set_masked(&mut self, masked: bool) {
+        self.0.set_bit(16, masked);
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+}
+
+pub struct IoApic {
+    base: usize,
+    id: u8,
+    gsi_base: u32,
+    pins: u32,
+}
+
+impl IoApic {
+    /// Map the I/O APIC at `phys`, whose first pin is `gsi_base`. All of
+    /// its pins are masked
+    pub fn new(phys: Physical, gsi_base: u32) -> Result<IoApic, MapError> {
+        let base = mmio::map(phys, 0x20)?;
+        let mut apic = IoApic {
+            base: base.as_usize(),
+            id: 0,
+            gsi_base,
+            pins: 0,
+        };
+        apic.id = (apic.read(REG_ID) >> 24) as u8 & 0xF;
+        apic.pins = ((apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+        for pin in 0..apic.pins {
+            let mut entry = apic.redirection(pin);
+            entry.set_masked(true);
+            apic.set_redirection(pin, entry);
+        }
+        Ok(apic)
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            ptr::read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Returns true if `gsi` is one of this I/O APIC's pins
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.pins
+    }
+
+    pub fn redirection(&self, pin: u32) -> Redirection {
+        let low = self.read(REG_REDIRECTION + pin * 2) as u64;
+        let high = self.read(REG_REDIRECTION + pin * 2 + 1) as u64;
+        Redirection(high << 32 | low)
+    }
+
+    pub fn set_redirection(&mut self, pin: u32, entry: Redirection) {
+        // Write the high half first, so that the entry is never unmasked
+        // with a stale destination
+        self.write(REG_REDIRECTION + pin * 2 + 1, (entry.0 >> 32) as u32);
+        self.write(REG_REDIRECTION + pin * 2, entry.0 as u32);
+    }
+}
+
+/// Every I/O APIC in the system, along with the ISA interrupt overrides
+#[derive(Default)]
+pub struct IoApics {
+    apics: Vec<IoApic>,
+    overrides: Vec<InterruptOverride>,
+}
+
+impl IoApics {
+    pub fn add(&mut self, apic: IoApic) {
+        self.apics.push(apic);
+    }
+
+    pub fn add_override(&mut self, over: InterruptOverride) {
+        self.overrides.retain(|o| o.source != over.source);
+        self.overrides.push(over);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.apics.is_empty()
+    }
+
+    /// Return the GSI and signalling of ISA `irq`, taking overrides into
+    /// account
+    pub fn resolve(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .find(|o| o.source == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                source: irq,
+                gsi: irq as u32,
+                polarity: Polarity::ActiveHigh,
+                trigger: Trigger::Edge,
+            })
+    }
+
+    /// Return the I/O APIC and pin that `gsi` is connected to
+    fn pin(&mut self, gsi: u32) -> Option<(&mut IoApic, u32)> {
+        self.apics
+            .iter_mut()
+            .find(|a| a.handles(gsi))
+            .map(|a| {
+                let pin = gsi - a.gsi_base;
+                (a, pin)
+            })
+    }
+
+    /// Route ISA `irq` to `vector` on the processor with APIC ID `dest`.
+    /// The entry is left masked. Returns false if no I/O APIC handles the
+    /// IRQ's GSI
+    pub fn route(&mut self, irq: u8, vector: u8, dest: u8) -> bool {
+        let over = self.resolve(irq);
+        let entry = Redirection::new(vector, dest, over.polarity, over.trigger);
+        match self.pin(over.gsi) {
+            Some((apic, pin)) => {
+                apic.set_redirection(pin, entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Mask or unmask ISA `irq`
+    pub fn set_masked(&mut self, irq: u8, masked: bool) {
+        let gsi = self.resolve(irq).gsi;
+        if let Some((apic, pin)) = self.pin(gsi) {
+            let mut entry = apic.redirection(pin);
+            entry.set_masked(masked);
+            apic.set_redirection(pin, entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redirection() {
+        let mut entry = Redirection::new(0x21, 3, Polarity::ActiveLow, Trigger::Level);
+        assert_eq!(entry.bits(), 0x0300_0000_0001_A021);
+        assert!(entry.masked());
+        entry.set_masked(false);
+        assert_eq!(entry.bits(), 0x0300_0000_0000_A021);
+        assert_eq!(entry.vector(), 0x21);
+
+        let entry = Redirection::new(0x20, 0, Polarity::ActiveHigh, Trigger::Edge);
+        assert_eq!(entry.bits(), 0x0001_0020);
+    }
+
+    #[test]
+    fn overrides() {
+        let mut apics = IoApics::default();
+        assert_eq!(apics.resolve(0).gsi, 0);
+
+        let timer = InterruptOverride {
+            source: 0,
+            gsi: 2,
+            polarity: Polarity::ActiveHigh,
+            trigger: Trigger::Edge,
+        };
+        let sci = InterruptOverride {
+            source: 9,
+            gsi: 9,
+            polarity: Polarity::ActiveHigh,
+            trigger: Trigger::Level,
+        };
+        apics.add_override(timer);
+        apics.add_override(sci);
+        assert_eq!(apics.resolve(0), timer);
+        assert_eq!(apics.resolve(9).trigger, Trigger::Level);
+        assert_eq!(apics.resolve(1).gsi, 1);
+        assert_eq!(apics.resolve(1).polarity, Polarity::ActiveHigh);
+
+        // A later override for the same source replaces the earlier one
+        apics.add_override(InterruptOverride { gsi: 20, ..timer });
+        assert_eq!(apics.resolve(0).gsi, 20);
+        assert!(!apics.route(0, 0x20, 0));
+    }
+}
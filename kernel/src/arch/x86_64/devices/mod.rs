@@ -1,10 +1,153 @@
+use crate::acpi::madt;
+use crate::paging::Physical;
 use crate::prelude::*;
+use crate::sync::Once;
 
+pub mod apic;
+pub mod ioapic;
 pub mod pic;
 pub mod pit;
 
-/// Run initialization functions for PIC, PIT, etc
+use ioapic::{InterruptOverride, IoApic, IoApics, Polarity, Trigger};
+use pic::Intel8259;
+
+/// Vector that ISA IRQ 0 is delivered on, with the rest following it
+pub const IRQ_BASE: u8 = 0x20;
+
+/// Interrupt controller that ISA IRQs are delivered through
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    Pic,
+    Apic,
+}
+
+static MODE: Once<Mode> = Once::new();
+
+/// Run initialization functions for PIC, PIT, etc.
+///
+/// The local and I/O APICs are used when the processor supports them, with
+/// the 8259 masked. Otherwise the 8259 is used. Every ISA IRQ starts out
+/// masked. This must run after paging is set up, since the APICs are
+/// mapped into the MMIO window
 pub fn init() {
-    let _ = pic::Intel8259::global().lock();
-    let _ = pit::Intel8253::init(100);
+    let _ = pit::Intel8253::init(crate::timer::HZ);
+    // The 8259 is always remapped, so that any spurious interrupts it
+    // raises while masked do not collide with exceptions
+    let mut pic = Intel8259::global().critical();
+
+    let mode = MODE.call_once(|| {
+        if apic::supported() {
+            pic.disable_all();
+            init_apic();
+            Mode::Apic
+        } else {
+            Mode::Pic
+        }
+    });
+    println!("interrupt controller: {:?}", mode);
+}
+
+fn init_apic() {
+    let lapic = apic::init();
+    {
+        let mut idt = crate::arch::idt::InterruptDescriptorTable::global().lock();
+        idt.register(apic::SPURIOUS_VECTOR, apic::spurious);
+        idt.register(apic::ERROR_VECTOR, apic::error);
+        idt.register(apic::DEBUG_VECTOR, crate::arch::debug::reload);
+    }
+
+    let mut ioapics = IoApics::global().lock();
+    if let Some(madt) = crate::acpi::tables().and_then(|t| t.madt()) {
+        for io in madt.io_apics() {
+            match IoApic::new(Physical::new(io.address as usize), io.gsi_base) {
+                Ok(apic) => ioapics.add(apic),
+                Err(err) => println!("failed to map I/O APIC {}: {:?}", io.id, err),
+            }
+        }
+        for over in madt.overrides().filter(|o| o.bus == 0) {
+            ioapics.add_override(InterruptOverride {
+                source: over.source,
+                gsi: over.gsi,
+                // ISA interrupts are active high and edge triggered
+                polarity: match over.polarity() {
+                    madt::Polarity::ActiveLow => Polarity::ActiveLow,
+                    _ => Polarity::ActiveHigh,
+                },
+                trigger: match over.trigger() {
+                    madt::Trigger::Level => Trigger::Level,
+                    _ => Trigger::Edge,
+                },
+            });
+        }
+    }
+
+    if ioapics.is_empty() {
+        // Without firmware tables, assume the standard PC wiring with the
+        // PIT connected to pin 2
+        let apic = IoApic::new(Physical::new(ioapic::DEFAULT_BASE), 0)
+            .expect("failed to map I/O APIC");
+        ioapics.add(apic);
+        ioapics.add_override(InterruptOverride {
+            source: 0,
+            gsi: 2,
+            polarity: Polarity::ActiveHigh,
+            trigger: Trigger::Edge,
+        });
+    }
+
+    for irq in 0..16 {
+        // IRQ 2 is the 8259 cascade, and never fires
+        if irq != 2 && !ioapics.route(irq, IRQ_BASE + irq, lapic.id()) {
+            println!("no I/O APIC for IRQ {}", irq);
+        }
+    }
+}
+
+/// Return the interrupt controller in use
+pub fn mode() -> Mode {
+    MODE.try_get().copied().unwrap_or(Mode::Pic)
+}
+
+/// Unmask ISA `irq`
+pub fn enable_irq(irq: u8) {
+    match mode() {
+        Mode::Apic => IoApics::global().lock().set_masked(irq, false),
+        Mode::Pic => Intel8259::global().critical().enable_irq(irq),
+    }
+}
+
+/// Mask ISA `irq`
+pub fn disable_irq(irq: u8) {
+    match mode() {
+        Mode::Apic => IoApics::global().lock().set_masked(irq, true),
+        Mode::Pic => Intel8259::global().critical().disable_irq(irq),
+    }
+}
+
+/// Signal the end of the interrupt for ISA `irq`. Called by
+/// [`crate::arch::interrupts::irq::handle`] once the handlers have run
+pub fn eoi(irq: u8) {
+    match mode() {
+        Mode::Apic => apic::local().eoi(),
+        Mode::Pic => Intel8259::global().critical().eoi(irq),
+    }
+}
+
+/// Returns true if an interrupt on ISA `irq` is spurious, and must not be
+/// handled. The 8259 raises IRQ 7 (or IRQ 15 on the slave) when an
+/// interrupt is withdrawn before it could be acknowledged, without setting
+/// its bit in the in-service register. A spurious IRQ 15 still occupies the
+/// cascade on the master, which must be acknowledged
+pub fn spurious(irq: u8) -> bool {
+    if mode() != Mode::Pic || (irq != 7 && irq != 15) {
+        return false;
+    }
+    let mut pic = Intel8259::global().critical();
+    if pic.in_service() & (1 << irq) != 0 {
+        return false;
+    }
+    if irq == 15 {
+        pic.eoi(2);
+    }
+    true
 }
@@ -4,9 +4,12 @@ use crate::sync;
 const PIC1_CMD: u16 = 0x20;
 const PIC2_CMD: u16 = 0xA0;
 const PIC1_DATA: u16 = 0x21;
-const PIC2_DATA: u16 = 0xA2;
+const PIC2_DATA: u16 = 0xA1;
 const IRQ_SLAVE: u8 = 0x02;
 const IRQ_ZERO: u8 = 0x20;
+const CMD_EOI: u8 = 0x20;
+/// OCW3: read the in-service register on the next read of the command port
+const CMD_READ_ISR: u8 = 0x0B;
 
 /// We have a global instance of the PIC because there are mutable operations
 /// that can be performed on it (masking/unmasking interrupts)
@@ -15,6 +18,8 @@ global!(Intel8259);
 /// Intel 8259A Programmable Interrupt Controller
 pub struct Intel8259 {
     mask: u16,
+    cmd1: Port<u8>,
+    cmd2: Port<u8>,
     data1: Port<u8>,
     data2: Port<u8>,
 }
@@ -36,24 +41,30 @@ impl Default for Intel8259 {
         // 0x20 is IRQ zero
         data1.write(IRQ_ZERO);
         data1.write(1 << IRQ_SLAVE);
-        data1.write(0x03);
+        data1.write(0x01);
 
         // Setup slave 8259A-2
         cmd2.write(0x11);
         data2.write(IRQ_ZERO + 8);
         data2.write(IRQ_SLAVE);
-        data2.write(0x03);
+        data2.write(0x01);
 
         cmd1.write(0x68);
         cmd1.write(0x0A);
         cmd2.write(0x68);
         cmd2.write(0x0A);
 
-        Intel8259 {
-            mask: 0xFFFF & !(1 << IRQ_SLAVE),
+        let mut pic = Intel8259 {
+            mask: 0,
+            cmd1,
+            cmd2,
             data1,
             data2,
-        }
+        };
+        // Initialization clears the mask registers, so mask everything but
+        // the cascade until IRQs are explicitly enabled
+        pic.set_mask(0xFFFF & !(1 << IRQ_SLAVE));
+        pic
     }
 }
 
@@ -77,4 +88,21 @@ impl Intel8259 {
     pub fn disable_all(&mut self) {
         self.set_mask(0xFFFF)
     }
+
+    /// Signal the end of the interrupt for `irq`. Interrupts from the
+    /// slave must be acknowledged on both controllers
+    pub fn eoi(&mut self, irq: u8) {
+        if irq >= 8 {
+            self.cmd2.write(CMD_EOI);
+        }
+        self.cmd1.write(CMD_EOI);
+    }
+
+    /// Read the in-service registers of both controllers, with the slave
+    /// in the high byte
+    pub fn in_service(&mut self) -> u16 {
+        self.cmd1.write(CMD_READ_ISR);
+        self.cmd2.write(CMD_READ_ISR);
+        (self.cmd1.read() as u16) | (self.cmd2.read() as u16) << 8
+    }
 }
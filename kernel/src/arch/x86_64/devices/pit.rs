@@ -1,15 +1,21 @@
 use crate::io::{Io, Port};
 use crate::sync::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Input clock of the PIT, in Hz
+const FREQUENCY: u64 = 1193180;
 
 /// Intel 8253 programmable interval timer
 pub struct Intel8253;
 
 static INIT: Once<()> = Once::new();
+static DIVISOR: AtomicUsize = AtomicUsize::new(0x10000);
 
 impl Intel8253 {
     pub fn init(frequency: u32) {
         INIT.call_once(|| {
-            let divisor: u32 = 1193180 / frequency;
+            let divisor: u32 = FREQUENCY as u32 / frequency;
+            DIVISOR.store(divisor as usize, Ordering::SeqCst);
 
             let mut cmd = Port::<u8>::new(0x43);
             let mut data = Port::<u8>::new(0x40);
@@ -21,4 +27,34 @@ impl Intel8253 {
             data.write((divisor >> 8) as u8);
         });
     }
+
+    /// Latch and read the current count of channel 0
+    fn count() -> u64 {
+        let mut cmd = Port::<u8>::new(0x43);
+        let data = Port::<u8>::new(0x40);
+        cmd.write(0x00);
+        let low = data.read() as u64;
+        let high = data.read() as u64;
+        high << 8 | low
+    }
+
+    /// Busy wait for at least `micros` microseconds by polling channel 0.
+    /// This works whether or not interrupts are enabled, but requires
+    /// [`Intel8253::init`] to have been called
+    pub fn delay(micros: u64) {
+        let divisor = DIVISOR.load(Ordering::SeqCst) as u64;
+        let target = micros * FREQUENCY / 1_000_000 + 1;
+        let mut elapsed = 0;
+        let mut last = Self::count();
+        while elapsed < target {
+            let now = Self::count();
+            // The counter runs down to 0 and then reloads from the divisor
+            elapsed += if now <= last {
+                last - now
+            } else {
+                last + divisor - now
+            };
+            last = now;
+        }
+    }
 }
@@ -0,0 +1,234 @@
+//! Global descriptor table and 64-bit task state segment
+//!
+//! The selector layout is fixed so that SYSCALL/SYSRET can derive every
+//! segment from the STAR MSR: the kernel data segment directly follows the
+//! kernel code segment, and the user data and 64-bit user code segments
+//! follow a (unused) 32-bit user code segment.
+use super::DescriptorTablePtr;
+use crate::prelude::*;
+use crate::sync::Once;
+use alloc::boxed::Box;
+use alloc::vec;
+use core::mem;
+
+pub const KERNEL_CODE: u16 = 0x08;
+pub const KERNEL_DATA: u16 = 0x10;
+pub const USER_CODE32: u16 = 0x18 | 3;
+pub const USER_DATA: u16 = 0x20 | 3;
+pub const USER_CODE: u16 = 0x28 | 3;
+pub const TSS: u16 = 0x30;
+
+/// Interrupt stack table indices. An IDT entry with a non-zero index
+/// always switches to the corresponding stack in the TSS, so these
+/// handlers run on a known-good stack even if the kernel stack is corrupt
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+/// Size, in bytes, of each interrupt stack
+pub const IST_STACK_SIZE: usize = 0x4000;
+
+/// Number of 8-byte descriptor slots in the GDT. The TSS descriptor takes
+/// up two slots
+const GDT_ENTRIES: usize = 8;
+
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    _res0: u32,
+    /// Stack pointers loaded on a privilege level change to rings 0-2
+    rsp: [u64; 3],
+    _res1: u64,
+    /// Interrupt stack table, index 1 is stored at `ist[0]`
+    ist: [u64; 7],
+    _res2: u64,
+    _res3: u16,
+    iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    pub const fn new() -> TaskStateSegment {
+        TaskStateSegment {
+            _res0: 0,
+            rsp: [0; 3],
+            _res1: 0,
+            ist: [0; 7],
+            _res2: 0,
+            _res3: 0,
+            // No I/O permission bitmap
+            iomap_base: mem::size_of::<TaskStateSegment>() as u16,
+        }
+    }
+
+    /// Set the stack pointer loaded when an interrupt or system call
+    /// arrives from ring 3
+    pub fn set_kernel_stack(&mut self, rsp: usize) {
+        // The struct is packed, so copy the array out rather than
+        // borrowing the field
+        let mut stacks = self.rsp;
+        stacks[0] = rsp as u64;
+        self.rsp = stacks;
+    }
+
+    /// Set the stack pointer for interrupt stack table entry `index` (1-7)
+    pub fn set_interrupt_stack(&mut self, index: u8, rsp: usize) {
+        assert!(index >= 1 && index <= 7, "invalid IST index {}", index);
+        let mut stacks = self.ist;
+        stacks[index as usize - 1] = rsp as u64;
+        self.ist = stacks;
+    }
+}
+
+/// Backing memory for one interrupt stack
+#[repr(C, align(16))]
+pub struct Stack([u8; IST_STACK_SIZE]);
+
+impl Stack {
+    pub const fn new() -> Stack {
+        Stack([0; IST_STACK_SIZE])
+    }
+
+    /// Address one past the end of the stack, which is where the stack
+    /// pointer starts
+    pub fn top(&self) -> usize {
+        self.0.as_ptr() as usize + IST_STACK_SIZE
+    }
+}
+
+pub struct GlobalDescriptorTable {
+    entries: [u64; GDT_ENTRIES],
+}
+
+impl GlobalDescriptorTable {
+    /// Build a GDT with the standard kernel and user segments, and a
+    /// descriptor for `tss`
+    pub fn new(tss: &'static TaskStateSegment) -> GlobalDescriptorTable {
+        // Present, descriptor type = code/data, readable/writable
+        let common = 1u64 << 47 | 1 << 44 | 1 << 41;
+        let code = common | 1 << 43;
+        let long = 1u64 << 53;
+        let ring3 = 3u64 << 45;
+        // 32-bit flat code segment: 4 GiB limit, page granular, 32-bit
+        let code32 = code | ring3 | 0xF << 48 | 0xFFFF | 1 << 55 | 1 << 54;
+
+        let base = tss as *const _ as u64;
+        let limit = (mem::size_of::<TaskStateSegment>() - 1) as u64;
+        let mut low = 0u64;
+        low.set_bits(0..16, limit);
+        low.set_bits(16..40, base & 0xFF_FFFF);
+        // Available 64-bit TSS, present
+        low.set_bits(40..44, 0b1001);
+        low.set_bit(47, true);
+        low.set_bits(56..64, (base >> 24) & 0xFF);
+
+        GlobalDescriptorTable {
+            entries: [
+                0,
+                code | long,
+                common,
+                code32,
+                common | ring3,
+                code | long | ring3,
+                low,
+                base >> 32,
+            ],
+        }
+    }
+
+    /// Load the GDT, reload every segment register with the kernel
+    /// selectors and load the task register
+    pub fn load(&'static self) {
+        let ptr = DescriptorTablePtr {
+            base: self as *const _ as usize,
+            limit: (mem::size_of::<Self>() - 1) as u16,
+        };
+        unsafe {
+            asm!("lgdt ($0)" :: "r"(&ptr) : "memory");
+
+            // CS can only be reloaded with a far jump or return
+            asm!("pushq $0
+                  leaq 1f(%rip), %rax
+                  pushq %rax
+                  lretq
+                  1:" :: "ri"(KERNEL_CODE as u64) : "rax", "memory" : "volatile");
+
+            asm!("mov ds, $0
+                  mov es, $0
+                  mov ss, $0" :: "r"(KERNEL_DATA) :: "intel", "volatile");
+            asm!("mov fs, $0
+                  mov gs, $0" :: "r"(0u16) :: "intel", "volatile");
+            asm!("ltr $0" :: "r"(TSS) :: "intel", "volatile");
+        }
+    }
+}
+
+static mut BSP_TSS: TaskStateSegment = TaskStateSegment::new();
+static mut BSP_STACKS: [Stack; 3] = [
+    Stack::new(),
+    Stack::new(),
+    Stack::new(),
+];
+static BSP_GDT: Once<GlobalDescriptorTable> = Once::new();
+
+/// Set up and load the bootstrap processor's GDT and TSS, replacing the
+/// GDT from the bootloader. This must happen before the IDT is loaded,
+/// since IDT entries refer to [`KERNEL_CODE`]
+pub fn init() {
+    let gdt = BSP_GDT.call_once(|| unsafe {
+        BSP_TSS.set_interrupt_stack(DOUBLE_FAULT_IST, BSP_STACKS[0].top());
+        BSP_TSS.set_interrupt_stack(NMI_IST, BSP_STACKS[1].top());
+        BSP_TSS.set_interrupt_stack(MACHINE_CHECK_IST, BSP_STACKS[2].top());
+        GlobalDescriptorTable::new(&BSP_TSS)
+    });
+    gdt.load();
+}
+
+/// Set up and load a GDT and TSS for an application processor, with
+/// interrupt stacks allocated from the heap. Returns the processor's TSS
+pub fn init_ap() -> &'static mut TaskStateSegment {
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    for &index in [DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST].iter() {
+        let stack = Box::leak(vec![0u8; IST_STACK_SIZE].into_boxed_slice());
+        tss.set_interrupt_stack(index, stack.as_ptr() as usize + IST_STACK_SIZE);
+    }
+    // The TSS is only read by the processor after this point, apart from
+    // updates to the kernel stack made by its owner
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new(unsafe {
+        &*(tss as *const TaskStateSegment)
+    })));
+    gdt.load();
+    tss
+}
+
+/// Return the bootstrap processor's TSS
+///
+/// # Safety
+///
+/// The caller must ensure it is running on the bootstrap processor, and
+/// that no other reference to the TSS is live
+pub unsafe fn bsp_tss() -> &'static mut TaskStateSegment {
+    &mut BSP_TSS
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn descriptors() {
+        static SEGMENT: TaskStateSegment = TaskStateSegment::new();
+        let gdt = GlobalDescriptorTable::new(&SEGMENT);
+        assert_eq!(gdt.entries[(KERNEL_CODE >> 3) as usize], 0x0020_9A00_0000_0000);
+        assert_eq!(gdt.entries[(KERNEL_DATA >> 3) as usize], 0x0000_9200_0000_0000);
+        assert_eq!(gdt.entries[(USER_CODE32 >> 3) as usize], 0x00CF_FA00_0000_FFFF);
+        assert_eq!(gdt.entries[(USER_DATA >> 3) as usize], 0x0000_F200_0000_0000);
+        assert_eq!(gdt.entries[(USER_CODE >> 3) as usize], 0x0020_FA00_0000_0000);
+
+        let base = &SEGMENT as *const _ as u64;
+        let low = gdt.entries[(TSS >> 3) as usize];
+        assert_eq!(mem::size_of::<TaskStateSegment>(), 0x68);
+        assert_eq!(low & 0xFFFF, 0x67);
+        assert_eq!(low >> 40 & 0xFF, 0x89);
+        assert_eq!(low >> 16 & 0xFF_FFFF | (low >> 56) << 24, base & 0xFFFF_FFFF);
+        assert_eq!(gdt.entries[(TSS >> 3) as usize + 1], base >> 32);
+    }
+}
@@ -1,4 +1,4 @@
-use super::{interrupts, PrivilegeLevel};
+use super::{devices, gdt, interrupts, syscall, PrivilegeLevel};
 use crate::prelude::*;
 use core::mem;
 use core::u16;
@@ -16,13 +16,17 @@ impl Default for InterruptDescriptorTable {
         let mut idt = InterruptDescriptorTable::new();
         idt.entries[0x00].set_handler(interrupts::divide_by_zero);
         idt.entries[0x01].set_handler(interrupts::debug);
-        idt.entries[0x02].set_handler(interrupts::nonmaskable);
+        idt.entries[0x02]
+            .set_handler(interrupts::nonmaskable)
+            .set_stack_index(gdt::NMI_IST);
         idt.entries[0x03].set_handler(interrupts::breakpoint);
         idt.entries[0x04].set_handler(interrupts::overflow);
         idt.entries[0x05].set_handler(interrupts::bound_range);
         idt.entries[0x06].set_handler(interrupts::invalid_opcode);
         idt.entries[0x07].set_handler(interrupts::device_not_available);
-        idt.entries[0x08].set_handler(interrupts::double_fault);
+        idt.entries[0x08]
+            .set_handler(interrupts::double_fault)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST);
         idt.entries[0x09].set_handler(interrupts::coprocessor_segment);
         idt.entries[0x0A].set_handler(interrupts::invalid_tss);
         idt.entries[0x0B].set_handler(interrupts::segment_not_present);
@@ -31,8 +35,15 @@ impl Default for InterruptDescriptorTable {
         idt.entries[0x0E].set_handler(interrupts::page);
         idt.entries[0x10].set_handler(interrupts::fpu);
         idt.entries[0x11].set_handler(interrupts::alignment_check);
-        idt.entries[0x12].set_handler(interrupts::machine_check);
+        idt.entries[0x12]
+            .set_handler(interrupts::machine_check)
+            .set_stack_index(gdt::MACHINE_CHECK_IST);
         idt.entries[0x13].set_handler(interrupts::simd);
+        for (irq, handler) in interrupts::IRQS.iter().enumerate() {
+            idt.entries[devices::IRQ_BASE as usize + irq].set_handler(*handler);
+        }
+        idt.register(syscall::SYSCALL_VECTOR, syscall::syscall_gate)
+            .set_privilege(PrivilegeLevel::Ring3);
         idt
     }
 }
@@ -58,8 +69,10 @@ impl InterruptDescriptorTable {
         &mut self.entries[index as usize]
     }
 
-    pub fn register(&mut self, irq: u8, handler: Handler) {
-        self.entry(irq).set_handler(handler);
+    /// Install `handler` for vector `irq`, returning the entry's options so
+    /// that the caller can adjust the privilege level or stack
+    pub fn register(&mut self, irq: u8, handler: Handler) -> &mut EntryType {
+        self.entry(irq).set_handler(handler)
     }
 }
 
@@ -88,14 +101,19 @@ impl Entry {
         }
     }
 
-    fn set_handler(&mut self, func: Handler) {
+    pub fn set_handler(&mut self, func: Handler) -> &mut EntryType {
         let addr = func as u64;
         self.offset_low = addr as u16;
         self.offset_mid = (addr >> 16) as u16;
         self.offset_high = (addr >> 32) as u32;
-        self.segment_selector = 0x18;
+        self.segment_selector = gdt::KERNEL_CODE;
 
         self.ty.set_present(true);
+        &mut self.ty
+    }
+
+    pub fn options(&mut self) -> &mut EntryType {
+        &mut self.ty
     }
 }
 
@@ -112,7 +130,8 @@ impl EntryType {
         // Bit 13..14 - descriptor privilege level
         // Bits 12 - set to 0 for interrupt and trap gates
         // Bits 8..11 - IDT gate type
-        // Bits 1..7 - must be 0
+        // Bits 3..7 - must be 0
+        // Bits 0..2 - interrupt stack table index, 0 for no stack switch
         EntryType(0b0000_1110_0000_0000)
     }
 
@@ -120,11 +139,23 @@ impl EntryType {
         self.0.set_bit(15, present);
     }
 
-    pub fn set_privilege(&mut self, privilege: PrivilegeLevel) {
-        self.0.set_bits(13..14, privilege as u16);
+    /// Set the lowest privilege level that may invoke this gate with a
+    /// software interrupt
+    pub fn set_privilege(&mut self, privilege: PrivilegeLevel) -> &mut Self {
+        self.0.set_bits(13..15, privilege as u16);
+        self
+    }
+
+    /// Switch to stack `index` of the interrupt stack table when this gate
+    /// is taken. An index of 0 disables the switch
+    pub fn set_stack_index(&mut self, index: u8) -> &mut Self {
+        assert!(index <= 7, "invalid IST index {}", index);
+        self.0.set_bits(0..3, index as u16);
+        self
     }
 
-    pub fn set_interrupt(&mut self, enable: bool) {
+    pub fn set_interrupt(&mut self, enable: bool) -> &mut Self {
         self.0.set_bit(8, enable);
+        self
     }
 }
@@ -4,8 +4,150 @@ pub fn cs() -> u16 {
     cs
 }
 
+/// Return the linear address that caused the most recent page fault
+pub fn cr2() -> usize {
+    let cr2: usize;
+    unsafe { asm!("mov $0, cr2" : "=r"(cr2) ::: "intel", "volatile") }
+    cr2
+}
+
+/// Read the frame pointer of the calling function
+#[inline(always)]
+pub fn rbp() -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov $0, rbp" : "=r"(rbp) ::: "intel", "volatile") }
+    rbp
+}
+
+/// Read the flags register
+pub fn rflags() -> u64 {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq
+              pop $0" : "=r"(rflags) :: "memory" : "intel", "volatile")
+    }
+    rflags
+}
+
+pub fn cr0() -> u64 {
+    let cr0: u64;
+    unsafe { asm!("mov $0, cr0" : "=r"(cr0) ::: "intel", "volatile") }
+    cr0
+}
+
+pub unsafe fn set_cr0(cr0: u64) {
+    asm!("mov cr0, $0" :: "r"(cr0) : "memory" : "intel", "volatile")
+}
+
 pub fn cr3() -> u64 {
     let cr3: u64;
     unsafe { asm!("mov $0, cr3" : "=r"(cr3) ::: "intel", "volatile") }
     cr3
 }
+
+/// Load a new PML4 physical address into CR3, flushing all non-global
+/// TLB entries
+pub unsafe fn set_cr3(cr3: u64) {
+    asm!("mov cr3, $0" :: "r"(cr3) : "memory" : "intel", "volatile")
+}
+
+pub fn cr4() -> u64 {
+    let cr4: u64;
+    unsafe { asm!("mov $0, cr4" : "=r"(cr4) ::: "intel", "volatile") }
+    cr4
+}
+
+pub unsafe fn set_cr4(cr4: u64) {
+    asm!("mov cr4, $0" :: "r"(cr4) : "memory" : "intel", "volatile")
+}
+
+/// Load `addr` into debug address register DR`index`, for `index` 0 to 3
+pub unsafe fn set_dr(index: usize, addr: usize) {
+    match index {
+        0 => asm!("mov dr0, $0" :: "r"(addr) :: "intel", "volatile"),
+        1 => asm!("mov dr1, $0" :: "r"(addr) :: "intel", "volatile"),
+        2 => asm!("mov dr2, $0" :: "r"(addr) :: "intel", "volatile"),
+        3 => asm!("mov dr3, $0" :: "r"(addr) :: "intel", "volatile"),
+        _ => panic!("no debug address register {}", index),
+    }
+}
+
+/// Return the debug status register
+pub fn dr6() -> u64 {
+    let dr6: u64;
+    unsafe { asm!("mov $0, dr6" : "=r"(dr6) ::: "intel", "volatile") }
+    dr6
+}
+
+pub unsafe fn set_dr6(dr6: u64) {
+    asm!("mov dr6, $0" :: "r"(dr6) :: "intel", "volatile")
+}
+
+/// Load the debug control register
+pub unsafe fn set_dr7(dr7: u64) {
+    asm!("mov dr7, $0" :: "r"(dr7) :: "intel", "volatile")
+}
+
+/// Flush all non-global TLB entries by reloading CR3
+pub fn flush_tlb() {
+    unsafe { set_cr3(cr3()) }
+}
+
+/// Invalidate the TLB entry for the page containing `addr`
+pub unsafe fn invlpg(addr: usize) {
+    asm!("invlpg [$0]" :: "r"(addr) : "memory" : "intel", "volatile")
+}
+
+/// Register values returned by the `cpuid` instruction
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+/// Execute `cpuid` for `leaf`, with a subleaf of 0
+pub fn cpuid(leaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+            : "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+            : "{eax}"(leaf), "{ecx}"(0)
+            :: "intel", "volatile");
+    }
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+/// Save the x87, MMX and SSE registers in the 512 bytes at `area`, which
+/// must be 16-byte aligned
+pub unsafe fn fxsave(area: *mut u8) {
+    asm!("fxsave [$0]" :: "r"(area) : "memory" : "intel", "volatile")
+}
+
+/// Load the x87, MMX and SSE registers from the 512 bytes at `area`, which
+/// must be 16-byte aligned
+pub unsafe fn fxrstor(area: *const u8) {
+    asm!("fxrstor [$0]" :: "r"(area) : "memory" : "intel", "volatile")
+}
+
+/// Read the time stamp counter
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "intel", "volatile") };
+    (high as u64) << 32 | low as u64
+}
+
+/// Read the model specific register `msr`
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "intel", "volatile");
+    (high as u64) << 32 | low as u64
+}
+
+/// Write `value` to the model specific register `msr`
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!("wrmsr"
+        :: "{ecx}"(msr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32)
+        : "memory" : "intel", "volatile");
+}
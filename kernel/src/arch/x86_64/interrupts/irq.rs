@@ -0,0 +1,196 @@
+//! Dispatch of hardware IRQs to handlers registered at runtime
+//!
+//! Every ISA IRQ line has an entry stub that calls [`handle`], which runs
+//! each handler registered for the line and then acknowledges the
+//! interrupt controller. Handlers never need to send an EOI themselves.
+use super::InterruptStack;
+use crate::arch::devices;
+use crate::prelude::*;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of ISA IRQ lines
+pub const IRQ_LINES: usize = 16;
+
+pub type Handler = Box<dyn FnMut(&mut InterruptStack) + Send>;
+
+/// Identifies a registered handler, so that it can be removed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HandlerId {
+    irq: u8,
+    id: usize,
+}
+
+global!(IrqHandlers);
+
+/// Handlers registered for each IRQ line. Several handlers may share a
+/// line, and they are run in the order they were added
+#[derive(Default)]
+pub struct IrqHandlers {
+    lines: [Vec<(usize, Handler)>; IRQ_LINES],
+    next: usize,
+}
+
+impl IrqHandlers {
+    pub fn add(&mut self, irq: u8, handler: Handler) -> HandlerId {
+        assert!((irq as usize) < IRQ_LINES, "invalid IRQ {}", irq);
+        let id = self.next;
+        self.next += 1;
+        self.lines[irq as usize].push((id, handler));
+        HandlerId { irq, id }
+    }
+
+    /// Remove the handler identified by `id`, returning false if it was
+    /// not registered
+    pub fn remove(&mut self, id: HandlerId) -> bool {
+        let line = &mut self.lines[id.irq as usize];
+        let before = line.len();
+        line.retain(|(i, _)| *i != id.id);
+        line.len() != before
+    }
+
+    /// Number of handlers registered for `irq`
+    pub fn handlers(&self, irq: u8) -> usize {
+        self.lines[irq as usize].len()
+    }
+
+    /// Run every handler registered for `irq`, returning how many ran
+    pub fn dispatch(&mut self, irq: u8, stack: &mut InterruptStack) -> usize {
+        let line = &mut self.lines[irq as usize];
+        for (_, handler) in line.iter_mut() {
+            handler(stack);
+        }
+        line.len()
+    }
+}
+
+/// Number of times each vector has been dispatched
+static mut COUNTS: [usize; 256] = [0; 256];
+
+/// Number of spurious IRQs that were filtered out
+static SPURIOUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of IRQs that arrived on a line with no handlers
+static UNHANDLED: AtomicUsize = AtomicUsize::new(0);
+
+fn counter(vector: u8) -> &'static AtomicUsize {
+    // AtomicUsize has the same in-memory representation as usize
+    unsafe { &*(&COUNTS[vector as usize] as *const usize as *const AtomicUsize) }
+}
+
+/// Number of interrupts delivered on `vector`, including spurious ones
+pub fn count(vector: u8) -> usize {
+    counter(vector).load(Ordering::Relaxed)
+}
+
+pub fn spurious() -> usize {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+pub fn unhandled() -> usize {
+    UNHANDLED.load(Ordering::Relaxed)
+}
+
+/// Register `handler` to run whenever `irq` fires. The line is unmasked
+/// when its first handler is registered
+pub fn register<F>(irq: u8, handler: F) -> HandlerId
+where
+    F: FnMut(&mut InterruptStack) + Send + 'static,
+{
+    let (id, first) = {
+        // Interrupts must be disabled while holding the lock, or an IRQ on
+        // this processor would deadlock in `handle`
+        let mut handlers = IrqHandlers::global().critical();
+        let id = handlers.add(irq, Box::new(handler));
+        (id, handlers.handlers(irq) == 1)
+    };
+    if first {
+        devices::enable_irq(irq);
+    }
+    id
+}
+
+/// Remove a handler added by [`register`]. The line is masked once it has
+/// no handlers left
+pub fn unregister(id: HandlerId) -> bool {
+    let (removed, empty) = {
+        let mut handlers = IrqHandlers::global().critical();
+        let removed = handlers.remove(id);
+        (removed, handlers.handlers(id.irq) == 0)
+    };
+    if removed && empty {
+        devices::disable_irq(id.irq);
+    }
+    removed
+}
+
+/// Common entry point of the IRQ stubs
+pub fn handle(irq: u8, stack: &mut InterruptStack) {
+    counter(devices::IRQ_BASE + irq).fetch_add(1, Ordering::Relaxed);
+    if devices::spurious(irq) {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    if IrqHandlers::global().critical().dispatch(irq, stack) == 0 {
+        UNHANDLED.fetch_add(1, Ordering::Relaxed);
+    }
+    devices::eoi(irq);
+    // Only switch threads once the interrupt has been acknowledged, or the
+    // line would stay blocked until this thread runs again
+    crate::thread::preempt();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+
+    #[test]
+    fn shared_lines() {
+        let mut handlers = IrqHandlers::default();
+        let mut stack: InterruptStack = unsafe { core::mem::zeroed() };
+        let hits = Arc::new(AtomicUsize::new(0));
+
+        let a = {
+            let hits = hits.clone();
+            handlers.add(
+                4,
+                Box::new(move |_| {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                }),
+            )
+        };
+        let b = {
+            let hits = hits.clone();
+            handlers.add(
+                4,
+                Box::new(move |s| {
+                    s.scratch.rax = 42;
+                    hits.fetch_add(10, Ordering::SeqCst);
+                }),
+            )
+        };
+        assert_eq!(handlers.handlers(4), 2);
+        assert_eq!(handlers.dispatch(4, &mut stack), 2);
+        assert_eq!(hits.load(Ordering::SeqCst), 11);
+        assert_eq!(stack.scratch.rax, 42);
+
+        assert!(handlers.remove(a));
+        assert!(!handlers.remove(a));
+        assert_eq!(handlers.dispatch(4, &mut stack), 1);
+        assert_eq!(hits.load(Ordering::SeqCst), 21);
+
+        assert!(handlers.remove(b));
+        assert_eq!(handlers.dispatch(4, &mut stack), 0);
+        assert_eq!(handlers.dispatch(3, &mut stack), 0);
+    }
+
+    #[test]
+    fn counters() {
+        let before = count(0x30);
+        counter(0x30).fetch_add(2, Ordering::Relaxed);
+        assert_eq!(count(0x30), before + 2);
+    }
+}
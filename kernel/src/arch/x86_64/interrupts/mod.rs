@@ -1,26 +1,159 @@
+use crate::arch::{instructions, percpu};
 use crate::prelude::*;
+use core::marker::PhantomData;
 
+pub mod irq;
+
+/// Interrupt enable flag in RFLAGS
+const IF: u64 = 1 << 9;
+
+#[cfg(not(test))]
 pub fn enable() {
     unsafe {
         asm!("sti" :::: "volatile");
     }
 }
 
+#[cfg(not(test))]
 pub fn disable() {
     unsafe {
         asm!("cli" :::: "volatile");
     }
 }
 
-/// Run a closure with interrupts disabled
+// Unit tests run on the host in user mode, where `sti` and `cli` fault, and
+// nothing interrupts them anyway
+#[cfg(test)]
+pub fn enable() {}
+
+#[cfg(test)]
+pub fn disable() {}
+
+/// Return true if maskable interrupts are enabled on this processor
+pub fn enabled() -> bool {
+    instructions::rflags() & IF != 0
+}
+
+/// Run a closure with interrupts disabled, restoring the previous
+/// interrupt state afterwards
 pub fn critical_section<T, F: FnOnce() -> T>(f: F) -> T {
-    disable();
-    let r = f();
-    enable();
-    r
+    let _guard = InterruptGuard::new();
+    f()
 }
 
-#[derive(Copy, Clone)]
+/// How deeply a processor is nested in critical sections and interrupt
+/// handlers. Kept in the per-CPU data, and saved and restored by the
+/// scheduler along with the rest of a thread's context
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Nesting {
+    /// Number of live [`InterruptGuard`]s
+    pub critical: usize,
+    /// Number of interrupt handlers running
+    pub interrupts: usize,
+    /// Number of locks of any kind held
+    pub locks: usize,
+}
+
+impl Nesting {
+    pub const fn new() -> Nesting {
+        Nesting {
+            critical: 0,
+            interrupts: 0,
+            locks: 0,
+        }
+    }
+
+    /// Return the calling processor's nesting
+    pub fn current() -> Nesting {
+        percpu::current_cpu().nesting.get()
+    }
+
+    /// Replace the calling processor's nesting, such as when switching to
+    /// a thread that was nested differently
+    ///
+    /// # Safety
+    ///
+    /// Must be called with interrupts disabled, and `self` must describe
+    /// the guards and handlers that are live on the calling processor
+    pub unsafe fn restore(self) {
+        percpu::current_cpu().nesting.set(self)
+    }
+
+    /// Change the calling processor's nesting with `f`
+    pub fn update<F: FnOnce(&mut Nesting)>(f: F) {
+        let cpu = percpu::current_cpu();
+        let mut nesting = cpu.nesting.get();
+        f(&mut nesting);
+        cpu.nesting.set(nesting);
+    }
+}
+
+/// Number of [`InterruptGuard`]s live on the calling processor
+pub fn depth() -> usize {
+    Nesting::current().critical
+}
+
+/// Return true if the calling processor is running an interrupt or
+/// exception handler
+pub fn in_interrupt() -> bool {
+    Nesting::current().interrupts != 0
+}
+
+/// Disables interrupts until it is dropped, and then restores the interrupt
+/// flag to what it was when the guard was created. Guards can be nested
+/// freely, since only the outermost one will enable interrupts again
+pub struct InterruptGuard {
+    enabled: bool,
+    /// The guard belongs to the processor that created it
+    _cpu: PhantomData<*const ()>,
+}
+
+impl InterruptGuard {
+    pub fn new() -> InterruptGuard {
+        let enabled = enabled();
+        disable();
+        Nesting::update(|nesting| nesting.critical += 1);
+        InterruptGuard {
+            enabled,
+            _cpu: PhantomData,
+        }
+    }
+
+    /// Return true if interrupts were enabled when the guard was created
+    pub fn was_enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        Nesting::update(|nesting| nesting.critical -= 1);
+        if self.enabled {
+            enable();
+        }
+    }
+}
+
+/// Marks the calling processor as running an interrupt handler while it
+/// is live. Created by the entry stubs that [`interrupt!`] defines
+pub struct HandlerGuard {
+    _cpu: PhantomData<*const ()>,
+}
+
+impl HandlerGuard {
+    pub fn enter() -> HandlerGuard {
+        Nesting::update(|nesting| nesting.interrupts += 1);
+        HandlerGuard { _cpu: PhantomData }
+    }
+}
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        Nesting::update(|nesting| nesting.interrupts -= 1);
+    }
+}
+
+#[derive(Copy, Clone, Default)]
 #[repr(C)]
 pub struct Preserved {
     pub r15: usize,
@@ -31,7 +164,7 @@ pub struct Preserved {
     pub rbx: usize,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default)]
 #[repr(C)]
 pub struct Scratch {
     pub r11: usize,
@@ -75,6 +208,20 @@ pub struct InterruptStack {
     pub ss: usize,
 }
 
+impl InterruptErrorStack {
+    /// The interrupted code was running in ring 3
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+impl InterruptStack {
+    /// The interrupted code was running in ring 3
+    pub fn from_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
 impl core::fmt::Debug for Preserved {
     fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
         write!(fmt, "rbx: {:#016X} rbp: {:#016X}\nr12: {:#016X} r13: {:#016X}\nr14: {:#016X} r15: {:#016X}\n",
@@ -156,28 +303,59 @@ macro_rules! pop_scratch {
     )};
 }
 
+/// Save the FS selector, for debugging output. FS itself is left alone:
+/// the kernel doesn't use it, and loading any selector would replace the
+/// FS base that user code keeps its thread pointer in
 macro_rules! push_fs {
     () => {asm!(
-        "push fs
-        mov rax, 0x20
-        mov fs, rax"
+        "mov rax, fs
+        push rax"
         :::: "intel", "volatile"
     )};
 }
 
 macro_rules! pop_fs {
-    () => {asm!("pop fs" :::: "intel", "volatile")};
+    () => {asm!("add rsp, 8" :::: "intel", "volatile")};
 }
 
 macro_rules! iretq {
     () => {asm!("iretq" :::: "intel", "volatile")};
 }
 
+/// Exchange the GS base with the kernel's if the interrupted code was in
+/// ring 3, which the saved CS just above the return address shows. User
+/// code can load any GS base, so the per-CPU data is only reachable after
+/// this on entry, and the user's must be swapped back in on the way out
+macro_rules! swapgs_if_user {
+    () => {asm!(
+        "test qword ptr [rsp + 8], 3
+        jz 2f
+        swapgs
+        2:"
+        :::: "intel", "volatile"
+    )};
+}
+
+/// As [`swapgs_if_user`], on entry to a handler for an exception that
+/// pushes an error code, which sits below the return address
+macro_rules! swapgs_if_user_error {
+    () => {asm!(
+        "test qword ptr [rsp + 16], 3
+        jz 2f
+        swapgs
+        2:"
+        :::: "intel", "volatile"
+    )};
+}
+
 #[macro_export]
 macro_rules! interrupt {
     ($name:ident, $stack:ident) => {
         interrupt!($name, $stack, {
-            println!("CPU fault: {}\n{:?}", stringify!($name), $stack);
+            if $stack.from_user() {
+                $crate::process::fault(stringify!($name), $stack.rip);
+            }
+            $crate::backtrace::fault(stringify!($name), $stack, $stack.rip, $stack.preserved.rbp);
         //    asm!("hlt" :::: "intel", "volatile");
         });
     };
@@ -186,9 +364,11 @@ macro_rules! interrupt {
         pub unsafe extern "C" fn $name() {
             #[inline(never)]
             unsafe fn inner() {
+                let _handler = $crate::arch::interrupts::HandlerGuard::enter();
                 $func
             }
 
+            swapgs_if_user!();
             push_preserved!();
             push_scratch!();
             push_fs!();
@@ -198,6 +378,7 @@ macro_rules! interrupt {
             pop_fs!();
             pop_scratch!();
             pop_preserved!();
+            swapgs_if_user!();
             iretq!();
         }
     };
@@ -206,9 +387,11 @@ macro_rules! interrupt {
         pub unsafe extern "C" fn $name() {
             #[inline(never)]
             unsafe fn inner($stack: &mut $crate::arch::interrupts::InterruptStack) {
+                let _handler = $crate::arch::interrupts::HandlerGuard::enter();
                 $func
             }
 
+            swapgs_if_user!();
             push_preserved!();
             push_scratch!();
             push_fs!();
@@ -221,6 +404,7 @@ macro_rules! interrupt {
             pop_fs!();
             pop_scratch!();
             pop_preserved!();
+            swapgs_if_user!();
             iretq!();
         }
     };
@@ -230,7 +414,10 @@ macro_rules! interrupt {
 macro_rules! interrupt_error {
     ($name:ident, $stack:ident) => {
         interrupt_error!($name, $stack, {
-            println!("CPU fault: {}\n{:?}", stringify!($name), $stack);
+            if $stack.from_user() {
+                $crate::process::fault(stringify!($name), $stack.rip);
+            }
+            $crate::backtrace::fault(stringify!($name), $stack, $stack.rip, $stack.preserved.rbp);
             // asm!("hlt" :::: "intel", "volatile");
         });
     };
@@ -239,9 +426,11 @@ macro_rules! interrupt_error {
         pub unsafe extern "C" fn $name() {
             #[inline(never)]
             unsafe fn inner() {
+                let _handler = $crate::arch::interrupts::HandlerGuard::enter();
                 $func
             }
 
+            swapgs_if_user_error!();
             push_preserved!();
             push_scratch!();
             push_fs!();
@@ -253,6 +442,7 @@ macro_rules! interrupt_error {
             pop_preserved!();
             // pop off error code
             asm!("add rsp, 8" :::: "intel", "volatile");
+            swapgs_if_user!();
             iretq!();
         }
     };
@@ -261,9 +451,11 @@ macro_rules! interrupt_error {
         pub unsafe extern "C" fn $name() {
             #[inline(never)]
             unsafe fn inner($stack: &mut $crate::arch::interrupts::InterruptErrorStack) {
+                let _handler = $crate::arch::interrupts::HandlerGuard::enter();
                 $func
             }
 
+            swapgs_if_user_error!();
             push_preserved!();
             push_scratch!();
             push_fs!();
@@ -279,21 +471,44 @@ macro_rules! interrupt_error {
             pop_preserved!();
             // pop off error code
             asm!("add rsp, 8" :::: "intel", "volatile");
+            swapgs_if_user!();
             iretq!();
         }
     };
 }
 
 interrupt!(divide_by_zero, stack);
-interrupt!(debug, stack);
-interrupt!(nonmaskable, stack);
-interrupt!(breakpoint, stack);
+interrupt!(debug, stack, {
+    // Watchpoints are handled first, and anything else belongs to the
+    // debugger, if there is one
+    if crate::arch::debug::handle(stack) && !crate::gdb::trap(stack, crate::gdb::Trap::Debug) {
+        if stack.from_user() {
+            crate::process::fault("debug", stack.rip);
+        }
+        crate::backtrace::fault("debug", stack, stack.rip, stack.preserved.rbp);
+    }
+});
+interrupt!(nonmaskable, stack, {
+    // Not caused by whatever was interrupted, even if it was user code
+    crate::backtrace::fault("nonmaskable", stack, stack.rip, stack.preserved.rbp);
+});
+interrupt!(breakpoint, stack, {
+    // The debugger only knows about the kernel
+    if stack.from_user() {
+        crate::process::fault("breakpoint", stack.rip);
+    }
+    if !crate::gdb::trap(stack, crate::gdb::Trap::Breakpoint) {
+        crate::backtrace::fault("breakpoint", stack, stack.rip, stack.preserved.rbp);
+    }
+});
 interrupt!(overflow, stack);
 interrupt!(bound_range, stack);
 interrupt!(invalid_opcode, stack);
 interrupt!(device_not_available, stack);
 interrupt_error!(double_fault, stack, {
-    //asm!("hlt");
+    // Runs on its own interrupt stack, since a double fault is commonly the
+    // result of a kernel stack overflow. There is no way to recover
+    panic!("CPU fault: double_fault\n{:?}", stack);
 });
 interrupt!(coprocessor_segment, stack);
 
@@ -301,14 +516,58 @@ interrupt_error!(invalid_tss, stack);
 interrupt_error!(segment_not_present, stack);
 interrupt_error!(stack_segment, stack);
 interrupt_error!(protection, stack);
-interrupt_error!(page, _stack, {
-    let cr2: usize;
-    asm!("mov rax, cr2" : "={rax}"(cr2) ::: "intel", "volatile");
-    println!("Page fault! {}", cr2);
+interrupt_error!(page, stack, {
+    use crate::paging::{fault, Virtual};
+
+    let cr2 = crate::arch::instructions::cr2();
+    let error = fault::PageFaultError::new(stack.error_code);
+    if let Err(reason) = fault::handle(Virtual::new(cr2), error) {
+        if stack.from_user() {
+            crate::process::page_fault(stack.rip, cr2);
+        }
+        panic!(
+            "unhandled page fault at {:#016X}: {:?} ({:?})\n{:?}",
+            cr2, error, reason, stack
+        );
+    }
 });
 interrupt!(fpu, stack);
 interrupt_error!(alignment_check, stack);
-interrupt!(machine_check, stack);
+interrupt!(machine_check, stack, {
+    panic!("CPU fault: machine_check\n{:?}", stack);
+});
 interrupt!(simd, stack);
 interrupt!(virtualization, stack);
 interrupt_error!(security, stack);
+
+/// Define an entry stub for each ISA IRQ, passing the IRQ number to
+/// [`irq::handle`], along with a table of the stubs
+macro_rules! irq {
+    ($($name:ident = $irq:expr),*) => {
+        $(interrupt!($name, stack, {
+            irq::handle($irq, stack);
+        });)*
+
+        /// Entry stubs for ISA IRQs 0 to 15, in order
+        pub static IRQS: [unsafe extern "C" fn(); irq::IRQ_LINES] = [$($name),*];
+    };
+}
+
+irq!(
+    irq0 = 0,
+    irq1 = 1,
+    irq2 = 2,
+    irq3 = 3,
+    irq4 = 4,
+    irq5 = 5,
+    irq6 = 6,
+    irq7 = 7,
+    irq8 = 8,
+    irq9 = 9,
+    irq10 = 10,
+    irq11 = 11,
+    irq12 = 12,
+    irq13 = 13,
+    irq14 = 14,
+    irq15 = 15
+);
@@ -1,8 +1,15 @@
+pub mod context;
+pub mod debug;
+pub mod gdt;
 pub mod idt;
 pub mod instructions;
 #[macro_use]
 pub mod interrupts;
 pub mod devices;
+pub mod percpu;
+pub mod smp;
+pub mod syscall;
+pub mod usermode;
 
 #[repr(u16)]
 pub enum PrivilegeLevel {
@@ -0,0 +1,178 @@
+//! Per-CPU data, reached through the GS base register
+//!
+//! Each processor owns a [`Cpu`] structure whose first field points back at
+//! itself, so that `gs:[0]` yields the address of the current processor's
+//! structure without reading any MSRs.
+use super::gdt::TaskStateSegment;
+use super::instructions;
+use super::interrupts::Nesting;
+use alloc::boxed::Box;
+use core::cell::Cell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+/// Maximum number of processors that are brought online
+pub const MAX_CPUS: usize = 64;
+
+const IA32_GS_BASE: u32 = 0xC000_0101;
+
+/// Offsets of the fields that the system call entry reaches through GS
+pub const KERNEL_STACK_OFFSET: usize = 8;
+pub const USER_STACK_OFFSET: usize = 16;
+
+#[repr(C)]
+pub struct Cpu {
+    /// Address of this structure. Must remain the first field
+    this: *const Cpu,
+    /// Top of the running thread's kernel stack, which the system call
+    /// entry switches to. Must remain at [`KERNEL_STACK_OFFSET`]
+    kernel_stack: Cell<usize>,
+    /// User stack pointer, kept by the system call entry until the kernel
+    /// stack is in place. Must remain at [`USER_STACK_OFFSET`]
+    user_stack: Cell<usize>,
+    /// Logical index of the processor, with the BSP at 0
+    pub id: usize,
+    pub apic_id: u8,
+    online: AtomicBool,
+    tss: AtomicPtr<TaskStateSegment>,
+    /// Only touched by the owning processor
+    pub(super) nesting: Cell<Nesting>,
+}
+
+unsafe impl Sync for Cpu {}
+unsafe impl Send for Cpu {}
+
+impl Cpu {
+    /// Allocate a [`Cpu`] structure that lives for the rest of the kernel
+    pub fn new(id: usize, apic_id: u8) -> &'static Cpu {
+        let cpu = Box::leak(Box::new(Cpu {
+            this: ptr::null(),
+            kernel_stack: Cell::new(0),
+            user_stack: Cell::new(0),
+            id,
+            apic_id,
+            online: AtomicBool::new(false),
+            tss: AtomicPtr::new(ptr::null_mut()),
+            nesting: Cell::new(Nesting::new()),
+        }));
+        let this = cpu as *const Cpu;
+        cpu.this = this;
+        cpu
+    }
+
+    /// The processor has finished initializing itself
+    pub fn online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+
+    pub fn set_online(&self) {
+        self.online.store(true, Ordering::SeqCst)
+    }
+
+    pub fn set_tss(&self, tss: &'static mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::SeqCst)
+    }
+
+    /// Set the stack that system calls and interrupts from ring 3 run on.
+    /// Must only be called on the processor that owns this structure
+    pub fn set_kernel_stack(&self, top: usize) {
+        self.kernel_stack.set(top);
+        unsafe { self.tss().set_kernel_stack(top) }
+    }
+
+    /// Return the processor's TSS
+    ///
+    /// # Safety
+    ///
+    /// Must only be called on the processor that owns this structure, with
+    /// no other reference to the TSS live
+    pub unsafe fn tss(&self) -> &mut TaskStateSegment {
+        &mut *self.tss.load(Ordering::SeqCst)
+    }
+}
+
+/// Stands in for the BSP's [`Cpu`] between [`early`] and [`init`]
+static BOOT: Cpu = Cpu {
+    this: &BOOT as *const Cpu,
+    kernel_stack: Cell::new(0),
+    user_stack: Cell::new(0),
+    id: 0,
+    apic_id: 0,
+    online: AtomicBool::new(false),
+    tss: AtomicPtr::new(ptr::null_mut()),
+    nesting: Cell::new(Nesting::new()),
+};
+
+/// Registered processors. Entries below `COUNT` are never changed once
+/// written
+static mut CPUS: [*const Cpu; MAX_CPUS] = [ptr::null(); MAX_CPUS];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Point the GS base of the calling processor at `cpu`
+///
+/// # Safety
+///
+/// `cpu` must describe the calling processor
+pub unsafe fn install(cpu: &'static Cpu) {
+    instructions::wrmsr(IA32_GS_BASE, cpu as *const Cpu as u64);
+}
+
+/// Add `cpu` to the list of processors. Only the BSP registers processors,
+/// one at a time
+pub fn register(cpu: &'static Cpu) {
+    let count = COUNT.load(Ordering::SeqCst);
+    assert!(count < MAX_CPUS, "too many processors");
+    assert_eq!(cpu.id, count, "processors must be registered in order");
+    unsafe { CPUS[count] = cpu };
+    COUNT.store(count + 1, Ordering::SeqCst);
+}
+
+/// Number of registered processors
+pub fn count() -> usize {
+    COUNT.load(Ordering::SeqCst)
+}
+
+/// Return the processor with logical index `id`
+pub fn cpu(id: usize) -> Option<&'static Cpu> {
+    if id < count() {
+        unsafe { Some(&*CPUS[id]) }
+    } else {
+        None
+    }
+}
+
+/// Return the calling processor's [`Cpu`] structure. Must not be called
+/// before [`early`] on the BSP, or before an AP has installed its own
+#[cfg(not(test))]
+pub fn current_cpu() -> &'static Cpu {
+    let cpu: *const Cpu;
+    unsafe {
+        asm!("mov $0, gs:[0]" : "=r"(cpu) ::: "intel", "volatile");
+        &*cpu
+    }
+}
+
+/// Unit tests run on the host, without a GS base to find the per-CPU data
+/// through, so each test thread stands in for a processor of its own
+#[cfg(test)]
+pub fn current_cpu() -> &'static Cpu {
+    std::thread_local!(static CPU: &'static Cpu = Cpu::new(0, 0));
+    CPU.with(|cpu| *cpu)
+}
+
+/// Point the BSP's GS base at a placeholder [`Cpu`], so that interrupt
+/// nesting can be tracked before the heap is available. Loading the GDT
+/// clears the GS base, so this must come after [`super::gdt::init`]
+pub fn early() {
+    unsafe { install(&BOOT) };
+}
+
+/// Set up the BSP's per-CPU data, as logical processor 0
+pub fn init(apic_id: u8) {
+    let cpu = Cpu::new(0, apic_id);
+    cpu.set_tss(unsafe { super::gdt::bsp_tss() });
+    cpu.nesting.set(current_cpu().nesting.get());
+    unsafe { install(cpu) };
+    register(cpu);
+    cpu.set_online();
+}
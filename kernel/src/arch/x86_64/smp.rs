@@ -0,0 +1,304 @@
+//! Start up the application processors
+//!
+//! Each AP is woken with an INIT-SIPI-SIPI sequence, and begins executing
+//! in real mode at [`TRAMPOLINE`]. The trampoline switches directly to long
+//! mode using the BSP's page tables, loads a stack prepared by the BSP and
+//! calls [`ap_main`]. APs are started one at a time, so that they can share
+//! the trampoline's data area.
+use super::devices::{self, apic, pit::Intel8253};
+use super::percpu::{self, Cpu};
+use super::{context, debug, gdt, idt, instructions, interrupts, syscall};
+use crate::memory::KERNEL_VIRT;
+use crate::paging;
+use crate::prelude::*;
+use alloc::boxed::Box;
+use alloc::vec;
+use core::ptr;
+
+/// Physical address the trampoline is copied to. It must be page aligned,
+/// below 1 MiB, and identity mapped
+pub const TRAMPOLINE: usize = 0x1000;
+
+/// Size, in bytes, of each AP's kernel stack
+const AP_STACK_SIZE: usize = 0x10000;
+
+global_asm!(
+    r#"
+.pushsection .text.trampoline, "ax"
+.set TRAMPOLINE, 0x1000
+
+.code16
+.global trampoline_start
+trampoline_start:
+    cli
+    cld
+    xor %ax, %ax
+    mov %ax, %ds
+    lgdtl (trampoline_gdt_desc - trampoline_start + TRAMPOLINE)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl $0x08, $(trampoline_32 - trampoline_start + TRAMPOLINE)
+
+.code32
+trampoline_32:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    # Enable PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    mov (trampoline_cr3 - trampoline_start + TRAMPOLINE), %eax
+    mov %eax, %cr3
+    # Set EFER.LME, and EFER.NXE if no-execute pages are supported, as the
+    # kernel's page tables already use them
+    xor %ebx, %ebx
+    mov $0x80000000, %eax
+    cpuid
+    cmp $0x80000001, %eax
+    jb 1f
+    mov $0x80000001, %eax
+    cpuid
+    and $(1 << 20), %edx
+    shr $9, %edx
+    mov %edx, %ebx
+1:
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $(1 << 8), %eax
+    or %ebx, %eax
+    wrmsr
+    # Enable paging, activating long mode
+    mov %cr0, %eax
+    or $(1 << 31), %eax
+    mov %eax, %cr0
+    ljmpl $0x18, $(trampoline_64 - trampoline_start + TRAMPOLINE)
+
+.code64
+trampoline_64:
+    xor %ax, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov (trampoline_stack - trampoline_start + TRAMPOLINE), %rsp
+    mov (trampoline_arg - trampoline_start + TRAMPOLINE), %rdi
+    mov (trampoline_entry - trampoline_start + TRAMPOLINE), %rax
+    call *%rax
+1:
+    hlt
+    jmp 1b
+
+.align 8
+trampoline_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    .quad 0x00209A0000000000
+trampoline_gdt_desc:
+    .word trampoline_gdt_desc - trampoline_gdt - 1
+    .long (trampoline_gdt - trampoline_start + TRAMPOLINE)
+
+.align 8
+.global trampoline_cr3
+trampoline_cr3:
+    .quad 0
+.global trampoline_stack
+trampoline_stack:
+    .quad 0
+.global trampoline_entry
+trampoline_entry:
+    .quad 0
+.global trampoline_arg
+trampoline_arg:
+    .quad 0
+.global trampoline_end
+trampoline_end:
+.popsection
+"#
+);
+
+extern "C" {
+    static trampoline_start: u8;
+    static trampoline_end: u8;
+    static trampoline_cr3: u8;
+    static trampoline_stack: u8;
+    static trampoline_entry: u8;
+    static trampoline_arg: u8;
+}
+
+/// Return the address of the copy of `symbol` at [`TRAMPOLINE`]
+fn relocate(symbol: &u8) -> *mut u64 {
+    let start = unsafe { &trampoline_start as *const u8 as usize };
+    let offset = symbol as *const u8 as usize - start;
+    (KERNEL_VIRT + TRAMPOLINE + offset) as *mut u64
+}
+
+/// Copy the trampoline code to [`TRAMPOLINE`]
+fn install_trampoline() {
+    unsafe {
+        let start = &trampoline_start as *const u8;
+        let len = &trampoline_end as *const u8 as usize - start as usize;
+        assert!(len <= 0x1000, "AP trampoline does not fit in a page");
+        ptr::copy_nonoverlapping(start, (KERNEL_VIRT + TRAMPOLINE) as *mut u8, len);
+    }
+}
+
+/// Start the processor with local APIC ID `apic_id`, returning true once
+/// it is online
+fn start(apic_id: u8) -> bool {
+    let cpu = Cpu::new(percpu::count(), apic_id);
+    let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let stack_top = (stack.as_ptr() as usize + AP_STACK_SIZE) & !0xF;
+
+    unsafe {
+        ptr::write_volatile(relocate(&trampoline_cr3), instructions::cr3());
+        ptr::write_volatile(relocate(&trampoline_stack), stack_top as u64);
+        ptr::write_volatile(relocate(&trampoline_entry), ap_main as usize as u64);
+        ptr::write_volatile(relocate(&trampoline_arg), cpu as *const Cpu as u64);
+    }
+
+    let lapic = apic::local();
+    lapic.send_init(apic_id);
+    Intel8253::delay(10_000);
+    for _ in 0..2 {
+        lapic.send_startup(apic_id, (TRAMPOLINE >> 12) as u8);
+        Intel8253::delay(200);
+        if cpu.online() {
+            break;
+        }
+    }
+
+    // Give the processor up to 100 ms to come up
+    for _ in 0..1000 {
+        if cpu.online() {
+            percpu::register(cpu);
+            return true;
+        }
+        Intel8253::delay(100);
+    }
+    false
+}
+
+/// Start every enabled processor listed in the MADT, returning the number
+/// of processors online, including the BSP.
+///
+/// Requires the APICs and per-CPU data of the BSP to be set up
+pub fn init() -> usize {
+    let madt = match crate::acpi::tables().and_then(|t| t.madt()) {
+        Some(madt) => madt,
+        None => return percpu::count(),
+    };
+    if devices::mode() != devices::Mode::Apic {
+        return percpu::count();
+    }
+
+    install_trampoline();
+    let bsp = percpu::current_cpu().apic_id;
+    for processor in madt.processors().filter(|p| p.enabled()) {
+        if processor.apic_id == bsp as u32 {
+            continue;
+        }
+        if processor.apic_id > 0xFF {
+            println!("CPU with x2APIC ID {} cannot be started", processor.apic_id);
+            continue;
+        }
+        if percpu::count() == percpu::MAX_CPUS {
+            break;
+        }
+        if !start(processor.apic_id as u8) {
+            println!("CPU with APIC ID {} did not start", processor.apic_id);
+        }
+    }
+    percpu::count()
+}
+
+/// Rust entry point for application processors, called by the trampoline
+/// on the stack prepared by [`start`]
+extern "C" fn ap_main(cpu: &'static Cpu) -> ! {
+    // Locks track interrupt nesting through the per-CPU data, which has to
+    // be installed again once the new GDT has cleared the GS base
+    unsafe { percpu::install(cpu) };
+    cpu.set_tss(gdt::init_ap());
+    unsafe { percpu::install(cpu) };
+    paging::enable_write_protect();
+    syscall::init();
+    context::init();
+    idt::InterruptDescriptorTable::global().lock().load();
+    apic::local().enable();
+    // Once online, the processor is sent any changes to the watchpoints,
+    // so they are loaded after that to not miss one
+    cpu.set_online();
+    debug::init_ap();
+
+    #[cfg(feature = "contention-test")]
+    contention::contend();
+    interrupts::enable();
+    loop {
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
+
+#[cfg(feature = "contention-test")]
+pub use self::contention::contention_test;
+
+/// Lock stress test, only built with the `contention-test` feature since it
+/// holds up every boot
+#[cfg(feature = "contention-test")]
+mod contention {
+    use super::percpu;
+    use crate::prelude::*;
+    use core::ptr;
+    use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+
+    /// Number of times each processor takes the lock in [`contention_test`]
+    const ITERATIONS: usize = 100_000;
+
+    static START: AtomicBool = AtomicBool::new(false);
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Default)]
+    struct Counter(usize);
+
+    global!(Counter);
+
+    /// Increment the shared counter under its lock, with a deliberately
+    /// non-atomic read-modify-write so that any failure of mutual exclusion
+    /// loses increments
+    pub(super) fn contend() {
+        while !START.load(Ordering::SeqCst) {
+            spin_loop_hint();
+        }
+        for _ in 0..ITERATIONS {
+            let mut counter = Counter::global().lock();
+            let value = unsafe { ptr::read_volatile(&counter.0) };
+            spin_loop_hint();
+            unsafe { ptr::write_volatile(&mut counter.0, value + 1) };
+        }
+        FINISHED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Have every online processor hammer the same
+    /// [`crate::sync::Mutex`], and check that no increments were lost
+    pub fn contention_test() {
+        let cpus = percpu::count();
+        let _ = Counter::global();
+        START.store(true, Ordering::SeqCst);
+        contend();
+        while FINISHED.load(Ordering::SeqCst) < cpus {
+            spin_loop_hint();
+        }
+
+        let total = Counter::global().lock().0;
+        assert_eq!(
+            total,
+            cpus * ITERATIONS,
+            "mutex lost increments under contention"
+        );
+        println!(
+            "mutex contention test: {} CPUs x {} iterations ok",
+            cpus, ITERATIONS
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! System call entry: the SYSCALL instruction, and an `int 0x80` gate
+//!
+//! SYSCALL neither switches stacks nor saves anything on one, so its entry
+//! swaps in the kernel's GS base and finds the running thread's kernel
+//! stack in the per-CPU data. It saves the user's registers there in a
+//! [`SyscallStack`], and returns with SYSRET. The `int 0x80` gate is slower,
+//! but goes through the ordinary interrupt path, so it is easier to follow
+//! in a debugger.
+//!
+//! Either way, the call runs with interrupts enabled, as part of the
+//! calling thread. It can block and be preempted, like any other kernel
+//! code running on behalf of a thread.
+use super::gdt::{KERNEL_CODE, USER_CODE32};
+use super::instructions;
+use super::interrupts::{self, InterruptStack, Preserved, Scratch};
+use crate::process::{self, loader::USER_END};
+use crate::syscall::{self, Args};
+
+const IA32_EFER: u32 = 0xC000_0080;
+/// System call enable bit in EFER
+const EFER_SCE: u64 = 1 << 0;
+const IA32_STAR: u32 = 0xC000_0081;
+const IA32_LSTAR: u32 = 0xC000_0082;
+const IA32_FMASK: u32 = 0xC000_0084;
+
+/// RFLAGS bits that SYSCALL clears: trap, interrupt enable, direction and
+/// alignment check
+const FLAGS_MASK: u64 = 1 << 8 | 1 << 9 | 1 << 10 | 1 << 18;
+
+/// Vector of the software interrupt gate
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Registers saved by the SYSCALL entry, in the order of an
+/// [`InterruptStack`]. The processor puts the return address in rcx and
+/// the flags in r11, so those fields of `scratch` are not the user's
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(C)]
+pub struct SyscallStack {
+    pub scratch: Scratch,
+    pub preserved: Preserved,
+    pub rip: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+}
+
+/// Enable SYSCALL on the calling processor. Every processor must call this
+pub fn init() {
+    // SYSCALL loads CS from bits 32..48 of STAR, and SS from the
+    // descriptor after it. SYSRET to 64-bit mode loads CS from two
+    // descriptors after bits 48..64, and SS from the one in between
+    let star = (USER_CODE32 as u64) << 48 | (KERNEL_CODE as u64) << 32;
+    unsafe {
+        instructions::wrmsr(IA32_STAR, star);
+        instructions::wrmsr(IA32_LSTAR, syscall_entry as usize as u64);
+        instructions::wrmsr(IA32_FMASK, FLAGS_MASK);
+        let efer = instructions::rdmsr(IA32_EFER);
+        instructions::wrmsr(IA32_EFER, efer | EFER_SCE);
+    }
+}
+
+/// Decode the number and arguments of a system call from the registers
+/// that user code passed them in
+fn args(scratch: &Scratch) -> Args {
+    Args::new(
+        scratch.rax,
+        [
+            scratch.rdi,
+            scratch.rsi,
+            scratch.rdx,
+            scratch.r10,
+            scratch.r8,
+            scratch.r9,
+        ],
+    )
+}
+
+#[inline(never)]
+unsafe fn handle(stack: &mut SyscallStack) {
+    interrupts::enable();
+    let args = args(&stack.scratch);
+    stack.scratch.rax = syscall::dispatch(&args, stack);
+    interrupts::disable();
+
+    // SYSRET faults in ring 0, on the user's stack, if the return address
+    // is not canonical
+    if stack.rip >= USER_END {
+        process::fault("system call return", stack.rip);
+    }
+}
+
+/// Entry point of the SYSCALL instruction, which arrives with interrupts
+/// masked, the user's GS base, and the user's stack
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    // The offsets are `percpu::USER_STACK_OFFSET` and `KERNEL_STACK_OFFSET`
+    asm!("swapgs
+          mov gs:[16], rsp
+          mov rsp, gs:[8]
+          push qword ptr gs:[16]
+          push r11
+          push rcx"
+          :::: "intel", "volatile");
+    push_preserved!();
+    push_scratch!();
+
+    let rsp: usize;
+    asm!("" : "={rsp}"(rsp) ::: "intel", "volatile");
+    handle(&mut *(rsp as *mut SyscallStack));
+
+    pop_scratch!();
+    pop_preserved!();
+    // Interrupts stay masked until SYSRET, since the GS base and stack
+    // are the user's from here on
+    asm!("pop rcx
+          pop r11
+          pop rsp
+          swapgs
+          sysretq"
+          :::: "intel", "volatile");
+}
+
+#[inline(never)]
+unsafe fn handle_gate(stack: &mut InterruptStack) {
+    let mut regs = SyscallStack {
+        scratch: stack.scratch,
+        preserved: stack.preserved,
+        rip: stack.rip,
+        rflags: stack.rflags,
+        rsp: stack.rsp,
+    };
+    interrupts::enable();
+    regs.scratch.rax = syscall::dispatch(&args(&regs.scratch), &mut regs);
+    interrupts::disable();
+
+    stack.scratch = regs.scratch;
+    stack.preserved = regs.preserved;
+    stack.rip = regs.rip;
+    stack.rflags = regs.rflags;
+    stack.rsp = regs.rsp;
+}
+
+/// Handler for the `int 0x80` gate. It is not an ordinary [`interrupt!`]
+/// handler, since system calls are not interrupt context and may block
+#[naked]
+pub unsafe extern "C" fn syscall_gate() {
+    swapgs_if_user!();
+    push_preserved!();
+    push_scratch!();
+    push_fs!();
+
+    let rsp: usize;
+    asm!("" : "={rsp}"(rsp) ::: "intel", "volatile");
+    handle_gate(&mut *(rsp as *mut InterruptStack));
+
+    pop_fs!();
+    pop_scratch!();
+    pop_preserved!();
+    swapgs_if_user!();
+    iretq!();
+}
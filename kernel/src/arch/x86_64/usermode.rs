@@ -0,0 +1,94 @@
+//! Entering ring 3
+//!
+//! A user thread is an ordinary kernel thread that leaves the kernel with
+//! `iretq`. It comes back on its kernel stack whenever an interrupt or
+//! exception arrives in ring 3, and returns to user mode the same way.
+use super::gdt::{USER_CODE, USER_DATA};
+use super::instructions;
+use super::interrupts::{Preserved, Scratch};
+use super::syscall::SyscallStack;
+
+/// Holds the GS base that is not in use: the user's while in the kernel
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// RFLAGS that user code starts with: interrupts enabled, and the
+/// reserved bit 1 that is always set
+pub const USER_RFLAGS: usize = 0x202;
+
+/// Stack frame that [`resume`] pops the user's registers from
+#[repr(C)]
+struct Frame {
+    scratch: Scratch,
+    preserved: Preserved,
+    rip: usize,
+    cs: usize,
+    rflags: usize,
+    rsp: usize,
+    ss: usize,
+}
+
+/// Start running user code at `entry`, with stack pointer `stack`, in the
+/// active address space. Every other general purpose register is cleared,
+/// so no kernel data leaks into ring 3
+///
+/// # Safety
+///
+/// As for [`resume`]
+pub unsafe fn enter(entry: usize, stack: usize) -> ! {
+    let regs = SyscallStack {
+        rip: entry,
+        rflags: USER_RFLAGS,
+        rsp: stack,
+        ..SyscallStack::default()
+    };
+    resume(&regs)
+}
+
+/// Return to user code in the active address space with the registers in
+/// `regs`, such as a copy of those another thread saved on a system call
+///
+/// # Safety
+///
+/// The calling thread's kernel stack is reused from the top for interrupts
+/// from ring 3, so nothing on it may still be needed. Anything the caller
+/// owns must be dropped beforehand, or it is leaked
+pub unsafe fn resume(regs: &SyscallStack) -> ! {
+    // User code can only change the interrupt flag, and the flags that
+    // don't affect the kernel
+    let rflags = regs.rflags & 0xCD5 | USER_RFLAGS;
+    let frame = Frame {
+        scratch: regs.scratch,
+        preserved: regs.preserved,
+        rip: regs.rip,
+        cs: USER_CODE as usize,
+        rflags,
+        rsp: regs.rsp,
+        ss: USER_DATA as usize,
+    };
+
+    // A kernel GS base must not leak into ring 3 either. Interrupts stay
+    // off until `iretq`, since nothing in the kernel works with a user GS
+    asm!("cli" :::: "volatile");
+    instructions::wrmsr(IA32_KERNEL_GS_BASE, 0);
+    asm!("mov rsp, $0
+          pop r11
+          pop r10
+          pop r9
+          pop r8
+          pop rsi
+          pop rdi
+          pop rdx
+          pop rcx
+          pop rax
+          pop r15
+          pop r14
+          pop r13
+          pop r12
+          pop rbp
+          pop rbx
+          swapgs
+          iretq"
+          :: "r"(&frame as *const Frame)
+          : "memory" : "intel", "volatile");
+    unreachable!("returned from ring 3")
+}
@@ -0,0 +1,223 @@
+//! Stack backtraces, walked through the chain of saved frame pointers
+//!
+//! Every function compiled for the kernel target keeps its caller's frame
+//! pointer at `[rbp]`, with the return address right above it at
+//! `[rbp + 8]`. Return addresses are resolved to `function+offset` using
+//! the kernel's symbol table, when one is available.
+use crate::elf::Elf;
+use crate::io::Serial;
+use crate::ksyms::Table;
+use crate::prelude::*;
+use crate::sync::Once;
+use crate::term::Terminal;
+use core::fmt;
+use core::ops::Range;
+
+/// Maximum number of frames printed in a backtrace
+const MAX_FRAMES: usize = 32;
+
+static TABLE: Once<Table<'static>> = Once::new();
+static ELF: Once<Elf<'static>> = Once::new();
+
+/// Resolve addresses in backtraces using the symbol table appended by the
+/// builder, falling back to the symbol table of `elf`, which is usually
+/// stripped
+pub fn init(elf: Elf<'static>, table: Option<Table<'static>>) {
+    ELF.call_once(|| elf);
+    if let Some(table) = table {
+        TABLE.call_once(|| table);
+    }
+}
+
+/// Find the function containing `addr`, returning its name and the offset
+/// of `addr` into it
+pub fn resolve(addr: usize) -> Option<(&'static str, usize)> {
+    if let Some(table) = TABLE.try_get() {
+        let (symbol, offset) = table.lookup(addr as u64)?;
+        return Some((symbol.name, offset as usize));
+    }
+    ELF.try_get()?.symbolize(addr)
+}
+
+/// Iterator over the return addresses found by following a chain of
+/// frame pointers
+pub struct Frames {
+    rbp: usize,
+    stack: Range<usize>,
+    text: Range<usize>,
+    depth: usize,
+}
+
+impl Frames {
+    /// Walk the stack starting at frame pointer `rbp`, stopping at the
+    /// first frame that isn't wholly inside `stack`, or return address
+    /// outside of `text`
+    pub fn new(rbp: usize, stack: Range<usize>, text: Range<usize>) -> Frames {
+        Frames {
+            rbp,
+            stack,
+            text,
+            depth: 0,
+        }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        // The frame pointer may be garbage, so it is only followed if both
+        // words of the frame are on the stack
+        let end = self.rbp.checked_add(16)?;
+        if self.rbp == 0
+            || self.rbp % 8 != 0
+            || self.rbp < self.stack.start
+            || end > self.stack.end
+            || self.depth == MAX_FRAMES
+        {
+            return None;
+        }
+        let (next, ret) = unsafe {
+            let frame = self.rbp as *const usize;
+            (*frame, *frame.add(1))
+        };
+        if !self.text.contains(&ret) {
+            return None;
+        }
+        // Callers' frames are always higher up the stack. Anything else
+        // means the chain is corrupt, or has reached the bottom
+        self.rbp = if next > self.rbp { next } else { 0 };
+        self.depth += 1;
+        Some(ret)
+    }
+}
+
+/// Range of addresses occupied by the kernel image
+#[cfg(not(test))]
+fn kernel_text() -> Range<usize> {
+    extern "C" {
+        static _kernel_start: u8;
+        static _kernel_end: u8;
+    }
+    unsafe { &_kernel_start as *const u8 as usize.._kernel_end as *const u8 as usize }
+}
+
+#[cfg(test)]
+fn kernel_text() -> Range<usize> {
+    0..usize::max_value()
+}
+
+/// Range of addresses that kernel stacks can be in: the higher half, where
+/// everything is mapped by the kernel rather than by user programs
+#[cfg(not(test))]
+fn kernel_stacks() -> Range<usize> {
+    0xFFFF_8000_0000_0000..usize::max_value()
+}
+
+#[cfg(test)]
+fn kernel_stacks() -> Range<usize> {
+    0..usize::max_value()
+}
+
+/// Write frame `index`, executing at `addr`, to `dest`
+fn write_frame<W: Write>(dest: &mut W, index: usize, addr: usize) -> fmt::Result {
+    match resolve(addr) {
+        Some((name, offset)) => {
+            writeln!(dest, "{:>4}: {:#018X} {}+{:#X}", index, addr, name, offset)
+        }
+        None => writeln!(dest, "{:>4}: {:#018X} <unknown>", index, addr),
+    }
+}
+
+/// Write a backtrace to `dest`, starting at the instruction `rip`, if
+/// known, and walking the stack from frame pointer `rbp`
+pub fn write<W: Write>(dest: &mut W, rip: Option<usize>, rbp: usize) -> fmt::Result {
+    writeln!(dest, "backtrace:")?;
+    // Return addresses point just past the call instruction, which may be
+    // the start of the next function, so they are resolved one byte back
+    let calls = Frames::new(rbp, kernel_stacks(), kernel_text()).map(|ret| ret - 1);
+    for (index, addr) in rip.into_iter().chain(calls).enumerate() {
+        write_frame(dest, index, addr)?;
+    }
+    Ok(())
+}
+
+/// Write a backtrace of the calling function to `dest`
+#[inline(always)]
+pub fn write_current<W: Write>(dest: &mut W) -> fmt::Result {
+    write(dest, None, crate::arch::instructions::rbp())
+}
+
+/// Report an unhandled CPU exception on both the serial port and the
+/// terminal, along with a backtrace of the interrupted code. The locks on
+/// both are forced, since the exception may have interrupted their holder
+pub fn fault<S: fmt::Debug>(name: &str, stack: &S, rip: usize, rbp: usize) {
+    fn report<W: Write, S: fmt::Debug>(
+        dest: &mut W,
+        name: &str,
+        stack: &S,
+        rip: usize,
+        rbp: usize,
+    ) {
+        let _ = writeln!(dest, "CPU fault: {}\n{:?}", name, stack);
+        let _ = write(dest, Some(rip), rbp);
+    }
+    let serial: &mut Serial = unsafe { Serial::global().force() };
+    let terminal: &mut Terminal = unsafe { Terminal::global().force() };
+    report(serial, name, stack, rip, rbp);
+    report(terminal, name, stack, rip, rbp);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ANYWHERE: Range<usize> = 0..usize::max_value();
+
+    #[test]
+    fn walk() {
+        // Three frames, each pointing at its caller's, ending with a null
+        // frame pointer
+        let mut stack = [0usize; 8];
+        let base = stack.as_ptr() as usize;
+        stack[0] = base + 16;
+        stack[1] = 0x1010;
+        stack[2] = base + 48;
+        stack[3] = 0x1020;
+        stack[6] = 0;
+        stack[7] = 0x1030;
+
+        let frames: Vec<usize> = Frames::new(base, ANYWHERE, 0x1000..0x2000).collect();
+        assert_eq!(frames, [0x1010, 0x1020, 0x1030]);
+
+        // A return address outside of the kernel ends the walk
+        stack[3] = 0x3000;
+        let frames: Vec<usize> = Frames::new(base, ANYWHERE, 0x1000..0x2000).collect();
+        assert_eq!(frames, [0x1010]);
+
+        // As does a frame pointer that moves down the stack
+        stack[3] = 0x1020;
+        stack[2] = base;
+        let frames: Vec<usize> = Frames::new(base, ANYWHERE, 0x1000..0x2000).collect();
+        assert_eq!(frames, [0x1010, 0x1020]);
+
+        // Frame pointers off the stack aren't followed
+        stack[2] = base + 48;
+        let frames: Vec<usize> = Frames::new(base, base..base + 48, 0x1000..0x2000).collect();
+        assert_eq!(frames, [0x1010, 0x1020]);
+        let frames: Vec<usize> = Frames::new(base, base + 8..base + 64, 0x1000..0x2000).collect();
+        assert!(frames.is_empty());
+
+        assert_eq!(Frames::new(0, ANYWHERE, ANYWHERE).count(), 0);
+        assert_eq!(Frames::new(base + 4, ANYWHERE, ANYWHERE).count(), 0);
+    }
+
+    #[test]
+    fn loops_are_bounded() {
+        let mut stack = [0usize; 2];
+        stack[0] = stack.as_ptr() as usize;
+        stack[1] = 0x1010;
+        let frames = Frames::new(stack.as_ptr() as usize, ANYWHERE, 0x1000..0x2000);
+        assert_eq!(frames.count(), 1);
+    }
+}
@@ -1,10 +1,12 @@
-//! Parse the kernel's executable file for advanced error-handling
+//! Parse ELF files: the kernel's own executable, for advanced
+//! error-handling, and user programs, to load them
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[repr(C)]
 pub struct Header {
     ident: [u8; 16],
-    object_type: HeaderType,
+    /// A [`HeaderType`], kept raw since the file may hold any value
+    object_type: u16,
     machine_type: u16,
     object_ver: u32,
     entry: usize,
@@ -44,7 +46,8 @@ pub enum SegmentType {
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[repr(C)]
 pub struct Segment {
-    ty: SegmentType,
+    /// A [`SegmentType`], or one of the many OS-specific types
+    ty: u32,
     flags: u32,
     offset: usize,
     pub vaddr: usize,
@@ -54,6 +57,85 @@ pub struct Segment {
     align: usize,
 }
 
+/// Reasons a file could not be parsed as ELF
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ElfError {
+    /// The file does not start with the ELF magic number
+    BadMagic,
+    /// The file is not a 64-bit little-endian ELF file
+    Unsupported,
+    /// The file is too short to hold the headers it describes
+    Truncated,
+    /// The file is not 8-byte aligned in memory, so its headers cannot be
+    /// read in place
+    Misaligned,
+}
+
+impl Header {
+    /// Machine type of x86-64 executables
+    pub const X86_64: u16 = 62;
+
+    pub fn object_type(&self) -> Option<HeaderType> {
+        match self.object_type {
+            0 => Some(HeaderType::None),
+            1 => Some(HeaderType::Relocatable),
+            2 => Some(HeaderType::Executable),
+            3 => Some(HeaderType::Dynamic),
+            4 => Some(HeaderType::Core),
+            _ => None,
+        }
+    }
+
+    pub fn machine(&self) -> u16 {
+        self.machine_type
+    }
+
+    /// Virtual address of the program's entry point
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Offset, in bytes, of the program headers in the file
+    pub fn program_headers(&self) -> usize {
+        self.phdr_off
+    }
+}
+
+impl Segment {
+    /// Segment flag bits
+    pub const EXECUTE: u32 = 1 << 0;
+    pub const WRITE: u32 = 1 << 1;
+    pub const READ: u32 = 1 << 2;
+
+    pub fn ty(&self) -> Option<SegmentType> {
+        match self.ty {
+            0 => Some(SegmentType::Null),
+            1 => Some(SegmentType::Loadable),
+            2 => Some(SegmentType::Dynamic),
+            3 => Some(SegmentType::Interpreter),
+            4 => Some(SegmentType::Note),
+            5 => Some(SegmentType::Reserved),
+            6 => Some(SegmentType::ProgramHeaderTable),
+            _ => None,
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Offset, in bytes, of the segment's contents in the file
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Size, in bytes, of the segment's contents in the file. Any memory
+    /// past this, up to `mem_size`, is zeroed
+    pub fn file_size(&self) -> usize {
+        self.file_size
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[repr(u32)]
 pub enum SectionType {
@@ -115,6 +197,28 @@ pub struct Symbol {
     size: usize,
 }
 
+impl Symbol {
+    /// Symbol type for functions and other executable code
+    const FUNCTION: u8 = 2;
+
+    pub fn is_function(&self) -> bool {
+        self.info & 0xF == Self::FUNCTION
+    }
+
+    pub fn address(&self) -> usize {
+        self.value
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns true if `addr` lies within the symbol
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.value && addr - self.value < self.size
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Elf<'a> {
     pub header: &'a Header,
@@ -125,49 +229,127 @@ pub struct Elf<'a> {
 impl<'a> Elf<'a> {
     const ELFMAGIC: [u8; 4] = [0x7F, 'E' as u8, 'L' as u8, 'F' as u8];
 
-    pub fn symbol(&self) -> &'a str {
+    /// Return the contents of `section`
+    pub fn section_data(&self, section: &Section) -> &'a [u8] {
+        // The bootloader leaves the whole file in memory after the header,
+        // so sections are found relative to it
+        unsafe {
+            let ptr = (self.header as *const Header as *const u8).add(section.offset);
+            core::slice::from_raw_parts(ptr, section.size)
+        }
+    }
+
+    /// Return the symbol table and its associated string table, if the
+    /// file has not been stripped
+    fn symbol_table(&self) -> Option<(&'a [Symbol], &'a [u8])> {
         let symtab = self
             .sections
             .iter()
-            .filter(|s| s.ty == SectionType::Strings)
-            .next()
-            .unwrap();
-
-        unsafe {
-            let ptr = (self.header as *const _ as *const Section).offset(symtab.offset as isize);
-            println!("symtab pointer {:0X}", ptr as usize);
+            .find(|s| s.ty == SectionType::Symbols)?;
+        let strtab = self.sections.get(symtab.link as usize)?;
+        if symtab.entry_size != core::mem::size_of::<Symbol>() {
+            return None;
         }
 
-        ""
+        let data = self.section_data(symtab);
+        let symbols = unsafe {
+            core::slice::from_raw_parts(
+                data.as_ptr() as *const Symbol,
+                data.len() / core::mem::size_of::<Symbol>(),
+            )
+        };
+        Some((symbols, self.section_data(strtab)))
+    }
+
+    /// Return the symbol table, which is empty if the file was stripped
+    pub fn symbols(&self) -> &'a [Symbol] {
+        self.symbol_table()
+            .map(|(symbols, _)| symbols)
+            .unwrap_or(&[])
+    }
+
+    /// Return the name of `symbol`
+    pub fn symbol_name(&self, symbol: &Symbol) -> Option<&'a str> {
+        let (_, strings) = self.symbol_table()?;
+        let name = strings.get(symbol.name as usize..)?;
+        let len = name.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&name[..len]).ok()
     }
 
-    /// TODO: pointer alignment issues?
+    /// Find the function containing `addr`, returning its name and the
+    /// offset of `addr` from the start of the function
+    pub fn symbolize(&self, addr: usize) -> Option<(&'a str, usize)> {
+        let symbol = self
+            .symbols()
+            .iter()
+            .find(|s| s.is_function() && s.contains(addr))?;
+        Some((self.symbol_name(symbol)?, addr - symbol.value))
+    }
+
+    /// Parse a file that is known to be valid, such as the kernel's own
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file is not a valid ELF file
     pub fn from(data: &'a [u8]) -> Elf<'a> {
-        if data[..Self::ELFMAGIC.len()] != Self::ELFMAGIC {
-            panic!("Invalid ELF header!");
+        match Self::parse(data) {
+            Ok(elf) => elf,
+            Err(err) => panic!("Invalid ELF header: {:?}", err),
         }
+    }
 
-        let ehdr = unsafe { &*(data.as_ptr() as *const Header) };
+    /// Parse the headers of a 64-bit ELF file, checking that the program
+    /// and section header tables lie within `data`
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        use core::mem::{align_of, size_of};
 
-        assert_eq!(ehdr.shdr_size as usize, core::mem::size_of::<Section>());
-        let segments = unsafe {
-            core::slice::from_raw_parts(
-                data.as_ptr().offset(ehdr.phdr_off as isize) as *const Segment,
-                ehdr.phdr_len as usize,
-            )
-        };
+        if data.len() < size_of::<Header>() {
+            return Err(ElfError::Truncated);
+        }
+        if data[..Self::ELFMAGIC.len()] != Self::ELFMAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        // 64-bit, little-endian
+        if data[4] != 2 || data[5] != 1 {
+            return Err(ElfError::Unsupported);
+        }
+        if data.as_ptr() as usize % align_of::<Header>() != 0 {
+            return Err(ElfError::Misaligned);
+        }
 
-        let sections = unsafe {
-            core::slice::from_raw_parts(
-                data.as_ptr().offset(ehdr.shdr_off as isize) as *const Section,
-                ehdr.shdr_len as usize,
-            )
-        };
+        let ehdr = unsafe { &*(data.as_ptr() as *const Header) };
+        let segments = Self::table::<Segment>(data, ehdr.phdr_off, ehdr.phdr_len, ehdr.phdr_size)?;
+        let sections = Self::table::<Section>(data, ehdr.shdr_off, ehdr.shdr_len, ehdr.shdr_size)?;
 
-        Elf {
+        Ok(Elf {
             header: ehdr,
             segments,
             sections,
+        })
+    }
+
+    /// Return the table of `len` entries of `size` bytes at `offset`
+    fn table<T>(data: &'a [u8], offset: usize, len: u16, size: u16) -> Result<&'a [T], ElfError> {
+        use core::mem::{align_of, size_of};
+
+        if len == 0 {
+            return Ok(&[]);
+        }
+        if size as usize != size_of::<T>() {
+            return Err(ElfError::Unsupported);
+        }
+        let end = (len as usize)
+            .checked_mul(size_of::<T>())
+            .and_then(|bytes| bytes.checked_add(offset))
+            .ok_or(ElfError::Truncated)?;
+        if end > data.len() {
+            return Err(ElfError::Truncated);
+        }
+        if offset % align_of::<T>() != 0 {
+            return Err(ElfError::Misaligned);
         }
+        Ok(unsafe {
+            core::slice::from_raw_parts(data.as_ptr().add(offset) as *const T, len as usize)
+        })
     }
 }
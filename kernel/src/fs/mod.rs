@@ -0,0 +1,192 @@
+//! A read-only file system of files built into the kernel image
+//!
+//! There are no disk drivers yet, so the programs that processes run, and
+//! any files they read, are embedded in the kernel and registered here by
+//! path at boot.
+//!
+//! Each process has its own table of [`Descriptors`], mapping the small
+//! numbers that system calls take to open [`File`]s.
+use crate::prelude::*;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+global!(Files);
+
+/// Maximum length, in bytes, of a path
+pub const PATH_MAX: usize = 4096;
+
+/// Maximum number of files a process can have open
+pub const OPEN_MAX: usize = 256;
+
+/// Registered files, by absolute path
+#[derive(Default)]
+pub struct Files {
+    files: BTreeMap<String, &'static [u8]>,
+}
+
+impl Files {
+    /// Register `data` as the contents of the file at `path`, replacing
+    /// any file already there
+    pub fn install(&mut self, path: &str, data: &'static [u8]) {
+        self.files.insert(String::from(path), data);
+    }
+
+    /// Return the contents of the file at `path`
+    pub fn lookup(&self, path: &[u8]) -> Option<&'static [u8]> {
+        let path = core::str::from_utf8(path).ok()?;
+        self.files.get(path).copied()
+    }
+}
+
+/// Register `data` as the contents of the file at `path`
+pub fn install(path: &str, data: &'static [u8]) {
+    Files::global().lock().install(path, data)
+}
+
+/// Return the contents of the file at `path`
+pub fn lookup(path: &[u8]) -> Option<&'static [u8]> {
+    Files::global().lock().lookup(path)
+}
+
+/// Resolve `path` against the working directory. Every process runs in the
+/// root directory, so relative paths just gain a leading '/'
+pub fn absolute(mut path: Vec<u8>) -> Vec<u8> {
+    if path.first() != Some(&b'/') {
+        path.insert(0, b'/');
+    }
+    path
+}
+
+/// A file opened by a process
+#[derive(Debug, Clone, PartialEq)]
+pub enum File {
+    /// The terminal, for writing. There is no keyboard input yet
+    Console,
+    /// A file from [`Files`], read from `offset` onwards
+    Data { data: &'static [u8], offset: usize },
+}
+
+impl File {
+    /// Read up to `max` bytes, advancing the offset past them. Files that
+    /// can't be read return nothing, as at the end of a file
+    pub fn read(&mut self, max: usize) -> &'static [u8] {
+        match self {
+            File::Console => &[],
+            File::Data { data, offset } => {
+                let start = (*offset).min(data.len());
+                let end = start + max.min(data.len() - start);
+                *offset = end;
+                &data[start..end]
+            }
+        }
+    }
+}
+
+/// Open files of a process, by descriptor
+#[derive(Debug, Clone, PartialEq)]
+pub struct Descriptors {
+    files: Vec<Option<File>>,
+}
+
+impl Descriptors {
+    /// A table with the console open as standard input, output and error
+    pub fn standard() -> Descriptors {
+        Descriptors {
+            files: vec![Some(File::Console); 3],
+        }
+    }
+
+    /// Return the file open as `fd`
+    pub fn get(&self, fd: i32) -> Option<&File> {
+        if fd < 0 {
+            return None;
+        }
+        self.files.get(fd as usize)?.as_ref()
+    }
+
+    /// Replace the file open as `fd`. Returns false if `fd` isn't open
+    pub fn update(&mut self, fd: i32, file: File) -> bool {
+        match self.files.get_mut(fd as usize) {
+            Some(Some(open)) if fd >= 0 => {
+                *open = file;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Open `file` as the lowest free descriptor, if there is one below
+    /// [`OPEN_MAX`]
+    pub fn open(&mut self, file: File) -> Option<i32> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if self.files.len() < OPEN_MAX => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+            None => return None,
+        };
+        self.files[fd] = Some(file);
+        Some(fd as i32)
+    }
+
+    /// Close `fd`, returning the file that was open
+    pub fn close(&mut self, fd: i32) -> Option<File> {
+        if fd < 0 {
+            return None;
+        }
+        self.files.get_mut(fd as usize)?.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn descriptors() {
+        let mut files = Descriptors::standard();
+        assert_eq!(files.get(1), Some(&File::Console));
+        assert_eq!(files.get(3), None);
+        assert_eq!(files.get(-1), None);
+
+        let data = File::Data {
+            data: b"contents",
+            offset: 0,
+        };
+        assert_eq!(files.open(data.clone()), Some(3));
+        assert_eq!(files.close(0), Some(File::Console));
+        assert_eq!(files.close(0), None);
+        // The lowest free descriptor is reused
+        assert_eq!(files.open(data.clone()), Some(0));
+
+        for fd in 4..OPEN_MAX {
+            assert_eq!(files.open(File::Console), Some(fd as i32));
+        }
+        assert_eq!(files.open(File::Console), None);
+        assert!(!files.update(OPEN_MAX as i32, File::Console));
+        assert!(files.update(3, File::Console));
+        assert_eq!(files.get(3), Some(&File::Console));
+    }
+
+    #[test]
+    fn reading() {
+        let mut file = File::Data {
+            data: b"contents",
+            offset: 0,
+        };
+        assert_eq!(file.read(3), b"con");
+        assert_eq!(file.read(100), b"tents");
+        assert_eq!(file.read(100), b"");
+        assert_eq!(File::Console.read(100), b"");
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(absolute(b"bin/hello".to_vec()), b"/bin/hello");
+        assert_eq!(absolute(b"/bin/hello".to_vec()), b"/bin/hello");
+        assert_eq!(absolute(Vec::new()), b"/");
+    }
+}
@@ -0,0 +1,362 @@
+//! GDB remote serial protocol stub
+//!
+//! Once [`init`] has found a UART on COM2, every `int3` and single-step
+//! trap enters the stub, which then serves GDB over the serial line until
+//! it is told to continue. Run QEMU with a second serial port, such as
+//! `-serial tcp::4321,server,nowait`, and attach with
+//! `target remote localhost:4321`. Call [`breakpoint`] to stop somewhere
+//! without a debugger having set a breakpoint first.
+//!
+//! Only the processor that trapped is stopped; the others keep running.
+use crate::arch::instructions;
+use crate::arch::interrupts::InterruptStack;
+use crate::io::{Io, Serial, COM2};
+use crate::paging::{mapper::Mapper, Virtual};
+use crate::prelude::*;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub mod packet;
+pub mod registers;
+
+use packet::{Command, Decoder, Event};
+use registers::Registers;
+
+/// Signal reported when the target stops at a breakpoint or after a step
+const SIGTRAP: u8 = 5;
+/// Opcode of `int3`
+const INT3: u8 = 0xCC;
+/// Trap flag in RFLAGS, which raises #DB after every instruction
+const RFLAGS_TF: u64 = 1 << 8;
+/// Write protect bit in CR0. While clear, the kernel may write to read-only
+/// pages, such as its own code
+const CR0_WP: u64 = 1 << 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Exception that entered the stub
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Trap {
+    /// `int3`, with `rip` pointing after the instruction
+    Breakpoint,
+    /// Debug exception, raised after a single step
+    Debug,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+global!(Stub, { Stub::new(Serial::new(COM2)) });
+
+pub struct Stub {
+    serial: Serial,
+    decoder: Decoder,
+    breakpoints: Vec<Breakpoint>,
+    /// GDB has sent a packet, and expects a stop reply whenever the target
+    /// stops again
+    attached: bool,
+}
+
+/// Look for a UART on COM2, and route breakpoints and debug exceptions to
+/// the stub if there is one
+pub fn init() -> bool {
+    if !Serial::present(COM2) {
+        return false;
+    }
+    let _ = Stub::global();
+    ENABLED.store(true, Ordering::SeqCst);
+    true
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Stop in the debugger
+#[inline(always)]
+pub fn breakpoint() {
+    unsafe { asm!("int3" :::: "intel", "volatile") }
+}
+
+/// Enter the stub from the #BP or #DB handler, returning once GDB resumes
+/// the target. Returns false without doing anything if the stub is not
+/// enabled, so that the exception can be handled normally
+pub fn trap(stack: &mut InterruptStack, trap: Trap) -> bool {
+    if !enabled() {
+        return false;
+    }
+    // A trap inside the stub itself cannot be serviced
+    let mut stub = match Stub::global().try_lock() {
+        Some(stub) => stub,
+        None => return false,
+    };
+
+    stack.rflags &= !(RFLAGS_TF as usize);
+    if trap == Trap::Breakpoint {
+        // Report breakpoints we inserted at their own address, so that GDB
+        // recognizes them and can execute the original instruction
+        let addr = stack.rip as u64 - 1;
+        if stub.breakpoints.iter().any(|b| b.addr == addr) {
+            stack.rip -= 1;
+        }
+    }
+    stub.session(stack);
+    true
+}
+
+/// Read GDB's register file from the interrupted state. `ds` and `es` are
+/// always the same as `ss` in the kernel, and `gs` is always null
+fn registers(stack: &InterruptStack) -> Registers {
+    let s = &stack.scratch;
+    let p = &stack.preserved;
+    let values = [
+        s.rax, p.rbx, s.rcx, s.rdx, s.rsi, s.rdi, p.rbp, stack.rsp, s.r8, s.r9, s.r10, s.r11,
+        p.r12, p.r13, p.r14, p.r15, stack.rip, stack.rflags, stack.cs, stack.ss, stack.ss,
+        stack.ss, stack.fs, 0,
+    ];
+    let mut regs = Registers::default();
+    for (reg, value) in regs.0.iter_mut().zip(values.iter()) {
+        *reg = *value as u64;
+    }
+    regs
+}
+
+/// Write GDB's register file back to the interrupted state. Segment
+/// registers cannot be changed
+fn set_registers(stack: &mut InterruptStack, regs: &Registers) {
+    let r = |n: usize| regs.0[n] as usize;
+    {
+        let s = &mut stack.scratch;
+        s.rax = r(0);
+        s.rcx = r(2);
+        s.rdx = r(3);
+        s.rsi = r(4);
+        s.rdi = r(5);
+        s.r8 = r(8);
+        s.r9 = r(9);
+        s.r10 = r(10);
+        s.r11 = r(11);
+    }
+    {
+        let p = &mut stack.preserved;
+        p.rbx = r(1);
+        p.rbp = r(6);
+        p.r12 = r(12);
+        p.r13 = r(13);
+        p.r14 = r(14);
+        p.r15 = r(15);
+    }
+    stack.rsp = r(registers::RSP);
+    stack.rip = r(registers::RIP);
+    stack.rflags = r(registers::EFLAGS);
+}
+
+/// Returns true if every byte from `addr` to `addr + len` is mapped
+fn mapped(addr: u64, len: usize) -> bool {
+    // The stub may have interrupted a holder of the lock. Translation only
+    // reads the page tables
+    let mapper = unsafe { Mapper::global().force() };
+    let end = match (addr as usize).checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let mut page = addr as usize & !0xFFF;
+    while page < end {
+        if mapper.translate(Virtual::new(page)).is_none() {
+            return false;
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+/// Write `data` to `addr`, even if it is in a read-only page
+unsafe fn poke(addr: u64, data: &[u8]) {
+    let cr0 = instructions::cr0();
+    instructions::set_cr0(cr0 & !CR0_WP);
+    for (i, byte) in data.iter().enumerate() {
+        ptr::write_volatile((addr as usize + i) as *mut u8, *byte);
+    }
+    instructions::set_cr0(cr0);
+}
+
+/// What to do after handling a packet
+enum Action {
+    Reply(Vec<u8>),
+    Resume,
+}
+
+const OK: &[u8] = b"OK";
+/// Error reply for invalid arguments
+const EINVAL: &[u8] = b"E16";
+/// Error reply for inaccessible memory
+const EFAULT: &[u8] = b"E0e";
+
+fn reply(data: &[u8]) -> Action {
+    Action::Reply(data.to_vec())
+}
+
+impl Stub {
+    fn new(serial: Serial) -> Stub {
+        Stub {
+            serial,
+            decoder: Decoder::default(),
+            breakpoints: Vec::new(),
+            attached: false,
+        }
+    }
+
+    fn send(&mut self, payload: &[u8]) {
+        for byte in packet::encode(payload) {
+            self.serial.write(byte);
+        }
+    }
+
+    /// Serve GDB until it resumes the target
+    fn session(&mut self, stack: &mut InterruptStack) {
+        if self.attached {
+            self.send(&[b'S', b'0', b'0' + SIGTRAP]);
+        }
+        loop {
+            let event = match self.decoder.feed(self.serial.read()) {
+                Some(event) => event,
+                None => continue,
+            };
+            match event {
+                Event::Packet(data) => {
+                    self.serial.write(b'+');
+                    self.attached = true;
+                    match self.handle(&data, stack) {
+                        Action::Reply(payload) => self.send(&payload),
+                        Action::Resume => return,
+                    }
+                }
+                Event::Invalid => self.serial.write(b'-'),
+                // The target is already stopped
+                Event::Interrupt => {}
+            }
+        }
+    }
+
+    fn handle(&mut self, data: &[u8], stack: &mut InterruptStack) -> Action {
+        let command = match Command::parse(data) {
+            Some(command) => command,
+            None => return reply(EINVAL),
+        };
+        match command {
+            Command::Status => reply(&[b'S', b'0', b'0' + SIGTRAP]),
+            Command::ReadRegisters => Action::Reply(registers(stack).encode()),
+            Command::WriteRegisters(bytes) => {
+                let mut regs = registers(stack);
+                if !regs.decode(&bytes) {
+                    return reply(EINVAL);
+                }
+                set_registers(stack, &regs);
+                reply(OK)
+            }
+            Command::ReadRegister(n) if n < registers::COUNT => {
+                let mut out = Vec::new();
+                registers(stack).push(&mut out, n);
+                Action::Reply(out)
+            }
+            Command::WriteRegister(n, bytes) => {
+                let mut regs = registers(stack);
+                if !regs.set(n, &bytes) {
+                    return reply(EINVAL);
+                }
+                set_registers(stack, &regs);
+                reply(OK)
+            }
+            Command::ReadMemory(addr, len) => {
+                if !mapped(addr, len) {
+                    return reply(EFAULT);
+                }
+                let mut out = Vec::with_capacity(len * 2);
+                for i in 0..len {
+                    let byte = unsafe { ptr::read_volatile((addr as usize + i) as *const u8) };
+                    packet::push_hex(&mut out, &[self.shadow(addr + i as u64, byte)]);
+                }
+                Action::Reply(out)
+            }
+            Command::WriteMemory(addr, bytes) => {
+                if !mapped(addr, bytes.len()) {
+                    return reply(EFAULT);
+                }
+                unsafe { poke(addr, &bytes) };
+                reply(OK)
+            }
+            Command::InsertBreakpoint(addr) => self.insert(addr),
+            Command::RemoveBreakpoint(addr) => self.remove(addr),
+            Command::Continue(addr) => {
+                if let Some(addr) = addr {
+                    stack.rip = addr as usize;
+                }
+                Action::Resume
+            }
+            Command::Step(addr) => {
+                if let Some(addr) = addr {
+                    stack.rip = addr as usize;
+                }
+                stack.rflags |= RFLAGS_TF as usize;
+                Action::Resume
+            }
+            Command::Supported => reply(b"PacketSize=1000"),
+            Command::Detach => {
+                self.detach();
+                self.send(OK);
+                Action::Resume
+            }
+            Command::Kill => {
+                self.detach();
+                Action::Resume
+            }
+            Command::ReadRegister(_) | Command::Unknown(_) => reply(b""),
+        }
+    }
+
+    /// Remove every breakpoint, and forget about the debugger
+    fn detach(&mut self) {
+        while let Some(bp) = self.breakpoints.pop() {
+            unsafe { poke(bp.addr, &[bp.original]) };
+        }
+        self.attached = false;
+    }
+
+    /// Return the byte GDB should see at `addr`, hiding the `int3` of any
+    /// breakpoint there
+    fn shadow(&self, addr: u64, byte: u8) -> u8 {
+        self.breakpoints
+            .iter()
+            .find(|b| b.addr == addr)
+            .map(|b| b.original)
+            .unwrap_or(byte)
+    }
+
+    fn insert(&mut self, addr: u64) -> Action {
+        if self.breakpoints.iter().any(|b| b.addr == addr) {
+            return reply(OK);
+        }
+        if !mapped(addr, 1) {
+            return reply(EFAULT);
+        }
+        let original = unsafe { ptr::read_volatile(addr as usize as *const u8) };
+        unsafe { poke(addr, &[INT3]) };
+        self.breakpoints.push(Breakpoint { addr, original });
+        reply(OK)
+    }
+
+    fn remove(&mut self, addr: u64) -> Action {
+        match self.breakpoints.iter().position(|b| b.addr == addr) {
+            Some(index) => {
+                let bp = self.breakpoints.remove(index);
+                unsafe { poke(bp.addr, &[bp.original]) };
+                reply(OK)
+            }
+            None => reply(EINVAL),
+        }
+    }
+}
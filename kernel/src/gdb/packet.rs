@@ -0,0 +1,367 @@
+//! Framing and parsing of GDB remote serial protocol packets
+//!
+//! Packets are sent as `$payload#cs`, where `cs` is the modulo 256 sum of
+//! the payload bytes in hex. Within a payload, `}` escapes the next byte,
+//! which is XORed with 0x20.
+use alloc::vec::Vec;
+
+/// Byte sent by GDB to interrupt the target
+pub const INTERRUPT: u8 = 0x03;
+
+/// Sum of `data`, modulo 256
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// Result of feeding a byte to a [`Decoder`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A complete packet with a valid checksum, which must be acknowledged
+    /// with `+`
+    Packet(Vec<u8>),
+    /// A packet with an invalid checksum, which must be rejected with `-`
+    Invalid,
+    /// GDB asked to stop the target
+    Interrupt,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Idle,
+    Payload,
+    Escape,
+    Checksum(Option<u8>),
+}
+
+/// Incremental packet decoder, fed one byte at a time
+pub struct Decoder {
+    state: State,
+    payload: Vec<u8>,
+    /// Checksum of the payload bytes as transmitted, before unescaping
+    sum: u8,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder {
+            state: State::Idle,
+            payload: Vec::new(),
+            sum: 0,
+        }
+    }
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+impl Decoder {
+    /// Feed `byte` to the decoder, returning an event once one is complete
+    pub fn feed(&mut self, byte: u8) -> Option<Event> {
+        match self.state {
+            // Acknowledgements and noise between packets are ignored
+            State::Idle => match byte {
+                b'$' => self.start(),
+                INTERRUPT => return Some(Event::Interrupt),
+                _ => {}
+            },
+            State::Payload => match byte {
+                b'#' => self.state = State::Checksum(None),
+                b'$' => self.start(),
+                _ => {
+                    self.sum = self.sum.wrapping_add(byte);
+                    if byte == b'}' {
+                        self.state = State::Escape;
+                    } else {
+                        self.payload.push(byte);
+                    }
+                }
+            },
+            State::Escape => {
+                self.sum = self.sum.wrapping_add(byte);
+                self.payload.push(byte ^ 0x20);
+                self.state = State::Payload;
+            }
+            State::Checksum(None) => match hex_digit(byte) {
+                Some(high) => self.state = State::Checksum(Some(high)),
+                None => {
+                    self.state = State::Idle;
+                    return Some(Event::Invalid);
+                }
+            },
+            State::Checksum(Some(high)) => {
+                self.state = State::Idle;
+                return match hex_digit(byte) {
+                    Some(low) if high << 4 | low == self.sum => {
+                        let mut payload = Vec::new();
+                        core::mem::swap(&mut payload, &mut self.payload);
+                        Some(Event::Packet(payload))
+                    }
+                    _ => Some(Event::Invalid),
+                };
+            }
+        }
+        None
+    }
+
+    fn start(&mut self) {
+        self.payload.clear();
+        self.sum = 0;
+        self.state = State::Payload;
+    }
+}
+
+/// Escape the bytes of `payload` that have special meaning in packets
+fn escape(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len());
+    for &byte in payload {
+        match byte {
+            b'$' | b'#' | b'}' | b'*' => {
+                out.push(b'}');
+                out.push(byte ^ 0x20);
+            }
+            _ => out.push(byte),
+        }
+    }
+    out
+}
+
+/// Frame `payload` as a packet, ready to send
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let body = escape(payload);
+    let sum = checksum(&body);
+    let mut packet = Vec::with_capacity(body.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(&body);
+    packet.push(b'#');
+    push_hex(&mut packet, &[sum]);
+    packet
+}
+
+/// Append the bytes of `data` to `out` as pairs of lower case hex digits
+pub fn push_hex(out: &mut Vec<u8>, data: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for &byte in data {
+        out.push(DIGITS[(byte >> 4) as usize]);
+        out.push(DIGITS[(byte & 0xF) as usize]);
+    }
+}
+
+/// Decode pairs of hex digits into bytes
+pub fn parse_hex_bytes(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+/// Parse a big endian hex number, as used for addresses and lengths
+pub fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter()
+        .try_fold(0u64, |n, &b| Some(n << 4 | hex_digit(b)? as u64))
+}
+
+/// Commands understood by the stub
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command<'a> {
+    /// `?`: report why the target stopped
+    Status,
+    /// `g`: read all registers
+    ReadRegisters,
+    /// `G`: write all registers
+    WriteRegisters(Vec<u8>),
+    /// `p n`: read register `n`
+    ReadRegister(usize),
+    /// `P n=v`: write register `n`
+    WriteRegister(usize, Vec<u8>),
+    /// `m addr,len`: read memory
+    ReadMemory(u64, usize),
+    /// `M addr,len:data`: write memory
+    WriteMemory(u64, Vec<u8>),
+    /// `c [addr]`: continue, optionally from a new address
+    Continue(Option<u64>),
+    /// `s [addr]`: step one instruction, optionally from a new address
+    Step(Option<u64>),
+    /// `Z0,addr,kind`: insert a software breakpoint
+    InsertBreakpoint(u64),
+    /// `z0,addr,kind`: remove a software breakpoint
+    RemoveBreakpoint(u64),
+    /// `qSupported`: feature negotiation
+    Supported,
+    /// `D`: detach, removing all breakpoints and continuing
+    Detach,
+    /// `k`: kill, which is treated as detach
+    Kill,
+    /// Any other packet, which gets an empty reply
+    Unknown(&'a [u8]),
+}
+
+/// Split `data` at the first `sep`
+fn split(data: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let index = data.iter().position(|&b| b == sep)?;
+    Some((&data[..index], &data[index + 1..]))
+}
+
+/// Parse the `addr,kind` arguments of a breakpoint packet, returning the
+/// address of type 0 (software) breakpoints only
+fn breakpoint(args: &[u8]) -> Option<u64> {
+    let (ty, rest) = split(args, b',')?;
+    let (addr, _) = split(rest, b',')?;
+    if ty != b"0" {
+        return None;
+    }
+    parse_hex(addr)
+}
+
+impl<'a> Command<'a> {
+    /// Parse a packet payload, returning `None` if it is malformed
+    pub fn parse(packet: &'a [u8]) -> Option<Command<'a>> {
+        let (&first, args) = packet.split_first()?;
+        let optional = |args: &[u8]| {
+            if args.is_empty() {
+                Some(None)
+            } else {
+                parse_hex(args).map(Some)
+            }
+        };
+        let command = match first {
+            b'?' => Command::Status,
+            b'g' => Command::ReadRegisters,
+            b'G' => Command::WriteRegisters(parse_hex_bytes(args)?),
+            b'p' => Command::ReadRegister(parse_hex(args)? as usize),
+            b'P' => {
+                let (reg, value) = split(args, b'=')?;
+                Command::WriteRegister(parse_hex(reg)? as usize, parse_hex_bytes(value)?)
+            }
+            b'm' => {
+                let (addr, len) = split(args, b',')?;
+                Command::ReadMemory(parse_hex(addr)?, parse_hex(len)? as usize)
+            }
+            b'M' => {
+                let (addr, rest) = split(args, b',')?;
+                let (len, data) = split(rest, b':')?;
+                let data = parse_hex_bytes(data)?;
+                if data.len() as u64 != parse_hex(len)? {
+                    return None;
+                }
+                Command::WriteMemory(parse_hex(addr)?, data)
+            }
+            b'c' => Command::Continue(optional(args)?),
+            b's' => Command::Step(optional(args)?),
+            b'Z' if args.starts_with(b"0") => Command::InsertBreakpoint(breakpoint(args)?),
+            b'z' if args.starts_with(b"0") => Command::RemoveBreakpoint(breakpoint(args)?),
+            b'q' if args.starts_with(b"Supported") => Command::Supported,
+            b'D' => Command::Detach,
+            b'k' => Command::Kill,
+            _ => Command::Unknown(packet),
+        };
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<Event> {
+        let mut decoder = Decoder::default();
+        bytes.iter().filter_map(|&b| decoder.feed(b)).collect()
+    }
+
+    #[test]
+    fn framing() {
+        assert_eq!(encode(b"OK"), b"$OK#9a".to_vec());
+        assert_eq!(encode(b""), b"$#00".to_vec());
+        // `#` is escaped as `}` followed by 0x03
+        assert_eq!(encode(b"a#"), b"$a}\x03#e1".to_vec());
+
+        assert_eq!(
+            decode(b"+$g#67"),
+            [Event::Packet(b"g".to_vec())]
+        );
+        assert_eq!(
+            decode(b"$m10,4#2e$g#00\x03"),
+            [
+                Event::Packet(b"m10,4".to_vec()),
+                Event::Invalid,
+                Event::Interrupt
+            ]
+        );
+        assert_eq!(decode(b"$a}\x03#e1"), [Event::Packet(b"a#".to_vec())]);
+        assert_eq!(decode(b"$g#zz"), [Event::Invalid]);
+
+        for payload in [&b"S05"[..], b"$}#*", b"0123456789abcdef"].iter() {
+            assert_eq!(decode(&encode(payload)), [Event::Packet(payload.to_vec())]);
+        }
+    }
+
+    #[test]
+    fn hex() {
+        let mut out = Vec::new();
+        push_hex(&mut out, &[0x00, 0xAB, 0x7F]);
+        assert_eq!(out, b"00ab7f");
+        assert_eq!(parse_hex_bytes(b"00ab7F"), Some(vec![0x00, 0xAB, 0x7F]));
+        assert_eq!(parse_hex_bytes(b"abc"), None);
+        assert_eq!(parse_hex_bytes(b"zz"), None);
+        assert_eq!(parse_hex(b"ffffffff80100000"), Some(0xFFFF_FFFF_8010_0000));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"10000000000000000"), None);
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(Command::parse(b"?"), Some(Command::Status));
+        assert_eq!(Command::parse(b"g"), Some(Command::ReadRegisters));
+        assert_eq!(
+            Command::parse(b"G0102"),
+            Some(Command::WriteRegisters(vec![1, 2]))
+        );
+        assert_eq!(Command::parse(b"p10"), Some(Command::ReadRegister(16)));
+        assert_eq!(
+            Command::parse(b"P10=efbeadde00000000"),
+            Some(Command::WriteRegister(
+                16,
+                vec![0xEF, 0xBE, 0xAD, 0xDE, 0, 0, 0, 0]
+            ))
+        );
+        assert_eq!(
+            Command::parse(b"mffffffff80100000,40"),
+            Some(Command::ReadMemory(0xFFFF_FFFF_8010_0000, 0x40))
+        );
+        assert_eq!(
+            Command::parse(b"M1000,2:cc90"),
+            Some(Command::WriteMemory(0x1000, vec![0xCC, 0x90]))
+        );
+        assert_eq!(Command::parse(b"M1000,3:cc90"), None);
+        assert_eq!(Command::parse(b"c"), Some(Command::Continue(None)));
+        assert_eq!(Command::parse(b"s1234"), Some(Command::Step(Some(0x1234))));
+        assert_eq!(
+            Command::parse(b"Z0,ffffffff80101234,1"),
+            Some(Command::InsertBreakpoint(0xFFFF_FFFF_8010_1234))
+        );
+        assert_eq!(
+            Command::parse(b"z0,1234,1"),
+            Some(Command::RemoveBreakpoint(0x1234))
+        );
+        assert_eq!(
+            Command::parse(b"Z2,1234,4"),
+            Some(Command::Unknown(b"Z2,1234,4"))
+        );
+        assert_eq!(
+            Command::parse(b"qSupported:multiprocess+"),
+            Some(Command::Supported)
+        );
+        assert_eq!(Command::parse(b"vMustReplyEmpty"), Some(Command::Unknown(b"vMustReplyEmpty")));
+        assert_eq!(Command::parse(b""), None);
+    }
+}
@@ -0,0 +1,116 @@
+//! Register file in the layout GDB expects for x86_64
+use super::packet::push_hex;
+use alloc::vec::Vec;
+
+/// Number of registers in the `g` packet
+pub const COUNT: usize = 24;
+
+pub const RSP: usize = 7;
+pub const RIP: usize = 16;
+pub const EFLAGS: usize = 17;
+/// First of the segment registers, which follow `eflags`
+pub const CS: usize = 18;
+
+/// Size, in bytes, of register `n`. The 16 general purpose registers and
+/// `rip` are 64 bits wide, and `eflags` and the segment registers are 32
+pub fn size(n: usize) -> usize {
+    if n < EFLAGS {
+        8
+    } else {
+        4
+    }
+}
+
+/// Values of `rax`, `rbx`, `rcx`, `rdx`, `rsi`, `rdi`, `rbp`, `rsp`,
+/// `r8`-`r15`, `rip`, `eflags`, `cs`, `ss`, `ds`, `es`, `fs` and `gs`, in
+/// that order
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Registers(pub [u64; COUNT]);
+
+impl Registers {
+    /// Encode register `n` as little endian hex
+    pub fn push(&self, out: &mut Vec<u8>, n: usize) {
+        push_hex(out, &self.0[n].to_le_bytes()[..size(n)]);
+    }
+
+    /// Encode every register, as in the reply to `g`
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for n in 0..COUNT {
+            self.push(&mut out, n);
+        }
+        out
+    }
+
+    /// Set register `n` from the little endian `bytes`, returning false if
+    /// they are the wrong size
+    pub fn set(&mut self, n: usize, bytes: &[u8]) -> bool {
+        if n >= COUNT || bytes.len() != size(n) {
+            return false;
+        }
+        let mut value = [0u8; 8];
+        value[..bytes.len()].copy_from_slice(bytes);
+        self.0[n] = u64::from_le_bytes(value);
+        true
+    }
+
+    /// Set registers in order from the contents of a `G` packet. GDB may
+    /// send fewer registers than there are
+    pub fn decode(&mut self, mut bytes: &[u8]) -> bool {
+        let mut n = 0;
+        while !bytes.is_empty() && n < COUNT {
+            let len = size(n);
+            if bytes.len() < len {
+                return false;
+            }
+            self.set(n, &bytes[..len]);
+            bytes = &bytes[len..];
+            n += 1;
+        }
+        bytes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::gdb::packet::parse_hex_bytes;
+
+    #[test]
+    fn round_trip() {
+        let mut regs = Registers::default();
+        for n in 0..COUNT {
+            regs.0[n] = 0x0101_0101_0101_0101 * n as u64;
+        }
+        regs.0[EFLAGS] = 0x202;
+        regs.0[CS] = 0x08;
+
+        let hex = regs.encode();
+        assert_eq!(hex.len(), (17 * 8 + 7 * 4) * 2);
+        assert_eq!(&hex[..16], b"0000000000000000");
+        assert_eq!(&hex[16..32], b"0101010101010101");
+        assert_eq!(&hex[17 * 16..17 * 16 + 16], b"0202000008000000");
+
+        let mut decoded = Registers::default();
+        assert!(decoded.decode(&parse_hex_bytes(&hex).unwrap()));
+        let mut expected = regs;
+        // Only the low halves of the 32 bit registers are transferred
+        for n in EFLAGS..COUNT {
+            expected.0[n] &= 0xFFFF_FFFF;
+        }
+        assert_eq!(decoded, expected);
+
+        // A partial register file only updates the registers it covers
+        let mut partial = Registers::default();
+        assert!(partial.decode(&[0xFF; 16]));
+        assert_eq!(partial.0[0], !0);
+        assert_eq!(partial.0[1], !0);
+        assert_eq!(partial.0[2], 0);
+        assert!(!partial.decode(&[0xFF; 12]));
+
+        assert!(partial.set(RIP, &[0x34, 0x12, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(partial.0[RIP], 0x1234);
+        assert!(!partial.set(EFLAGS, &[0; 8]));
+        assert!(!partial.set(COUNT, &[0; 4]));
+    }
+}
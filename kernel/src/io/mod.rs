@@ -3,7 +3,7 @@ mod serial;
 mod volatile;
 
 pub use port::Port;
-pub use serial::Serial;
+pub use serial::{Serial, COM1, COM2};
 pub use volatile::Volatile;
 
 pub trait Io {
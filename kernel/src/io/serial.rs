@@ -9,23 +9,41 @@ pub struct Serial {
     output: Port<u8>,
 }
 
-const COM1: u16 = 0x3F8;
+/// Base I/O port of the first serial port, used for kernel output
+pub const COM1: u16 = 0x3F8;
+/// Base I/O port of the second serial port
+pub const COM2: u16 = 0x2F8;
 
 impl Default for Serial {
     fn default() -> Serial {
-        Port::<u8>::new(COM1 + 1).write(0u8);
-        Port::<u8>::new(COM1 + 3).write(0x80u8);
-        Port::<u8>::new(COM1 + 0).write(0x03u8);
-        Port::<u8>::new(COM1 + 1).write(0u8);
-        Port::<u8>::new(COM1 + 3).write(0x03u8);
-        Port::<u8>::new(COM1 + 2).write(0xC7u8);
-        Port::<u8>::new(COM1 + 4).write(0u8);
+        Serial::new(COM1)
+    }
+}
+
+impl Serial {
+    /// Initialize the 16550 UART at I/O port `base` for 38400 baud, 8N1
+    pub fn new(base: u16) -> Serial {
+        Port::<u8>::new(base + 1).write(0u8);
+        Port::<u8>::new(base + 3).write(0x80u8);
+        Port::<u8>::new(base + 0).write(0x03u8);
+        Port::<u8>::new(base + 1).write(0u8);
+        Port::<u8>::new(base + 3).write(0x03u8);
+        Port::<u8>::new(base + 2).write(0xC7u8);
+        Port::<u8>::new(base + 4).write(0u8);
 
         Serial {
-            input: Port::new(COM1 + 5),
-            output: Port::new(COM1),
+            input: Port::new(base + 5),
+            output: Port::new(base),
         }
     }
+
+    /// Returns true if a UART responds at I/O port `base`, by checking
+    /// that its scratch register holds a written value
+    pub fn present(base: u16) -> bool {
+        let mut scratch = Port::<u8>::new(base + 7);
+        scratch.write(0xAE);
+        scratch.read() == 0xAE
+    }
 }
 
 impl Io for Serial {
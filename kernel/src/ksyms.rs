@@ -0,0 +1,261 @@
+//! Compact kernel symbol table
+//!
+//! The builder extracts the functions from the kernel's ELF symbol table
+//! before stripping it, and appends them to the disk image in this format.
+//! All fields are little endian:
+//!
+//! | offset | size        | contents                                   |
+//! |--------|-------------|--------------------------------------------|
+//! | 0      | 4           | magic, `KSYM`                              |
+//! | 4      | 4           | number of entries                          |
+//! | 8      | 4           | size of the string table                   |
+//! | 12     | 4           | reserved, zero                             |
+//! | 16     | 16 * count  | entries, sorted by address                 |
+//! | ...    | ...         | string table of NUL terminated names       |
+//!
+//! Each entry is an 8 byte address, followed by the 4 byte size of the
+//! function and the 4 byte offset of its name in the string table.
+//!
+//! This file is shared with the builder, and must only depend on `core`.
+
+pub const MAGIC: [u8; 4] = *b"KSYM";
+/// Size, in bytes, of the table header
+pub const HEADER_SIZE: usize = 16;
+/// Size, in bytes, of each entry
+pub const ENTRY_SIZE: usize = 16;
+
+/// Errors that can occur while parsing a symbol table
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KsymError {
+    /// The table does not start with [`MAGIC`]
+    BadMagic,
+    /// The table is shorter than its header says
+    Truncated,
+    /// The entries are not sorted by address
+    Unsorted,
+    /// The name of the entry at this index is out of bounds, missing a
+    /// terminator, or not UTF-8
+    BadName(usize),
+}
+
+/// A function in the symbol table
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Symbol<'a> {
+    pub address: u64,
+    pub size: u32,
+    pub name: &'a str,
+}
+
+impl<'a> Symbol<'a> {
+    /// Returns true if `addr` lies within the function
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.address && addr - self.address < self.size as u64
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Table<'a> {
+    entries: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Table<'a> {
+    /// Return the total size, in bytes, of the table starting with
+    /// `header`
+    pub fn size(header: &[u8]) -> Result<usize, KsymError> {
+        if header.len() < HEADER_SIZE {
+            return Err(KsymError::Truncated);
+        }
+        if header[..4] != MAGIC {
+            return Err(KsymError::BadMagic);
+        }
+        let count = read_u32(header, 4) as usize;
+        let strings = read_u32(header, 8) as usize;
+        Ok(HEADER_SIZE + count * ENTRY_SIZE + strings)
+    }
+
+    /// Parse and validate the table in `data`, which may be followed by
+    /// unrelated bytes
+    pub fn parse(data: &'a [u8]) -> Result<Table<'a>, KsymError> {
+        let size = Self::size(data)?;
+        if data.len() < size {
+            return Err(KsymError::Truncated);
+        }
+        let count = read_u32(data, 4) as usize;
+        let strings_start = HEADER_SIZE + count * ENTRY_SIZE;
+        let table = Table {
+            entries: &data[HEADER_SIZE..strings_start],
+            strings: &data[strings_start..size],
+        };
+
+        let mut last = 0;
+        for index in 0..count {
+            let address = read_u64(table.entries, index * ENTRY_SIZE);
+            if address < last {
+                return Err(KsymError::Unsorted);
+            }
+            last = address;
+            if table.name(index).is_none() {
+                return Err(KsymError::BadName(index));
+            }
+        }
+        Ok(table)
+    }
+
+    /// Parse the table at `ptr`
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to readable memory covering at least the header,
+    /// and the whole table if the header is valid
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Table<'static>, KsymError> {
+        let header = core::slice::from_raw_parts(ptr, HEADER_SIZE);
+        let size = Self::size(header)?;
+        Table::parse(core::slice::from_raw_parts(ptr, size))
+    }
+
+    fn name(&self, index: usize) -> Option<&'a str> {
+        let offset = read_u32(self.entries, index * ENTRY_SIZE + 12) as usize;
+        let name = self.strings.get(offset..)?;
+        let len = name.iter().position(|&b| b == 0)?;
+        core::str::from_utf8(&name[..len]).ok()
+    }
+
+    /// Number of symbols in the table
+    pub fn len(&self) -> usize {
+        self.entries.len() / ENTRY_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        if index >= self.len() {
+            return None;
+        }
+        let entry = index * ENTRY_SIZE;
+        Some(Symbol {
+            address: read_u64(self.entries, entry),
+            size: read_u32(self.entries, entry + 8),
+            // Names were validated by `parse`
+            name: self.name(index)?,
+        })
+    }
+
+    pub fn iter<'t>(&'t self) -> impl Iterator<Item = Symbol<'a>> + 't {
+        (0..self.len()).filter_map(move |index| self.get(index))
+    }
+
+    /// Find the function containing `addr`, returning it along with the
+    /// offset of `addr` into it
+    pub fn lookup(&self, addr: u64) -> Option<(Symbol<'a>, u64)> {
+        // Binary search for the first entry starting after `addr`
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if read_u64(self.entries, mid * ENTRY_SIZE) <= addr {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let symbol = self.get(lo.checked_sub(1)?)?;
+        if symbol.contains(addr) {
+            Some((symbol, addr - symbol.address))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Two functions, `a` at 0x1000 and `bc` at 0x1010
+    fn table() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&5u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        for &(address, size, name) in &[(0x1000u64, 0x10u32, 0u32), (0x1010, 0x20, 2)] {
+            data.extend_from_slice(&address.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&name.to_le_bytes());
+        }
+        data.extend_from_slice(b"a\0bc\0");
+        data
+    }
+
+    #[test]
+    fn lookup() {
+        let data = table();
+        assert_eq!(Table::size(&data), Ok(data.len()));
+        let table = Table::parse(&data).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(1).map(|s| s.name), Some("bc"));
+
+        assert_eq!(table.lookup(0xFFF), None);
+        assert_eq!(
+            table.lookup(0x1000).map(|(s, off)| (s.name, off)),
+            Some(("a", 0))
+        );
+        assert_eq!(
+            table.lookup(0x100F).map(|(s, off)| (s.name, off)),
+            Some(("a", 0xF))
+        );
+        assert_eq!(
+            table.lookup(0x1010).map(|(s, off)| (s.name, off)),
+            Some(("bc", 0))
+        );
+        assert_eq!(
+            table.lookup(0x102F).map(|(s, off)| (s.name, off)),
+            Some(("bc", 0x1F))
+        );
+        assert_eq!(table.lookup(0x1030), None);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut data = table();
+        data[0] = b'X';
+        assert_eq!(Table::parse(&data).unwrap_err(), KsymError::BadMagic);
+
+        let data = table();
+        assert_eq!(
+            Table::parse(&data[..data.len() - 1]).unwrap_err(),
+            KsymError::Truncated
+        );
+        assert_eq!(Table::parse(&data[..8]).unwrap_err(), KsymError::Truncated);
+
+        let mut data = table();
+        // Move the second function below the first
+        data[HEADER_SIZE + ENTRY_SIZE + 1] = 0x0F;
+        assert_eq!(Table::parse(&data).unwrap_err(), KsymError::Unsorted);
+
+        let mut data = table();
+        // Point the second name past the end of the string table
+        data[HEADER_SIZE + ENTRY_SIZE + 12] = 5;
+        assert_eq!(Table::parse(&data).unwrap_err(), KsymError::BadName(1));
+
+        let mut data = table();
+        // Remove the final terminator
+        let last = data.len() - 1;
+        data[last] = b'd';
+        assert_eq!(Table::parse(&data).unwrap_err(), KsymError::BadName(1));
+    }
+}
@@ -1,20 +1,30 @@
 #![cfg_attr(not(test), no_std)]
 #![cfg_attr(not(test), no_main)]
-#![feature(asm, panic_info_message, naked_functions)]
-#![feature(lang_items)]
+#![feature(asm, global_asm, panic_info_message, naked_functions)]
+#![feature(lang_items, alloc_error_handler)]
 #![allow(dead_code)]
 
+extern crate alloc;
+
 #[macro_use]
 pub mod sync;
 #[macro_use]
 pub mod prelude;
+pub mod acpi;
 #[macro_use]
 pub mod arch;
+pub mod backtrace;
 pub mod elf;
+pub mod fs;
+pub mod gdb;
 pub mod io;
+pub mod ksyms;
 pub mod memory;
 pub mod paging;
+pub mod process;
+pub mod syscall;
 pub mod term;
+pub mod thread;
 pub mod timer;
 
 mod panic;
@@ -22,38 +32,115 @@ mod panic;
 use memory::physical::MemoryMapInfo;
 use prelude::*;
 
+/// Aligns an embedded program, so that its ELF headers can be read in place
+#[cfg(not(test))]
+#[repr(C, align(8))]
+struct Program<T: ?Sized>(T);
+
+/// The first user program, which the builder assembles from `user/init.asm`
+#[cfg(not(test))]
+static INIT: &Program<[u8]> = &Program(*include_bytes!("../../build/user/init"));
+/// A program for init to run, from `user/hello.asm`
+#[cfg(not(test))]
+static HELLO: &Program<[u8]> = &Program(*include_bytes!("../../build/user/hello"));
+/// A C program for init to run, from `user/musl-hello.c`
+#[cfg(not(test))]
+static MUSL_HELLO: &Program<[u8]> = &Program(*include_bytes!("../../build/user/musl-hello"));
+
+/// Return the kernel's own executable, which the bootloader leaves in memory
+fn kernel_elf(info: &'static MemoryMapInfo) -> elf::Elf<'static> {
+    let ehdr = unsafe { core::slice::from_raw_parts(boot_ptr(info.elf_ptr), info.elf_len) };
+    elf::Elf::from(ehdr)
+}
+
+/// Move a pointer into the bootloader's identity mapping of low memory over
+/// to the alias at [`memory::KERNEL_VIRT`], which is also mapped in user
+/// address spaces
+fn boot_ptr(ptr: *const u8) -> *const u8 {
+    if (ptr as usize) < 0x40_0000 {
+        (memory::KERNEL_VIRT + ptr as usize) as *const u8
+    } else {
+        ptr
+    }
+}
+
 #[cfg(not(test))]
 #[no_mangle]
 extern "C" fn _start(info: &'static MemoryMapInfo) -> ! {
     arch::interrupts::disable();
-    arch::devices::init();
+    arch::gdt::init();
+    arch::percpu::early();
 
     {
         let mut idt = arch::idt::InterruptDescriptorTable::global().lock();
         idt.load();
-        idt.register(0x20, timer::timer);
     }
+    arch::syscall::init();
+    arch::context::init();
+    let ksyms = unsafe { ksyms::Table::from_ptr(boot_ptr(info.ksyms_ptr)) };
+    if let Err(err) = ksyms {
+        println!("no kernel symbol table: {:?}", err);
+    }
+    backtrace::init(kernel_elf(info), ksyms.ok());
 
     println!(
         "kernel pages: {:?}",
         paging::TableIndices::from_virt(info.elf_ptr as usize)
     );
 
-    let mut allocator = memory::physical::allocator::BumpAllocator::new(info);
+    {
+        let frames = memory::physical::init(info).lock();
+        println!(
+            "physical memory: {} of {} frames free",
+            frames.free_frames(),
+            frames.total_frames()
+        );
+    }
+    println!("{}", memory::physical::contiguous().lock().stats());
+    paging::init();
+    memory::physical::init_refcounts();
+    println!("{}", memory::heap::stats());
+    match acpi::init() {
+        Ok(tables) => println!("{}", tables),
+        Err(err) => println!("no ACPI tables: {:?}", err),
+    }
+    arch::devices::init();
+    if gdb::init() {
+        println!("GDB stub listening on COM2");
+    }
+
+    let apic_id = match arch::devices::mode() {
+        arch::devices::Mode::Apic => arch::devices::apic::local().id(),
+        arch::devices::Mode::Pic => 0,
+    };
+    arch::percpu::init(apic_id);
+    println!("{} CPUs online", arch::smp::init());
+    #[cfg(feature = "contention-test")]
+    arch::smp::contention_test();
 
-    let ehdr = unsafe { core::slice::from_raw_parts(info.elf_ptr, info.elf_len) };
-    let elf = elf::Elf::from(ehdr);
+    let elf = kernel_elf(info);
     println!("{:?}", elf.header);
     for section in elf.sections {
         println!("{:?}", section);
     }
-    elf.symbol();
 
     let cr3 = arch::instructions::cr3();
 
     println!("cr3 = 0x{:#016X}", cr3);
+    thread::init();
+    arch::interrupts::irq::register(0, timer::tick);
     arch::interrupts::enable();
 
-    println!("Entering final loop");
-    loop {}
+    fs::install("/init", &INIT.0);
+    fs::install("/bin/hello", &HELLO.0);
+    fs::install("/bin/musl-hello", &MUSL_HELLO.0);
+    fs::install("/etc/motd", b"Welcome to rust-os\n");
+    process::init();
+    match process::spawn("init", &INIT.0, &["init"], &["HOME=/"]) {
+        Ok(pid) => println!("started init as process {}", pid),
+        Err(err) => println!("failed to start init: {:?}", err),
+    }
+
+    println!("Boot thread exiting");
+    thread::exit();
 }
@@ -0,0 +1,381 @@
+//! Kernel heap, backing the `alloc` crate through [`GlobalAlloc`]
+//!
+//! The heap lives at a fixed virtual address range starting at
+//! [`HEAP_START`], and is grown on demand by mapping fresh frames from
+//! [`super::physical`] onto its end.
+use crate::prelude::*;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::mem;
+use core::ptr;
+
+/// Virtual address of the start of the kernel heap (PML4 entry 509)
+pub const HEAP_START: usize = 0xFFFF_FE80_0000_0000;
+
+/// Maximum size, in bytes, that the heap may grow to
+pub const HEAP_MAX_SIZE: usize = 0x1000_0000;
+
+/// Minimum number of bytes mapped each time the heap grows
+const HEAP_GROW_SIZE: usize = 0x1_0000;
+
+/// Smallest block handed out, and alignment of every block. Each free
+/// block must be able to hold a [`Hole`]
+const BLOCK: usize = 16;
+
+global!(Heap);
+
+/// Header stored at the start of each free block
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+/// Usage statistics for a [`Heap`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeapStats {
+    /// Number of bytes of virtual memory backed by the heap
+    pub size: usize,
+    /// Number of bytes currently allocated, including rounding
+    pub used: usize,
+    /// Number of live allocations
+    pub allocations: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "heap: {} of {} KiB used in {} allocations",
+            self.used / 1024,
+            self.size / 1024,
+            self.allocations
+        )
+    }
+}
+
+/// First-fit free list allocator over a contiguous range of memory.
+///
+/// Free blocks are kept in a singly linked list sorted by address, so that
+/// neighbouring blocks can be merged as they are freed
+pub struct Heap {
+    head: Hole,
+    start: usize,
+    size: usize,
+    used: usize,
+    allocations: usize,
+}
+
+unsafe impl Send for Heap {}
+
+impl Default for Heap {
+    /// An empty heap at [`HEAP_START`], which is grown on the first
+    /// allocation
+    fn default() -> Heap {
+        Heap::empty(HEAP_START)
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+impl Heap {
+    /// Create a [`Heap`] with no memory, that will extend from `start`
+    pub const fn empty(start: usize) -> Heap {
+        Heap {
+            head: Hole {
+                size: 0,
+                next: ptr::null_mut(),
+            },
+            start,
+            size: 0,
+            used: 0,
+            allocations: 0,
+        }
+    }
+
+    /// Create a [`Heap`] managing `size` bytes starting at `start`
+    ///
+    /// # Safety
+    ///
+    /// The memory range must be valid for reads and writes, unused, and
+    /// `start` must be aligned to 16 bytes
+    pub unsafe fn new(start: usize, size: usize) -> Heap {
+        let mut heap = Heap::empty(start);
+        heap.extend(size);
+        heap
+    }
+
+    /// Add `bytes` of memory directly after the current end of the heap
+    ///
+    /// # Safety
+    ///
+    /// The memory must be valid for reads and writes, and unused
+    pub unsafe fn extend(&mut self, bytes: usize) {
+        let bytes = bytes & !(BLOCK - 1);
+        if bytes == 0 {
+            return;
+        }
+        let end = self.start + self.size;
+        self.size += bytes;
+        self.insert(end, bytes);
+    }
+
+    /// Size of a block satisfying `layout`
+    fn block_size(layout: &Layout) -> usize {
+        align_up(layout.size().max(mem::size_of::<Hole>()), BLOCK)
+    }
+
+    /// Insert a free block into the sorted free list, merging it with
+    /// its neighbours when they are adjacent
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut Hole = &mut self.head;
+        while !(*prev).next.is_null() && ((*prev).next as usize) < addr {
+            prev = (*prev).next;
+        }
+
+        let next = (*prev).next;
+        let hole = addr as *mut Hole;
+        hole.write(Hole { size, next });
+
+        if !next.is_null() && addr + size == next as usize {
+            (*hole).size += (*next).size;
+            (*hole).next = (*next).next;
+        }
+
+        if prev != &mut self.head as *mut Hole && prev as usize + (*prev).size == addr {
+            (*prev).size += (*hole).size;
+            (*prev).next = (*hole).next;
+        } else {
+            (*prev).next = hole;
+        }
+    }
+
+    /// Allocate a block of memory satisfying `layout` from the free list,
+    /// returning a null pointer if no free block is large enough
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = Self::block_size(&layout);
+        let align = layout.align().max(BLOCK);
+
+        unsafe {
+            let mut prev: *mut Hole = &mut self.head;
+            while !(*prev).next.is_null() {
+                let hole = (*prev).next;
+                let start = hole as usize;
+                let hole_size = (*hole).size;
+                let aligned = align_up(start, align);
+
+                if aligned + size <= start + hole_size {
+                    (*prev).next = (*hole).next;
+                    let back = start + hole_size - aligned - size;
+                    if back > 0 {
+                        self.insert(aligned + size, back);
+                    }
+                    if aligned > start {
+                        self.insert(start, aligned - start);
+                    }
+                    self.used += size;
+                    self.allocations += 1;
+                    return aligned as *mut u8;
+                }
+                prev = hole;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    /// Return a block previously handed out by [`Heap::allocate`]
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`Heap::allocate`] on this heap
+    /// with the same `layout`, and must not be used afterwards
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let size = Self::block_size(&layout);
+        self.used -= size;
+        self.allocations -= 1;
+        self.insert(ptr as usize, size);
+    }
+
+    /// Return the address one past the last byte of the heap
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.size,
+            used: self.used,
+            allocations: self.allocations,
+        }
+    }
+}
+
+/// Map at least `bytes` of additional memory onto the end of `heap`,
+/// returning false if the heap is at its maximum size or if we are out of
+/// physical memory
+#[cfg(not(test))]
+fn grow(heap: &mut Heap, bytes: usize) -> bool {
+    use crate::memory::physical::{self, Allocator};
+    use crate::paging::{EntryFlags, Mapper, Page, Virtual, PAGE_SIZE};
+
+    let bytes = align_up(bytes.max(HEAP_GROW_SIZE), PAGE_SIZE);
+    if heap.end() + bytes > HEAP_START + HEAP_MAX_SIZE {
+        return false;
+    }
+
+    let mut mapper = Mapper::global().critical();
+    let mut frames = physical::frames().critical();
+    let first = Page::containing(Virtual::new(heap.end()));
+    let mut mapped = 0;
+
+    for i in 0..bytes / PAGE_SIZE {
+        let frame = match frames.allocate() {
+            Some(frame) => frame,
+            None => break,
+        };
+        let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE;
+        if mapper.map(first.offset(i), frame, flags, &mut *frames).is_err() {
+            frames.deallocate(frame);
+            break;
+        }
+        mapped += PAGE_SIZE;
+    }
+
+    unsafe { heap.extend(mapped) };
+    mapped != 0
+}
+
+/// Return usage statistics for the kernel heap
+pub fn stats() -> HeapStats {
+    Heap::global().critical().stats()
+}
+
+/// Zero-sized handle that forwards allocations to the global [`Heap`]
+pub struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = Heap::global().critical();
+        let mut ptr = heap.allocate(layout);
+
+        #[cfg(not(test))]
+        {
+            // Grow by enough to satisfy the request even if the new memory
+            // does not merge with the last free block
+            let needed = layout.size() + layout.align() + BLOCK;
+            while ptr.is_null() && grow(&mut heap, needed) {
+                ptr = heap.allocate(layout);
+            }
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Heap::global().critical().deallocate(ptr, layout)
+    }
+}
+
+#[cfg(not(test))]
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "kernel heap exhausted allocating {} bytes (align {}), {}",
+        layout.size(),
+        layout.align(),
+        stats()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Backing memory for a test heap, aligned to a page
+    #[repr(align(4096))]
+    struct Arena([u8; 0x4000]);
+
+    fn heap(arena: &mut Arena, size: usize) -> Heap {
+        unsafe { Heap::new(arena.0.as_mut_ptr() as usize, size) }
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        let mut arena = Arena([0; 0x4000]);
+        let mut heap = heap(&mut arena, 0x1000);
+
+        let a = heap.allocate(layout(24, 8));
+        let b = heap.allocate(layout(100, 8));
+        assert!(!a.is_null() && !b.is_null());
+        assert_eq!(b as usize - a as usize, 32);
+        assert_eq!(heap.stats().used, 32 + 112);
+        assert_eq!(heap.stats().allocations, 2);
+
+        unsafe {
+            heap.deallocate(a, layout(24, 8));
+            heap.deallocate(b, layout(100, 8));
+        }
+        assert_eq!(heap.stats().used, 0);
+
+        // Freed blocks are merged back into a single hole
+        let c = heap.allocate(layout(0x1000, 8));
+        assert_eq!(c, a);
+    }
+
+    #[test]
+    fn alignment() {
+        let mut arena = Arena([0; 0x4000]);
+        let mut heap = heap(&mut arena, 0x4000);
+
+        let small = heap.allocate(layout(8, 8));
+        let page = heap.allocate(layout(0x1000, 0x1000));
+        assert_eq!(page as usize % 0x1000, 0);
+
+        // The padding in front of the aligned block is still usable
+        let filler = heap.allocate(layout(0x100, 16));
+        assert!((filler as usize) < page as usize);
+        assert!(filler as usize > small as usize);
+    }
+
+    #[test]
+    fn exhaustion_and_extend() {
+        let mut arena = Arena([0; 0x4000]);
+        let mut heap = heap(&mut arena, 0x1000);
+
+        let a = heap.allocate(layout(0x1000, 16));
+        assert!(!a.is_null());
+        assert!(heap.allocate(layout(16, 16)).is_null());
+
+        unsafe { heap.extend(0x2000) };
+        assert_eq!(heap.stats().size, 0x3000);
+        let b = heap.allocate(layout(0x2000, 16));
+        assert_eq!(b as usize, a as usize + 0x1000);
+    }
+
+    #[test]
+    fn reuse_between_neighbours() {
+        let mut arena = Arena([0; 0x4000]);
+        let mut heap = heap(&mut arena, 0x1000);
+
+        let blocks = (0..8)
+            .map(|_| heap.allocate(layout(0x100, 16)))
+            .collect::<Vec<_>>();
+        unsafe {
+            heap.deallocate(blocks[2], layout(0x100, 16));
+            heap.deallocate(blocks[4], layout(0x100, 16));
+            heap.deallocate(blocks[3], layout(0x100, 16));
+        }
+
+        // Three freed neighbours merge into one block large enough for this
+        let big = heap.allocate(layout(0x300, 16));
+        assert_eq!(big, blocks[2]);
+    }
+}
@@ -0,0 +1,42 @@
+//! Uncached mappings of memory-mapped device registers
+use crate::memory::physical::{self, Frame};
+use crate::paging::{EntryFlags, MapError, Mapper, Page, Physical, Virtual, PAGE_SIZE};
+use crate::prelude::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Virtual address of the start of the MMIO window (PML4 entry 508)
+pub const MMIO_START: usize = 0xFFFF_FE00_0000_0000;
+
+/// Maximum size, in bytes, of all MMIO mappings combined
+pub const MMIO_MAX_SIZE: usize = 0x4000_0000;
+
+static NEXT: AtomicUsize = AtomicUsize::new(MMIO_START);
+
+/// Map `size` bytes of device memory starting at `phys` into the MMIO
+/// window with caching disabled, returning the virtual address that
+/// corresponds to `phys`. Mappings are never removed
+pub fn map(phys: Physical, size: usize) -> Result<Virtual, MapError> {
+    let offset = phys.as_usize() & (PAGE_SIZE - 1);
+    let pages = (offset + size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let base = NEXT.fetch_add(pages * PAGE_SIZE, Ordering::SeqCst);
+    assert!(
+        base + pages * PAGE_SIZE <= MMIO_START + MMIO_MAX_SIZE,
+        "MMIO window exhausted mapping {:?}",
+        phys
+    );
+
+    let mut mapper = Mapper::global().lock();
+    let mut frames = physical::frames().lock();
+    let first = Page::containing(Virtual::new(base));
+    let frame = Frame::containing(phys.as_usize());
+    let flags = EntryFlags::PRESENT
+        | EntryFlags::WRITABLE
+        | EntryFlags::WRITE_THROUGH
+        | EntryFlags::NO_CACHE;
+
+    for i in 0..pages {
+        let frame = Frame::containing(frame.address() + i * PAGE_SIZE);
+        mapper.map(first.offset(i), frame, flags, &mut *frames)?;
+    }
+    Ok(Virtual::new(base + offset))
+}
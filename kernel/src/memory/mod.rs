@@ -1 +1,7 @@
+pub mod heap;
+pub mod mmio;
 pub mod physical;
+
+/// Virtual address at which the bootloader maps the first 4 MiB of physical
+/// memory, and where the kernel image is linked (see `linker.ld`)
+pub const KERNEL_VIRT: usize = 0xFFFF_FFFF_8000_0000;
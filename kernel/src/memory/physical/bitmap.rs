@@ -0,0 +1,435 @@
+//! Physical frame allocator that tracks every usable region of the memory
+//! map with one bit per frame
+use super::*;
+use crate::prelude::*;
+use core::ops::Range;
+
+/// Maximum number of distinct usable regions that will be tracked
+const MAX_REGIONS: usize = 32;
+
+/// Contiguous run of usable frames, and the position of its first bit
+/// in the bitmap. Regions always start on a word boundary
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Region {
+    first: usize,
+    count: usize,
+    offset: usize,
+}
+
+impl Region {
+    const fn empty() -> Region {
+        Region {
+            first: 0,
+            count: 0,
+            offset: 0,
+        }
+    }
+}
+
+/// Physical frame allocator backed by a bitmap, where a set bit marks a
+/// frame that is either allocated or reserved.
+///
+/// Frames are handed out lowest-address-first from a rolling hint, and
+/// [`Allocator::deallocate`] returns them to the pool for reuse
+pub struct BitmapAllocator<'a> {
+    bitmap: &'a mut [u64],
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+    /// Word index at which the next search starts
+    next: usize,
+    free: usize,
+    total: usize,
+}
+
+impl<'a> BitmapAllocator<'a> {
+    /// Create a new [`BitmapAllocator`] that manages every
+    /// [`RegionType::Usable`] region in `map`.
+    ///
+    /// Partial frames at the edges of a usable region are discarded, and
+    /// any frame that overlaps a non-usable region of `map` or one of the
+    /// `reserved` physical address ranges is never handed out. Frames that
+    /// do not fit into `storage` (64 frames per word) are ignored
+    pub fn new(
+        map: &[MemoryMap],
+        reserved: &[Range<usize>],
+        storage: &'a mut [u64],
+    ) -> BitmapAllocator<'a> {
+        let mut ranges = [(0usize, 0usize); MAX_REGIONS];
+        let mut n = 0;
+
+        for r in map.iter().filter(|r| r.region_type == RegionType::Usable) {
+            let start = (r.base + FRAME_SIZE - 1) / FRAME_SIZE;
+            let end = (r.base + r.len) / FRAME_SIZE;
+            if start < end && n < MAX_REGIONS {
+                ranges[n] = (start, end);
+                n += 1;
+            }
+        }
+
+        // E820 entries are not guaranteed to be sorted or disjoint, so merge
+        // any overlapping or adjacent usable ranges
+        let ranges = &mut ranges[..n];
+        ranges.sort_unstable();
+
+        let mut alloc = BitmapAllocator {
+            bitmap: storage,
+            regions: [Region::empty(); MAX_REGIONS],
+            len: 0,
+            next: 0,
+            free: 0,
+            total: 0,
+        };
+
+        for word in alloc.bitmap.iter_mut() {
+            *word = !0;
+        }
+
+        let capacity = alloc.bitmap.len() * 64;
+        let mut offset = 0;
+        let mut i = 0;
+        while i < ranges.len() {
+            let (start, mut end) = ranges[i];
+            i += 1;
+            while i < ranges.len() && ranges[i].0 <= end {
+                end = end.max(ranges[i].1);
+                i += 1;
+            }
+
+            let count = (end - start).min(capacity.saturating_sub(offset));
+            if count == 0 {
+                break;
+            }
+
+            alloc.regions[alloc.len] = Region {
+                first: start,
+                count,
+                offset,
+            };
+            alloc.len += 1;
+            alloc.mark(offset..offset + count, false);
+            alloc.free += count;
+            alloc.total += count;
+            offset += (count + 63) & !63;
+        }
+
+        for r in map.iter().filter(|r| r.region_type != RegionType::Usable) {
+            alloc.reserve(r.base..r.base + r.len);
+        }
+
+        for range in reserved {
+            alloc.reserve(range.clone());
+        }
+
+        alloc
+    }
+
+    /// Set or clear the bits in `bits`
+    fn mark(&mut self, bits: Range<usize>, used: bool) {
+        for bit in bits {
+            self.bitmap[bit / 64].set_bit((bit % 64) as u8, used);
+        }
+    }
+
+    /// Permanently remove every managed frame that overlaps the physical
+    /// address range `range` from the pool of free frames
+    pub fn reserve(&mut self, range: Range<usize>) {
+        if range.start >= range.end {
+            return;
+        }
+        let start = range.start / FRAME_SIZE;
+        let end = (range.end + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        for r in 0..self.len {
+            let region = self.regions[r];
+            let lo = start.max(region.first);
+            let hi = end.min(region.first + region.count);
+            for frame in lo..hi {
+                let bit = region.offset + frame - region.first;
+                if !self.bitmap[bit / 64].get_bit((bit % 64) as u8) {
+                    self.bitmap[bit / 64].set_bit((bit % 64) as u8, true);
+                    self.free -= 1;
+                }
+            }
+        }
+    }
+
+    /// Return the bit index tracking `frame`, if it is managed by
+    /// this allocator
+    fn bit_of(&self, frame: Frame) -> Option<usize> {
+        let number = frame.number();
+        self.regions[..self.len]
+            .iter()
+            .find(|r| number >= r.first && number < r.first + r.count)
+            .map(|r| r.offset + number - r.first)
+    }
+
+    /// Return the [`Frame`] tracked by bit index `bit`
+    fn frame_of(&self, bit: usize) -> Frame {
+        let region = self.regions[..self.len]
+            .iter()
+            .find(|r| bit >= r.offset && bit < r.offset + r.count)
+            .expect("bitmap bit outside of any region");
+        Frame {
+            physical_addr: (region.first + bit - region.offset) * FRAME_SIZE,
+        }
+    }
+
+    /// Allocate `count` physically contiguous frames, where the first
+    /// frame number is a multiple of `align`. The first [`Frame`] of the
+    /// run is returned
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<Frame> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+        for r in 0..self.len {
+            let region = self.regions[r];
+            let mut first = (region.first + align - 1) / align * align;
+            'search: while first + count <= region.first + region.count {
+                let bit = region.offset + first - region.first;
+                for i in 0..count {
+                    if self.bitmap[(bit + i) / 64].get_bit(((bit + i) % 64) as u8) {
+                        // Restart the search after the used frame
+                        first = (first + i + align) / align * align;
+                        continue 'search;
+                    }
+                }
+                self.mark(bit..bit + count, true);
+                self.free -= count;
+                return Some(Frame {
+                    physical_addr: first * FRAME_SIZE,
+                });
+            }
+        }
+        None
+    }
+
+    /// Number of frames currently available for allocation
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Number of usable frames managed by this allocator, including those
+    /// that are reserved or allocated
+    pub fn total_frames(&self) -> usize {
+        self.total
+    }
+
+    /// Number of the frame after the highest one this allocator manages
+    pub fn limit(&self) -> usize {
+        self.regions[..self.len]
+            .iter()
+            .map(|region| region.first + region.count)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns true if `frame` is managed by this allocator and is
+    /// currently allocated or reserved
+    pub fn is_used(&self, frame: Frame) -> bool {
+        match self.bit_of(frame) {
+            Some(bit) => self.bitmap[bit / 64].get_bit((bit % 64) as u8),
+            None => false,
+        }
+    }
+}
+
+impl<'a> Allocator for BitmapAllocator<'a> {
+    fn allocate(&mut self) -> Option<Frame> {
+        if self.free == 0 {
+            return None;
+        }
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let w = (self.next + i) % words;
+            let word = self.bitmap[w];
+            if word != !0 {
+                let bit = (!word).trailing_zeros() as u8;
+                self.bitmap[w].set_bit(bit, true);
+                self.free -= 1;
+                self.next = w;
+                return Some(self.frame_of(w * 64 + bit as usize));
+            }
+        }
+        None
+    }
+
+    /// Return `frame` to the pool of free frames.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` is not managed by this allocator, or if it is
+    /// not currently allocated
+    fn deallocate(&mut self, frame: Frame) {
+        let bit = match self.bit_of(frame) {
+            Some(bit) => bit,
+            None => panic!("deallocating unmanaged frame {:#X}", frame.physical_addr),
+        };
+        let word = &mut self.bitmap[bit / 64];
+        assert!(
+            word.get_bit((bit % 64) as u8),
+            "double free of frame {:#X}",
+            frame.physical_addr
+        );
+        word.set_bit((bit % 64) as u8, false);
+        self.free += 1;
+        self.next = self.next.min(bit / 64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn region(base: usize, len: usize, region_type: RegionType) -> MemoryMap {
+        MemoryMap {
+            base,
+            len,
+            region_type,
+            acpi_attributes: 1,
+        }
+    }
+
+    /// A memory map shaped like the one QEMU reports for `-m 8M`
+    fn qemu_map() -> Vec<MemoryMap> {
+        vec![
+            region(0x0, 0x9FC00, RegionType::Usable),
+            region(0x9FC00, 0x400, RegionType::Reserved),
+            region(0xF0000, 0x10000, RegionType::Reserved),
+            region(0x100000, 0x6E0000, RegionType::Usable),
+            region(0x7E0000, 0x20000, RegionType::Reserved),
+            region(0xFFFC0000, 0x40000, RegionType::Reserved),
+        ]
+    }
+
+    fn drain(alloc: &mut BitmapAllocator) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = alloc.allocate() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn covers_every_usable_region() {
+        let map = qemu_map();
+        let mut storage = vec![0u64; 64];
+        let mut alloc = BitmapAllocator::new(&map, &[], &mut storage);
+
+        // 0x9F full frames below the EBDA, and 0x6E0 above 1 MiB
+        assert_eq!(alloc.total_frames(), 0x9F + 0x6E0);
+        assert_eq!(alloc.free_frames(), alloc.total_frames());
+        assert_eq!(alloc.limit(), 0x7E0);
+
+        let frames = drain(&mut alloc);
+        assert_eq!(frames.len(), 0x9F + 0x6E0);
+        assert!(frames.contains(&Frame::containing(0x9E000)));
+        assert!(frames.contains(&Frame::containing(0x100000)));
+        assert!(frames.contains(&Frame::containing(0x7DF000)));
+        assert_eq!(alloc.free_frames(), 0);
+    }
+
+    #[test]
+    fn never_hands_out_a_frame_twice() {
+        let map = qemu_map();
+        let mut storage = vec![0u64; 64];
+        let mut alloc = BitmapAllocator::new(&map, &[], &mut storage);
+
+        let frames = drain(&mut alloc);
+        let unique = frames.iter().collect::<HashSet<_>>();
+        assert_eq!(unique.len(), frames.len());
+
+        for frame in &frames {
+            assert!(map.iter().any(|r| r.region_type == RegionType::Usable
+                && frame.address() >= r.base
+                && frame.address() + FRAME_SIZE <= r.base + r.len));
+        }
+    }
+
+    #[test]
+    fn carves_out_reserved_ranges() {
+        let map = qemu_map();
+        let reserved = [0..0x100000, 0x100000..0x123456, 0x3F1000..0x3F6000];
+        let mut storage = vec![0u64; 64];
+        let mut alloc = BitmapAllocator::new(&map, &reserved, &mut storage);
+
+        assert_eq!(alloc.free_frames(), 0x6E0 - 0x24 - 5);
+        for frame in drain(&mut alloc) {
+            for r in &reserved {
+                assert!(frame.address() + FRAME_SIZE <= r.start || frame.address() >= r.end);
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_regions() {
+        let map = vec![
+            region(0x200000, 0x100000, RegionType::Usable),
+            region(0x100000, 0x180000, RegionType::Usable),
+            region(0x2F0000, 0x20000, RegionType::BadMemory),
+        ];
+        let mut storage = vec![0u64; 16];
+        let mut alloc = BitmapAllocator::new(&map, &[], &mut storage);
+
+        assert_eq!(alloc.total_frames(), 0x200);
+        let frames = drain(&mut alloc);
+        assert_eq!(frames.len(), 0x1F0);
+        assert_eq!(frames.iter().collect::<HashSet<_>>().len(), frames.len());
+        assert!(!frames.contains(&Frame::containing(0x2F0000)));
+    }
+
+    #[test]
+    fn deallocate_and_reuse() {
+        let map = qemu_map();
+        let mut storage = vec![0u64; 64];
+        let mut alloc = BitmapAllocator::new(&map, &[], &mut storage);
+
+        let frames = drain(&mut alloc);
+        alloc.deallocate(frames[10]);
+        alloc.deallocate(frames[500]);
+        assert_eq!(alloc.free_frames(), 2);
+        assert!(!alloc.is_used(frames[10]));
+
+        let mut again = drain(&mut alloc);
+        again.sort();
+        assert_eq!(again, vec![frames[10], frames[500]]);
+        assert!(alloc.allocate().is_none());
+    }
+
+    #[test]
+    fn truncates_to_storage() {
+        let map = qemu_map();
+        let mut storage = vec![0u64; 2];
+        let mut alloc = BitmapAllocator::new(&map, &[], &mut storage);
+        assert_eq!(alloc.total_frames(), 128);
+        assert_eq!(drain(&mut alloc).len(), 128);
+    }
+
+    #[test]
+    fn contiguous() {
+        let map = qemu_map();
+        let mut storage = vec![0u64; 64];
+        let mut alloc = BitmapAllocator::new(&map, &[0x100000..0x101000], &mut storage);
+
+        let run = alloc.allocate_contiguous(0x100, 0x100).unwrap();
+        assert_eq!(run.address(), 0x200000);
+        assert_eq!(alloc.free_frames(), 0x9F + 0x6E0 - 1 - 0x100);
+        assert!(alloc.allocate_contiguous(0x500, 1).is_none());
+
+        let frames = drain(&mut alloc);
+        assert!(frames
+            .iter()
+            .all(|f| f.address() < 0x200000 || f.address() >= 0x300000));
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let map = qemu_map();
+        let mut storage = vec![0u64; 64];
+        let mut alloc = BitmapAllocator::new(&map, &[], &mut storage);
+        let frame = alloc.allocate().unwrap();
+        alloc.deallocate(frame);
+        alloc.deallocate(frame);
+    }
+}
@@ -0,0 +1,334 @@
+//! Binary buddy allocator for physically contiguous, naturally aligned
+//! runs of frames
+use super::*;
+use crate::prelude::*;
+use core::fmt;
+
+/// Largest supported block order. A block of order `n` spans `1 << n`
+/// frames, so the largest block is 4 MiB
+pub const MAX_ORDER: usize = 10;
+
+/// Snapshot of the state of a [`BuddyAllocator`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BuddyStats {
+    /// Number of free blocks at each order
+    pub free_blocks: [usize; MAX_ORDER + 1],
+    /// Total number of free frames, across all orders
+    pub free_frames: usize,
+    /// Number of frames managed by the allocator
+    pub total_frames: usize,
+}
+
+impl BuddyStats {
+    /// Order of the largest free block, if any frames are free
+    pub fn largest_free_order(&self) -> Option<usize> {
+        (0..=MAX_ORDER).rev().find(|&k| self.free_blocks[k] != 0)
+    }
+
+    /// External fragmentation, as the percentage of free frames that are
+    /// not part of the largest free block. 0 means all free memory is in
+    /// a single block, values approaching 100 mean free memory is scattered
+    /// across many small blocks
+    pub fn fragmentation(&self) -> usize {
+        match self.largest_free_order() {
+            Some(k) => 100 - (100 << k) / self.free_frames,
+            None => 0,
+        }
+    }
+}
+
+impl fmt::Display for BuddyStats {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            fmt,
+            "buddy: {}/{} frames free, {}% fragmented",
+            self.free_frames,
+            self.total_frames,
+            self.fragmentation()
+        )?;
+        for (order, count) in self.free_blocks.iter().enumerate() {
+            write!(fmt, "{:>2}:{:<4}", order, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Buddy allocator managing a single contiguous zone of physical memory.
+///
+/// Free blocks are tracked with one bitmap per order, where bit `i` of
+/// order `k` is set if the block covering frames `i << k .. (i + 1) << k`
+/// (relative to the start of the zone) is free. The buddy of block `i` is
+/// always block `i ^ 1`
+pub struct BuddyAllocator<'a> {
+    storage: &'a mut [u64],
+    /// Word offset of the bitmap for each order within `storage`
+    offsets: [usize; MAX_ORDER + 1],
+    free: [usize; MAX_ORDER + 1],
+    base: usize,
+    frames: usize,
+}
+
+impl<'a> BuddyAllocator<'a> {
+    /// Number of words of storage required to manage `frames` frames
+    pub fn storage_words(frames: usize) -> usize {
+        (0..=MAX_ORDER).map(|k| ((frames >> k) + 63) / 64).sum()
+    }
+
+    /// Create a new [`BuddyAllocator`] managing `frames` frames starting at
+    /// `base`, with every frame initially free.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `base` is not aligned to a block of [`MAX_ORDER`], or if
+    /// `storage` holds fewer than [`BuddyAllocator::storage_words`] words
+    pub fn new(base: Frame, frames: usize, storage: &'a mut [u64]) -> BuddyAllocator<'a> {
+        assert_eq!(
+            base.number() % (1 << MAX_ORDER),
+            0,
+            "buddy zone must be aligned to a block of the maximum order"
+        );
+        assert!(storage.len() >= Self::storage_words(frames));
+
+        let mut offsets = [0; MAX_ORDER + 1];
+        let mut offset = 0;
+        for k in 0..=MAX_ORDER {
+            offsets[k] = offset;
+            offset += ((frames >> k) + 63) / 64;
+        }
+        for word in storage[..offset].iter_mut() {
+            *word = 0;
+        }
+
+        let mut alloc = BuddyAllocator {
+            storage,
+            offsets,
+            free: [0; MAX_ORDER + 1],
+            base: base.number(),
+            frames,
+        };
+
+        // Carve the zone into the largest naturally aligned blocks possible
+        let mut pos = 0;
+        while pos < frames {
+            let mut k = MAX_ORDER;
+            while pos % (1 << k) != 0 || pos + (1 << k) > frames {
+                k -= 1;
+            }
+            alloc.set_free(k, pos >> k, true);
+            pos += 1 << k;
+        }
+        alloc
+    }
+
+    fn is_free(&self, order: usize, block: usize) -> bool {
+        let bit = self.offsets[order] * 64 + block;
+        self.storage[bit / 64].get_bit((bit % 64) as u8)
+    }
+
+    fn set_free(&mut self, order: usize, block: usize, free: bool) {
+        let bit = self.offsets[order] * 64 + block;
+        self.storage[bit / 64].set_bit((bit % 64) as u8, free);
+        if free {
+            self.free[order] += 1;
+        } else {
+            self.free[order] -= 1;
+        }
+    }
+
+    /// Return the index of the first free block of `order`
+    fn find_free(&self, order: usize) -> Option<usize> {
+        let words = ((self.frames >> order) + 63) / 64;
+        let start = self.offsets[order];
+        self.storage[start..start + words]
+            .iter()
+            .position(|&w| w != 0)
+            .map(|w| w * 64 + self.storage[start + w].trailing_zeros() as usize)
+    }
+
+    /// Allocate `1 << order` physically contiguous frames, aligned to
+    /// `1 << order` frames. The first [`Frame`] of the block is returned
+    pub fn allocate_order(&mut self, order: usize) -> Option<Frame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut k = (order..=MAX_ORDER).find(|&k| self.free[k] != 0)?;
+        let mut block = self.find_free(k)?;
+        self.set_free(k, block, false);
+
+        // Split the block, returning the upper half to the free list of
+        // the next order down until we reach the requested order
+        while k > order {
+            k -= 1;
+            block <<= 1;
+            self.set_free(k, block | 1, true);
+        }
+
+        Some(Frame {
+            physical_addr: (self.base + (block << order)) * FRAME_SIZE,
+        })
+    }
+
+    /// Return a block of `1 << order` frames that was previously returned
+    /// by [`BuddyAllocator::allocate_order`] with the same `order`, merging
+    /// it with its buddy as long as the buddy is also free
+    ///
+    /// # Panics
+    ///
+    /// Panics if `frame` is outside of the zone, is not aligned to `order`,
+    /// or is already free
+    pub fn deallocate_order(&mut self, frame: Frame, order: usize) {
+        assert!(order <= MAX_ORDER);
+        let number = frame.number();
+        assert!(
+            number >= self.base && number + (1 << order) <= self.base + self.frames,
+            "frame {:#X} is outside of the buddy zone",
+            frame.physical_addr
+        );
+        let offset = number - self.base;
+        assert_eq!(offset % (1 << order), 0, "misaligned block");
+
+        let mut k = order;
+        let mut block = offset >> order;
+        // The block may have been merged into a larger free block already
+        assert!(
+            (k..=MAX_ORDER)
+                .filter(|&j| block >> (j - k) < self.frames >> j)
+                .all(|j| !self.is_free(j, block >> (j - k))),
+            "double free of block {:#X}",
+            frame.physical_addr
+        );
+
+        while k < MAX_ORDER {
+            let buddy = block ^ 1;
+            if buddy >= self.frames >> k || !self.is_free(k, buddy) {
+                break;
+            }
+            self.set_free(k, buddy, false);
+            block >>= 1;
+            k += 1;
+        }
+        self.set_free(k, block, true);
+    }
+
+    /// Return a snapshot of the allocator's free lists
+    pub fn stats(&self) -> BuddyStats {
+        BuddyStats {
+            free_blocks: self.free,
+            free_frames: (0..=MAX_ORDER).map(|k| self.free[k] << k).sum(),
+            total_frames: self.frames,
+        }
+    }
+}
+
+impl<'a> Allocator for BuddyAllocator<'a> {
+    fn allocate(&mut self) -> Option<Frame> {
+        self.allocate_order(0)
+    }
+
+    fn deallocate(&mut self, frame: Frame) {
+        self.deallocate_order(frame, 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: usize = 0x400000;
+
+    fn zone(frames: usize) -> Vec<u64> {
+        vec![0xDEAD_BEEF; BuddyAllocator::storage_words(frames)]
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut storage = zone(1 << MAX_ORDER);
+        let mut buddy = BuddyAllocator::new(Frame::containing(BASE), 1 << MAX_ORDER, &mut storage);
+        assert_eq!(buddy.stats().free_blocks[MAX_ORDER], 1);
+
+        let a = buddy.allocate_order(0).unwrap();
+        assert_eq!(a.address(), BASE);
+        let stats = buddy.stats();
+        assert_eq!(stats.free_frames, (1 << MAX_ORDER) - 1);
+        assert_eq!(stats.free_blocks[MAX_ORDER], 0);
+        for k in 0..MAX_ORDER {
+            assert_eq!(stats.free_blocks[k], 1);
+        }
+
+        buddy.deallocate_order(a, 0);
+        let stats = buddy.stats();
+        assert_eq!(stats.free_blocks[MAX_ORDER], 1);
+        assert_eq!(stats.free_frames, 1 << MAX_ORDER);
+        assert_eq!(stats.fragmentation(), 0);
+    }
+
+    #[test]
+    fn aligned_and_disjoint() {
+        let frames = 3 << MAX_ORDER;
+        let mut storage = zone(frames);
+        let mut buddy = BuddyAllocator::new(Frame::containing(BASE), frames, &mut storage);
+
+        let mut blocks = Vec::new();
+        for &order in [0, 9, 3, 0, 5, 9, 1, 2].iter() {
+            let frame = buddy.allocate_order(order).unwrap();
+            assert_eq!(frame.address() % (FRAME_SIZE << order), 0);
+            blocks.push((frame.address(), frame.address() + (FRAME_SIZE << order)));
+        }
+        for (i, a) in blocks.iter().enumerate() {
+            for b in &blocks[i + 1..] {
+                assert!(a.1 <= b.0 || b.1 <= a.0, "{:X?} overlaps {:X?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn exhaustion() {
+        let frames = 100;
+        let mut storage = zone(frames);
+        let mut buddy = BuddyAllocator::new(Frame::containing(BASE), frames, &mut storage);
+        assert_eq!(buddy.stats().free_frames, 100);
+        assert!(buddy.allocate_order(7).is_none());
+
+        let mut all = Vec::new();
+        while let Some(frame) = buddy.allocate() {
+            all.push(frame);
+        }
+        assert_eq!(all.len(), 100);
+        assert!(buddy.allocate_order(0).is_none());
+
+        for frame in all {
+            buddy.deallocate(frame);
+        }
+        let stats = buddy.stats();
+        assert_eq!(stats.free_frames, 100);
+        // 100 = 64 + 32 + 4
+        assert_eq!(stats.free_blocks[6], 1);
+        assert_eq!(stats.free_blocks[5], 1);
+        assert_eq!(stats.free_blocks[2], 1);
+    }
+
+    #[test]
+    fn fragmentation() {
+        let mut storage = zone(16);
+        let mut buddy = BuddyAllocator::new(Frame::containing(BASE), 16, &mut storage);
+        let frames = (0..16).map(|_| buddy.allocate().unwrap()).collect::<Vec<_>>();
+        for frame in frames.iter().step_by(2) {
+            buddy.deallocate(*frame);
+        }
+        let stats = buddy.stats();
+        assert_eq!(stats.free_frames, 8);
+        assert_eq!(stats.largest_free_order(), Some(0));
+        assert_eq!(stats.fragmentation(), 88);
+        assert!(buddy.allocate_order(1).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let mut storage = zone(16);
+        let mut buddy = BuddyAllocator::new(Frame::containing(BASE), 16, &mut storage);
+        let a = buddy.allocate_order(2).unwrap();
+        buddy.deallocate_order(a, 2);
+        buddy.deallocate_order(a, 2);
+    }
+}
@@ -1,4 +1,16 @@
 pub mod allocator;
+pub mod bitmap;
+pub mod buddy;
+pub mod refcount;
+
+use crate::sync::{Mutex, Once};
+use bitmap::BitmapAllocator;
+use buddy::BuddyAllocator;
+use core::ops::Range;
+use refcount::RefCounts;
+
+/// Size, in bytes, of a physical page frame
+pub const FRAME_SIZE: usize = 0x1000;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[repr(u32)]
@@ -19,6 +31,22 @@ pub struct MemoryMap {
     acpi_attributes: u32,
 }
 
+impl MemoryMap {
+    /// Physical address of the first byte in the region
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Length of the region, in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn region_type(&self) -> RegionType {
+        self.region_type
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
 pub struct MemoryMapInfo {
@@ -26,14 +54,163 @@ pub struct MemoryMapInfo {
     len: usize,
     pub elf_ptr: *const u8,
     pub elf_len: usize,
+    /// Kernel symbol table appended to the kernel by the builder. See
+    /// [`crate::ksyms`]
+    pub ksyms_ptr: *const u8,
+}
+
+impl MemoryMapInfo {
+    /// Return the E820 memory map collected by the bootloader
+    pub fn regions(&self) -> &[MemoryMap] {
+        // Unsafe because we are trusting that the bootloader has given us
+        // the correct pointer and length to the memory map
+        unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Frame {
     physical_addr: usize,
 }
 
+impl Frame {
+    /// Return the [`Frame`] that contains the physical address `addr`
+    pub fn containing(addr: usize) -> Frame {
+        Frame {
+            physical_addr: addr & !(FRAME_SIZE - 1),
+        }
+    }
+
+    /// Physical address of the first byte in the [`Frame`]
+    pub fn address(&self) -> usize {
+        self.physical_addr
+    }
+
+    /// Index of the [`Frame`], counting from physical address 0
+    pub fn number(&self) -> usize {
+        self.physical_addr / FRAME_SIZE
+    }
+}
+
 pub trait Allocator {
     fn allocate(&mut self) -> Option<Frame>;
     fn deallocate(&mut self, frame: Frame);
 }
+
+/// Number of words in the bitmap backing the global frame allocator. Each
+/// word tracks 64 frames, so physical memory above 4 GiB is ignored
+const BITMAP_WORDS: usize = 0x4000;
+
+/// Number of frames in the zone handed to the buddy allocator for
+/// contiguous allocations (16 MiB)
+const BUDDY_FRAMES: usize = 0x1000;
+
+static mut BITMAP: [u64; BITMAP_WORDS] = [0; BITMAP_WORDS];
+/// Large enough to hold `BuddyAllocator::storage_words(BUDDY_FRAMES)`
+static mut BUDDY_BITMAP: [u64; 0x90] = [0; 0x90];
+static FRAMES: Once<Mutex<BitmapAllocator<'static>>> = Once::new();
+static CONTIGUOUS: Once<Mutex<BuddyAllocator<'static>>> = Once::new();
+static REFCOUNTS: Once<Mutex<RefCounts>> = Once::new();
+
+#[cfg(not(test))]
+extern "C" {
+    static _kernel_start: u8;
+    static _kernel_end: u8;
+}
+
+/// Physical memory ranges that are in use before the frame allocator is
+/// initialized, and must never be handed out
+#[cfg(not(test))]
+fn boot_reserved() -> [Range<usize>; 4] {
+    let (start, end) = unsafe {
+        (
+            &_kernel_start as *const u8 as usize - super::KERNEL_VIRT,
+            &_kernel_end as *const u8 as usize - super::KERNEL_VIRT,
+        )
+    };
+    [
+        // Real mode IVT, BIOS data area, bootloader, E820 memory map, the
+        // raw kernel ELF image and the EBDA/ROM/video memory hole. The AP
+        // startup trampoline is also copied to 0x1000
+        0..0x0010_0000,
+        // Kernel image, as loaded from the ELF program headers
+        start..end,
+        // Bootloader stack, which grows down from 0x300000
+        0x0020_0000..0x0030_0000,
+        // Page tables set up by the bootloader
+        0x003F_1000..0x003F_6000,
+    ]
+}
+
+/// Initialize the global frame allocator from the bootloader's memory map,
+/// and set aside a zone of memory for the contiguous buddy allocator.
+///
+/// Subsequent calls return the already initialized allocator
+#[cfg(not(test))]
+pub fn init(info: &MemoryMapInfo) -> &'static Mutex<BitmapAllocator<'static>> {
+    FRAMES.call_once(|| {
+        // The bitmaps are only ever borrowed here, and `call_once`
+        // guarantees that we do so exactly once
+        let storage = unsafe { &mut BITMAP[..] };
+        let mut frames = BitmapAllocator::new(info.regions(), &boot_reserved(), storage);
+
+        CONTIGUOUS.call_once(|| {
+            let storage = unsafe { &mut BUDDY_BITMAP[..] };
+            let align = 1 << buddy::MAX_ORDER;
+            // Settle for a smaller zone on machines with little memory
+            let mut count = BUDDY_FRAMES;
+            while count >= align {
+                if let Some(base) = frames.allocate_contiguous(count, align) {
+                    return Mutex::new(BuddyAllocator::new(base, count, storage));
+                }
+                count /= 2;
+            }
+            Mutex::new(BuddyAllocator::new(Frame::containing(0), 0, storage))
+        });
+
+        Mutex::new(frames)
+    })
+}
+
+/// Return the global frame allocator.
+///
+/// # Panics
+///
+/// Panics if [`init`] has not been called yet
+pub fn frames() -> &'static Mutex<BitmapAllocator<'static>> {
+    FRAMES
+        .try_get()
+        .expect("physical frame allocator used before initialization")
+}
+
+/// Return the global buddy allocator, used for physically contiguous
+/// multi-frame allocations.
+///
+/// # Panics
+///
+/// Panics if [`init`] has not been called yet
+pub fn contiguous() -> &'static Mutex<BuddyAllocator<'static>> {
+    CONTIGUOUS
+        .try_get()
+        .expect("buddy allocator used before initialization")
+}
+
+/// Create the table of frame reference counts, covering every frame that
+/// the global frame allocator manages. The table lives on the kernel heap,
+/// so this must be called once paging is initialized
+pub fn init_refcounts() {
+    let limit = frames().lock().limit();
+    REFCOUNTS.call_once(|| Mutex::new(RefCounts::new(limit)));
+}
+
+/// Return the global frame reference counts. When locked together with the
+/// frame allocator, they must be locked last.
+///
+/// # Panics
+///
+/// Panics if [`init_refcounts`] has not been called yet
+pub fn refcounts() -> &'static Mutex<RefCounts> {
+    REFCOUNTS
+        .try_get()
+        .expect("frame reference counts used before initialization")
+}
@@ -0,0 +1,92 @@
+//! Reference counts for frames that are mapped more than once, such as the
+//! pages that address spaces share copy-on-write after a fork.
+//!
+//! A frame starts out with a single reference, held by whoever allocated
+//! it, so only the references beyond the first are stored. A table of
+//! zeroes describes frames that are not shared at all, and frames that are
+//! never shared need no bookkeeping.
+use super::Frame;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Reference counts of the frames numbered below a limit
+pub struct RefCounts {
+    /// Number of references to each frame, less one
+    extra: Vec<u16>,
+}
+
+impl RefCounts {
+    /// Create a table for the frames numbered below `limit`, none of which
+    /// are shared yet
+    pub fn new(limit: usize) -> RefCounts {
+        RefCounts {
+            extra: vec![0; limit],
+        }
+    }
+
+    /// Number of references to `frame`
+    pub fn count(&self, frame: Frame) -> usize {
+        self.extra
+            .get(frame.number())
+            .map(|&extra| extra as usize + 1)
+            .unwrap_or(1)
+    }
+
+    /// Add a reference to `frame`. Returns false, without adding one, if
+    /// the frame is beyond the table or already has as many references as
+    /// can be counted. The caller should copy the frame instead
+    pub fn share(&mut self, frame: Frame) -> bool {
+        match self.extra.get_mut(frame.number()) {
+            Some(extra) if *extra < u16::max_value() => {
+                *extra += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drop a reference to `frame`. Returns true if it was the last one, in
+    /// which case the caller should deallocate the frame
+    pub fn release(&mut self, frame: Frame) -> bool {
+        match self.extra.get_mut(frame.number()) {
+            Some(extra) if *extra > 0 => {
+                *extra -= 1;
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::physical::FRAME_SIZE;
+
+    #[test]
+    fn counting() {
+        let mut refs = RefCounts::new(16);
+        let frame = Frame::containing(3 * FRAME_SIZE);
+        assert_eq!(refs.count(frame), 1);
+
+        assert!(refs.share(frame));
+        assert!(refs.share(frame));
+        assert_eq!(refs.count(frame), 3);
+        assert!(!refs.release(frame));
+        assert!(!refs.release(frame));
+        assert_eq!(refs.count(frame), 1);
+        assert!(refs.release(frame));
+
+        // Frames beyond the table are never shared
+        let beyond = Frame::containing(16 * FRAME_SIZE);
+        assert!(!refs.share(beyond));
+        assert_eq!(refs.count(beyond), 1);
+        assert!(refs.release(beyond));
+
+        for _ in 0..u16::max_value() {
+            assert!(refs.share(frame));
+        }
+        assert!(!refs.share(frame));
+        assert_eq!(refs.count(frame), 0x10000);
+    }
+}
@@ -0,0 +1,178 @@
+//! Page table entries and the tables that hold them
+use crate::memory::physical::Frame;
+use core::fmt;
+use core::ops::{BitOr, BitOrAssign, Index, IndexMut};
+
+/// Number of entries in each level of the page tables
+pub const ENTRY_COUNT: usize = 512;
+
+/// Bits of an [`Entry`] that hold the physical address of the next table
+/// or of the mapped frame
+const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Flags that control access to the memory referenced by an [`Entry`]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct EntryFlags(u64);
+
+impl EntryFlags {
+    pub const PRESENT: EntryFlags = EntryFlags(1 << 0);
+    pub const WRITABLE: EntryFlags = EntryFlags(1 << 1);
+    pub const USER: EntryFlags = EntryFlags(1 << 2);
+    pub const WRITE_THROUGH: EntryFlags = EntryFlags(1 << 3);
+    pub const NO_CACHE: EntryFlags = EntryFlags(1 << 4);
+    pub const ACCESSED: EntryFlags = EntryFlags(1 << 5);
+    pub const DIRTY: EntryFlags = EntryFlags(1 << 6);
+    /// Entry maps a 2 MiB (level 2) or 1 GiB (level 3) page instead of
+    /// pointing to the next level table
+    pub const HUGE: EntryFlags = EntryFlags(1 << 7);
+    pub const GLOBAL: EntryFlags = EntryFlags(1 << 8);
+    /// Ignored by the processor. Marks a read-only page whose frame may be
+    /// shared with other address spaces, and which gets a frame of its own
+    /// when it is written to. See [`super::Mapper::unshare`]
+    pub const COPY_ON_WRITE: EntryFlags = EntryFlags(1 << 9);
+    /// Only valid when NXE is set in the EFER MSR, otherwise the processor
+    /// treats this as a reserved bit
+    pub const NO_EXECUTE: EntryFlags = EntryFlags(1 << 63);
+
+    pub const fn empty() -> EntryFlags {
+        EntryFlags(0)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns true if every flag in `other` is also set in `self`
+    pub fn contains(self, other: EntryFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: EntryFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: EntryFlags) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for EntryFlags {
+    type Output = EntryFlags;
+    fn bitor(self, rhs: EntryFlags) -> EntryFlags {
+        EntryFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for EntryFlags {
+    fn bitor_assign(&mut self, rhs: EntryFlags) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl fmt::Debug for EntryFlags {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [(EntryFlags, &str); 11] = [
+            (EntryFlags::PRESENT, "PRESENT"),
+            (EntryFlags::WRITABLE, "WRITABLE"),
+            (EntryFlags::USER, "USER"),
+            (EntryFlags::WRITE_THROUGH, "WRITE_THROUGH"),
+            (EntryFlags::NO_CACHE, "NO_CACHE"),
+            (EntryFlags::ACCESSED, "ACCESSED"),
+            (EntryFlags::DIRTY, "DIRTY"),
+            (EntryFlags::HUGE, "HUGE"),
+            (EntryFlags::GLOBAL, "GLOBAL"),
+            (EntryFlags::COPY_ON_WRITE, "COPY_ON_WRITE"),
+            (EntryFlags::NO_EXECUTE, "NO_EXECUTE"),
+        ];
+        let mut first = true;
+        for (flag, name) in NAMES.iter() {
+            if self.contains(*flag) {
+                if !first {
+                    fmt.write_str(" | ")?;
+                }
+                fmt.write_str(name)?;
+                first = false;
+            }
+        }
+        if first {
+            fmt.write_str("(empty)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A single entry in any level of the page tables
+#[derive(Copy, Clone, PartialEq)]
+#[repr(transparent)]
+pub struct Entry(u64);
+
+impl Entry {
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn set_unused(&mut self) {
+        self.0 = 0;
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        EntryFlags(self.0 & !ADDRESS_MASK)
+    }
+
+    /// Return the [`Frame`] referenced by this entry, if it is present
+    pub fn frame(&self) -> Option<Frame> {
+        if self.flags().contains(EntryFlags::PRESENT) {
+            Some(Frame::containing((self.0 & ADDRESS_MASK) as usize))
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, frame: Frame, flags: EntryFlags) {
+        self.0 = (frame.address() as u64 & ADDRESS_MASK) | flags.bits();
+    }
+
+    pub fn set_flags(&mut self, flags: EntryFlags) {
+        self.0 = (self.0 & ADDRESS_MASK) | flags.bits();
+    }
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{:#016X} {:?}", self.0 & ADDRESS_MASK, self.flags())
+    }
+}
+
+/// One page-sized level of the page tables
+#[repr(C, align(4096))]
+pub struct Table {
+    entries: [Entry; ENTRY_COUNT],
+}
+
+impl Table {
+    /// Mark every entry in the table as unused
+    pub fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.set_unused();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+}
+
+impl Index<usize> for Table {
+    type Output = Entry;
+
+    fn index(&self, index: usize) -> &Entry {
+        &self.entries[index]
+    }
+}
+
+impl IndexMut<usize> for Table {
+    fn index_mut(&mut self, index: usize) -> &mut Entry {
+        &mut self.entries[index]
+    }
+}
@@ -0,0 +1,332 @@
+//! Page fault decoding, demand paging of lazily-backed regions, and
+//! copy-on-write
+use super::{EntryFlags, MapError, Mapper, Page, Virtual, PAGE_SIZE};
+use crate::memory::physical::{self, Allocator};
+use crate::prelude::*;
+use crate::sync::{self, CriticalMutexGuard, Mutex};
+use core::fmt;
+
+/// Maximum number of lazily-backed regions that can be registered
+const MAX_LAZY_REGIONS: usize = 32;
+
+global!(LazyRegions);
+
+/// Error code pushed by the processor for a page fault
+#[derive(Copy, Clone, PartialEq)]
+pub struct PageFaultError(usize);
+
+impl PageFaultError {
+    pub fn new(code: usize) -> PageFaultError {
+        PageFaultError(code)
+    }
+
+    /// The fault was caused by a protection violation on a present page,
+    /// rather than by a non-present page
+    pub fn present(&self) -> bool {
+        self.0.get_bit(0)
+    }
+
+    /// The faulting access was a write
+    pub fn write(&self) -> bool {
+        self.0.get_bit(1)
+    }
+
+    /// The faulting access came from ring 3
+    pub fn user(&self) -> bool {
+        self.0.get_bit(2)
+    }
+
+    /// A reserved bit was set in one of the paging structures
+    pub fn reserved(&self) -> bool {
+        self.0.get_bit(3)
+    }
+
+    /// The faulting access was an instruction fetch
+    pub fn instruction_fetch(&self) -> bool {
+        self.0.get_bit(4)
+    }
+}
+
+impl fmt::Debug for PageFaultError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} {} {}{}{}",
+            if self.user() { "user" } else { "kernel" },
+            if self.instruction_fetch() {
+                "instruction fetch"
+            } else if self.write() {
+                "write"
+            } else {
+                "read"
+            },
+            if self.present() {
+                "protection violation"
+            } else {
+                "of non-present page"
+            },
+            if self.reserved() { ", reserved bit set" } else { "" },
+            if self.0 >> 5 != 0 { ", unknown bits set" } else { "" },
+        )
+    }
+}
+
+/// Reasons a page fault could not be resolved
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FaultError {
+    /// The address is not part of any lazily-backed region
+    Unmapped,
+    /// The access is not permitted by the page or region's flags
+    AccessViolation,
+    /// A paging structure contained a reserved bit
+    ReservedBit,
+    /// No physical memory was available to back the page
+    OutOfMemory,
+    /// The fault occured while the faulting code held locks, and the page
+    /// tables or frame allocator were locked, so it cannot be serviced
+    Locked,
+}
+
+/// Reasons a region could not be registered
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegionError {
+    /// The region's start or size is not page aligned, or it is empty
+    Misaligned,
+    /// The region overlaps an already registered region
+    Overlap,
+    /// The maximum number of regions are already registered
+    Full,
+}
+
+/// A range of virtual memory that is backed by zeroed frames the first
+/// time each page is touched
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LazyRegion {
+    pub start: Virtual,
+    pub size: usize,
+    pub flags: EntryFlags,
+}
+
+impl LazyRegion {
+    pub fn contains(&self, addr: Virtual) -> bool {
+        addr >= self.start && addr.as_usize() - self.start.as_usize() < self.size
+    }
+
+    /// Returns true if `error` describes an access that this region's
+    /// flags allow
+    pub fn permits(&self, error: PageFaultError) -> bool {
+        !(error.write() && !self.flags.contains(EntryFlags::WRITABLE)
+            || error.user() && !self.flags.contains(EntryFlags::USER)
+            || error.instruction_fetch() && self.flags.contains(EntryFlags::NO_EXECUTE))
+    }
+}
+
+/// Set of registered [`LazyRegion`]s
+pub struct LazyRegions {
+    regions: [Option<LazyRegion>; MAX_LAZY_REGIONS],
+}
+
+impl Default for LazyRegions {
+    fn default() -> LazyRegions {
+        LazyRegions {
+            regions: [None; MAX_LAZY_REGIONS],
+        }
+    }
+}
+
+impl LazyRegions {
+    /// Register `size` bytes starting at `start` to be backed on demand,
+    /// with pages mapped using `flags`
+    pub fn register(
+        &mut self,
+        start: Virtual,
+        size: usize,
+        flags: EntryFlags,
+    ) -> Result<(), RegionError> {
+        if size == 0 || start.as_usize() % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(RegionError::Misaligned);
+        }
+        let end = start.as_usize() + size;
+        let overlaps = self.regions.iter().flatten().any(|r| {
+            start.as_usize() < r.start.as_usize() + r.size && r.start.as_usize() < end
+        });
+        if overlaps {
+            return Err(RegionError::Overlap);
+        }
+
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(RegionError::Full)?;
+        *slot = Some(LazyRegion { start, size, flags });
+        Ok(())
+    }
+
+    /// Remove the region starting at `start`, returning it. Pages that were
+    /// already backed stay mapped
+    pub fn unregister(&mut self, start: Virtual) -> Option<LazyRegion> {
+        self.regions
+            .iter_mut()
+            .find(|r| r.map(|r| r.start == start).unwrap_or(false))
+            .and_then(|r| r.take())
+    }
+
+    /// Return the region containing `addr`
+    pub fn find(&self, addr: Virtual) -> Option<LazyRegion> {
+        self.regions
+            .iter()
+            .flatten()
+            .find(|r| r.contains(addr))
+            .copied()
+    }
+}
+
+/// Lock `mutex` to resolve a fault. If the faulting code held locks, it
+/// may hold this one, which would never be released while the fault
+/// handler spins on it, so then the lock is only tried
+fn lock<T>(mutex: &Mutex<T>, nested: bool) -> Result<CriticalMutexGuard<T>, FaultError> {
+    if nested {
+        mutex.try_critical().ok_or(FaultError::Locked)
+    } else {
+        Ok(mutex.critical())
+    }
+}
+
+/// Outcome of a fault on a lazily-backed page that could not be mapped.
+/// If it is already mapped, another fault on the same page backed it first
+fn backed(err: MapError) -> Result<(), FaultError> {
+    match err {
+        MapError::AlreadyMapped(_) => Ok(()),
+        _ => Err(FaultError::OutOfMemory),
+    }
+}
+
+/// Map a zeroed frame at `page` with `flags`, unless another fault on it,
+/// which held the locks first, already has
+fn back(page: Page, flags: EntryFlags, nested: bool) -> Result<(), FaultError> {
+    let mut mapper = lock(Mapper::global(), nested)?;
+    if mapper.translate_page(page).is_some() {
+        return Ok(());
+    }
+    let mut frames = lock(physical::frames(), nested)?;
+
+    let frame = frames.allocate().ok_or(FaultError::OutOfMemory)?;
+    // Map the page writable while zeroing it, since the region may be
+    // read-only. User pages need user-accessible tables above them
+    let mut writable = EntryFlags::PRESENT | EntryFlags::WRITABLE;
+    if flags.contains(EntryFlags::USER) {
+        writable.insert(EntryFlags::USER);
+    }
+    if let Err(err) = mapper.map(page, frame, writable, &mut *frames) {
+        frames.deallocate(frame);
+        return backed(err);
+    }
+    unsafe {
+        core::ptr::write_bytes(page.start_address().as_usize() as *mut u8, 0, PAGE_SIZE);
+    }
+    mapper
+        .update_flags(page, flags)
+        .map_err(|_| FaultError::Unmapped)
+}
+
+/// Give the copy-on-write page `page` a writable frame of its own
+fn copy_on_write(page: Page, nested: bool) -> Result<(), FaultError> {
+    let mut mapper = lock(Mapper::global(), nested)?;
+    match mapper.translate_page(page) {
+        Some((_, flags)) if flags.contains(EntryFlags::COPY_ON_WRITE) => (),
+        _ => return Err(FaultError::AccessViolation),
+    }
+    let mut frames = lock(physical::frames(), nested)?;
+    let mut refs = lock(physical::refcounts(), nested)?;
+    mapper
+        .unshare(page, &mut *frames, &mut *refs)
+        .map_err(|_| FaultError::OutOfMemory)
+}
+
+/// Attempt to resolve a page fault at `addr`.
+///
+/// Faults on non-present pages inside a registered [`LazyRegion`] are
+/// resolved by mapping a zeroed frame, provided the access is permitted by
+/// the region's flags, and writes to [`EntryFlags::COPY_ON_WRITE`] pages by
+/// giving them a frame of their own. Any other fault is a genuine violation
+pub fn handle(addr: Virtual, error: PageFaultError) -> Result<(), FaultError> {
+    if error.reserved() {
+        return Err(FaultError::ReservedBit);
+    }
+    // Locks are counted per processor, so this is whether the faulting code
+    // held any
+    let nested = sync::held() != 0;
+    if error.present() {
+        if error.write() && !error.instruction_fetch() {
+            return copy_on_write(Page::containing(addr), nested);
+        }
+        return Err(FaultError::AccessViolation);
+    }
+
+    let region = lock(LazyRegions::global(), nested)?
+        .find(addr)
+        .ok_or(FaultError::Unmapped)?;
+    if !region.permits(error) {
+        return Err(FaultError::AccessViolation);
+    }
+    back(Page::containing(addr), region.flags, nested)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        let e = PageFaultError::new(0b00110);
+        assert!(!e.present() && e.write() && e.user());
+        assert!(!e.reserved() && !e.instruction_fetch());
+        assert_eq!(format!("{:?}", e), "user write of non-present page");
+
+        let e = PageFaultError::new(0b10001);
+        assert!(e.present() && e.instruction_fetch());
+        assert_eq!(format!("{:?}", e), "kernel instruction fetch protection violation");
+    }
+
+    #[test]
+    fn regions() {
+        let mut regions = LazyRegions::default();
+        let rw = EntryFlags::WRITABLE | EntryFlags::NO_EXECUTE;
+        let base = Virtual::new(0xFFFF_8000_0000_0000);
+
+        assert_eq!(regions.register(base, 0x4000, rw), Ok(()));
+        assert_eq!(
+            regions.register(Virtual::new(0xFFFF_8000_0000_3000), 0x1000, rw),
+            Err(RegionError::Overlap)
+        );
+        assert_eq!(regions.register(base, 0x1234, rw), Err(RegionError::Misaligned));
+        assert_eq!(
+            regions.register(Virtual::new(0xFFFF_8000_0000_4000), 0x1000, EntryFlags::empty()),
+            Ok(())
+        );
+
+        let r = regions.find(Virtual::new(0xFFFF_8000_0000_3FFF)).unwrap();
+        assert_eq!(r.start, base);
+        assert!(r.permits(PageFaultError::new(0b010)));
+        assert!(!r.permits(PageFaultError::new(0b100)));
+        assert!(!r.permits(PageFaultError::new(0b10000)));
+
+        let ro = regions.find(Virtual::new(0xFFFF_8000_0000_4000)).unwrap();
+        assert!(!ro.permits(PageFaultError::new(0b010)));
+        assert!(ro.permits(PageFaultError::new(0b000)));
+
+        assert_eq!(regions.unregister(base), Some(r));
+        assert_eq!(regions.find(base), None);
+    }
+
+    #[test]
+    fn already_backed() {
+        let frame = physical::Frame::containing(0x1000);
+        assert_eq!(backed(MapError::AlreadyMapped(frame)), Ok(()));
+        assert_eq!(
+            backed(MapError::FrameAllocationFailed),
+            Err(FaultError::OutOfMemory)
+        );
+    }
+}
@@ -0,0 +1,506 @@
+//! Walk and edit the active page tables through the recursive PML4 entry
+use super::entry::{EntryFlags, Table};
+use super::{Page, Physical, TableIndices, Virtual, KERNEL_INDEX, PAGE_SIZE, RECURSIVE_INDEX};
+use crate::arch::instructions;
+use crate::memory::physical::refcount::RefCounts;
+use crate::memory::physical::{Allocator, Frame};
+use crate::prelude::*;
+
+global!(Mapper, { unsafe { Mapper::new() } });
+
+/// Virtual address of a page used to edit frames that are not otherwise
+/// mapped, such as the PML4 of a new address space (PML4 entry 507)
+pub const SCRATCH: usize = 0xFFFF_FD80_0000_0000;
+
+/// Number of consecutive pages from [`SCRATCH`] that are used while
+/// cloning an address space: one for each level of the new tables, and
+/// one to copy frames through
+pub(super) const SCRATCH_PAGES: usize = 5;
+
+/// Scratch page that frames are copied through
+const COPY_SCRATCH: usize = 4;
+
+/// Return scratch page `index`, counting from [`SCRATCH`]
+fn scratch(index: usize) -> Page {
+    Page::containing(Virtual(SCRATCH + index * PAGE_SIZE))
+}
+
+/// Recursive address of the PML4 itself
+const PML4: TableIndices = TableIndices {
+    level4: RECURSIVE_INDEX,
+    level3: RECURSIVE_INDEX,
+    level2: RECURSIVE_INDEX,
+    level1: RECURSIVE_INDEX,
+};
+
+/// Errors that can occur while editing the page tables
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MapError {
+    /// The page is already mapped to the given frame
+    AlreadyMapped(Frame),
+    /// The page is not mapped
+    NotMapped,
+    /// A new page table was required, but the frame allocator is empty
+    FrameAllocationFailed,
+    /// The page lies within a 2 MiB or 1 GiB page, which the [`Mapper`]
+    /// does not split
+    HugePage,
+}
+
+/// Provides access to the active 4-level page tables.
+///
+/// Every table is reached through the recursive entry at index
+/// [`RECURSIVE_INDEX`] of the PML4, which [`super::init`] installs. Since
+/// that entry always points at the PML4 loaded in CR3, a [`Mapper`] edits
+/// whichever address space is currently active
+pub struct Mapper {
+    _private: (),
+}
+
+impl Mapper {
+    /// Create a new [`Mapper`] for the active page tables
+    ///
+    /// # Safety
+    ///
+    /// The recursive PML4 entry must be installed, and only one [`Mapper`]
+    /// should exist at a time - use [`Mapper::global`] instead
+    pub unsafe fn new() -> Mapper {
+        Mapper { _private: () }
+    }
+
+    /// Return the table at the recursive address formed by `indices`
+    fn table(&self, indices: TableIndices) -> &'static mut Table {
+        unsafe { &mut *(indices.to_virt() as *mut Table) }
+    }
+
+    /// Return the recursive address of the table referenced by entry
+    /// `index` of the table at recursive address `parent`
+    fn child(&self, parent: TableIndices, index: usize) -> TableIndices {
+        TableIndices {
+            level4: parent.level3,
+            level3: parent.level2,
+            level2: parent.level1,
+            level1: index,
+        }
+    }
+
+    /// Walk one level down from `parent`, returning the recursive address
+    /// of the next table if the entry at `index` points to one
+    fn next(&self, parent: TableIndices, index: usize) -> Result<TableIndices, MapError> {
+        let entry = self.table(parent)[index];
+        if !entry.flags().contains(EntryFlags::PRESENT) {
+            Err(MapError::NotMapped)
+        } else if entry.flags().contains(EntryFlags::HUGE) {
+            Err(MapError::HugePage)
+        } else {
+            Ok(self.child(parent, index))
+        }
+    }
+
+    /// Walk one level down from `parent`, allocating and zeroing a new
+    /// table if the entry at `index` is not present
+    fn next_create<A: Allocator>(
+        &mut self,
+        parent: TableIndices,
+        index: usize,
+        user: bool,
+        allocator: &mut A,
+    ) -> Result<TableIndices, MapError> {
+        let child = self.child(parent, index);
+        let entry = &mut self.table(parent)[index];
+        let mut flags = EntryFlags::PRESENT | EntryFlags::WRITABLE;
+        if user {
+            flags |= EntryFlags::USER;
+        }
+
+        if entry.is_unused() {
+            let frame = allocator
+                .allocate()
+                .ok_or(MapError::FrameAllocationFailed)?;
+            entry.set(frame, flags);
+            Self::flush(Page::containing(Virtual(child.to_virt())));
+            self.table(child).zero();
+        } else if entry.flags().contains(EntryFlags::HUGE) {
+            return Err(MapError::HugePage);
+        } else if user && !entry.flags().contains(EntryFlags::USER) {
+            // Access is restricted by every level of the tables, so the
+            // intermediate entries must permit user access too
+            let mut current = entry.flags();
+            current.insert(EntryFlags::USER);
+            entry.set_flags(current);
+        }
+        Ok(child)
+    }
+
+    /// Return the recursive address of the level 1 table that maps `page`
+    fn level1(&self, page: Page) -> Result<TableIndices, MapError> {
+        let idx = page.indices();
+        let p3 = self.next(PML4, idx.level4)?;
+        let p2 = self.next(p3, idx.level3)?;
+        self.next(p2, idx.level2)
+    }
+
+    /// Translate a virtual address to the physical address it is mapped
+    /// to, if any. 2 MiB and 1 GiB pages are supported
+    pub fn translate(&self, addr: Virtual) -> Option<Physical> {
+        let idx = TableIndices::from_virt(addr.as_usize());
+        if self.table(PML4)[idx.level4].frame().is_none() {
+            return None;
+        }
+        let p3 = self.child(PML4, idx.level4);
+
+        let entry = self.table(p3)[idx.level3];
+        let frame = entry.frame()?;
+        if entry.flags().contains(EntryFlags::HUGE) {
+            let offset = addr.as_usize() & 0x3FFF_FFFF;
+            return Some(Physical(frame.address() + offset));
+        }
+        let p2 = self.child(p3, idx.level3);
+
+        let entry = self.table(p2)[idx.level2];
+        let frame = entry.frame()?;
+        if entry.flags().contains(EntryFlags::HUGE) {
+            let offset = addr.as_usize() & 0x1F_FFFF;
+            return Some(Physical(frame.address() + offset));
+        }
+        let p1 = self.child(p2, idx.level2);
+
+        let frame = self.table(p1)[idx.level1].frame()?;
+        Some(Physical(frame.address() + (addr.as_usize() & (PAGE_SIZE - 1))))
+    }
+
+    /// Return the [`Frame`] that `page` is mapped to, along with the flags
+    /// of the mapping
+    pub fn translate_page(&self, page: Page) -> Option<(Frame, EntryFlags)> {
+        let p1 = self.level1(page).ok()?;
+        let entry = self.table(p1)[page.indices().level1];
+        entry.frame().map(|frame| (frame, entry.flags()))
+    }
+
+    /// Map `page` to `frame` with `flags`, allocating any missing
+    /// intermediate tables from `allocator`. [`EntryFlags::PRESENT`] is
+    /// always added to `flags`
+    pub fn map<A: Allocator>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: EntryFlags,
+        allocator: &mut A,
+    ) -> Result<(), MapError> {
+        let idx = page.indices();
+        let user = flags.contains(EntryFlags::USER);
+
+        let p3 = self.next_create(PML4, idx.level4, user, allocator)?;
+        let p2 = self.next_create(p3, idx.level3, user, allocator)?;
+        let p1 = self.next_create(p2, idx.level2, user, allocator)?;
+
+        let entry = &mut self.table(p1)[idx.level1];
+        if let Some(existing) = entry.frame() {
+            return Err(MapError::AlreadyMapped(existing));
+        }
+        entry.set(frame, flags | EntryFlags::PRESENT);
+        Self::flush(page);
+        Ok(())
+    }
+
+    /// Remove the mapping for `page`, returning the [`Frame`] it was mapped
+    /// to. The frame is not deallocated, and intermediate tables are not
+    /// freed even if they become empty
+    pub fn unmap(&mut self, page: Page) -> Result<Frame, MapError> {
+        let p1 = self.level1(page)?;
+        let entry = &mut self.table(p1)[page.indices().level1];
+        let frame = entry.frame().ok_or(MapError::NotMapped)?;
+        entry.set_unused();
+        Self::flush(page);
+        Ok(frame)
+    }
+
+    /// Replace the flags of an existing mapping for `page`
+    pub fn update_flags(&mut self, page: Page, flags: EntryFlags) -> Result<(), MapError> {
+        let p1 = self.level1(page)?;
+        let entry = &mut self.table(p1)[page.indices().level1];
+        if entry.frame().is_none() {
+            return Err(MapError::NotMapped);
+        }
+        entry.set_flags(flags | EntryFlags::PRESENT);
+        Self::flush(page);
+        Ok(())
+    }
+
+    /// Make sure that PML4 entry `index` points to a level 3 table, so that
+    /// address spaces created afterwards share everything mapped under it
+    pub fn reserve<A: Allocator>(
+        &mut self,
+        index: usize,
+        allocator: &mut A,
+    ) -> Result<(), MapError> {
+        self.next_create(PML4, index, false, allocator).map(|_| ())
+    }
+
+    /// Create a new address space, returning the frame holding its PML4.
+    /// The user half is empty, and the kernel half shares the active
+    /// address space's tables
+    pub fn create_space<A: Allocator>(&mut self, allocator: &mut A) -> Result<Frame, MapError> {
+        let frame = allocator
+            .allocate()
+            .ok_or(MapError::FrameAllocationFailed)?;
+        let scratch = Page::containing(Virtual(SCRATCH));
+        if let Err(err) = self.map(scratch, frame, EntryFlags::WRITABLE, allocator) {
+            allocator.deallocate(frame);
+            return Err(err);
+        }
+
+        let table = unsafe { &mut *(SCRATCH as *mut Table) };
+        let kernel = self.table(PML4);
+        table.zero();
+        for index in KERNEL_INDEX..RECURSIVE_INDEX {
+            table[index] = kernel[index];
+        }
+        table[RECURSIVE_INDEX].set(frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+        table[RECURSIVE_INDEX + 1] = kernel[RECURSIVE_INDEX + 1];
+
+        self.unmap(scratch)?;
+        Ok(frame)
+    }
+
+    /// Unmap everything in the user half of the active address space,
+    /// returning the page tables to `allocator`. Mapped frames are released
+    /// in `refs`, and returned too once nothing else maps them
+    ///
+    /// # Safety
+    ///
+    /// The active address space must have been created by
+    /// [`Mapper::create_space`]. The boot address space uses its user half
+    /// for an identity mapping that shares tables with the kernel
+    pub unsafe fn clear_user<A: Allocator>(&mut self, allocator: &mut A, refs: &mut RefCounts) {
+        for l4 in 0..KERNEL_INDEX {
+            let p3 = match self.next(PML4, l4) {
+                Ok(p3) => p3,
+                Err(_) => continue,
+            };
+            for l3 in 0..512 {
+                let p2 = match self.next(p3, l3) {
+                    Ok(p2) => p2,
+                    Err(_) => continue,
+                };
+                for l2 in 0..512 {
+                    let p1 = match self.next(p2, l2) {
+                        Ok(p1) => p1,
+                        Err(_) => continue,
+                    };
+                    for entry in self.table(p1).iter() {
+                        match entry.frame() {
+                            Some(frame) if refs.release(frame) => allocator.deallocate(frame),
+                            _ => (),
+                        }
+                    }
+                    self.free_table(p2, l2, allocator);
+                }
+                self.free_table(p3, l3, allocator);
+            }
+            self.free_table(PML4, l4, allocator);
+        }
+        instructions::flush_tlb();
+    }
+
+    /// Create a new address space whose user half maps the same frames as
+    /// the active one's, returning the frame holding its PML4. Writable
+    /// pages become copy-on-write in both address spaces, and are only
+    /// copied once one of them writes to them. A frame whose references
+    /// cannot be counted is copied straight away.
+    ///
+    /// Nothing is allocated from the heap, since growing it needs the
+    /// [`Mapper`]. The active address space must not be in use on any other
+    /// processor, since only this one's TLB is flushed
+    pub fn clone_user<A: Allocator>(
+        &mut self,
+        allocator: &mut A,
+        refs: &mut RefCounts,
+    ) -> Result<Frame, MapError> {
+        let space = self.create_space(allocator)?;
+        let result = self.share_user(space, allocator, refs);
+        for level in 0..SCRATCH_PAGES {
+            let _ = self.unmap(scratch(level));
+        }
+        instructions::flush_tlb();
+
+        if let Err(err) = result {
+            // Every page mapped in the new address space so far was counted,
+            // so clearing it releases them again
+            unsafe {
+                let active = super::current_space();
+                super::activate(space);
+                self.clear_user(allocator, refs);
+                super::activate(active);
+            }
+            allocator.deallocate(space);
+            return Err(err);
+        }
+        Ok(space)
+    }
+
+    /// Fill in the empty user half of the address space with PML4 `space`
+    /// from the active one. The new tables are edited through a scratch
+    /// page for each level, which the caller unmaps afterwards
+    fn share_user<A: Allocator>(
+        &mut self,
+        space: Frame,
+        allocator: &mut A,
+        refs: &mut RefCounts,
+    ) -> Result<(), MapError> {
+        let pml4 = self.map_scratch(0, space, allocator)?;
+        for l4 in 0..KERNEL_INDEX {
+            let p3 = match self.next(PML4, l4) {
+                Ok(p3) => p3,
+                Err(_) => continue,
+            };
+            let (frame, new3) = self.new_table(1, allocator)?;
+            pml4[l4].set(frame, self.table(PML4)[l4].flags());
+            for l3 in 0..512 {
+                let p2 = match self.next(p3, l3) {
+                    Ok(p2) => p2,
+                    Err(_) => continue,
+                };
+                let (frame, new2) = self.new_table(2, allocator)?;
+                new3[l3].set(frame, self.table(p3)[l3].flags());
+                for l2 in 0..512 {
+                    let p1 = match self.next(p2, l2) {
+                        Ok(p1) => p1,
+                        Err(_) => continue,
+                    };
+                    let (frame, new1) = self.new_table(3, allocator)?;
+                    new2[l2].set(frame, self.table(p2)[l2].flags());
+                    let table = self.table(p1);
+                    for l1 in 0..512 {
+                        let frame = match table[l1].frame() {
+                            Some(frame) => frame,
+                            None => continue,
+                        };
+                        let mut flags = table[l1].flags();
+                        if refs.share(frame) {
+                            if flags.contains(EntryFlags::WRITABLE) {
+                                flags.remove(EntryFlags::WRITABLE);
+                                flags.insert(EntryFlags::COPY_ON_WRITE);
+                                table[l1].set_flags(flags);
+                            }
+                            new1[l1].set(frame, flags);
+                        } else {
+                            let indices = TableIndices {
+                                level4: l4,
+                                level3: l3,
+                                level2: l2,
+                                level1: l1,
+                            };
+                            let page = Page::containing(Virtual(indices.to_virt()));
+                            new1[l1].set(self.copy_frame(page, allocator)?, flags);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make the copy-on-write page `page` writable. If another mapping
+    /// still shares its frame, the page gets a copy of its own first.
+    /// Otherwise the frame is simply reclaimed
+    pub fn unshare<A: Allocator>(
+        &mut self,
+        page: Page,
+        allocator: &mut A,
+        refs: &mut RefCounts,
+    ) -> Result<(), MapError> {
+        let p1 = self.level1(page)?;
+        let entry = &mut self.table(p1)[page.indices().level1];
+        let frame = entry.frame().ok_or(MapError::NotMapped)?;
+        let mut flags = entry.flags();
+        debug_assert!(flags.contains(EntryFlags::COPY_ON_WRITE));
+        flags.remove(EntryFlags::COPY_ON_WRITE);
+        flags.insert(EntryFlags::WRITABLE);
+
+        if refs.count(frame) > 1 {
+            let copy = self.copy_frame(page, allocator)?;
+            refs.release(frame);
+            entry.set(copy, flags);
+        } else {
+            entry.set_flags(flags);
+        }
+        Self::flush(page);
+        Ok(())
+    }
+
+    /// Copy the contents of the mapped page `page` into a new frame
+    fn copy_frame<A: Allocator>(
+        &mut self,
+        page: Page,
+        allocator: &mut A,
+    ) -> Result<Frame, MapError> {
+        let frame = allocator
+            .allocate()
+            .ok_or(MapError::FrameAllocationFailed)?;
+        let copy = match self.map_scratch(COPY_SCRATCH, frame, allocator) {
+            Ok(copy) => copy,
+            Err(err) => {
+                allocator.deallocate(frame);
+                return Err(err);
+            }
+        };
+        unsafe {
+            let src = page.start_address().as_usize() as *const u8;
+            core::ptr::copy_nonoverlapping(src, copy as *mut Table as *mut u8, PAGE_SIZE);
+        }
+        self.unmap(scratch(COPY_SCRATCH))?;
+        Ok(frame)
+    }
+
+    /// Allocate and zero a page table, leaving it mapped at scratch page
+    /// `index`
+    fn new_table<A: Allocator>(
+        &mut self,
+        index: usize,
+        allocator: &mut A,
+    ) -> Result<(Frame, &'static mut Table), MapError> {
+        let frame = allocator
+            .allocate()
+            .ok_or(MapError::FrameAllocationFailed)?;
+        match self.map_scratch(index, frame, allocator) {
+            Ok(table) => {
+                table.zero();
+                Ok((frame, table))
+            }
+            Err(err) => {
+                allocator.deallocate(frame);
+                Err(err)
+            }
+        }
+    }
+
+    /// Map `frame` at scratch page `index`, replacing whatever was mapped
+    /// there, and return it as a page table
+    fn map_scratch<A: Allocator>(
+        &mut self,
+        index: usize,
+        frame: Frame,
+        allocator: &mut A,
+    ) -> Result<&'static mut Table, MapError> {
+        let page = scratch(index);
+        let _ = self.unmap(page);
+        self.map(page, frame, EntryFlags::WRITABLE, allocator)?;
+        Ok(unsafe { &mut *(page.start_address().as_usize() as *mut Table) })
+    }
+
+    /// Clear entry `index` of the table at recursive address `parent`, and
+    /// free the table it pointed to
+    fn free_table<A: Allocator>(&mut self, parent: TableIndices, index: usize, allocator: &mut A) {
+        let entry = &mut self.table(parent)[index];
+        if let Some(frame) = entry.frame() {
+            entry.set_unused();
+            allocator.deallocate(frame);
+        }
+    }
+
+    /// Invalidate the TLB entry for `page` on the current processor
+    pub fn flush(page: Page) {
+        unsafe { instructions::invlpg(page.start_address().as_usize()) }
+    }
+}
@@ -0,0 +1,268 @@
+use crate::arch::instructions;
+use crate::memory::physical::{self, Frame};
+use crate::memory::{heap, mmio, KERNEL_VIRT};
+use crate::prelude::*;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub mod entry;
+pub mod fault;
+pub mod mapper;
+
+pub use entry::{Entry, EntryFlags, Table};
+pub use mapper::{MapError, Mapper};
+
+/// Size, in bytes, of a (non-huge) page
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Index of the PML4 entry that points back to the PML4 itself, giving
+/// access to every page table through virtual addresses
+pub const RECURSIVE_INDEX: usize = 510;
+
+/// Index of the first PML4 entry of the kernel's half of the address
+/// space, which is shared by every address space
+pub const KERNEL_INDEX: usize = 256;
+
+/// Extended feature enable register
+const IA32_EFER: u32 = 0xC000_0080;
+/// No-execute enable bit in EFER
+const EFER_NXE: u64 = 1 << 11;
+/// Write protect bit in CR0
+const CR0_WP: u64 = 1 << 16;
+
+/// Physical address of the PML4 that the kernel runs in
+static KERNEL_SPACE: AtomicUsize = AtomicUsize::new(0);
+/// Set once EFER.NXE is enabled
+static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct Virtual(usize);
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct Physical(usize);
+
+impl Virtual {
+    pub const fn new(addr: usize) -> Virtual {
+        Virtual(addr)
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+}
+
+impl Physical {
+    pub const fn new(addr: usize) -> Physical {
+        Physical(addr)
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Debug for Virtual {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Virtual({:#X})", self.0)
+    }
+}
+
+impl fmt::Debug for Physical {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Physical({:#X})", self.0)
+    }
+}
+
+/// A 4 KiB page of virtual memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Page {
+    virtual_addr: usize,
+}
+
+impl Page {
+    /// Return the [`Page`] that contains the virtual address `addr`
+    pub fn containing(addr: Virtual) -> Page {
+        Page {
+            virtual_addr: addr.0 & !(PAGE_SIZE - 1),
+        }
+    }
+
+    pub fn start_address(&self) -> Virtual {
+        Virtual(self.virtual_addr)
+    }
+
+    pub fn indices(&self) -> TableIndices {
+        TableIndices::from_virt(self.virtual_addr)
+    }
+
+    /// Return the page `n` pages after this one
+    pub fn offset(&self, n: usize) -> Page {
+        Page {
+            virtual_addr: self.virtual_addr + n * PAGE_SIZE,
+        }
+    }
+}
+
+/// Helper struct that holds the index in each level of the page tables
+/// for a virtual address
+#[derive(Copy, Clone, PartialEq)]
+pub struct TableIndices {
+    pub level4: usize,
+    pub level3: usize,
+    pub level2: usize,
+    pub level1: usize,
+}
+
+impl fmt::Debug for TableIndices {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "level 4: {:0X}\nlevel 3: {:0X}\nlevel 2: {:0X}\nlevel 1: {:0X}",
+            self.level4, self.level3, self.level2, self.level1
+        )
+    }
+}
+
+impl TableIndices {
+    pub const fn from_virt(vaddr: usize) -> TableIndices {
+        TableIndices {
+            level4: (vaddr & (0x1FF << 39)) >> 39,
+            level3: (vaddr & (0x1FF << 30)) >> 30,
+            level2: (vaddr & (0x1FF << 21)) >> 21,
+            level1: (vaddr & (0x1FF << 12)) >> 12,
+        }
+    }
+
+    pub fn to_virt(self) -> usize {
+        let mut base =
+            (self.level4 << 39) | (self.level3 << 30) | (self.level2 << 21) | (self.level1 << 12);
+        let fill = if base.get_bit(47) {
+            core::usize::MAX
+        } else {
+            0
+        };
+        base.set_bits(47..64, fill);
+        base
+    }
+}
+
+/// PML4 entries of the kernel's fixed regions
+const SCRATCH_INDEX: usize = TableIndices::from_virt(mapper::SCRATCH).level4;
+const MMIO_INDEX: usize = TableIndices::from_virt(mmio::MMIO_START).level4;
+const HEAP_INDEX: usize = TableIndices::from_virt(heap::HEAP_START).level4;
+const IMAGE_INDEX: usize = TableIndices::from_virt(KERNEL_VIRT).level4;
+
+/// Whether `size` bytes from `start` lie within a single PML4 entry
+const fn within_entry(start: usize, size: usize) -> bool {
+    TableIndices::from_virt(start).level4 == TableIndices::from_virt(start + size - 1).level4
+}
+
+/// Fails to compile unless every kernel region has a PML4 entry to itself,
+/// in the kernel half, and apart from the recursive entry
+#[allow(dead_code)]
+const REGIONS_DISJOINT: [(); 0] = [(); !((SCRATCH_INDEX >= KERNEL_INDEX)
+    & (SCRATCH_INDEX != MMIO_INDEX)
+    & (SCRATCH_INDEX != HEAP_INDEX)
+    & (SCRATCH_INDEX != RECURSIVE_INDEX)
+    & (SCRATCH_INDEX != IMAGE_INDEX)
+    & (MMIO_INDEX >= KERNEL_INDEX)
+    & (MMIO_INDEX != HEAP_INDEX)
+    & (MMIO_INDEX != RECURSIVE_INDEX)
+    & (MMIO_INDEX != IMAGE_INDEX)
+    & (HEAP_INDEX >= KERNEL_INDEX)
+    & (HEAP_INDEX != RECURSIVE_INDEX)
+    & (HEAP_INDEX != IMAGE_INDEX)
+    & (RECURSIVE_INDEX != IMAGE_INDEX)
+    & within_entry(mapper::SCRATCH, mapper::SCRATCH_PAGES * PAGE_SIZE)
+    & within_entry(mmio::MMIO_START, mmio::MMIO_MAX_SIZE)
+    & within_entry(heap::HEAP_START, heap::HEAP_MAX_SIZE))
+    as usize];
+
+/// Install the recursive PML4 entry, so that the page tables can be edited
+/// through [`Mapper`].
+///
+/// The bootloader places the PML4 within the first 4 MiB of physical
+/// memory, which it also maps at [`KERNEL_VIRT`]
+pub fn init() {
+    let pml4 = instructions::cr3() as usize & !(PAGE_SIZE - 1);
+    assert!(pml4 < 0x40_0000, "PML4 is outside of the boot mapping");
+
+    let table = unsafe { &mut *((KERNEL_VIRT + pml4) as *mut Table) };
+    table[RECURSIVE_INDEX].set(
+        Frame::containing(pml4),
+        EntryFlags::PRESENT | EntryFlags::WRITABLE,
+    );
+    instructions::flush_tlb();
+    KERNEL_SPACE.store(pml4, Ordering::SeqCst);
+    enable_no_execute();
+    enable_write_protect();
+
+    // Address spaces copy the kernel's PML4 entries when they are created,
+    // so the kernel regions that are mapped on demand need their level 3
+    // tables up front for later mappings to show up everywhere
+    let mut mapper = Mapper::global().lock();
+    let mut frames = physical::frames().lock();
+    for &index in [SCRATCH_INDEX, MMIO_INDEX, HEAP_INDEX].iter() {
+        mapper
+            .reserve(index, &mut *frames)
+            .expect("failed to reserve kernel page tables");
+    }
+}
+
+/// Set EFER.NXE on the calling processor if it supports no-execute pages.
+/// Without it, [`EntryFlags::NO_EXECUTE`] is a reserved bit
+pub fn enable_no_execute() {
+    if instructions::cpuid(0x8000_0000).eax < 0x8000_0001
+        || !instructions::cpuid(0x8000_0001).edx.get_bit(20)
+    {
+        return;
+    }
+    unsafe {
+        let efer = instructions::rdmsr(IA32_EFER);
+        instructions::wrmsr(IA32_EFER, efer | EFER_NXE);
+    }
+    NO_EXECUTE.store(true, Ordering::SeqCst);
+}
+
+/// Set CR0.WP on the calling processor, so that writes from ring 0 to
+/// read-only pages fault too. System calls write to user memory, which
+/// would otherwise go straight through copy-on-write pages
+pub fn enable_write_protect() {
+    unsafe { instructions::set_cr0(instructions::cr0() | CR0_WP) }
+}
+
+/// Return [`EntryFlags::NO_EXECUTE`] if no-execute pages are enabled, or
+/// no flags otherwise
+pub fn no_execute() -> EntryFlags {
+    if NO_EXECUTE.load(Ordering::Relaxed) {
+        EntryFlags::NO_EXECUTE
+    } else {
+        EntryFlags::empty()
+    }
+}
+
+/// Return the PML4 of the address space that the kernel was booted in,
+/// which kernel threads run in
+pub fn kernel_space() -> Frame {
+    Frame::containing(KERNEL_SPACE.load(Ordering::Relaxed))
+}
+
+/// Return the PML4 of the active address space
+pub fn current_space() -> Frame {
+    Frame::containing(instructions::cr3() as usize)
+}
+
+/// Switch to the address space with PML4 `pml4`, unless it is already
+/// active
+///
+/// # Safety
+///
+/// `pml4` must have been created by [`Mapper::create_space`], or be the
+/// [`kernel_space`], so that the kernel stays mapped
+pub unsafe fn activate(pml4: Frame) {
+    if current_space() != pml4 {
+        instructions::set_cr3(pml4.address() as u64);
+    }
+}
@@ -27,6 +27,7 @@ fn panic(info: &PanicInfo) -> ! {
         if let Some(args) = info.message() {
             let _ = dest.write_fmt(*args);
         }
+        let _ = dest.write_str("\n");
     }
 
     crate::arch::interrupts::disable();
@@ -36,7 +37,9 @@ fn panic(info: &PanicInfo) -> ! {
     terminal.set_color(Color::White, Color::Red);
 
     write_info(serial, info);
+    let _ = crate::backtrace::write_current(serial);
     write_info(terminal, info);
+    let _ = crate::backtrace::write_current(terminal);
 
     loop {}
 }
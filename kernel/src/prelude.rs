@@ -1,7 +1,7 @@
 //! Provides support functions
 #![allow(dead_code)]
 
-pub use crate::sync::Global;
+pub use crate::sync::{Global, GlobalRwLock, GlobalTicketLock};
 pub use core::fmt::Write;
 
 use crate::term::Terminal;
@@ -25,10 +25,11 @@ macro_rules! println {
 
 /// Print formatted [`fmt::Arguments`] to the global VGA terminal.
 ///
-/// This function locks the global terminal
+/// This function locks the global terminal with interrupts disabled, so it
+/// can be used from interrupt handlers
 pub fn print(args: fmt::Arguments) {
     use fmt::Write;
-    Terminal::global().lock().write_fmt(args).unwrap();
+    Terminal::global().critical().write_fmt(args).unwrap();
 }
 
 pub struct BytesBuf<'a> {
@@ -0,0 +1,410 @@
+//! Loading ELF executables into a user address space
+use crate::arch::instructions;
+use crate::elf::{Elf, ElfError, Header, HeaderType, Segment, SegmentType};
+use crate::memory::physical::{self, Allocator};
+use crate::paging::{self, EntryFlags, MapError, Mapper, Page, Virtual, PAGE_SIZE};
+use crate::prelude::*;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+/// Lowest address a program may be loaded at. The first page is never
+/// mapped, so that null pointer dereferences fault
+pub const USER_START: usize = PAGE_SIZE;
+
+/// End of the user half of the address space
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// Address just past the initial stack. The page above is never mapped
+pub const STACK_TOP: usize = USER_END - PAGE_SIZE;
+
+/// Size, in bytes, of the region below [`STACK_TOP`] that the stack can
+/// grow into. Pages are backed as they are first touched
+pub const STACK_SIZE: usize = 0x80_0000;
+
+/// Address just past the area that anonymous mappings are placed in,
+/// leaving an unmapped page below the stack
+pub const MMAP_TOP: usize = STACK_TOP - STACK_SIZE - PAGE_SIZE;
+
+/// Lowest address of the mapping area. Programs, and the heap above them,
+/// must end below it
+pub const MMAP_BOTTOM: usize = 0x0000_2000_0000_0000;
+
+/// Maximum size, in bytes, of the arguments, environment and auxiliary
+/// vector on the initial stack
+pub const ARG_MAX: usize = 0x2_0000;
+
+/// Auxiliary vector entry types
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_UID: usize = 11;
+const AT_EUID: usize = 12;
+const AT_GID: usize = 13;
+const AT_EGID: usize = 14;
+const AT_HWCAP: usize = 16;
+const AT_CLKTCK: usize = 17;
+const AT_SECURE: usize = 23;
+const AT_RANDOM: usize = 25;
+
+/// Reasons a program could not be loaded
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExecError {
+    /// The file could not be parsed
+    Elf(ElfError),
+    /// The file is not a statically linked x86-64 executable
+    NotExecutable,
+    /// A loadable segment lies outside of the file, or of the part of user
+    /// space that programs are loaded into
+    BadSegment,
+    /// The arguments and environment do not fit in [`ARG_MAX`] bytes
+    TooBig,
+    /// No physical memory was available
+    OutOfMemory,
+    /// The page tables could not be updated
+    Map(MapError),
+}
+
+impl From<ElfError> for ExecError {
+    fn from(err: ElfError) -> ExecError {
+        ExecError::Elf(err)
+    }
+}
+
+impl From<MapError> for ExecError {
+    fn from(err: MapError) -> ExecError {
+        match err {
+            MapError::FrameAllocationFailed => ExecError::OutOfMemory,
+            err => ExecError::Map(err),
+        }
+    }
+}
+
+/// Where a program was loaded
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Loaded {
+    /// Entry point
+    pub entry: usize,
+    /// Initial stack pointer
+    pub stack: usize,
+    /// Start of the heap: the first page boundary past every segment
+    pub brk: usize,
+}
+
+/// Parse `data` as an executable, and check that it can be loaded
+pub fn parse(data: &[u8]) -> Result<Elf, ExecError> {
+    let elf = Elf::parse(data)?;
+    let header = elf.header;
+    if header.object_type() != Some(HeaderType::Executable) || header.machine() != Header::X86_64 {
+        return Err(ExecError::NotExecutable);
+    }
+    // Dynamically linked programs need an interpreter to load them
+    if elf
+        .segments
+        .iter()
+        .any(|segment| segment.ty() == Some(SegmentType::Interpreter))
+    {
+        return Err(ExecError::NotExecutable);
+    }
+
+    for segment in loadable(&elf) {
+        let file_end = segment.offset().checked_add(segment.file_size());
+        let mem_end = segment.vaddr.checked_add(segment.mem_size);
+        match (file_end, mem_end) {
+            (Some(file_end), Some(mem_end))
+                if file_end <= data.len()
+                    && segment.file_size() <= segment.mem_size
+                    && segment.vaddr >= USER_START
+                    && mem_end <= MMAP_BOTTOM => {}
+            _ => return Err(ExecError::BadSegment),
+        }
+    }
+    Ok(elf)
+}
+
+/// Load the executable in `data` into the active address space, which must
+/// have an empty user half, and set up its initial stack
+pub fn load(data: &[u8], args: &[&[u8]], env: &[&[u8]]) -> Result<Loaded, ExecError> {
+    let elf = parse(data)?;
+    map_segments(&elf, data)?;
+
+    // Everything runs as root. The C library treats a program as setuid
+    // unless the IDs match and AT_SECURE is 0
+    let mut auxv = vec![
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf.header.entry()),
+        (AT_PHENT, size_of::<Segment>()),
+        (AT_PHNUM, elf.segments.len()),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_SECURE, 0),
+        (AT_HWCAP, 0),
+        (AT_CLKTCK, crate::timer::HZ as usize),
+    ];
+    if let Some(phdr) = program_headers(&elf) {
+        auxv.push((AT_PHDR, phdr));
+    }
+
+    // The stack is backed on demand, so only the pages written to here are
+    // allocated
+    let memory =
+        unsafe { core::slice::from_raw_parts_mut((STACK_TOP - ARG_MAX) as *mut u8, ARG_MAX) };
+    let stack = build_stack(memory, STACK_TOP, args, env, &auxv, &random())?;
+    let end = loadable(&elf)
+        .map(|segment| segment.vaddr + segment.mem_size)
+        .max()
+        .unwrap_or(USER_START);
+    Ok(Loaded {
+        entry: elf.header.entry(),
+        stack,
+        brk: (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+    })
+}
+
+fn loadable<'a>(elf: &'a Elf) -> impl Iterator<Item = &'a Segment> {
+    elf.segments
+        .iter()
+        .filter(|segment| segment.ty() == Some(SegmentType::Loadable) && segment.mem_size != 0)
+}
+
+/// Map fresh frames for every page that a loadable segment touches, and
+/// copy in the segments' contents. A page shared by two segments gets the
+/// permissions of both
+fn map_segments(elf: &Elf, data: &[u8]) -> Result<(), ExecError> {
+    let mut pages = BTreeMap::new();
+    for segment in loadable(elf) {
+        let first = Page::containing(Virtual::new(segment.vaddr));
+        let last = Page::containing(Virtual::new(segment.vaddr + segment.mem_size - 1));
+        let mut page = first;
+        while page <= last {
+            let flags = pages
+                .entry(page)
+                .or_insert(EntryFlags::USER | paging::no_execute());
+            if segment.flags() & Segment::WRITE != 0 {
+                flags.insert(EntryFlags::WRITABLE);
+            }
+            if segment.flags() & Segment::EXECUTE != 0 {
+                flags.remove(EntryFlags::NO_EXECUTE);
+            }
+            page = page.offset(1);
+        }
+    }
+
+    let mut mapper = Mapper::global().critical();
+    let mut frames = physical::frames().critical();
+    for &page in pages.keys() {
+        let frame = frames.allocate().ok_or(ExecError::OutOfMemory)?;
+        // Writable until the contents are copied in
+        let flags = EntryFlags::USER | EntryFlags::WRITABLE;
+        if let Err(err) = mapper.map(page, frame, flags, &mut *frames) {
+            frames.deallocate(frame);
+            return Err(err.into());
+        }
+        unsafe {
+            core::ptr::write_bytes(page.start_address().as_usize() as *mut u8, 0, PAGE_SIZE);
+        }
+    }
+
+    for segment in loadable(elf) {
+        let contents = &data[segment.offset()..segment.offset() + segment.file_size()];
+        unsafe {
+            let dest = segment.vaddr as *mut u8;
+            core::ptr::copy_nonoverlapping(contents.as_ptr(), dest, contents.len());
+            // The frames were zeroed, but be explicit about the BSS
+            let bss = segment.mem_size - segment.file_size();
+            core::ptr::write_bytes(dest.add(contents.len()), 0, bss);
+        }
+    }
+
+    for (&page, &flags) in pages.iter() {
+        mapper.update_flags(page, flags)?;
+    }
+    Ok(())
+}
+
+/// Return the address that the program headers are loaded at, if a
+/// loadable segment includes them
+fn program_headers(elf: &Elf) -> Option<usize> {
+    let offset = elf.header.program_headers();
+    loadable(elf)
+        .find(|segment| {
+            offset >= segment.offset() && offset - segment.offset() < segment.file_size()
+        })
+        .map(|segment| segment.vaddr + offset - segment.offset())
+}
+
+/// Bytes for [`AT_RANDOM`], which the C library seeds stack protectors
+/// with. Unpredictable enough to be useful, but not cryptographically
+fn random() -> [u8; 16] {
+    // splitmix64, seeded from the time stamp counter
+    let mut state = instructions::rdtsc();
+    let mut next = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&next().to_ne_bytes());
+    bytes[8..].copy_from_slice(&next().to_ne_bytes());
+    bytes
+}
+
+/// Writes the initial stack of a program downwards from the top of
+/// `memory`, which ends at virtual address `top`
+struct StackBuilder<'a> {
+    memory: &'a mut [u8],
+    top: usize,
+    /// Offset in `memory` of the lowest byte written
+    offset: usize,
+}
+
+impl<'a> StackBuilder<'a> {
+    fn new(memory: &'a mut [u8], top: usize) -> StackBuilder<'a> {
+        let offset = memory.len();
+        StackBuilder {
+            memory,
+            top,
+            offset,
+        }
+    }
+
+    /// Virtual address of the lowest byte written
+    fn address(&self) -> usize {
+        self.top - (self.memory.len() - self.offset)
+    }
+
+    /// Push `bytes`, returning their virtual address
+    fn push(&mut self, bytes: &[u8]) -> Result<usize, ExecError> {
+        let offset = self
+            .offset
+            .checked_sub(bytes.len())
+            .ok_or(ExecError::TooBig)?;
+        self.memory[offset..self.offset].copy_from_slice(bytes);
+        self.offset = offset;
+        Ok(self.address())
+    }
+
+    /// Push a NUL-terminated copy of `string`, returning its address
+    fn push_string(&mut self, string: &[u8]) -> Result<usize, ExecError> {
+        self.push(&[0])?;
+        self.push(string)
+    }
+
+    fn push_word(&mut self, word: usize) -> Result<(), ExecError> {
+        self.push(&word.to_ne_bytes()).map(|_| ())
+    }
+
+    /// Align the stack down to `align` bytes
+    fn align(&mut self, align: usize) -> Result<(), ExecError> {
+        let padding = self.address() % align;
+        self.offset = self.offset.checked_sub(padding).ok_or(ExecError::TooBig)?;
+        Ok(())
+    }
+}
+
+/// Lay out the initial stack as the System V x86-64 ABI describes it. From
+/// the returned stack pointer up: `argc`, the `argv` pointers and a null
+/// pointer, the `envp` pointers and a null pointer, then the auxiliary
+/// vector, ending with [`AT_NULL`]. The strings and [`AT_RANDOM`] bytes are
+/// above those
+fn build_stack(
+    memory: &mut [u8],
+    top: usize,
+    args: &[&[u8]],
+    env: &[&[u8]],
+    auxv: &[(usize, usize)],
+    random: &[u8; 16],
+) -> Result<usize, ExecError> {
+    let mut stack = StackBuilder::new(memory, top);
+    let mut strings = Vec::with_capacity(args.len() + env.len());
+    for string in args.iter().chain(env.iter()) {
+        strings.push(stack.push_string(string)?);
+    }
+    let random = stack.push(random)?;
+    stack.align(16)?;
+
+    // The stack pointer must end up 16-byte aligned
+    let words = 1 + args.len() + 1 + env.len() + 1 + 2 * (auxv.len() + 2);
+    if words % 2 != 0 {
+        stack.push_word(0)?;
+    }
+
+    let end = [(AT_RANDOM, random), (AT_NULL, 0)];
+    for &(key, value) in auxv.iter().chain(end.iter()).rev() {
+        stack.push_word(value)?;
+        stack.push_word(key)?;
+    }
+    let (argv, envp) = strings.split_at(args.len());
+    for pointers in [envp, argv].iter() {
+        stack.push_word(0)?;
+        for &pointer in pointers.iter().rev() {
+            stack.push_word(pointer)?;
+        }
+    }
+    stack.push_word(args.len())?;
+    Ok(stack.address())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn initial_stack() {
+        let top = 0x7000_0000;
+        let mut memory = vec![0u8; 0x1000];
+        let args: [&[u8]; 2] = [b"init", b"-v"];
+        let env: [&[u8]; 1] = [b"HOME=/"];
+        let auxv = [(AT_PAGESZ, PAGE_SIZE), (AT_ENTRY, 0x40_1000)];
+        let sp = build_stack(&mut memory, top, &args, &env, &auxv, &[7; 16]).unwrap();
+        assert_eq!(sp % 16, 0);
+
+        let base = top - memory.len();
+        let word = |addr: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&memory[addr - base..addr - base + 8]);
+            usize::from_ne_bytes(bytes)
+        };
+        let string = |addr: usize| {
+            let bytes = &memory[addr - base..];
+            &bytes[..bytes.iter().position(|&b| b == 0).unwrap()]
+        };
+
+        assert_eq!(word(sp), 2);
+        assert_eq!(string(word(sp + 8)), b"init");
+        assert_eq!(string(word(sp + 16)), b"-v");
+        assert_eq!(word(sp + 24), 0);
+        assert_eq!(string(word(sp + 32)), b"HOME=/");
+        assert_eq!(word(sp + 40), 0);
+
+        let mut auxv = Vec::new();
+        let mut addr = sp + 48;
+        loop {
+            let (key, value) = (word(addr), word(addr + 8));
+            auxv.push((key, value));
+            addr += 16;
+            if key == AT_NULL {
+                break;
+            }
+        }
+        assert_eq!(&auxv[..2], &[(AT_PAGESZ, PAGE_SIZE), (AT_ENTRY, 0x40_1000)]);
+        assert_eq!(auxv[2].0, AT_RANDOM);
+        assert_eq!(&memory[auxv[2].1 - base..auxv[2].1 - base + 16], &[7; 16]);
+        assert_eq!(auxv[3], (AT_NULL, 0));
+
+        // Everything has to fit
+        let mut small = vec![0u8; 64];
+        assert_eq!(
+            build_stack(&mut small, top, &args, &env, &auxv[..2], &[0; 16]),
+            Err(ExecError::TooBig)
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! Memory that a process asks for while it runs: the heap that `brk` moves
+//! the end of, and anonymous mappings from `mmap`
+//!
+//! The heap starts just past the program and grows up towards
+//! [`MMAP_BOTTOM`]. Mappings are placed downwards from [`MMAP_TOP`], and
+//! addresses are not reused once unmapped, since the area is far larger
+//! than physical memory.
+use super::loader::{MMAP_BOTTOM, MMAP_TOP, USER_START};
+use crate::memory::physical::refcount::RefCounts;
+use crate::memory::physical::{self, Allocator};
+use crate::paging::{EntryFlags, MapError, Mapper, Page, Virtual, PAGE_SIZE};
+use crate::prelude::*;
+
+/// Layout of a process's heap and mappings
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Memory {
+    /// Start of the heap, just past the program
+    pub heap_start: usize,
+    /// The program break: the end of the heap
+    pub brk: usize,
+    /// Lowest address mapped so far
+    pub mmap_bottom: usize,
+}
+
+impl Memory {
+    /// A process with an empty heap starting at `heap_start`, which must be
+    /// page aligned, and nothing mapped
+    pub fn new(heap_start: usize) -> Memory {
+        Memory {
+            heap_start,
+            brk: heap_start,
+            mmap_bottom: MMAP_TOP,
+        }
+    }
+
+    /// Choose the address of a new mapping of `len` bytes, a multiple of
+    /// the page size
+    pub fn place(&mut self, len: usize) -> Option<usize> {
+        let start = self.mmap_bottom.checked_sub(len)?;
+        if start < MMAP_BOTTOM {
+            return None;
+        }
+        self.mmap_bottom = start;
+        Some(start)
+    }
+}
+
+/// Round `len` up to a multiple of the page size
+pub fn page_align(len: usize) -> Option<usize> {
+    len.checked_add(PAGE_SIZE - 1)
+        .map(|len| len & !(PAGE_SIZE - 1))
+}
+
+/// Whether `len` bytes from `start` lie in the part of user space that
+/// programs can map memory in
+pub fn in_user(start: usize, len: usize) -> bool {
+    start >= USER_START && start.checked_add(len).map_or(false, |end| end <= MMAP_TOP)
+}
+
+/// Back the `len` bytes at `start` with zeroed frames, mapped with `flags`.
+/// Both must be page aligned, and nothing may be mapped there yet. If it
+/// fails, nothing new is left mapped
+pub fn map_zeroed(start: usize, len: usize, flags: EntryFlags) -> Result<(), MapError> {
+    let first = Page::containing(Virtual::new(start));
+    for index in 0..len / PAGE_SIZE {
+        if let Err(err) = map_zeroed_page(first.offset(index), flags) {
+            unmap(start, index * PAGE_SIZE);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Back `page` with a zeroed frame, mapped with `flags`. The page tables
+/// and frames are locked for one page at a time, so that a large mapping
+/// doesn't hold up every other processor
+fn map_zeroed_page(page: Page, flags: EntryFlags) -> Result<(), MapError> {
+    let mut mapper = Mapper::global().critical();
+    let mut frames = physical::frames().critical();
+    let frame = frames.allocate().ok_or(MapError::FrameAllocationFailed)?;
+    // Writable while it is zeroed, since `flags` may not be
+    let writable = EntryFlags::USER | EntryFlags::WRITABLE;
+    if let Err(err) = mapper.map(page, frame, writable, &mut *frames) {
+        frames.deallocate(frame);
+        return Err(err);
+    }
+    unsafe {
+        core::ptr::write_bytes(page.start_address().as_usize() as *mut u8, 0, PAGE_SIZE);
+    }
+    mapper.update_flags(page, flags).map_err(|err| {
+        if mapper.unmap(page).is_ok() {
+            frames.deallocate(frame);
+        }
+        err
+    })
+}
+
+/// Unmap the `len` bytes at `start`, both page aligned, returning frames
+/// that nothing else maps. Pages that aren't mapped are skipped
+pub fn unmap(start: usize, len: usize) {
+    let mut mapper = Mapper::global().critical();
+    let mut frames = physical::frames().critical();
+    let mut refs = physical::refcounts().critical();
+    let first = Page::containing(Virtual::new(start));
+    release(&mut mapper, &mut *frames, &mut refs, first, len / PAGE_SIZE);
+}
+
+/// Unmap `count` pages from `first`, releasing their frames in `refs`
+fn release<A: Allocator>(
+    mapper: &mut Mapper,
+    allocator: &mut A,
+    refs: &mut RefCounts,
+    first: Page,
+    count: usize,
+) {
+    for index in 0..count {
+        let frame = match mapper.unmap(first.offset(index)) {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        if refs.release(frame) {
+            allocator.deallocate(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn placement() {
+        let mut memory = Memory::new(0x40_0000);
+        assert_eq!(memory.brk, 0x40_0000);
+        assert_eq!(memory.place(2 * PAGE_SIZE), Some(MMAP_TOP - 2 * PAGE_SIZE));
+        assert_eq!(memory.place(PAGE_SIZE), Some(MMAP_TOP - 3 * PAGE_SIZE));
+        assert_eq!(memory.place(MMAP_TOP), None);
+        assert_eq!(memory.mmap_bottom, MMAP_TOP - 3 * PAGE_SIZE);
+
+        assert_eq!(page_align(1), Some(PAGE_SIZE));
+        assert_eq!(page_align(PAGE_SIZE), Some(PAGE_SIZE));
+        assert_eq!(page_align(usize::max_value()), None);
+        assert!(in_user(USER_START, PAGE_SIZE));
+        assert!(!in_user(0, PAGE_SIZE));
+        assert!(!in_user(MMAP_TOP, PAGE_SIZE));
+        assert!(!in_user(USER_START, usize::max_value()));
+    }
+}
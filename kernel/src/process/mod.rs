@@ -0,0 +1,536 @@
+//! User processes
+//!
+//! A process is a kernel thread that runs a program in ring 3, in an
+//! address space of its own. The kernel half of every address space is
+//! shared, so the thread enters the kernel on interrupts and exceptions
+//! without changing page tables. An exception in user code kills the
+//! process, rather than the kernel.
+//!
+//! Processes form a tree. When a process exits it becomes a zombie, holding
+//! only its exit status, until its parent reaps it with [`wait`]. Children
+//! of a process that exits are adopted by [`INIT`], which is expected to
+//! reap them.
+//!
+//! Each process has its own open files, and its own heap and anonymous
+//! mappings, which [`memory`] manages. Open files stay open across `exec`,
+//! while the heap and mappings go with the old program's address space.
+use crate::arch::context;
+use crate::arch::interrupts::Nesting;
+use crate::arch::syscall::SyscallStack;
+use crate::arch::usermode;
+use crate::fs::Descriptors;
+use crate::memory::physical::{self, Allocator, Frame};
+use crate::paging::fault::LazyRegions;
+use crate::paging::{self, EntryFlags, MapError, Mapper, Virtual};
+use crate::prelude::*;
+use crate::sync::{Once, WaitQueue};
+use crate::thread::{self, Tid};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub mod loader;
+pub mod memory;
+
+pub use loader::ExecError;
+pub use memory::Memory;
+
+global!(Processes);
+
+/// Process ID
+pub type Pid = usize;
+
+/// Parent of the processes that the kernel starts
+pub const KERNEL: Pid = 0;
+
+/// The first process, which adopts orphaned processes
+pub const INIT: Pid = 1;
+
+/// Signal numbers, as on Linux, which report how a process was killed
+pub mod signal {
+    pub const SIGILL: u8 = 4;
+    pub const SIGTRAP: u8 = 5;
+    pub const SIGFPE: u8 = 8;
+    pub const SIGKILL: u8 = 9;
+    pub const SIGSEGV: u8 = 11;
+}
+
+/// Queue of threads waiting for a process to exit. The process table is
+/// locked with interrupts disabled throughout, since waiting checks it
+/// with interrupts disabled
+static EXITED: Once<WaitQueue> = Once::new();
+
+/// How a process ended
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExitStatus {
+    /// The process exited with a status code
+    Exited(u8),
+    /// The process was killed, by a signal as Linux would see it
+    Killed(u8),
+}
+
+impl ExitStatus {
+    /// Encode as the status word that `wait4` stores, as Linux does
+    pub fn wait_status(self) -> u32 {
+        match self {
+            ExitStatus::Exited(code) => (code as u32) << 8,
+            ExitStatus::Killed(signal) => signal as u32,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    Running,
+    /// Exited, but not yet reaped by the parent
+    Zombie(ExitStatus),
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub pid: Pid,
+    pub parent: Pid,
+    pub name: String,
+    /// The thread that runs the program, once it has started
+    pub thread: Option<Tid>,
+    /// PML4 of the process's address space, until it exits
+    pub space: Option<Frame>,
+    pub state: State,
+    pub files: Descriptors,
+    pub memory: Memory,
+}
+
+/// Result of waiting for a child process
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Wait {
+    /// The child exited, and has been reaped
+    Exited(Pid, ExitStatus),
+    /// The children are all still running
+    Running,
+    /// There are no children to wait for
+    NoChildren,
+}
+
+/// Table of processes, including zombies
+pub struct Processes {
+    table: BTreeMap<Pid, Process>,
+    next_pid: Pid,
+}
+
+impl Default for Processes {
+    fn default() -> Processes {
+        Processes {
+            table: BTreeMap::new(),
+            next_pid: INIT,
+        }
+    }
+}
+
+impl Processes {
+    /// Add a running process with a new ID, returning the ID. It starts
+    /// with the console open as its standard files, and an empty heap
+    /// until a program is loaded
+    fn insert(&mut self, parent: Pid, name: String, space: Option<Frame>) -> Pid {
+        let pid = self.next_pid;
+        self.next_pid += 1;
+        let process = Process {
+            pid,
+            parent,
+            name,
+            thread: None,
+            space,
+            state: State::Running,
+            files: Descriptors::standard(),
+            memory: Memory::new(loader::USER_START),
+        };
+        self.table.insert(pid, process);
+        pid
+    }
+
+    /// Add a child of `parent` that runs in `space`, with the same name,
+    /// open files and memory layout. Returns the child's ID
+    fn fork(&mut self, parent: Pid, space: Frame) -> Option<Pid> {
+        let (name, files, memory) = self
+            .get(parent)
+            .map(|process| (process.name.clone(), process.files.clone(), process.memory))?;
+        let pid = self.insert(parent, name, Some(space));
+        if let Some(child) = self.table.get_mut(&pid) {
+            child.files = files;
+            child.memory = memory;
+        }
+        Some(pid)
+    }
+
+    pub fn get(&self, pid: Pid) -> Option<&Process> {
+        self.table.get(&pid)
+    }
+
+    /// Return the process that thread `id` runs, if any
+    pub fn by_thread(&self, id: Tid) -> Option<&Process> {
+        self.table
+            .values()
+            .find(|process| process.thread == Some(id))
+    }
+
+    /// Record that `pid` exited with `status`. It stays in the table as a
+    /// zombie until its parent reaps it, unless the kernel started it, and
+    /// [`INIT`] adopts its children
+    fn exited(&mut self, pid: Pid, status: ExitStatus) {
+        let parent = match self.table.get_mut(&pid) {
+            Some(process) => {
+                process.state = State::Zombie(status);
+                process.thread = None;
+                process.space = None;
+                process.parent
+            }
+            None => return,
+        };
+        if parent == KERNEL {
+            self.table.remove(&pid);
+        }
+        for process in self.table.values_mut() {
+            if process.parent == pid {
+                process.parent = INIT;
+            }
+        }
+    }
+
+    /// Reap a child of `parent` that has exited, which must be `pid` if
+    /// that is given
+    fn reap(&mut self, parent: Pid, pid: Option<Pid>) -> Wait {
+        let mut found = false;
+        let mut zombie = None;
+        let children = self.table.values().filter(|process| {
+            process.parent == parent && pid.map(|pid| process.pid == pid).unwrap_or(true)
+        });
+        for child in children {
+            found = true;
+            if let State::Zombie(status) = child.state {
+                zombie = Some((child.pid, status));
+                break;
+            }
+        }
+        match zombie {
+            Some((pid, status)) => {
+                self.table.remove(&pid);
+                Wait::Exited(pid, status)
+            }
+            None if found => Wait::Running,
+            None => Wait::NoChildren,
+        }
+    }
+}
+
+fn exited() -> &'static WaitQueue {
+    EXITED.call_once(WaitQueue::new)
+}
+
+/// Run `f` on the calling process, with the process table locked. Returns
+/// `None` if the calling thread is not running a user program
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let id = thread::current();
+    Processes::global()
+        .critical()
+        .table
+        .values_mut()
+        .find(|process| process.thread == Some(id))
+        .map(f)
+}
+
+/// Return the ID of the calling process, or `None` if the calling thread
+/// is not running a user program
+pub fn current() -> Option<Pid> {
+    with_current(|process| process.pid)
+}
+
+/// Return the ID of the calling process's parent
+pub fn parent() -> Option<Pid> {
+    with_current(|process| process.parent)
+}
+
+/// Register the user stack region, which is backed on demand in every
+/// address space
+pub fn init() {
+    let start = Virtual::new(loader::STACK_TOP - loader::STACK_SIZE);
+    let flags = EntryFlags::USER | EntryFlags::WRITABLE | paging::no_execute();
+    LazyRegions::global()
+        .lock()
+        .register(start, loader::STACK_SIZE, flags)
+        .expect("failed to register the user stack region");
+}
+
+/// Create a new, empty address space
+fn create_space() -> Result<Frame, MapError> {
+    let mut mapper = Mapper::global().critical();
+    let mut frames = physical::frames().critical();
+    mapper.create_space(&mut *frames)
+}
+
+/// First thing a process's thread does: move into the process's address
+/// space, and record which thread runs the process
+fn start(pid: Pid, space: Frame) {
+    unsafe { thread::set_space(Some(space)) };
+    if let Some(process) = Processes::global().critical().table.get_mut(&pid) {
+        process.thread = Some(thread::current());
+    }
+}
+
+/// Start a process named `name` that runs the executable `image`, with
+/// arguments `args` and environment `env`. The image is checked before the
+/// process is created, but if loading it fails later on, for instance for
+/// lack of memory, the process is killed straight away
+pub fn spawn(
+    name: &str,
+    image: &'static [u8],
+    args: &[&str],
+    env: &[&str],
+) -> Result<Pid, ExecError> {
+    loader::parse(image)?;
+    let space = create_space()?;
+    let pid = Processes::global()
+        .critical()
+        .insert(KERNEL, String::from(name), Some(space));
+
+    let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+    let env: Vec<Vec<u8>> = env.iter().map(|var| var.as_bytes().to_vec()).collect();
+    thread::spawn(move || {
+        start(pid, space);
+        let loaded = {
+            let args: Vec<&[u8]> = args.iter().map(|arg| &arg[..]).collect();
+            let env: Vec<&[u8]> = env.iter().map(|var| &var[..]).collect();
+            loader::load(image, &args, &env)
+        };
+        // Nothing on this stack is dropped once the thread is in user mode
+        drop(args);
+        drop(env);
+        match loaded {
+            Ok(loaded) => {
+                with_current(|process| process.memory = Memory::new(loaded.brk));
+                unsafe { usermode::enter(loaded.entry, loaded.stack) }
+            }
+            Err(err) => {
+                println!("process {}: failed to load: {:?}", pid, err);
+                exit(ExitStatus::Killed(signal::SIGKILL));
+            }
+        }
+    });
+    Ok(pid)
+}
+
+/// Create a child of the calling process, with a copy-on-write copy of its
+/// address space, that returns to user mode with the registers in `regs`, but 0 in
+/// rax. The child also gets copies of the open files and the FS base.
+/// Returns the child's ID
+pub fn fork(regs: &SyscallStack) -> Result<Pid, MapError> {
+    let parent = current().expect("fork from a kernel thread");
+    let space = {
+        let mut mapper = Mapper::global().critical();
+        let mut frames = physical::frames().critical();
+        let mut refs = physical::refcounts().critical();
+        mapper.clone_user(&mut *frames, &mut *refs)?
+    };
+    let pid = Processes::global()
+        .critical()
+        .fork(parent, space)
+        .expect("forking process not in the table");
+
+    let mut regs = *regs;
+    regs.scratch.rax = 0;
+    let fs_base = context::fs_base();
+    thread::spawn(move || {
+        start(pid, space);
+        context::set_fs_base(fs_base);
+        unsafe { usermode::resume(&regs) }
+    });
+    Ok(pid)
+}
+
+/// Replace the program that the calling process runs with the executable
+/// `image`, named `name`. Returns the entry point and stack pointer to
+/// enter user mode with. Once the image has been checked the old program
+/// is unmapped, so if the new one then fails to load, the process is killed.
+/// Open files stay open
+pub fn exec(
+    name: &str,
+    image: &'static [u8],
+    args: &[&[u8]],
+    env: &[&[u8]],
+) -> Result<(usize, usize), ExecError> {
+    loader::parse(image)?;
+    unsafe {
+        let mut mapper = Mapper::global().critical();
+        let mut frames = physical::frames().critical();
+        let mut refs = physical::refcounts().critical();
+        mapper.clear_user(&mut *frames, &mut *refs);
+    }
+
+    context::set_fs_base(0);
+    context::reset_fpu();
+
+    let pid = current();
+    match loader::load(image, args, env) {
+        Ok(loaded) => {
+            with_current(|process| {
+                process.name = String::from(name);
+                process.memory = Memory::new(loaded.brk);
+            });
+            Ok((loaded.entry, loaded.stack))
+        }
+        Err(err) => {
+            println!("process {:?}: failed to load {}: {:?}", pid, name, err);
+            exit(ExitStatus::Killed(signal::SIGKILL));
+        }
+    }
+}
+
+/// Wait for a child of the calling process to exit, which must be `pid` if
+/// that is given, and reap it. If `block` is true, blocks while the
+/// children are still running
+pub fn wait(pid: Option<Pid>, block: bool) -> Wait {
+    let parent = match current() {
+        Some(parent) => parent,
+        None => return Wait::NoChildren,
+    };
+    let mut result = Wait::Running;
+    exited().wait_while(|| {
+        result = Processes::global().critical().reap(parent, pid);
+        block && result == Wait::Running
+    });
+    result
+}
+
+/// End the calling process with `status`, freeing its address space. It
+/// remains a zombie until its parent reaps it
+pub fn exit(status: ExitStatus) -> ! {
+    let pid = current();
+    if let Some(pid) = pid {
+        if pid == INIT {
+            panic!("init exited: {:?}", status);
+        }
+        let space = Processes::global()
+            .critical()
+            .table
+            .get_mut(&pid)
+            .and_then(|process| process.space.take());
+        if let Some(space) = space {
+            unsafe {
+                let mut mapper = Mapper::global().critical();
+                let mut frames = physical::frames().critical();
+                let mut refs = physical::refcounts().critical();
+                mapper.clear_user(&mut *frames, &mut *refs);
+            }
+            unsafe { thread::set_space(None) };
+            physical::frames().critical().deallocate(space);
+        }
+        Processes::global().critical().exited(pid, status);
+        exited().notify_all();
+    }
+    thread::exit()
+}
+
+/// Kill the calling process, whose user code caused the exception `what`
+/// at `rip`. Called from exception handlers
+pub fn fault(what: &str, rip: usize) -> ! {
+    kill(what, rip, None)
+}
+
+/// Kill the calling process, whose user code at `rip` made an access to
+/// `addr` that caused a page fault which couldn't be resolved
+pub fn page_fault(rip: usize, addr: usize) -> ! {
+    kill("page fault", rip, Some(addr))
+}
+
+fn kill(what: &str, rip: usize, addr: Option<usize>) -> ! {
+    // The exception arrived from ring 3, so the kernel was not nested in
+    // anything, and the handler will never return
+    unsafe { Nesting::new().restore() };
+    {
+        let processes = Processes::global().critical();
+        match (processes.by_thread(thread::current()), addr) {
+            (Some(process), Some(addr)) => println!(
+                "process {} ({}): {} at {:#X} accessing {:#X}, killed",
+                process.pid, process.name, what, rip, addr
+            ),
+            (Some(process), None) => println!(
+                "process {} ({}): {} at {:#X}, killed",
+                process.pid, process.name, what, rip
+            ),
+            (None, Some(addr)) => println!(
+                "{} at {:#X} accessing {:#X} in ring 3, killed",
+                what, rip, addr
+            ),
+            (None, None) => println!("{} at {:#X} in ring 3, killed", what, rip),
+        }
+    }
+    let signal = match what {
+        "divide_by_zero" | "fpu" | "simd" => signal::SIGFPE,
+        "invalid_opcode" => signal::SIGILL,
+        "debug" | "breakpoint" => signal::SIGTRAP,
+        _ => signal::SIGSEGV,
+    };
+    exit(ExitStatus::Killed(signal))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn status() {
+        assert_eq!(ExitStatus::Exited(3).wait_status(), 0x300);
+        assert_eq!(ExitStatus::Killed(signal::SIGSEGV).wait_status(), 11);
+    }
+
+    #[test]
+    fn reaping() {
+        let mut processes = Processes::default();
+        let init = processes.insert(KERNEL, String::from("init"), None);
+        assert_eq!(init, INIT);
+        let parent = processes.insert(init, String::from("sh"), None);
+        let child = processes.insert(parent, String::from("ls"), None);
+        let other = processes.insert(parent, String::from("cat"), None);
+
+        assert_eq!(processes.reap(parent, None), Wait::Running);
+        assert_eq!(processes.reap(parent, Some(init)), Wait::NoChildren);
+        assert_eq!(processes.reap(child, None), Wait::NoChildren);
+
+        processes.exited(child, ExitStatus::Exited(0));
+        assert_eq!(processes.reap(parent, Some(other)), Wait::Running);
+        assert_eq!(
+            processes.reap(parent, None),
+            Wait::Exited(child, ExitStatus::Exited(0))
+        );
+        assert!(processes.get(child).is_none());
+
+        // Orphans are adopted by init, even once they have exited
+        processes.exited(other, ExitStatus::Killed(signal::SIGSEGV));
+        processes.exited(parent, ExitStatus::Exited(1));
+        assert_eq!(processes.get(other).map(|p| p.parent), Some(INIT));
+        assert_eq!(
+            processes.reap(INIT, Some(other)),
+            Wait::Exited(other, ExitStatus::Killed(signal::SIGSEGV))
+        );
+        assert_eq!(
+            processes.reap(INIT, None),
+            Wait::Exited(parent, ExitStatus::Exited(1))
+        );
+        assert_eq!(processes.reap(INIT, None), Wait::NoChildren);
+
+        // Children inherit the parent's files and memory layout
+        let space = Frame::containing(0x1000);
+        let files = &mut processes.table.get_mut(&init).unwrap().files;
+        assert_eq!(files.close(0), Some(crate::fs::File::Console));
+        let child = processes.fork(init, space).unwrap();
+        let child = processes.get(child).unwrap();
+        assert_eq!(child.parent, init);
+        assert_eq!(child.name, "init");
+        assert_eq!(child.space, Some(space));
+        assert_eq!(child.files.get(0), None);
+        assert_eq!(child.files.get(1), Some(&crate::fs::File::Console));
+        assert_eq!(processes.fork(parent, space), None);
+
+        // Nothing waits for processes that the kernel started
+        processes.exited(init, ExitStatus::Exited(0));
+        assert!(processes.get(init).is_none());
+    }
+}
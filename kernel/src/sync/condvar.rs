@@ -0,0 +1,81 @@
+use super::sleep_mutex::{SleepMutex, SleepMutexGuard};
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+/// A condition variable, used with a [`SleepMutex`] to wait until the data
+/// it protects is in some state. As with any condition variable, waiters
+/// should check the state again in a loop once they wake
+#[derive(Default)]
+pub struct Condvar {
+    /// Incremented by every notification, so that a waiter can tell whether
+    /// one arrived between unlocking the mutex and blocking
+    generation: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    pub fn new() -> Condvar {
+        Condvar::default()
+    }
+
+    /// Unlock `guard` and block until notified, then lock it again
+    pub fn wait<'a, T: ?Sized>(&self, guard: SleepMutexGuard<'a, T>) -> SleepMutexGuard<'a, T> {
+        let mutex = SleepMutexGuard::mutex(&guard);
+        let generation = self.generation.load(Ordering::SeqCst);
+        drop(guard);
+        self.queue
+            .wait_while(|| self.generation.load(Ordering::SeqCst) == generation);
+        mutex.lock()
+    }
+
+    /// Like [`Condvar::wait`], but gives up waiting after `timeout`. The
+    /// mutex is always locked again, and the returned flag is false if the
+    /// wait timed out
+    pub fn wait_timeout<'a, T: ?Sized>(
+        &self,
+        guard: SleepMutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (SleepMutexGuard<'a, T>, bool) {
+        let mutex: &'a SleepMutex<T> = SleepMutexGuard::mutex(&guard);
+        let generation = self.generation.load(Ordering::SeqCst);
+        drop(guard);
+        let notified = self.queue.wait_while_timeout(timeout, || {
+            self.generation.load(Ordering::SeqCst) == generation
+        });
+        (mutex.lock(), notified)
+    }
+
+    /// Wake one waiting thread
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.queue.notify_one();
+    }
+
+    /// Wake every waiting thread
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.queue.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn notified_before_wait() {
+        let mutex = SleepMutex::new(false);
+        let condvar = Condvar::new();
+        condvar.notify_all();
+        *mutex.lock() = true;
+        condvar.notify_one();
+
+        let guard = mutex.lock();
+        assert!(*guard);
+        assert_eq!(condvar.generation.load(Ordering::SeqCst), 2);
+        assert!(condvar.queue.is_empty());
+        drop(guard);
+        assert!(!mutex.is_locked());
+    }
+}
@@ -28,6 +28,15 @@ impl<T> Once<T> {
         }
     }
 
+    /// Return a reference to the inner value if the [`Once`] has already
+    /// been initialized, without blocking
+    pub fn try_get<'a>(&'a self) -> Option<&'a T> {
+        match self.state.load(Ordering::SeqCst) {
+            FINISH => Some(unsafe { self.get() }),
+            _ => None,
+        }
+    }
+
     pub fn call_once<'a, F: FnOnce() -> T>(&'a self, func: F) -> &'a T {
         let mut state = self.state.load(Ordering::SeqCst);
         if state == EMPTY {
@@ -1,10 +1,27 @@
 //! Module providing low-level synchronization primitives for use within
-//! the operating system kernel
+//! the operating system kernel. [`Mutex`] spins, and may be used anywhere,
+//! although a lock shared with interrupt handlers must always be taken with
+//! [`Mutex::critical`]; the blocking primitives put the waiting thread to
+//! sleep, and may only be used from thread context
+mod condvar;
 mod init;
 mod mutex;
+mod rwlock;
+mod semaphore;
+mod sleep_mutex;
+mod ticket;
+mod wait;
 
+pub use condvar::Condvar;
 pub use init::Once;
-pub use mutex::{Mutex, MutexGuard};
+pub use mutex::{held, CriticalMutexGuard, Mutex, MutexGuard};
+pub use rwlock::{
+    CriticalRwLockReadGuard, CriticalRwLockWriteGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+pub use semaphore::Semaphore;
+pub use sleep_mutex::{SleepMutex, SleepMutexGuard};
+pub use ticket::{CriticalTicketLockGuard, TicketLock, TicketLockGuard};
+pub use wait::WaitQueue;
 
 /// Trait that automatically generates a globl variable wrapping a struct
 /// behind a [`Once<Mutex<T>>`], along with an associated function for the
@@ -17,9 +34,50 @@ pub trait Global {
     fn global<'a>() -> &'a Mutex<Self>;
 }
 
-/// Implement [`Global`] for `T`
+/// Like [`Global`], for a struct wrapped in an [`RwLock`]. Generated by
+/// `global!(T => RwLock)`
+pub trait GlobalRwLock {
+    fn global<'a>() -> &'a RwLock<Self>;
+}
+
+/// Like [`Global`], for a struct wrapped in a [`TicketLock`]. Generated by
+/// `global!(T => TicketLock)`
+pub trait GlobalTicketLock {
+    fn global<'a>() -> &'a TicketLock<Self>;
+}
+
+/// Implement [`Global`] for `T`. `global!(T => RwLock)` and
+/// `global!(T => TicketLock)` wrap `T` in the named lock instead,
+/// implementing [`GlobalRwLock`] or [`GlobalTicketLock`]
 #[macro_export]
 macro_rules! global {
+    ($T:ty => RwLock) => {
+        global!(@lock $T, GlobalRwLock, RwLock, Default::default());
+    };
+    ($T:ty => RwLock, $func:block) => {
+        global!(@lock $T, GlobalRwLock, RwLock, $func);
+    };
+    ($T:ty => TicketLock) => {
+        global!(@lock $T, GlobalTicketLock, TicketLock, Default::default());
+    };
+    ($T:ty => TicketLock, $func:block) => {
+        global!(@lock $T, GlobalTicketLock, TicketLock, $func);
+    };
+    (@lock $T:ty, $Trait:ident, $Lock:ident, $init:expr) => {
+        static __GLOBAL: $crate::sync::Once<$crate::sync::$Lock<$T>> = $crate::sync::Once::new();
+        impl $crate::sync::$Trait for $T {
+            #[inline(always)]
+            fn global<'a>() -> &'a $crate::sync::$Lock<$T> {
+                #[inline(never)]
+                fn inner() -> $T {
+                    $init
+                }
+
+                __GLOBAL.call_once(|| $crate::sync::$Lock::new(inner()))
+            }
+        }
+    };
+
     ($T:ty) => {
         static __GLOBAL: $crate::sync::Once<$crate::sync::Mutex<$T>> = $crate::sync::Once::new();
         impl $crate::sync::Global for $T
@@ -63,6 +121,28 @@ mod test {
         GlobalTester
     });
 
+    // Each global needs a module of its own
+    mod table {
+        #[derive(Default)]
+        pub struct Table(pub usize);
+        global!(Table => RwLock);
+    }
+
+    mod counter {
+        pub struct Counter(pub usize);
+        global!(Counter => TicketLock, { Counter(5) });
+    }
+
+    #[test]
+    fn other_locks() {
+        use counter::Counter;
+        use table::Table;
+        Table::global().write().0 += 1;
+        assert_eq!(Table::global().read().0, 1);
+        Counter::global().lock().0 += 1;
+        assert_eq!(Counter::global().lock().0, 6);
+    }
+
     #[test]
     fn call_once_only() {
         assert_eq!(ATOMIC.load(Ordering::SeqCst), 0);
@@ -1,7 +1,39 @@
+use crate::arch::interrupts::{self, InterruptGuard, Nesting};
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
 
+/// Return the number of locks of any kind that the calling processor
+/// holds. The scheduler does not preempt a thread while this is non-zero,
+/// since another thread spinning on the same lock would never let it run
+/// again. Locks held on other processors don't matter, as their holders
+/// keep running
+pub fn held() -> usize {
+    Nesting::current().locks
+}
+
+/// Record that a lock of any kind was taken
+pub(super) fn acquired() {
+    Nesting::update(|nesting| nesting.locks += 1);
+}
+
+/// Record that a lock of any kind was released
+pub(super) fn released() {
+    Nesting::update(|nesting| nesting.locks -= 1);
+}
+
+/// Catch a blocking lock taken without disabling interrupts from an
+/// interrupt handler. If the interrupted code holds the same lock, the
+/// handler spins forever, so locks shared with handlers must always be
+/// taken with the `critical` functions
+#[inline(always)]
+pub(super) fn check_context() {
+    debug_assert!(
+        !interrupts::in_interrupt(),
+        "non-critical lock taken in interrupt context"
+    );
+}
+
 /// A synchronization primitive that guarantees mutually exclusive access
 /// to the wrapped data. Only one thread may have access at any given time
 pub struct Mutex<T: ?Sized> {
@@ -18,9 +50,11 @@ pub struct MutexGuard<'a, T: ?Sized + 'a> {
 
 /// A `CriticalMutexGuard` guarantees that maskable hardware interrupts
 /// will not fire while the guard is held. Dropping the `CriticalMutexGuard`
-/// first releases the `Mutex` and then enables hardware interrupts
+/// first releases the `Mutex` and then restores the interrupt flag to what
+/// it was before the lock was taken
 pub struct CriticalMutexGuard<'a, T: ?Sized + 'a> {
     _mutex: &'a Mutex<T>,
+    _interrupts: InterruptGuard,
 }
 
 impl<T> Mutex<T> {
@@ -66,6 +100,7 @@ impl<T: ?Sized> Mutex<T> {
                 spin_loop_hint();
             }
         }
+        acquired();
     }
 
     /// Release the [`Mutex`]
@@ -77,6 +112,7 @@ impl<T: ?Sized> Mutex<T> {
     #[inline(always)]
     unsafe fn release(&self) {
         self.lock.store(false, Ordering::Release);
+        released();
     }
 
     /// Forcefully obtain a mutable reference to the [`Mutex`]'s interior data,
@@ -99,14 +135,17 @@ impl<T: ?Sized> Mutex<T> {
     pub fn try_lock(&self) -> Option<MutexGuard<T>> {
         // If lock was not held we just acquired it
         if self.lock.compare_and_swap(false, true, Ordering::Acquire) == false {
+            acquired();
             Some(MutexGuard { _mutex: self })
         } else {
             None
         }
     }
 
-    /// Block until the [`Mutex`] can be locked
+    /// Block until the [`Mutex`] can be locked. Must not be used from an
+    /// interrupt handler, which should use [`Mutex::critical`]
     pub fn lock(&self) -> MutexGuard<T> {
+        check_context();
         match self.try_lock() {
             Some(guard) => guard,
             None => {
@@ -121,33 +160,30 @@ impl<T: ?Sized> Mutex<T> {
     ///
     /// This funciton will not block
     ///
-    /// # Safety
-    ///
-    /// This function will disable interrupts, and then *enable* hardware
-    /// interrupts if the [`Mutex`]'s lock cannot be obtained.
-    ///
-    /// Hardware interrupts will also be enabled with the
-    /// [`CriticalMutexGuard`] is dropped - so the `critical` functions
-    /// should only be used in contexts where hardware interrupts are
-    /// expected
+    /// Interrupts are disabled while the lock is held. If the [`Mutex`]'s
+    /// lock cannot be obtained, or once the [`CriticalMutexGuard`] is
+    /// dropped, the interrupt flag is restored to its previous state, so
+    /// critical locks can be nested and taken from interrupt handlers
     pub fn try_critical(&self) -> Option<CriticalMutexGuard<T>> {
-        crate::arch::interrupts::disable();
+        let interrupts = InterruptGuard::new();
         if self.lock.compare_and_swap(false, true, Ordering::Acquire) == false {
-            Some(CriticalMutexGuard { _mutex: self })
+            acquired();
+            Some(CriticalMutexGuard {
+                _mutex: self,
+                _interrupts: interrupts,
+            })
         } else {
-            crate::arch::interrupts::enable();
             None
         }
     }
 
-    /// Block until the [`Mutex`] can be locked -
+    /// Disable interrupts and block until the [`Mutex`] can be locked
     pub fn critical(&self) -> CriticalMutexGuard<T> {
-        match self.try_critical() {
-            Some(guard) => guard,
-            None => {
-                self.acquire();
-                CriticalMutexGuard { _mutex: self }
-            }
+        let interrupts = InterruptGuard::new();
+        self.acquire();
+        CriticalMutexGuard {
+            _mutex: self,
+            _interrupts: interrupts,
         }
     }
 }
@@ -207,12 +243,12 @@ impl<'a, T: ?Sized> DerefMut for CriticalMutexGuard<'a, T> {
 
 impl<'a, T: ?Sized> Drop for CriticalMutexGuard<'a, T> {
     #[inline]
-    /// Dropping the `CriticalMutexGuard` releases the lock and enables interrupts
+    /// Dropping the `CriticalMutexGuard` releases the lock, and then the
+    /// [`InterruptGuard`] restores the interrupt flag
     fn drop(&mut self) {
         unsafe {
             self._mutex.release();
         }
-        crate::arch::interrupts::enable();
     }
 }
 
@@ -0,0 +1,279 @@
+use super::mutex::{acquired, check_context, released};
+use crate::arch::interrupts::InterruptGuard;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+/// Set in `state` while a writer holds the lock
+const WRITER: usize = 1 << (core::mem::size_of::<usize>() * 8 - 1);
+
+/// A spinning reader-writer lock, which allows any number of readers or a
+/// single writer at a time. Writers are preferred: once a writer is
+/// waiting, new readers wait until it has had the lock
+pub struct RwLock<T: ?Sized> {
+    /// Number of readers, or [`WRITER`]
+    state: AtomicUsize,
+    /// Number of writers waiting for the lock
+    writers: AtomicUsize,
+    inner: UnsafeCell<T>,
+}
+
+/// Shared access to the data in an [`RwLock`], released when the guard is
+/// dropped
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+/// Exclusive access to the data in an [`RwLock`], released when the guard
+/// is dropped
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+}
+
+/// A [`RwLockReadGuard`] taken with interrupts disabled. Dropping it
+/// releases the lock and then restores the interrupt flag
+pub struct CriticalRwLockReadGuard<'a, T: ?Sized + 'a> {
+    guard: RwLockReadGuard<'a, T>,
+    _interrupts: InterruptGuard,
+}
+
+/// A [`RwLockWriteGuard`] taken with interrupts disabled. Dropping it
+/// releases the lock and then restores the interrupt flag
+pub struct CriticalRwLockWriteGuard<'a, T: ?Sized + 'a> {
+    guard: RwLockWriteGuard<'a, T>,
+    _interrupts: InterruptGuard,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub fn new(data: T) -> RwLock<T> {
+        RwLock {
+            state: AtomicUsize::new(0),
+            writers: AtomicUsize::new(0),
+            inner: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the [`RwLock`], returning the interior data
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Take a shared lock if no writer holds or is waiting for the lock
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        if self.writers.load(Ordering::Relaxed) != 0 {
+            return None;
+        }
+        let state = self.state.load(Ordering::Relaxed);
+        if state & WRITER != 0 {
+            return None;
+        }
+        if self
+            .state
+            .compare_and_swap(state, state + 1, Ordering::Acquire)
+            == state
+        {
+            acquired();
+            Some(RwLockReadGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Spin until a shared lock can be taken. Must not be used from an
+    /// interrupt handler, which should use [`RwLock::critical_read`]
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        check_context();
+        self.spin_read()
+    }
+
+    fn spin_read(&self) -> RwLockReadGuard<T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            spin_loop_hint();
+        }
+    }
+
+    /// Take the exclusive lock if nobody holds the lock
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        if self.state.compare_and_swap(0, WRITER, Ordering::Acquire) == 0 {
+            acquired();
+            Some(RwLockWriteGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Spin until the exclusive lock can be taken. New readers are held off
+    /// while waiting. Must not be used from an interrupt handler, which
+    /// should use [`RwLock::critical_write`]
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        check_context();
+        self.spin_write()
+    }
+
+    fn spin_write(&self) -> RwLockWriteGuard<T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+        self.writers.fetch_add(1, Ordering::Relaxed);
+        let guard = loop {
+            if let Some(guard) = self.try_write() {
+                break guard;
+            }
+            spin_loop_hint();
+        };
+        self.writers.fetch_sub(1, Ordering::Relaxed);
+        guard
+    }
+
+    /// Disable interrupts, then spin until a shared lock can be taken
+    pub fn critical_read(&self) -> CriticalRwLockReadGuard<T> {
+        let interrupts = InterruptGuard::new();
+        CriticalRwLockReadGuard {
+            guard: self.spin_read(),
+            _interrupts: interrupts,
+        }
+    }
+
+    /// Disable interrupts, then spin until the exclusive lock can be taken
+    pub fn critical_write(&self) -> CriticalRwLockWriteGuard<T> {
+        let interrupts = InterruptGuard::new();
+        CriticalRwLockWriteGuard {
+            guard: self.spin_write(),
+            _interrupts: interrupts,
+        }
+    }
+
+    /// Number of readers holding the lock
+    pub fn readers(&self) -> usize {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & WRITER != 0 {
+            0
+        } else {
+            state
+        }
+    }
+
+    /// Forcefully obtain a mutable reference to the interior data,
+    /// regardless of whether the lock is held.
+    ///
+    /// # Safety
+    ///
+    /// As for [`super::Mutex::force`]
+    pub unsafe fn force(&self) -> &mut T {
+        &mut *self.inner.get()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        released();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        released();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for CriticalRwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Deref for CriticalRwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for CriticalRwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_and_exclusive() {
+        let lock = RwLock::new(5);
+        {
+            let a = lock.read();
+            let b = lock.try_read().unwrap();
+            assert_eq!(*a + *b, 10);
+            assert_eq!(lock.readers(), 2);
+            assert!(lock.try_write().is_none());
+        }
+        {
+            let mut w = lock.try_write().unwrap();
+            *w += 1;
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_write().is_none());
+            assert_eq!(lock.readers(), 0);
+        }
+        assert_eq!(*lock.critical_read(), 6);
+        *lock.critical_write() += 1;
+        assert_eq!(lock.into_inner(), 7);
+    }
+
+    #[test]
+    fn writer_preference() {
+        let lock = RwLock::new(());
+        let reader = lock.read();
+        // A waiting writer keeps new readers out
+        lock.writers.fetch_add(1, Ordering::Relaxed);
+        assert!(lock.try_read().is_none());
+        drop(reader);
+        let writer = lock.try_write().unwrap();
+        lock.writers.fetch_sub(1, Ordering::Relaxed);
+        assert!(lock.try_read().is_none());
+        drop(writer);
+        assert!(lock.try_read().is_some());
+    }
+}
@@ -0,0 +1,71 @@
+use super::{Mutex, WaitQueue};
+use core::time::Duration;
+
+/// A counting semaphore. Threads that cannot take a unit block until one
+/// is released
+pub struct Semaphore {
+    count: Mutex<usize>,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    /// Initialize a new [`Semaphore`] holding `count` units
+    pub fn new(count: usize) -> Semaphore {
+        Semaphore {
+            count: Mutex::new(count),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Number of units available
+    pub fn count(&self) -> usize {
+        *self.count.critical()
+    }
+
+    /// Take a unit if one is available, without blocking
+    pub fn try_acquire(&self) -> bool {
+        // `release` takes the count from interrupt handlers, so interrupts
+        // stay disabled while it is locked
+        let mut count = self.count.critical();
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    /// Block until a unit can be taken
+    pub fn acquire(&self) {
+        self.queue.wait_while(|| !self.try_acquire());
+    }
+
+    /// Block until a unit can be taken, or `timeout` passes. Returns false if
+    /// no unit was taken
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.queue
+            .wait_while_timeout(timeout, || !self.try_acquire())
+    }
+
+    /// Return a unit, waking a thread waiting for one
+    pub fn release(&self) {
+        *self.count.critical() += 1;
+        self.queue.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counting() {
+        let sem = Semaphore::new(2);
+        sem.acquire();
+        assert!(sem.try_acquire());
+        assert!(!sem.try_acquire());
+        assert_eq!(sem.count(), 0);
+        sem.release();
+        assert!(sem.acquire_timeout(Duration::from_millis(10)));
+        assert_eq!(sem.count(), 0);
+    }
+}
@@ -0,0 +1,126 @@
+use super::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+/// A mutual exclusion lock that blocks waiting threads instead of spinning,
+/// for data that is held for a long time, such as across disk I/O. Unlike
+/// [`super::Mutex`], it must not be locked from an interrupt handler
+pub struct SleepMutex<T: ?Sized> {
+    lock: AtomicBool,
+    queue: WaitQueue,
+    inner: UnsafeCell<T>,
+}
+
+/// Guarantees exclusive access to the data in a [`SleepMutex`], which is
+/// unlocked when the guard is dropped
+pub struct SleepMutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a SleepMutex<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SleepMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    pub fn new(data: T) -> SleepMutex<T> {
+        SleepMutex {
+            lock: AtomicBool::new(false),
+            queue: WaitQueue::new(),
+            inner: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> SleepMutex<T> {
+    fn acquire(&self) -> bool {
+        !self.lock.compare_and_swap(false, true, Ordering::Acquire)
+    }
+
+    /// Lock the [`SleepMutex`] if it is free, without blocking
+    pub fn try_lock(&self) -> Option<SleepMutexGuard<T>> {
+        if self.acquire() {
+            Some(SleepMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Block until the [`SleepMutex`] can be locked
+    pub fn lock(&self) -> SleepMutexGuard<T> {
+        self.queue.wait_while(|| !self.acquire());
+        SleepMutexGuard { mutex: self }
+    }
+
+    /// Block until the [`SleepMutex`] can be locked, or `timeout` passes
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<SleepMutexGuard<T>> {
+        if self.queue.wait_while_timeout(timeout, || !self.acquire()) {
+            Some(SleepMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.lock.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Default> Default for SleepMutex<T> {
+    fn default() -> SleepMutex<T> {
+        SleepMutex::new(T::default())
+    }
+}
+
+impl<'a, T: ?Sized> SleepMutexGuard<'a, T> {
+    /// The [`SleepMutex`] this guard locks
+    pub fn mutex(guard: &Self) -> &'a SleepMutex<T> {
+        guard.mutex
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SleepMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.inner.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SleepMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.inner.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SleepMutexGuard<'a, T> {
+    /// Unlock the [`SleepMutex`], and wake the thread that has waited for
+    /// it longest
+    fn drop(&mut self) {
+        self.mutex.lock.store(false, Ordering::Release);
+        self.mutex.queue.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn try_lock() {
+        let mutex = SleepMutex::new(1);
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(mutex.is_locked());
+            assert!(mutex.try_lock().is_none());
+        }
+        assert_eq!(*mutex.try_lock().unwrap(), 2);
+        assert!(mutex.lock_timeout(Duration::from_millis(10)).is_some());
+        assert_eq!(mutex.into_inner(), 2);
+    }
+}
@@ -0,0 +1,191 @@
+use super::mutex::{acquired, check_context, released};
+use crate::arch::interrupts::InterruptGuard;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+/// A fair spinning lock. Each thread takes a ticket, and the lock is handed
+/// out in ticket order, so that no waiter can be starved by others that
+/// happen to win the race for the cache line
+pub struct TicketLock<T: ?Sized> {
+    /// Ticket given to the next thread that tries to lock
+    next: AtomicUsize,
+    /// Ticket of the thread holding the lock
+    serving: AtomicUsize,
+    inner: UnsafeCell<T>,
+}
+
+/// Exclusive access to the data in a [`TicketLock`], released when the
+/// guard is dropped
+pub struct TicketLockGuard<'a, T: ?Sized + 'a> {
+    lock: &'a TicketLock<T>,
+}
+
+/// A [`TicketLockGuard`] taken with interrupts disabled. Dropping it
+/// releases the lock and then restores the interrupt flag
+pub struct CriticalTicketLockGuard<'a, T: ?Sized + 'a> {
+    guard: TicketLockGuard<'a, T>,
+    _interrupts: InterruptGuard,
+}
+
+unsafe impl<T: ?Sized + Send> Send for TicketLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    pub fn new(data: T) -> TicketLock<T> {
+        TicketLock {
+            next: AtomicUsize::new(0),
+            serving: AtomicUsize::new(0),
+            inner: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consume the [`TicketLock`], returning the interior data
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketLock<T> {
+    /// Lock the [`TicketLock`] if nobody holds it or is waiting for it
+    pub fn try_lock(&self) -> Option<TicketLockGuard<T>> {
+        let serving = self.serving.load(Ordering::Acquire);
+        if self
+            .next
+            .compare_and_swap(serving, serving.wrapping_add(1), Ordering::Acquire)
+            == serving
+        {
+            acquired();
+            Some(TicketLockGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    /// Take a ticket, and spin until it is served. Must not be used from an
+    /// interrupt handler, which should use [`TicketLock::critical`]
+    pub fn lock(&self) -> TicketLockGuard<T> {
+        check_context();
+        self.wait()
+    }
+
+    fn wait(&self) -> TicketLockGuard<T> {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            spin_loop_hint();
+        }
+        acquired();
+        TicketLockGuard { lock: self }
+    }
+
+    /// Disable interrupts, then take a ticket and spin until it is served
+    pub fn critical(&self) -> CriticalTicketLockGuard<T> {
+        let interrupts = InterruptGuard::new();
+        CriticalTicketLockGuard {
+            guard: self.wait(),
+            _interrupts: interrupts,
+        }
+    }
+
+    /// Number of threads holding or waiting for the lock
+    pub fn queued(&self) -> usize {
+        let serving = self.serving.load(Ordering::Relaxed);
+        self.next.load(Ordering::Relaxed).wrapping_sub(serving)
+    }
+
+    /// Forcefully obtain a mutable reference to the interior data,
+    /// regardless of whether the lock is held.
+    ///
+    /// # Safety
+    ///
+    /// As for [`super::Mutex::force`]
+    pub unsafe fn force(&self) -> &mut T {
+        &mut *self.inner.get()
+    }
+}
+
+impl<T: Default> Default for TicketLock<T> {
+    fn default() -> TicketLock<T> {
+        TicketLock::new(T::default())
+    }
+}
+
+impl<'a, T: ?Sized> Deref for TicketLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for TicketLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for TicketLockGuard<'a, T> {
+    /// Serve the next ticket
+    fn drop(&mut self) {
+        self.lock.serving.fetch_add(1, Ordering::Release);
+        released();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for CriticalTicketLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for CriticalTicketLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn tickets() {
+        let lock = TicketLock::new(0);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert_eq!(lock.queued(), 1);
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(lock.queued(), 0);
+        *lock.critical() += 1;
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn exclusion() {
+        let lock = Arc::new(TicketLock::new(0usize));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        let mut guard = lock.lock();
+                        // Non-atomic read-modify-write, which loses
+                        // increments without mutual exclusion
+                        let value = *guard;
+                        *guard = value + 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.lock(), 40_000);
+    }
+}
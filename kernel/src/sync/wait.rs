@@ -0,0 +1,155 @@
+use super::Mutex;
+use crate::thread::{self, Tid};
+use alloc::collections::VecDeque;
+use core::time::Duration;
+
+/// A queue of threads waiting for something to happen, such as a lock
+/// being released. Waiting threads are blocked rather than spinning, so a
+/// [`WaitQueue`] must not be waited on from an interrupt handler
+#[derive(Default)]
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Tid>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue::default()
+    }
+
+    /// Block until woken by [`WaitQueue::notify_one`] or
+    /// [`WaitQueue::notify_all`]
+    pub fn wait(&self) {
+        self.block(None, || true);
+    }
+
+    /// Block while `condition` returns true, checking it again each time the
+    /// queue is notified. Whatever makes `condition` false must then notify
+    /// the queue. `condition` may have side effects, such as taking a lock,
+    /// only when it returns false
+    pub fn wait_while<F: FnMut() -> bool>(&self, mut condition: F) {
+        while condition() {
+            if let Wait::Done = self.block(None, &mut condition) {
+                return;
+            }
+        }
+    }
+
+    /// Like [`WaitQueue::wait_while`], but gives up after `timeout`.
+    /// Returns false if `condition` was still true when it did
+    pub fn wait_while_timeout<F: FnMut() -> bool>(
+        &self,
+        timeout: Duration,
+        mut condition: F,
+    ) -> bool {
+        let deadline = thread::deadline(timeout);
+        while condition() {
+            match self.block(Some(deadline), &mut condition) {
+                Wait::Done => return true,
+                Wait::Notified => (),
+                Wait::TimedOut => return !condition(),
+            }
+        }
+        true
+    }
+
+    /// Block the calling thread until it is notified, or tick `deadline`
+    /// passes. `condition` is checked again once the thread is queued, so
+    /// that a notification sent after the caller last checked is not missed
+    fn block<F: FnMut() -> bool>(&self, deadline: Option<u64>, mut condition: F) -> Wait {
+        let mut queued = None;
+        let mut done = false;
+        let notified = thread::block(deadline, |id| {
+            // Interrupts are already disabled
+            self.waiters.lock().push_back(id);
+            if condition() {
+                queued = Some(id);
+                return true;
+            }
+            remove(&mut self.waiters.lock(), id);
+            done = true;
+            false
+        });
+        match queued {
+            _ if done => Wait::Done,
+            // A notification may have raced with the deadline, in which case
+            // it already took the thread off the queue
+            Some(id) if !notified && remove(&mut self.waiters.critical(), id) => Wait::TimedOut,
+            _ => Wait::Notified,
+        }
+    }
+
+    /// Wake the thread that has been waiting longest, returning false if
+    /// there was none
+    pub fn notify_one(&self) -> bool {
+        let waiter = self.waiters.critical().pop_front();
+        match waiter {
+            Some(id) => {
+                thread::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake every waiting thread, returning how many there were
+    pub fn notify_all(&self) -> usize {
+        let waiters = core::mem::replace(&mut *self.waiters.critical(), VecDeque::new());
+        for &id in waiters.iter() {
+            thread::wake(id);
+        }
+        waiters.len()
+    }
+
+    /// Number of threads waiting
+    pub fn len(&self) -> usize {
+        self.waiters.critical().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// How a blocked thread got going again
+enum Wait {
+    /// The condition became false before it needed to block
+    Done,
+    Notified,
+    TimedOut,
+}
+
+/// Take `id` off `waiters`, returning false if it was not there
+fn remove(waiters: &mut VecDeque<Tid>, id: Tid) -> bool {
+    match waiters.iter().position(|&waiter| waiter == id) {
+        Some(index) => {
+            waiters.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queue() {
+        let queue = WaitQueue::new();
+        assert!(!queue.notify_one());
+        assert_eq!(queue.notify_all(), 0);
+
+        // Nothing blocks while the condition is already false
+        queue.wait_while(|| false);
+        assert!(queue.wait_while_timeout(Duration::from_secs(1), || false));
+
+        queue.waiters.lock().extend(&[7, 8, 9]);
+        assert_eq!(queue.len(), 3);
+        assert!(remove(&mut queue.waiters.lock(), 8));
+        assert!(!remove(&mut queue.waiters.lock(), 8));
+        assert!(queue.notify_one());
+        assert_eq!(*queue.waiters.lock(), [9]);
+        assert_eq!(queue.notify_all(), 1);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+//! File system calls
+//!
+//! Processes start with the console open as standard input, output and
+//! error, and can open the files built into the kernel for reading. Console
+//! output goes to the screen, and is copied to the first serial port so
+//! that it can be captured from outside the machine.
+use super::user::{self, UserPtr};
+use super::{Args, Errno, Result};
+use crate::arch::syscall::SyscallStack;
+use crate::fs::{self, File, PATH_MAX};
+use crate::io::{Io, Serial};
+use crate::paging::PAGE_SIZE;
+use crate::prelude::*;
+use crate::process;
+use crate::term::{self, Terminal};
+
+/// Access mode bits of the `open` flags
+const O_ACCMODE: u32 = 0o3;
+const O_RDONLY: u32 = 0o0;
+const O_CREAT: u32 = 0o100;
+const O_DIRECTORY: u32 = 0o200000;
+
+/// `openat` directory descriptor standing for the working directory
+const AT_FDCWD: i32 = -100;
+
+/// `ioctl` request for the terminal's size
+const TIOCGWINSZ: u32 = 0x5413;
+
+/// Maximum number of buffers that `writev` takes
+const IOV_MAX: usize = 1024;
+
+/// `struct iovec`
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct IoVec {
+    base: usize,
+    len: usize,
+}
+
+/// `struct winsize`
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct WinSize {
+    rows: u16,
+    columns: u16,
+    x_pixels: u16,
+    y_pixels: u16,
+}
+
+/// Return a copy of the calling process's file `fd`. Files are copied out
+/// so that the process table isn't locked while user memory is accessed
+fn file(fd: i32) -> core::result::Result<File, Errno> {
+    process::with_current(|process| process.files.get(fd).cloned())
+        .and_then(|file| file)
+        .ok_or(Errno::EBADF)
+}
+
+/// Write `count` bytes from `buf` to `file`
+fn write_file(file: &File, buf: usize, count: usize) -> Result {
+    match file {
+        File::Console => (),
+        File::Data { .. } => return Err(Errno::EBADF),
+    }
+
+    // Copy a page at a time, rather than allocating for all of it. The
+    // bytes are passed on as they are, since a character may be split
+    // across pages, and programs may write bytes that aren't UTF-8
+    let mut written = 0;
+    while written < count {
+        let len = (count - written).min(PAGE_SIZE);
+        let data = user::read_bytes(buf + written, len)?;
+        {
+            let mut terminal = Terminal::global().critical();
+            data.iter().for_each(|&byte| terminal.write_byte(byte));
+        }
+        let mut serial = Serial::global().critical();
+        data.iter().for_each(|&byte| serial.write(byte));
+        written += len;
+    }
+    Ok(written)
+}
+
+/// `read(fd, buf, count)`. The console has no input yet, so reading it
+/// returns end of file
+pub fn read(args: &Args, _: &mut SyscallStack) -> Result {
+    let fd: i32 = args.get(0)?;
+    let buf: usize = args.get(1)?;
+    let count: usize = args.get(2)?;
+    let mut file = file(fd)?;
+    let data = file.read(count);
+    user::write_bytes(buf, data)?;
+    process::with_current(|process| process.files.update(fd, file));
+    Ok(data.len())
+}
+
+/// `write(fd, buf, count)`
+pub fn write(args: &Args, _: &mut SyscallStack) -> Result {
+    let fd: i32 = args.get(0)?;
+    let buf: usize = args.get(1)?;
+    let count: usize = args.get(2)?;
+    write_file(&file(fd)?, buf, count)
+}
+
+/// `writev(fd, iov, iovcnt)`. If a buffer can't be written after others
+/// have been, the call returns how much was written
+pub fn writev(args: &Args, _: &mut SyscallStack) -> Result {
+    let fd: i32 = args.get(0)?;
+    let iov: UserPtr<IoVec> = args.get(1)?;
+    let count: usize = args.get(2)?;
+    if count > IOV_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let file = file(fd)?;
+
+    let mut written = 0;
+    for index in 0..count {
+        let result = iov
+            .add(index)
+            .and_then(|vec| vec.read())
+            .and_then(|vec| write_file(&file, vec.base, vec.len));
+        match result {
+            Ok(len) => written += len,
+            Err(_) if written > 0 => break,
+            Err(errno) => return Err(errno),
+        }
+    }
+    Ok(written)
+}
+
+/// Open the file at `path`, relative to the directory `dirfd`, with
+/// `flags`. Returns the new descriptor
+fn open_at(dirfd: i32, path: usize, flags: u32) -> Result {
+    let path = user::read_str(path, PATH_MAX)?;
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    // No directories can be opened, so relative paths can only be relative
+    // to the working directory
+    if path[0] != b'/' && dirfd != AT_FDCWD {
+        return Err(file(dirfd).map_or(Errno::EBADF, |_| Errno::ENOTDIR));
+    }
+
+    let data = fs::lookup(&fs::absolute(path));
+    if flags & O_ACCMODE != O_RDONLY || (flags & O_CREAT != 0 && data.is_none()) {
+        return Err(Errno::EROFS);
+    }
+    let data = data.ok_or(Errno::ENOENT)?;
+    if flags & O_DIRECTORY != 0 {
+        return Err(Errno::ENOTDIR);
+    }
+
+    let file = File::Data { data, offset: 0 };
+    let fd = process::with_current(|process| process.files.open(file)).ok_or(Errno::ESRCH)?;
+    fd.map(|fd| fd as usize).ok_or(Errno::EMFILE)
+}
+
+/// `open(path, flags, mode)`. Files can only be opened for reading
+pub fn open(args: &Args, _: &mut SyscallStack) -> Result {
+    open_at(AT_FDCWD, args.get(0)?, args.get(1)?)
+}
+
+/// `openat(dirfd, path, flags, mode)`
+pub fn openat(args: &Args, _: &mut SyscallStack) -> Result {
+    open_at(args.get(0)?, args.get(1)?, args.get(2)?)
+}
+
+/// `close(fd)`
+pub fn close(args: &Args, _: &mut SyscallStack) -> Result {
+    let fd: i32 = args.get(0)?;
+    process::with_current(|process| process.files.close(fd))
+        .and_then(|file| file)
+        .map(|_| 0)
+        .ok_or(Errno::EBADF)
+}
+
+/// `ioctl(fd, request, arg)`. The only request is `TIOCGWINSZ`, for the
+/// console's size
+pub fn ioctl(args: &Args, _: &mut SyscallStack) -> Result {
+    let fd: i32 = args.get(0)?;
+    let request: u32 = args.get(1)?;
+    match file(fd)? {
+        File::Console if request == TIOCGWINSZ => {
+            let size: UserPtr<WinSize> = args.get(2)?;
+            size.write(WinSize {
+                rows: term::ROWS as u16,
+                columns: term::COLUMNS as u16,
+                x_pixels: 0,
+                y_pixels: 0,
+            })?;
+            Ok(0)
+        }
+        File::Console => Err(Errno::EINVAL),
+        File::Data { .. } => Err(Errno::ENOTTY),
+    }
+}
@@ -0,0 +1,133 @@
+//! Memory management system calls
+use super::{Args, Errno, Result};
+use crate::arch::syscall::SyscallStack;
+use crate::paging::{self, EntryFlags, PAGE_SIZE};
+use crate::process::loader::MMAP_BOTTOM;
+use crate::process::{self, memory};
+
+/// `mmap` protection bits
+const PROT_WRITE: u32 = 0x2;
+const PROT_EXEC: u32 = 0x4;
+
+/// `mmap` flags
+const MAP_SHARED: u32 = 0x01;
+const MAP_PRIVATE: u32 = 0x02;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+/// Page flags for memory mapped with the protection bits `prot`. Pages
+/// can't be writable without being readable, so every mapping is readable
+fn page_flags(prot: u32) -> EntryFlags {
+    let mut flags = EntryFlags::USER;
+    if prot & PROT_WRITE != 0 {
+        flags.insert(EntryFlags::WRITABLE);
+    }
+    if prot & PROT_EXEC == 0 {
+        flags.insert(paging::no_execute());
+    }
+    flags
+}
+
+/// `brk(addr)`. Moves the end of the heap to `addr`, and returns the new
+/// end. If it can't be moved there, returns the current end instead, as
+/// Linux does. An `addr` of 0 just asks for the current end
+pub fn brk(args: &Args, _: &mut SyscallStack) -> Result {
+    let addr: usize = args.get(0)?;
+    let current = process::with_current(|process| process.memory).ok_or(Errno::ESRCH)?;
+    if addr < current.heap_start || addr > MMAP_BOTTOM {
+        return Ok(current.brk);
+    }
+
+    // Neither can overflow, being below MMAP_BOTTOM
+    let old_end = memory::page_align(current.brk).unwrap();
+    let new_end = memory::page_align(addr).unwrap();
+    if new_end > old_end {
+        let flags = EntryFlags::USER | EntryFlags::WRITABLE | paging::no_execute();
+        if memory::map_zeroed(old_end, new_end - old_end, flags).is_err() {
+            return Ok(current.brk);
+        }
+    } else if new_end < old_end {
+        memory::unmap(new_end, old_end - new_end);
+    }
+    process::with_current(|process| process.memory.brk = addr);
+    Ok(addr)
+}
+
+/// `mmap(addr, length, prot, flags, fd, offset)`. Only private anonymous
+/// mappings are supported, there being no files to map. Without
+/// `MAP_FIXED`, `addr` is ignored. A mapping with `PROT_NONE` reserves its
+/// addresses, but nothing is mapped there
+pub fn mmap(args: &Args, _: &mut SyscallStack) -> Result {
+    let addr: usize = args.get(0)?;
+    let len: usize = args.get(1)?;
+    let prot: u32 = args.get(2)?;
+    let flags: u32 = args.get(3)?;
+    if len == 0 || flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE {
+        return Err(Errno::EINVAL);
+    }
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno::ENODEV);
+    }
+    let len = memory::page_align(len).ok_or(Errno::ENOMEM)?;
+
+    let start = if flags & MAP_FIXED != 0 {
+        if addr % PAGE_SIZE != 0 || !memory::in_user(addr, len) {
+            return Err(Errno::EINVAL);
+        }
+        // A fixed mapping replaces whatever was there
+        memory::unmap(addr, len);
+        addr
+    } else {
+        process::with_current(|process| process.memory.place(len))
+            .ok_or(Errno::ESRCH)?
+            .ok_or(Errno::ENOMEM)?
+    };
+
+    if prot != 0 {
+        memory::map_zeroed(start, len, page_flags(prot)).map_err(|_| Errno::ENOMEM)?;
+    }
+    Ok(start)
+}
+
+/// `munmap(addr, length)`. Parts of the range that aren't mapped are
+/// skipped
+pub fn munmap(args: &Args, _: &mut SyscallStack) -> Result {
+    let addr: usize = args.get(0)?;
+    let len: usize = args.get(1)?;
+    let len = memory::page_align(len).ok_or(Errno::EINVAL)?;
+    if len == 0 || addr % PAGE_SIZE != 0 || !memory::in_user(addr, len) {
+        return Err(Errno::EINVAL);
+    }
+    memory::unmap(addr, len);
+    Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protection() {
+        assert_eq!(
+            page_flags(0x1 | PROT_WRITE),
+            EntryFlags::USER | EntryFlags::WRITABLE | paging::no_execute()
+        );
+        assert_eq!(page_flags(0x1 | PROT_EXEC), EntryFlags::USER);
+
+        // Only private anonymous mappings
+        let mut regs = SyscallStack::default();
+        let args = Args::new(0, [0, PAGE_SIZE, 0x3, MAP_PRIVATE as usize, 3, 0]);
+        assert_eq!(mmap(&args, &mut regs), Err(Errno::ENODEV));
+        let shared = (MAP_SHARED | MAP_ANONYMOUS) as usize;
+        let args = Args::new(0, [0, PAGE_SIZE, 0x3, shared, 0, 0]);
+        assert_eq!(mmap(&args, &mut regs), Err(Errno::EINVAL));
+        let fixed = (MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED) as usize;
+        let args = Args::new(0, [0x1001, PAGE_SIZE, 0x3, fixed, 0, 0]);
+        assert_eq!(mmap(&args, &mut regs), Err(Errno::EINVAL));
+
+        let args = Args::new(0, [0x1000, 0, 0, 0, 0, 0]);
+        assert_eq!(munmap(&args, &mut regs), Err(Errno::EINVAL));
+        let args = Args::new(0, [0, PAGE_SIZE, 0, 0, 0, 0]);
+        assert_eq!(munmap(&args, &mut regs), Err(Errno::EINVAL));
+    }
+}
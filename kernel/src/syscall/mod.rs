@@ -0,0 +1,332 @@
+//! System calls
+//!
+//! Calls are numbered as on Linux x86-64, take up to six arguments, and
+//! return a non-negative value or a negated [`Errno`], again as Linux does.
+//! The architecture's entry points collect the number and arguments into
+//! [`Args`] and pass them to [`dispatch`], which looks the handler up in
+//! [`TABLE`]. Handlers decode each argument into the type they expect with
+//! [`Args::get`], so that invalid arguments fail with an error before the
+//! handler does anything. They also get the registers that user code will
+//! return with, which `fork` copies and `execve` replaces.
+//!
+//! The calls implemented are enough for statically linked programs built
+//! against musl to start up, use stdio and `malloc`, and read files.
+use crate::arch::syscall::SyscallStack;
+
+pub mod fs;
+pub mod mm;
+pub mod proc;
+pub mod time;
+pub mod user;
+
+/// System call numbers
+pub mod nr {
+    pub const READ: usize = 0;
+    pub const WRITE: usize = 1;
+    pub const OPEN: usize = 2;
+    pub const CLOSE: usize = 3;
+    pub const MMAP: usize = 9;
+    pub const MUNMAP: usize = 11;
+    pub const BRK: usize = 12;
+    pub const IOCTL: usize = 16;
+    pub const WRITEV: usize = 20;
+    pub const SCHED_YIELD: usize = 24;
+    pub const NANOSLEEP: usize = 35;
+    pub const GETPID: usize = 39;
+    pub const FORK: usize = 57;
+    pub const EXECVE: usize = 59;
+    pub const EXIT: usize = 60;
+    pub const WAIT4: usize = 61;
+    pub const GETPPID: usize = 110;
+    pub const ARCH_PRCTL: usize = 158;
+    pub const SET_TID_ADDRESS: usize = 218;
+    pub const CLOCK_GETTIME: usize = 228;
+    pub const EXIT_GROUP: usize = 231;
+    pub const OPENAT: usize = 257;
+}
+
+/// Error numbers, with Linux's values
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Errno {
+    /// Operation not permitted
+    EPERM = 1,
+    /// No such file or directory
+    ENOENT = 2,
+    /// No such process
+    ESRCH = 3,
+    /// Interrupted system call
+    EINTR = 4,
+    /// I/O error
+    EIO = 5,
+    /// Argument list too long
+    E2BIG = 7,
+    /// Exec format error
+    ENOEXEC = 8,
+    /// Bad file descriptor
+    EBADF = 9,
+    /// No child processes
+    ECHILD = 10,
+    /// Try again
+    EAGAIN = 11,
+    /// Out of memory
+    ENOMEM = 12,
+    /// Bad address
+    EFAULT = 14,
+    /// No such device
+    ENODEV = 19,
+    /// Not a directory
+    ENOTDIR = 20,
+    /// Invalid argument
+    EINVAL = 22,
+    /// Too many open files
+    EMFILE = 24,
+    /// Not a terminal
+    ENOTTY = 25,
+    /// Read-only file system
+    EROFS = 30,
+    /// Function not implemented
+    ENOSYS = 38,
+}
+
+/// Result of a system call
+pub type Result = core::result::Result<usize, Errno>;
+
+/// Number and raw arguments of a system call. The arguments are passed in
+/// rdi, rsi, rdx, r10, r8 and r9, in that order
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Args {
+    pub number: usize,
+    raw: [usize; 6],
+}
+
+impl Args {
+    pub fn new(number: usize, raw: [usize; 6]) -> Args {
+        Args { number, raw }
+    }
+
+    /// Decode argument `index` as a `T`
+    pub fn get<T: FromArg>(&self, index: usize) -> core::result::Result<T, Errno> {
+        T::from_arg(self.raw[index])
+    }
+}
+
+/// Types that a raw system call argument can be decoded as
+pub trait FromArg: Sized {
+    fn from_arg(arg: usize) -> core::result::Result<Self, Errno>;
+}
+
+impl FromArg for usize {
+    fn from_arg(arg: usize) -> core::result::Result<usize, Errno> {
+        Ok(arg)
+    }
+}
+
+impl FromArg for isize {
+    fn from_arg(arg: usize) -> core::result::Result<isize, Errno> {
+        Ok(arg as isize)
+    }
+}
+
+/// C `int`s only use the low 32 bits of the register
+impl FromArg for i32 {
+    fn from_arg(arg: usize) -> core::result::Result<i32, Errno> {
+        Ok(arg as i32)
+    }
+}
+
+impl FromArg for u32 {
+    fn from_arg(arg: usize) -> core::result::Result<u32, Errno> {
+        Ok(arg as u32)
+    }
+}
+
+/// A system call handler, given the arguments and the caller's registers
+type Handler = fn(&Args, &mut SyscallStack) -> Result;
+
+/// An entry in the system call table
+pub struct Syscall {
+    pub number: usize,
+    pub name: &'static str,
+    handler: Handler,
+}
+
+/// Implemented system calls, sorted by number
+pub static TABLE: &[Syscall] = &[
+    Syscall {
+        number: nr::READ,
+        name: "read",
+        handler: fs::read,
+    },
+    Syscall {
+        number: nr::WRITE,
+        name: "write",
+        handler: fs::write,
+    },
+    Syscall {
+        number: nr::OPEN,
+        name: "open",
+        handler: fs::open,
+    },
+    Syscall {
+        number: nr::CLOSE,
+        name: "close",
+        handler: fs::close,
+    },
+    Syscall {
+        number: nr::MMAP,
+        name: "mmap",
+        handler: mm::mmap,
+    },
+    Syscall {
+        number: nr::MUNMAP,
+        name: "munmap",
+        handler: mm::munmap,
+    },
+    Syscall {
+        number: nr::BRK,
+        name: "brk",
+        handler: mm::brk,
+    },
+    Syscall {
+        number: nr::IOCTL,
+        name: "ioctl",
+        handler: fs::ioctl,
+    },
+    Syscall {
+        number: nr::WRITEV,
+        name: "writev",
+        handler: fs::writev,
+    },
+    Syscall {
+        number: nr::SCHED_YIELD,
+        name: "sched_yield",
+        handler: proc::sched_yield,
+    },
+    Syscall {
+        number: nr::NANOSLEEP,
+        name: "nanosleep",
+        handler: time::nanosleep,
+    },
+    Syscall {
+        number: nr::GETPID,
+        name: "getpid",
+        handler: proc::getpid,
+    },
+    Syscall {
+        number: nr::FORK,
+        name: "fork",
+        handler: proc::fork,
+    },
+    Syscall {
+        number: nr::EXECVE,
+        name: "execve",
+        handler: proc::execve,
+    },
+    Syscall {
+        number: nr::EXIT,
+        name: "exit",
+        handler: proc::exit,
+    },
+    Syscall {
+        number: nr::WAIT4,
+        name: "wait4",
+        handler: proc::wait4,
+    },
+    Syscall {
+        number: nr::GETPPID,
+        name: "getppid",
+        handler: proc::getppid,
+    },
+    Syscall {
+        number: nr::ARCH_PRCTL,
+        name: "arch_prctl",
+        handler: proc::arch_prctl,
+    },
+    Syscall {
+        number: nr::SET_TID_ADDRESS,
+        name: "set_tid_address",
+        handler: proc::set_tid_address,
+    },
+    Syscall {
+        number: nr::CLOCK_GETTIME,
+        name: "clock_gettime",
+        handler: time::clock_gettime,
+    },
+    Syscall {
+        number: nr::EXIT_GROUP,
+        name: "exit_group",
+        handler: proc::exit,
+    },
+    Syscall {
+        number: nr::OPENAT,
+        name: "openat",
+        handler: fs::openat,
+    },
+];
+
+/// Return the table entry for system call `number`
+pub fn lookup(number: usize) -> Option<&'static Syscall> {
+    TABLE
+        .binary_search_by_key(&number, |syscall| syscall.number)
+        .ok()
+        .map(|index| &TABLE[index])
+}
+
+/// Run the system call described by `args`, made with the registers in
+/// `regs`, returning the value to pass back to user code
+pub fn dispatch(args: &Args, regs: &mut SyscallStack) -> usize {
+    let result = match lookup(args.number) {
+        Some(syscall) => (syscall.handler)(args, regs),
+        None => Err(Errno::ENOSYS),
+    };
+    encode(result)
+}
+
+/// Encode `result` as a single register: errors are returned negated
+pub fn encode(result: Result) -> usize {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (errno as usize).wrapping_neg(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn table() {
+        for pair in TABLE.windows(2) {
+            assert!(
+                pair[0].number < pair[1].number,
+                "{} is out of order",
+                pair[1].name
+            );
+        }
+        assert_eq!(lookup(nr::GETPID).map(|s| s.name), Some("getpid"));
+        assert!(lookup(1000).is_none());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(encode(Ok(42)), 42);
+        assert_eq!(encode(Err(Errno::EFAULT)) as isize, -14);
+        let mut regs = SyscallStack::default();
+        assert_eq!(dispatch(&Args::new(1000, [0; 6]), &mut regs) as isize, -38);
+        // Only processes have open files
+        let args = Args::new(nr::WRITE, [3, 0x1000, 1, 0, 0, 0]);
+        assert_eq!(
+            dispatch(&args, &mut regs) as isize,
+            -(Errno::EBADF as isize)
+        );
+    }
+
+    #[test]
+    fn decode() {
+        let args = Args::new(0, [usize::max_value(), 0x1_0000_0005, 0, 0, 0, 0]);
+        assert_eq!(args.get::<isize>(0), Ok(-1));
+        assert_eq!(args.get::<i32>(1), Ok(5));
+        assert_eq!(args.get::<u32>(0), Ok(0xFFFF_FFFF));
+    }
+}
@@ -0,0 +1,159 @@
+//! Process and scheduling system calls
+use super::user::{self, UserPtr};
+use super::{Args, Errno, Result};
+use crate::arch::context;
+use crate::arch::syscall::SyscallStack;
+use crate::arch::usermode::USER_RFLAGS;
+use crate::fs::{self, PATH_MAX};
+use crate::process::loader::{ARG_MAX, USER_END};
+use crate::process::{self, ExitStatus, Pid, Wait};
+use crate::thread;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// `wait4` option: return straight away if no child has exited
+const WNOHANG: u32 = 1;
+
+/// `arch_prctl` codes
+const ARCH_SET_FS: u32 = 0x1002;
+const ARCH_GET_FS: u32 = 0x1003;
+
+/// `exit(status)`, and `exit_group(status)`, since every process has a
+/// single thread
+pub fn exit(args: &Args, _: &mut SyscallStack) -> Result {
+    let status: i32 = args.get(0)?;
+    process::exit(ExitStatus::Exited(status as u8))
+}
+
+/// `getpid()`
+pub fn getpid(_: &Args, _: &mut SyscallStack) -> Result {
+    process::current().ok_or(Errno::ESRCH)
+}
+
+/// `getppid()`. Processes that the kernel started have parent 0
+pub fn getppid(_: &Args, _: &mut SyscallStack) -> Result {
+    process::parent().ok_or(Errno::ESRCH)
+}
+
+/// `set_tid_address(tidptr)`. Returns the caller's thread ID, which is its
+/// process ID. The address is for waking threads that wait for the caller
+/// to exit, and with a single thread per process there are none, so it is
+/// not kept
+pub fn set_tid_address(_: &Args, _: &mut SyscallStack) -> Result {
+    process::current().ok_or(Errno::ESRCH)
+}
+
+/// `arch_prctl(code, addr)`. User code can set and read its FS base, which
+/// the C library keeps the thread pointer in. GS is reserved for the
+/// kernel's per-CPU data
+pub fn arch_prctl(args: &Args, _: &mut SyscallStack) -> Result {
+    let code: u32 = args.get(0)?;
+    match code {
+        ARCH_SET_FS => {
+            let base: usize = args.get(1)?;
+            if base >= USER_END {
+                return Err(Errno::EPERM);
+            }
+            context::set_fs_base(base);
+            Ok(0)
+        }
+        ARCH_GET_FS => {
+            let addr: UserPtr<usize> = args.get(1)?;
+            addr.write(context::fs_base())?;
+            Ok(0)
+        }
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+/// `sched_yield()`
+pub fn sched_yield(_: &Args, _: &mut SyscallStack) -> Result {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// `fork()`
+pub fn fork(_: &Args, regs: &mut SyscallStack) -> Result {
+    process::fork(regs).map_err(|_| Errno::ENOMEM)
+}
+
+/// Copy the NULL-terminated array of strings at `array` into the kernel,
+/// adding their size to `size`, which may not exceed [`ARG_MAX`]
+fn read_strings(
+    array: Option<UserPtr<usize>>,
+    size: &mut usize,
+) -> core::result::Result<Vec<Vec<u8>>, Errno> {
+    let mut strings = Vec::new();
+    let mut array = match array {
+        Some(array) => array,
+        None => return Ok(strings),
+    };
+    loop {
+        let addr = array.read()?;
+        if addr == 0 {
+            return Ok(strings);
+        }
+        let max = ARG_MAX.checked_sub(*size).ok_or(Errno::E2BIG)?;
+        let string = user::read_str(addr, max)?;
+        // Count the terminator and the pointer as well
+        *size += string.len() + 1 + core::mem::size_of::<usize>();
+        strings.push(string);
+        array = array.add(1)?;
+    }
+}
+
+/// `execve(path, argv, envp)`. On success the call does not return to the
+/// old program, but enters the new one with every other register cleared.
+/// Only an executable that fails to parse is reported, with `ENOEXEC`. The
+/// old program is gone by the time the new one is loaded, so if that fails,
+/// for instance for lack of memory, the process is killed
+pub fn execve(args: &Args, regs: &mut SyscallStack) -> Result {
+    let path = fs::absolute(user::read_str(args.get(0)?, PATH_MAX)?);
+    let image = fs::lookup(&path).ok_or(Errno::ENOENT)?;
+    let mut size = 0;
+    let argv = read_strings(args.get(1)?, &mut size)?;
+    let envp = read_strings(args.get(2)?, &mut size)?;
+
+    let name = path.rsplit(|&b| b == b'/').next().unwrap_or(&path);
+    let name = String::from_utf8_lossy(name);
+    let argv: Vec<&[u8]> = argv.iter().map(|arg| &arg[..]).collect();
+    let envp: Vec<&[u8]> = envp.iter().map(|var| &var[..]).collect();
+    let (entry, stack) = process::exec(&name, image, &argv, &envp).map_err(|_| Errno::ENOEXEC)?;
+
+    *regs = SyscallStack {
+        rip: entry,
+        rflags: USER_RFLAGS,
+        rsp: stack,
+        ..SyscallStack::default()
+    };
+    Ok(0)
+}
+
+/// `wait4(pid, status, options, rusage)`. There are no process groups, so
+/// a `pid` of 0 or less waits for any child. Resource usage is not
+/// reported
+pub fn wait4(args: &Args, _: &mut SyscallStack) -> Result {
+    let pid: i32 = args.get(0)?;
+    let status: Option<UserPtr<i32>> = args.get(1)?;
+    let options: u32 = args.get(2)?;
+    if options & !WNOHANG != 0 {
+        return Err(Errno::EINVAL);
+    }
+    // Check the status pointer before reaping anything, so that a bad one
+    // doesn't lose the child's status
+    if let Some(status) = status {
+        user::check(status.addr(), core::mem::size_of::<i32>(), true)?;
+    }
+
+    let pid = if pid > 0 { Some(pid as Pid) } else { None };
+    match process::wait(pid, options & WNOHANG == 0) {
+        Wait::Exited(pid, exit) => {
+            if let Some(status) = status {
+                status.write(exit.wait_status() as i32)?;
+            }
+            Ok(pid)
+        }
+        Wait::Running => Ok(0),
+        Wait::NoChildren => Err(Errno::ECHILD),
+    }
+}
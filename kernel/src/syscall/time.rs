@@ -0,0 +1,95 @@
+//! Time system calls
+use super::user::UserPtr;
+use super::{Args, Errno, Result};
+use crate::arch::syscall::SyscallStack;
+use crate::thread;
+use crate::timer::HZ;
+use core::time::Duration;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// Clock IDs
+const CLOCK_REALTIME: i32 = 0;
+const CLOCK_MONOTONIC: i32 = 1;
+const CLOCK_MONOTONIC_RAW: i32 = 4;
+const CLOCK_REALTIME_COARSE: i32 = 5;
+const CLOCK_MONOTONIC_COARSE: i32 = 6;
+const CLOCK_BOOTTIME: i32 = 7;
+
+/// `struct timespec`
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    /// Convert to a [`Duration`], if this is a valid, non-negative time
+    pub fn duration(self) -> Option<Duration> {
+        if self.tv_sec < 0 || self.tv_nsec < 0 || self.tv_nsec >= NANOS_PER_SEC {
+            return None;
+        }
+        Some(Duration::new(self.tv_sec as u64, self.tv_nsec as u32))
+    }
+}
+
+impl From<Duration> for Timespec {
+    fn from(duration: Duration) -> Timespec {
+        Timespec {
+            tv_sec: duration.as_secs() as i64,
+            tv_nsec: duration.subsec_nanos() as i64,
+        }
+    }
+}
+
+/// `nanosleep(req, rem)`. There are no signals to interrupt the sleep, so
+/// `rem` is never written
+pub fn nanosleep(args: &Args, _: &mut SyscallStack) -> Result {
+    let req: UserPtr<Timespec> = args.get(0)?;
+    let duration = req.read()?.duration().ok_or(Errno::EINVAL)?;
+    thread::sleep(duration);
+    Ok(0)
+}
+
+/// `clock_gettime(clock, tp)`. Every clock counts timer ticks since boot.
+/// The real time clock isn't read, so the wall clock starts at the epoch
+pub fn clock_gettime(args: &Args, _: &mut SyscallStack) -> Result {
+    let clock: i32 = args.get(0)?;
+    let tp: UserPtr<Timespec> = args.get(1)?;
+    match clock {
+        CLOCK_REALTIME
+        | CLOCK_MONOTONIC
+        | CLOCK_MONOTONIC_RAW
+        | CLOCK_REALTIME_COARSE
+        | CLOCK_MONOTONIC_COARSE
+        | CLOCK_BOOTTIME => (),
+        _ => return Err(Errno::EINVAL),
+    }
+    let nanos = thread::ticks() * (NANOS_PER_SEC as u64 / HZ as u64);
+    tp.write(Timespec::from(Duration::from_nanos(nanos)))?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timespec() {
+        let time = Timespec::from(Duration::from_millis(2_500));
+        assert_eq!(
+            time,
+            Timespec {
+                tv_sec: 2,
+                tv_nsec: 500_000_000
+            }
+        );
+        assert_eq!(time.duration(), Some(Duration::from_millis(2_500)));
+        let invalid = Timespec {
+            tv_sec: 1,
+            tv_nsec: NANOS_PER_SEC,
+        };
+        assert_eq!(invalid.duration(), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! Access to user memory from system calls
+//!
+//! User code passes arbitrary addresses, so every access is checked against
+//! the page tables first: the memory must lie in the user half of the
+//! address space and be mapped with [`EntryFlags::USER`], or belong to a
+//! lazily-backed region that will map it on access. The page tables are
+//! not locked while copying, since touching a lazily-backed page has to
+//! lock them to back it, and writing to a copy-on-write page to copy it.
+use super::{Errno, FromArg};
+use crate::paging::fault::LazyRegions;
+use crate::paging::{EntryFlags, Mapper, Page, Virtual, PAGE_SIZE};
+use crate::prelude::*;
+use crate::process::loader::USER_END;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+
+/// Check that the `len` bytes at `addr` are user memory that may be read,
+/// and also written if `write` is true
+pub fn check(addr: usize, len: usize, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(Errno::EFAULT)?;
+    if addr == 0 || end > USER_END {
+        return Err(Errno::EFAULT);
+    }
+
+    let mapper = Mapper::global().lock();
+    let mut page = Page::containing(Virtual::new(addr));
+    let last = Page::containing(Virtual::new(end - 1));
+    loop {
+        let flags = match mapper.translate_page(page) {
+            Some((_, flags)) => flags,
+            None => LazyRegions::global()
+                .lock()
+                .find(page.start_address())
+                .map(|region| region.flags)
+                .ok_or(Errno::EFAULT)?,
+        };
+        let writable =
+            flags.contains(EntryFlags::WRITABLE) || flags.contains(EntryFlags::COPY_ON_WRITE);
+        if !flags.contains(EntryFlags::USER) || write && !writable {
+            return Err(Errno::EFAULT);
+        }
+        if page == last {
+            return Ok(());
+        }
+        page = page.offset(1);
+    }
+}
+
+/// Copy `len` bytes of user memory at `addr` into the kernel
+pub fn read_bytes(addr: usize, len: usize) -> Result<Vec<u8>, Errno> {
+    check(addr, len, false)?;
+    let mut data = Vec::with_capacity(len);
+    unsafe {
+        core::ptr::copy_nonoverlapping(addr as *const u8, data.as_mut_ptr(), len);
+        data.set_len(len);
+    }
+    Ok(data)
+}
+
+/// Copy `data` into user memory at `addr`
+pub fn write_bytes(addr: usize, data: &[u8]) -> Result<(), Errno> {
+    check(addr, data.len(), true)?;
+    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), addr as *mut u8, data.len()) };
+    Ok(())
+}
+
+/// Copy a NUL-terminated string of at most `max` bytes, not counting the
+/// terminator, from user memory at `addr`
+pub fn read_str(addr: usize, max: usize) -> Result<Vec<u8>, Errno> {
+    let mut string = Vec::new();
+    let mut addr = addr;
+    loop {
+        // Check a page at a time, since the end is not known up front
+        let len = PAGE_SIZE - addr % PAGE_SIZE;
+        check(addr, len, false)?;
+        let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+        match bytes.iter().position(|&b| b == 0) {
+            Some(nul) => string.extend_from_slice(&bytes[..nul]),
+            None => string.extend_from_slice(bytes),
+        }
+        if string.len() > max {
+            return Err(Errno::E2BIG);
+        }
+        if bytes.contains(&0) {
+            return Ok(string);
+        }
+        addr += len;
+    }
+}
+
+/// Pointer to a `T` in user memory. Decoding one as a system call argument
+/// only checks that it is aligned and lies in the user half, since the
+/// mapping can change before it is used
+pub struct UserPtr<T> {
+    addr: usize,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> UserPtr<T> {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> core::fmt::Debug for UserPtr<T> {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(fmt, "UserPtr({:#X})", self.addr)
+    }
+}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Return the pointer `n` elements past this one
+    pub fn add(&self, n: usize) -> Result<UserPtr<T>, Errno> {
+        let addr = n
+            .checked_mul(size_of::<T>())
+            .and_then(|offset| offset.checked_add(self.addr))
+            .ok_or(Errno::EFAULT)?;
+        UserPtr::from_arg(addr)
+    }
+
+    pub fn read(&self) -> Result<T, Errno> {
+        check(self.addr, size_of::<T>(), false)?;
+        Ok(unsafe { core::ptr::read(self.addr as *const T) })
+    }
+
+    pub fn write(&self, value: T) -> Result<(), Errno> {
+        check(self.addr, size_of::<T>(), true)?;
+        unsafe { core::ptr::write(self.addr as *mut T, value) };
+        Ok(())
+    }
+}
+
+impl<T> FromArg for UserPtr<T> {
+    fn from_arg(addr: usize) -> Result<UserPtr<T>, Errno> {
+        let end = addr.checked_add(size_of::<T>()).ok_or(Errno::EFAULT)?;
+        if addr == 0 || end > USER_END || addr % align_of::<T>() != 0 {
+            return Err(Errno::EFAULT);
+        }
+        Ok(UserPtr {
+            addr,
+            _type: PhantomData,
+        })
+    }
+}
+
+/// A null pointer decodes as `None`
+impl<T> FromArg for Option<UserPtr<T>> {
+    fn from_arg(addr: usize) -> Result<Option<UserPtr<T>>, Errno> {
+        match addr {
+            0 => Ok(None),
+            addr => UserPtr::from_arg(addr).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::syscall::Args;
+
+    #[test]
+    fn pointers() {
+        let args = Args::new(0, [0, 0x1000, 0x1004, USER_END - 8, USER_END - 4, 0]);
+        assert_eq!(args.get::<UserPtr<u64>>(0).err(), Some(Errno::EFAULT));
+        assert_eq!(
+            args.get::<Option<UserPtr<u64>>>(0)
+                .ok()
+                .map(|p| p.is_none()),
+            Some(true)
+        );
+        assert_eq!(
+            args.get::<UserPtr<u64>>(1).map(|p| p.addr()).ok(),
+            Some(0x1000)
+        );
+        // Misaligned
+        assert_eq!(args.get::<UserPtr<u64>>(2).err(), Some(Errno::EFAULT));
+        assert!(args.get::<UserPtr<u32>>(2).is_ok());
+        // Must end below the kernel half
+        assert!(args.get::<UserPtr<u64>>(3).is_ok());
+        assert_eq!(args.get::<UserPtr<u64>>(4).err(), Some(Errno::EFAULT));
+        assert_eq!(check(USER_END - 8, 16, false), Err(Errno::EFAULT));
+        assert_eq!(check(usize::max_value(), 2, false), Err(Errno::EFAULT));
+
+        let ptr = args.get::<UserPtr<u64>>(1).unwrap();
+        assert_eq!(ptr.add(2).map(|p| p.addr()).ok(), Some(0x1010));
+        assert_eq!(ptr.add(usize::max_value()).err(), Some(Errno::EFAULT));
+    }
+}
@@ -60,9 +60,15 @@ impl Character {
     }
 }
 
+/// Number of lines on the screen
+pub const ROWS: usize = 25;
+
+/// Number of characters on a line
+pub const COLUMNS: usize = 80;
+
 /// Handles writing to VGA video memory
 pub struct Terminal {
-    buffer: &'static mut [[Volatile<Character>; 80]; 25],
+    buffer: &'static mut [[Volatile<Character>; COLUMNS]; ROWS],
     pos: usize,
     color: TextColor,
 }
@@ -76,7 +82,8 @@ impl Default for Terminal {
     /// should be created and stored behind a [`Mutex`]
     fn default() -> Terminal {
         Terminal {
-            buffer: unsafe { &mut *(0xB8000 as *mut _) },
+            // Through the higher half alias, which user address spaces share
+            buffer: unsafe { &mut *((crate::memory::KERNEL_VIRT + 0xB8000) as *mut _) },
             pos: 0,
             color: TextColor::default(),
         }
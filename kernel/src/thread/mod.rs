@@ -0,0 +1,714 @@
+//! Preemptive kernel threads
+//!
+//! Every thread has its own kernel stack, and a [`Context`] holding its
+//! registers while it is not running. Which thread runs next, and when the
+//! running thread is preempted by the timer interrupt, is decided by the
+//! [`policy`]. When no thread is ready, the idle thread halts until the
+//! next interrupt.
+//!
+//! A thread that belongs to a user process also has the process's address
+//! space, which is switched to along with it.
+//!
+//! Threads only run on the BSP.
+use crate::arch::context::{self, Context};
+use crate::arch::interrupts::{self, InterruptGuard, Nesting};
+use crate::memory::physical::Frame;
+use crate::paging;
+use crate::prelude::*;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
+
+pub mod policy;
+
+use policy::Policy;
+pub use policy::Priority;
+
+/// Size, in bytes, of each thread's kernel stack
+pub const STACK_SIZE: usize = 0x8000;
+
+/// Nanoseconds between timer ticks. CPU time is sampled at each tick, and
+/// charged to whichever thread is running
+const TICK: u64 = 1_000_000_000 / crate::timer::HZ as u64;
+
+pub type Tid = usize;
+
+/// Thread that `_start` runs on, which uses the boot stack
+const BOOT: Tid = 0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum State {
+    /// Waiting in the run queue
+    Ready,
+    Running,
+    /// Waiting to be woken, or for a timeout
+    Blocked,
+    /// Finished, and waiting for its stack to be freed
+    Dead,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SchedError {
+    NoSuchThread,
+    InvalidPriority,
+}
+
+pub struct Thread {
+    pub id: Tid,
+    pub state: State,
+    context: Context,
+    /// `None` for the boot thread
+    stack: Option<Box<[u8]>>,
+    /// Function to run, taken when the thread first starts
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// Tick at which a blocked thread times out
+    deadline: Option<u64>,
+    /// Woken while it was still running, so that its next attempt to block
+    /// returns straight away instead of missing the wakeup
+    woken: bool,
+    /// Last woken by its deadline passing
+    timed_out: bool,
+    /// PML4 of the thread's address space, or `None` for the kernel's
+    space: Option<Frame>,
+}
+
+impl Thread {
+    /// Allocate a stack for a thread that will run `entry`
+    fn new(id: Tid, entry: Box<dyn FnOnce() + Send>) -> Thread {
+        let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+        let context = Context::new(&mut stack, start);
+        Thread {
+            id,
+            state: State::Ready,
+            context,
+            stack: Some(stack),
+            entry: Some(entry),
+            deadline: None,
+            woken: false,
+            timed_out: false,
+            space: None,
+        }
+    }
+
+    /// Address one past the end of the thread's kernel stack
+    fn stack_top(&self) -> Option<usize> {
+        self.stack
+            .as_ref()
+            .map(|stack| stack.as_ptr() as usize + stack.len())
+    }
+}
+
+/// What changes when the scheduler switches threads
+struct Switch {
+    prev: *mut Context,
+    next: *const Context,
+    /// PML4 of the next thread's address space
+    space: Frame,
+    /// Top of the next thread's kernel stack, if it has its own
+    stack: Option<usize>,
+}
+
+global!(Scheduler);
+
+pub struct Scheduler {
+    /// Threads are boxed, so that their contexts stay put while the map
+    /// changes
+    threads: BTreeMap<Tid, Box<Thread>>,
+    /// Every thread but the idle thread
+    policy: Policy,
+    current: Tid,
+    idle: Option<Tid>,
+    next_id: Tid,
+    /// Threads that have exited. The stack of a dead thread may still be
+    /// in use until the next switch
+    dead: Vec<Box<Thread>>,
+    /// Nanoseconds spent in the idle thread
+    idle_time: u64,
+    /// Timer ticks since the scheduler started
+    ticks: u64,
+    /// Blocked threads with a deadline, by deadline
+    sleepers: BTreeSet<(u64, Tid)>,
+    need_resched: bool,
+}
+
+impl Default for Scheduler {
+    /// A scheduler that only knows about the boot thread, which is running
+    fn default() -> Scheduler {
+        let mut threads = BTreeMap::new();
+        threads.insert(
+            BOOT,
+            Box::new(Thread {
+                id: BOOT,
+                state: State::Running,
+                context: Context::default(),
+                stack: None,
+                entry: None,
+                deadline: None,
+                woken: false,
+                timed_out: false,
+                space: None,
+            }),
+        );
+        let mut policy = Policy::default();
+        policy.add(BOOT, Priority::default());
+        policy.wake(BOOT);
+        policy.pick_next();
+        Scheduler {
+            threads,
+            policy,
+            current: BOOT,
+            idle: None,
+            next_id: BOOT + 1,
+            dead: Vec::new(),
+            idle_time: 0,
+            ticks: 0,
+            sleepers: BTreeSet::new(),
+            need_resched: false,
+        }
+    }
+}
+
+impl Scheduler {
+    /// Add a thread that runs `entry`, without queueing it
+    fn create(&mut self, entry: Box<dyn FnOnce() + Send>) -> Tid {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.threads.insert(id, Box::new(Thread::new(id, entry)));
+        id
+    }
+
+    /// Add a runnable thread that runs `entry`. A reschedule is requested
+    /// if it should preempt the running thread
+    pub fn spawn(&mut self, priority: Priority, entry: Box<dyn FnOnce() + Send>) -> Tid {
+        let id = self.create(entry);
+        self.policy.add(id, priority);
+        self.policy.wake(id);
+        if Some(self.current) == self.idle || self.policy.should_preempt(id) {
+            self.need_resched = true;
+        }
+        id
+    }
+
+    pub fn set_priority(&mut self, id: Tid, priority: Priority) -> Result<(), SchedError> {
+        if !priority.is_valid() {
+            return Err(SchedError::InvalidPriority);
+        }
+        if !self.policy.set_priority(id, priority) {
+            return Err(SchedError::NoSuchThread);
+        }
+        Ok(())
+    }
+
+    /// Nanoseconds of CPU time used by thread `id`
+    pub fn cpu_time(&self, id: Tid) -> Option<u64> {
+        if Some(id) == self.idle {
+            return Some(self.idle_time);
+        }
+        self.policy.get(id).map(|entity| entity.runtime)
+    }
+
+    /// ID of the running thread
+    pub fn current(&self) -> Tid {
+        self.current
+    }
+
+    /// Number of threads that have not exited, including the idle thread
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    pub fn state(&self, id: Tid) -> Option<State> {
+        self.threads.get(&id).map(|thread| thread.state)
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        self.threads
+            .get_mut(&self.current)
+            .expect("running thread is not in the thread table")
+    }
+
+    /// Account for a timer tick, returning true if the running thread
+    /// should be preempted
+    pub fn tick(&mut self) -> bool {
+        self.ticks += 1;
+        while let Some(&(deadline, id)) = self.sleepers.iter().next() {
+            if deadline > self.ticks {
+                break;
+            }
+            self.threads.get_mut(&id).unwrap().timed_out = true;
+            self.wake(id);
+        }
+
+        if Some(self.current) == self.idle {
+            self.idle_time += TICK;
+            self.need_resched = self.policy.runnable() > 0;
+        } else if self.policy.tick(TICK) {
+            self.need_resched = true;
+        }
+        self.need_resched
+    }
+
+    /// Block the running thread until it is woken, or until tick `deadline`.
+    /// Returns false without blocking if the thread was woken since it last
+    /// called [`Scheduler::prepare`], or the deadline has already passed
+    fn block(&mut self, deadline: Option<u64>) -> bool {
+        let id = self.current;
+        assert!(Some(id) != self.idle, "the idle thread blocked");
+        let ticks = self.ticks;
+        let thread = self.current_mut();
+        if thread.woken {
+            return false;
+        }
+        if let Some(deadline) = deadline {
+            if deadline <= ticks {
+                thread.timed_out = true;
+                return false;
+            }
+            self.sleepers.insert((deadline, id));
+        }
+        let thread = self.current_mut();
+        thread.state = State::Blocked;
+        thread.deadline = deadline;
+        self.policy.block(id);
+        true
+    }
+
+    /// Forget any earlier wakeup of the running thread, before it checks
+    /// whether it needs to block
+    fn prepare(&mut self) {
+        let thread = self.current_mut();
+        thread.woken = false;
+        thread.timed_out = false;
+    }
+
+    /// Make a blocked thread runnable. A thread that has not blocked yet
+    /// will not block the next time it tries. Returns true if the thread
+    /// was blocked
+    pub fn wake(&mut self, id: Tid) -> bool {
+        let thread = match self.threads.get_mut(&id) {
+            Some(thread) => thread,
+            None => return false,
+        };
+        if thread.state != State::Blocked {
+            thread.woken = true;
+            return false;
+        }
+        thread.state = State::Ready;
+        if let Some(deadline) = thread.deadline.take() {
+            self.sleepers.remove(&(deadline, id));
+        }
+        self.policy.wake(id);
+        if Some(self.current) == self.idle || self.policy.should_preempt(id) {
+            self.need_resched = true;
+        }
+        true
+    }
+
+    /// Mark the running thread as dead. It is removed from the thread
+    /// table, and its stack freed after it has been switched away from
+    fn exit(&mut self) {
+        let id = self.current;
+        assert!(Some(id) != self.idle, "the idle thread exited");
+        self.current_mut().state = State::Dead;
+        self.policy.remove(id);
+    }
+
+    /// Free the stacks of threads that exited before the last switch
+    fn reap(&mut self) {
+        self.dead.clear();
+    }
+
+    /// Set the address space of the running thread, returning the PML4 to
+    /// switch to
+    fn set_space(&mut self, space: Option<Frame>) -> Frame {
+        self.current_mut().space = space;
+        space.unwrap_or_else(paging::kernel_space)
+    }
+
+    /// Choose the thread to run next, returning the running thread to the
+    /// run queue if it can still run. Returns the contexts to switch
+    /// between, or `None` if the running thread should continue
+    fn switch(&mut self) -> Option<Switch> {
+        let prev = self.current;
+        let prev_state = self.current_mut().state;
+        if prev_state == State::Running && Some(prev) != self.idle {
+            self.policy.put_prev(prev);
+        }
+        let next = self
+            .policy
+            .pick_next()
+            .or(self.idle)
+            .expect("no thread to run");
+        self.need_resched = false;
+        if next == prev {
+            // It may have been woken before it could block
+            self.current_mut().state = State::Running;
+            return None;
+        }
+
+        if prev_state == State::Running {
+            self.current_mut().state = State::Ready;
+        }
+        self.current = next;
+        self.current_mut().state = State::Running;
+
+        let next_context = &self.current_mut().context as *const Context;
+        let space = self
+            .current_mut()
+            .space
+            .unwrap_or_else(paging::kernel_space);
+        let stack = self.current_mut().stack_top();
+        let prev_context = match prev_state {
+            State::Dead => {
+                // Keep the stack alive until we are off it
+                let thread = self.threads.remove(&prev).unwrap();
+                self.dead.push(thread);
+                &mut self.dead.last_mut().unwrap().context as *mut Context
+            }
+            _ => &mut self.threads.get_mut(&prev).unwrap().context as *mut Context,
+        };
+        Some(Switch {
+            prev: prev_context,
+            next: next_context,
+            space,
+            stack,
+        })
+    }
+}
+
+/// Create the idle thread. The caller becomes the boot thread, which
+/// can be preempted like any other once the timer is running
+pub fn init() {
+    let mut scheduler = Scheduler::global().lock();
+    let idle = scheduler.create(Box::new(idle));
+    scheduler.idle = Some(idle);
+}
+
+/// Run `entry` on a new kernel thread, with the default priority
+pub fn spawn<F>(entry: F) -> Tid
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with(Priority::default(), entry)
+}
+
+/// Run `entry` on a new kernel thread with `priority`, switching to it
+/// straight away if it should preempt the caller
+pub fn spawn_with<F>(priority: Priority, entry: F) -> Tid
+where
+    F: FnOnce() + Send + 'static,
+{
+    assert!(priority.is_valid(), "invalid priority {:?}", priority);
+    let (id, preempt) = {
+        let mut scheduler = Scheduler::global().critical();
+        let id = scheduler.spawn(priority, Box::new(entry));
+        (id, scheduler.need_resched)
+    };
+    if preempt {
+        yield_now();
+    }
+    id
+}
+
+/// ID of the calling thread
+pub fn current() -> Tid {
+    Scheduler::global().critical().current()
+}
+
+/// Change the priority of thread `id`. Takes effect the next time the
+/// scheduler runs
+pub fn set_priority(id: Tid, priority: Priority) -> Result<(), SchedError> {
+    Scheduler::global().critical().set_priority(id, priority)
+}
+
+/// Nanoseconds of CPU time used by thread `id`, as sampled by the timer.
+/// The idle thread's time is the time spent idle
+pub fn cpu_time(id: Tid) -> Option<u64> {
+    Scheduler::global().critical().cpu_time(id)
+}
+
+/// Give up the rest of the time slice to the next ready thread
+pub fn yield_now() {
+    let _guard = InterruptGuard::new();
+    schedule(false);
+}
+
+/// End the calling thread. May be called from an exception handler, to end
+/// a thread whose user code faulted
+pub fn exit() -> ! {
+    interrupts::disable();
+    Scheduler::global().critical().exit();
+    schedule(false);
+    unreachable!("dead thread was scheduled");
+}
+
+/// Timer ticks since the scheduler started
+pub fn ticks() -> u64 {
+    Scheduler::global().critical().ticks
+}
+
+/// Return the tick by which `timeout` will have passed
+pub fn deadline(timeout: Duration) -> u64 {
+    let timeout = (timeout.as_nanos() + TICK as u128 - 1) / TICK as u128;
+    ticks() + timeout as u64
+}
+
+/// Block the calling thread until it is woken with [`wake`], or tick
+/// `deadline` passes. `enqueue` is called first with the thread's ID, to
+/// record it wherever its waker will find it, and returns false if the
+/// thread no longer needs to block. A wakeup that arrives after `enqueue`
+/// is called is never lost. Returns false if the deadline passed
+pub fn block<F>(deadline: Option<u64>, enqueue: F) -> bool
+where
+    F: FnOnce(Tid) -> bool,
+{
+    let _guard = InterruptGuard::new();
+    let id = {
+        let mut scheduler = Scheduler::global().lock();
+        scheduler.prepare();
+        scheduler.current
+    };
+    if enqueue(id) && Scheduler::global().lock().block(deadline) {
+        schedule(false);
+    }
+    let timed_out = Scheduler::global().lock().current_mut().timed_out;
+    !timed_out
+}
+
+/// Make thread `id` runnable if it is blocked, or stop its next attempt to
+/// block. It runs when the scheduler next switches threads. Returns true if
+/// it was blocked
+pub fn wake(id: Tid) -> bool {
+    Scheduler::global().critical().wake(id)
+}
+
+/// Move the calling thread into the address space with PML4 `space`, or
+/// back into the kernel's if `None`, and switch to it
+///
+/// # Safety
+///
+/// As for [`paging::activate`]. Nothing may still refer to memory in the
+/// user half of the address space being left
+pub unsafe fn set_space(space: Option<Frame>) {
+    let pml4 = Scheduler::global().critical().set_space(space);
+    paging::activate(pml4);
+}
+
+/// PML4 of the calling thread's address space, or `None` if it is the
+/// kernel's
+pub fn space() -> Option<Frame> {
+    Scheduler::global().critical().current_mut().space
+}
+
+/// Block the calling thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    block(Some(deadline(duration)), |_| true);
+}
+
+/// Called on every timer tick, from the interrupt handler
+pub fn tick() {
+    Scheduler::global().critical().tick();
+}
+
+/// Switch threads if the running one has used up its time slice. Called at
+/// the end of interrupt handling, after the EOI, since the interrupted
+/// thread may not run again for a while
+pub fn preempt() {
+    // A thread spinning on a lock held by the preempted thread would never
+    // give the CPU back
+    if crate::sync::held() == 0 {
+        schedule(true);
+    }
+}
+
+/// Switch to the next thread. Interrupts must be disabled. If `preempt` is
+/// set, only switch if a reschedule is due
+fn schedule(preempt: bool) {
+    let switch = {
+        let mut scheduler = Scheduler::global().critical();
+        if preempt && !scheduler.need_resched {
+            return;
+        }
+        scheduler.reap();
+        match scheduler.switch() {
+            Some(switch) => switch,
+            None => return,
+        }
+    };
+    // Each thread has its own critical section and interrupt handler
+    // nesting, which only the thread switching in knows
+    let nesting = Nesting::current();
+    unsafe {
+        paging::activate(switch.space);
+        if let Some(top) = switch.stack {
+            context::set_kernel_stack(top);
+        }
+        context::switch(switch.prev, switch.next);
+        nesting.restore();
+    }
+}
+
+/// First function run by every new thread, on its own stack
+extern "C" fn start() -> ! {
+    // Threads are switched to with interrupts disabled, from whatever the
+    // previous thread was nested in
+    unsafe { Nesting::new().restore() };
+    let entry = Scheduler::global().lock().current_mut().entry.take();
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+fn idle() {
+    loop {
+        interrupts::enable();
+        unsafe { asm!("hlt" :::: "volatile") };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scheduler() -> Scheduler {
+        let mut scheduler = Scheduler::default();
+        let idle = scheduler.create(Box::new(|| ()));
+        scheduler.idle = Some(idle);
+        scheduler
+    }
+
+    #[test]
+    fn switching() {
+        let mut s = scheduler();
+        let a = s.spawn(Priority::default(), Box::new(|| ()));
+        let b = s.spawn(Priority::default(), Box::new(|| ()));
+        assert_eq!(s.len(), 4);
+
+        // Threads with the same weight take turns
+        let mut order = Vec::new();
+        for _ in 0..6 {
+            while !s.tick() {}
+            assert!(s.switch().is_some());
+            order.push(s.current());
+        }
+        order.sort();
+        assert_eq!(order, [BOOT, BOOT, a, a, b, b]);
+        assert_eq!(s.state(s.current()), Some(State::Running));
+
+        // The last thread keeps running
+        let first = s.current();
+        s.exit();
+        assert!(s.switch().is_some());
+        assert_ne!(s.current(), first);
+        s.exit();
+        assert!(s.switch().is_some());
+        let last = s.current();
+        assert!(!s.tick());
+        assert!(s.switch().is_none());
+        assert_eq!(s.current(), last);
+        assert_eq!(s.dead.len(), 2);
+        s.reap();
+        assert_eq!(s.len(), 2);
+        assert_eq!(s.cpu_time(first), None);
+        assert!(s.cpu_time(last).unwrap() > 0);
+    }
+
+    #[test]
+    fn idle_and_priorities() {
+        let mut s = scheduler();
+        let idle = s.idle.unwrap();
+        s.exit();
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), idle);
+        assert!(!s.tick());
+        assert_eq!(s.cpu_time(idle), Some(TICK));
+
+        // The idle thread is never queued, and gives way to new threads
+        let a = s.spawn(Priority::default(), Box::new(|| ()));
+        assert!(s.need_resched);
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), a);
+        for _ in 0..10 {
+            assert!(!s.tick());
+        }
+
+        let rt = s.spawn(Priority::Fifo(10), Box::new(|| ()));
+        assert!(s.need_resched);
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), rt);
+        let b = s.spawn(Priority::Normal(-20), Box::new(|| ()));
+        assert!(!s.need_resched);
+        for _ in 0..10 {
+            assert!(!s.tick());
+        }
+
+        assert_eq!(s.set_priority(rt, Priority::Normal(19)), Ok(()));
+        assert_eq!(
+            s.set_priority(rt, Priority::Fifo(0)),
+            Err(SchedError::InvalidPriority)
+        );
+        assert_eq!(
+            s.set_priority(99, Priority::Normal(0)),
+            Err(SchedError::NoSuchThread)
+        );
+        assert!(s.tick());
+        assert!(s.switch().is_some());
+        assert!(s.current() == a || s.current() == b);
+        assert_eq!(s.cpu_time(rt), Some(11 * TICK));
+    }
+
+    #[test]
+    fn blocking() {
+        let mut s = scheduler();
+        let a = s.spawn(Priority::default(), Box::new(|| ()));
+
+        // A wakeup before blocking is not lost
+        s.prepare();
+        assert!(!s.wake(BOOT));
+        assert!(!s.block(None));
+        s.prepare();
+        assert!(s.block(None));
+        assert_eq!(s.state(BOOT), Some(State::Blocked));
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), a);
+        assert!(s.wake(BOOT));
+        assert_eq!(s.state(BOOT), Some(State::Ready));
+        assert!(!s.wake(BOOT));
+
+        // Timeouts
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), BOOT);
+        s.prepare();
+        assert!(!s.block(Some(s.ticks)));
+        assert!(s.threads[&BOOT].timed_out);
+        s.prepare();
+        assert!(s.block(Some(s.ticks + 2)));
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), a);
+        s.tick();
+        assert_eq!(s.state(BOOT), Some(State::Blocked));
+        s.tick();
+        assert_eq!(s.state(BOOT), Some(State::Ready));
+        assert!(s.threads[&BOOT].timed_out);
+        assert!(s.sleepers.is_empty());
+
+        // With nothing else to run, the idle thread waits for wakeups
+        s.exit();
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), BOOT);
+        s.prepare();
+        assert!(s.block(Some(s.ticks + 100)));
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), s.idle.unwrap());
+        assert!(s.wake(BOOT));
+        assert!(s.need_resched);
+        assert!(s.sleepers.is_empty());
+        assert!(s.switch().is_some());
+        assert_eq!(s.current(), BOOT);
+        assert!(!s.threads[&BOOT].timed_out);
+    }
+}
@@ -0,0 +1,454 @@
+//! Scheduling policy
+//!
+//! Threads are either real-time, with a fixed FIFO priority, or normal,
+//! with a nice level. Runnable real-time threads always run before normal
+//! ones, highest priority first, and keep the processor until they exit,
+//! yield or a higher priority becomes runnable. Normal threads share the
+//! rest in proportion to their weights, as in Linux's CFS: each one
+//! accumulates virtual runtime, its CPU time scaled by its weight, and the
+//! one with the least runs next.
+//!
+//! The policy only deals in thread IDs and nanoseconds, so that it can be
+//! tested on the host.
+use super::Tid;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+pub const FIFO_MIN: u8 = 1;
+pub const FIFO_MAX: u8 = 99;
+
+/// Period, in nanoseconds, in which every runnable normal thread should get
+/// to run
+const LATENCY: u64 = 24_000_000;
+/// Shortest time a normal thread runs before it can be preempted by another
+/// normal thread
+const MIN_GRANULARITY: u64 = 3_000_000;
+/// Virtual runtime by which the running thread must be ahead of a thread
+/// that becomes runnable, for the new thread to preempt it
+const WAKEUP_GRANULARITY: u64 = 1_000_000;
+
+/// Weight of a thread with a nice level of 0
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weights of nice levels -20 to 19. Each level gets about 25% more CPU
+/// time than the next
+const WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Priority {
+    /// Shares the processor with other normal threads, with a nice level
+    /// from [`NICE_MIN`], the most favoured, to [`NICE_MAX`]
+    Normal(i8),
+    /// Runs before every normal thread, with a priority from [`FIFO_MIN`]
+    /// to [`FIFO_MAX`], the highest
+    Fifo(u8),
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal(0)
+    }
+}
+
+impl Priority {
+    pub fn is_valid(self) -> bool {
+        match self {
+            Priority::Normal(nice) => nice >= NICE_MIN && nice <= NICE_MAX,
+            Priority::Fifo(prio) => prio >= FIFO_MIN && prio <= FIFO_MAX,
+        }
+    }
+
+    fn weight(self) -> u64 {
+        match self {
+            Priority::Normal(nice) => WEIGHTS[(nice - NICE_MIN) as usize],
+            Priority::Fifo(_) => NICE_0_WEIGHT,
+        }
+    }
+}
+
+/// Scheduling state of a thread
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Entity {
+    pub priority: Priority,
+    /// Total CPU time, in nanoseconds
+    pub runtime: u64,
+    /// CPU time scaled by `NICE_0_WEIGHT / weight`. Only meaningful for
+    /// normal threads
+    pub vruntime: u64,
+    /// CPU time since the thread was last picked
+    slice: u64,
+    /// Waiting in a run queue
+    queued: bool,
+}
+
+#[derive(Default)]
+pub struct Policy {
+    entities: BTreeMap<Tid, Entity>,
+    /// Runnable normal threads, ordered by virtual runtime
+    fair: BTreeSet<(u64, Tid)>,
+    /// Sum of the weights of the threads in `fair`
+    fair_weight: u64,
+    /// Runnable real-time threads, in FIFO order for each priority
+    fifo: BTreeMap<u8, VecDeque<Tid>>,
+    /// Thread that was last picked, which is in neither queue
+    current: Option<Tid>,
+    /// Virtual runtime that new normal threads start from. Never decreases
+    min_vruntime: u64,
+}
+
+impl Policy {
+    /// Start tracking a thread, which is not yet runnable
+    pub fn add(&mut self, id: Tid, priority: Priority) {
+        assert!(priority.is_valid(), "invalid priority {:?}", priority);
+        self.entities.insert(
+            id,
+            Entity {
+                priority,
+                runtime: 0,
+                vruntime: self.min_vruntime,
+                slice: 0,
+                queued: false,
+            },
+        );
+    }
+
+    /// Stop tracking a thread, returning its final state
+    pub fn remove(&mut self, id: Tid) -> Option<Entity> {
+        self.dequeue(id);
+        if self.current == Some(id) {
+            self.current = None;
+        }
+        self.entities.remove(&id)
+    }
+
+    pub fn get(&self, id: Tid) -> Option<&Entity> {
+        self.entities.get(&id)
+    }
+
+    /// Thread that was last picked to run
+    pub fn current(&self) -> Option<Tid> {
+        self.current
+    }
+
+    /// Number of threads waiting to run, not including the current one
+    pub fn runnable(&self) -> usize {
+        self.fair.len() + self.fifo.values().map(VecDeque::len).sum::<usize>()
+    }
+
+    fn enqueue(&mut self, id: Tid) {
+        let entity = match self.entities.get_mut(&id) {
+            Some(entity) if !entity.queued => entity,
+            _ => return,
+        };
+        entity.queued = true;
+        match entity.priority {
+            Priority::Normal(_) => {
+                self.fair.insert((entity.vruntime, id));
+                self.fair_weight += entity.priority.weight();
+            }
+            Priority::Fifo(prio) => self.fifo.entry(prio).or_default().push_back(id),
+        }
+    }
+
+    fn dequeue(&mut self, id: Tid) {
+        let entity = match self.entities.get_mut(&id) {
+            Some(entity) if entity.queued => entity,
+            _ => return,
+        };
+        entity.queued = false;
+        match entity.priority {
+            Priority::Normal(_) => {
+                self.fair.remove(&(entity.vruntime, id));
+                self.fair_weight -= entity.priority.weight();
+            }
+            Priority::Fifo(prio) => {
+                let queue = self.fifo.get_mut(&prio).unwrap();
+                queue.retain(|&queued| queued != id);
+                if queue.is_empty() {
+                    self.fifo.remove(&prio);
+                }
+            }
+        }
+    }
+
+    /// Make a thread that was not runnable runnable again. A normal
+    /// thread's virtual runtime is brought up close to the others', so that
+    /// time spent asleep cannot be used to monopolize the processor later
+    pub fn wake(&mut self, id: Tid) {
+        let floor = self.min_vruntime.saturating_sub(LATENCY / 2);
+        if let Some(entity) = self.entities.get_mut(&id) {
+            if entity.vruntime < floor {
+                entity.vruntime = floor;
+            }
+        }
+        self.enqueue(id);
+    }
+
+    /// Put the current thread back in the run queue, where it waits its
+    /// turn like any other
+    pub fn put_prev(&mut self, id: Tid) {
+        if self.current == Some(id) {
+            self.current = None;
+        }
+        self.enqueue(id);
+    }
+
+    /// The current thread can no longer run, until it is woken with
+    /// [`Policy::wake`]
+    pub fn block(&mut self, id: Tid) {
+        if self.current == Some(id) {
+            self.current = None;
+        }
+        self.dequeue(id);
+    }
+
+    /// Remove the next thread to run from the run queue, and make it the
+    /// current thread
+    pub fn pick_next(&mut self) -> Option<Tid> {
+        let id = match self.fifo.values().next_back() {
+            Some(queue) => queue[0],
+            None => self.fair.iter().next()?.1,
+        };
+        self.dequeue(id);
+        self.entities.get_mut(&id).unwrap().slice = 0;
+        self.current = Some(id);
+        self.update_min_vruntime();
+        Some(id)
+    }
+
+    fn update_min_vruntime(&mut self) {
+        let current = self
+            .current
+            .and_then(|id| self.entities.get(&id))
+            .filter(|entity| match entity.priority {
+                Priority::Normal(_) => true,
+                Priority::Fifo(_) => false,
+            })
+            .map(|entity| entity.vruntime);
+        let leftmost = self.fair.iter().next().map(|&(vruntime, _)| vruntime);
+        let min = match (current, leftmost) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => return,
+        };
+        if min > self.min_vruntime {
+            self.min_vruntime = min;
+        }
+    }
+
+    /// Time a normal thread with `weight` should get in each period, given
+    /// the runnable normal threads
+    fn ideal_slice(&self, weight: u64) -> u64 {
+        let slice = LATENCY * weight / (self.fair_weight + weight);
+        slice.max(MIN_GRANULARITY)
+    }
+
+    /// Charge the current thread for `delta` nanoseconds of CPU time,
+    /// returning true if it should now be preempted
+    pub fn tick(&mut self, delta: u64) -> bool {
+        let id = match self.current {
+            Some(id) => id,
+            None => return false,
+        };
+        let entity = self.entities.get_mut(&id).unwrap();
+        entity.runtime += delta;
+        entity.slice += delta;
+        let entity = *entity;
+
+        match entity.priority {
+            Priority::Fifo(prio) => self.highest_fifo().map(|p| p > prio) == Some(true),
+            Priority::Normal(_) => {
+                let vruntime = entity.vruntime + delta * NICE_0_WEIGHT / entity.priority.weight();
+                self.entities.get_mut(&id).unwrap().vruntime = vruntime;
+                self.update_min_vruntime();
+
+                if !self.fifo.is_empty() {
+                    return true;
+                }
+                let leftmost = match self.fair.iter().next() {
+                    Some(&(leftmost, _)) => leftmost,
+                    None => return false,
+                };
+                let ideal = self.ideal_slice(entity.priority.weight());
+                entity.slice > ideal || vruntime.saturating_sub(leftmost) > ideal
+            }
+        }
+    }
+
+    fn highest_fifo(&self) -> Option<u8> {
+        self.fifo.keys().next_back().cloned()
+    }
+
+    /// Returns true if `id`, which has just become runnable, should preempt
+    /// the current thread
+    pub fn should_preempt(&self, id: Tid) -> bool {
+        let current = match self.current.and_then(|id| self.entities.get(&id)) {
+            Some(current) => current,
+            None => return true,
+        };
+        let entity = match self.entities.get(&id) {
+            Some(entity) => entity,
+            None => return false,
+        };
+        match (entity.priority, current.priority) {
+            (Priority::Fifo(new), Priority::Fifo(current)) => new > current,
+            (Priority::Fifo(_), Priority::Normal(_)) => true,
+            (Priority::Normal(_), Priority::Fifo(_)) => false,
+            (Priority::Normal(_), Priority::Normal(_)) => {
+                current.vruntime.saturating_sub(entity.vruntime) > WAKEUP_GRANULARITY
+            }
+        }
+    }
+
+    /// Change the priority of a thread, returning false if it is not
+    /// tracked
+    pub fn set_priority(&mut self, id: Tid, priority: Priority) -> bool {
+        assert!(priority.is_valid(), "invalid priority {:?}", priority);
+        let queued = match self.entities.get(&id) {
+            Some(entity) => entity.queued,
+            None => return false,
+        };
+        self.dequeue(id);
+        let min_vruntime = self.min_vruntime;
+        let entity = self.entities.get_mut(&id).unwrap();
+        if let Priority::Fifo(_) = entity.priority {
+            // Virtual runtime did not advance while it was real-time
+            entity.vruntime = entity.vruntime.max(min_vruntime);
+        }
+        entity.priority = priority;
+        if queued {
+            self.enqueue(id);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TICK: u64 = 10_000_000;
+
+    /// Run the policy for `ticks` timer ticks, switching whenever it asks
+    /// to, and return how many ticks each thread got
+    fn run(policy: &mut Policy, ticks: usize) -> BTreeMap<Tid, usize> {
+        let mut counts = BTreeMap::new();
+        if policy.current().is_none() {
+            policy.pick_next();
+        }
+        for _ in 0..ticks {
+            let current = policy.current().unwrap();
+            *counts.entry(current).or_insert(0) += 1;
+            if policy.tick(TICK) {
+                policy.put_prev(current);
+                policy.pick_next();
+            }
+        }
+        counts
+    }
+
+    #[test]
+    fn weighted_fairness() {
+        let mut policy = Policy::default();
+        for (id, nice) in [(1, 0), (2, 0), (3, 5)].iter() {
+            policy.add(*id, Priority::Normal(*nice));
+            policy.wake(*id);
+        }
+        let counts = run(&mut policy, 3000);
+        let (a, b, c) = (counts[&1], counts[&2], counts[&3]);
+        // Equal weights get equal time, and nice 5 gets about a third of
+        // what nice 0 does
+        assert!((a as isize - b as isize).abs() <= 2, "{} {}", a, b);
+        let ratio = a as f64 / c as f64;
+        assert!(ratio > 2.7 && ratio < 3.4, "{}", ratio);
+
+        let runtime = policy.get(1).unwrap().runtime;
+        assert_eq!(runtime, a as u64 * TICK);
+        let total: u64 = (1..4).map(|id| policy.get(id).unwrap().runtime).sum();
+        assert_eq!(total, 3000 * TICK);
+    }
+
+    #[test]
+    fn fifo_preempts_normal() {
+        let mut policy = Policy::default();
+        policy.add(1, Priority::Normal(-20));
+        policy.wake(1);
+        assert_eq!(policy.pick_next(), Some(1));
+
+        policy.add(2, Priority::Fifo(10));
+        policy.wake(2);
+        assert!(policy.should_preempt(2));
+        assert!(policy.tick(TICK));
+        policy.put_prev(1);
+        assert_eq!(policy.pick_next(), Some(2));
+
+        // Real-time threads run until something of higher priority is
+        // runnable, and do not time out
+        let counts = run(&mut policy, 100);
+        assert_eq!(counts.get(&1), None);
+
+        policy.add(3, Priority::Fifo(5));
+        policy.wake(3);
+        assert!(!policy.should_preempt(3));
+        assert!(!policy.tick(TICK));
+
+        policy.add(4, Priority::Fifo(50));
+        policy.wake(4);
+        assert!(policy.should_preempt(4));
+        assert!(policy.tick(TICK));
+        policy.put_prev(2);
+        assert_eq!(policy.pick_next(), Some(4));
+
+        // Equal priorities run in FIFO order
+        policy.add(5, Priority::Fifo(50));
+        policy.wake(5);
+        assert!(!policy.should_preempt(5));
+        policy.remove(4);
+        assert_eq!(policy.pick_next(), Some(5));
+        policy.remove(5);
+        assert_eq!(policy.pick_next(), Some(2));
+        policy.remove(2);
+        assert_eq!(policy.pick_next(), Some(3));
+        policy.remove(3);
+        assert_eq!(policy.pick_next(), Some(1));
+        assert_eq!(policy.runnable(), 0);
+    }
+
+    #[test]
+    fn placement() {
+        let mut policy = Policy::default();
+        policy.add(1, Priority::Normal(0));
+        policy.wake(1);
+        policy.pick_next();
+        run(&mut policy, 100);
+        let vruntime = policy.get(1).unwrap().vruntime;
+        assert_eq!(vruntime, 100 * TICK);
+
+        // A new thread starts level with the others rather than at zero,
+        // and waits its turn
+        policy.add(2, Priority::Normal(0));
+        assert_eq!(policy.get(2).unwrap().vruntime, vruntime);
+        policy.wake(2);
+        assert!(!policy.should_preempt(2));
+
+        // A thread that slept for a long time only gets a bounded credit,
+        // which is enough to run next
+        policy.remove(2);
+        policy.add(2, Priority::Normal(0));
+        policy.entities.get_mut(&2).unwrap().vruntime = 0;
+        policy.wake(2);
+        assert_eq!(policy.get(2).unwrap().vruntime, vruntime - LATENCY / 2);
+        assert!(policy.should_preempt(2));
+
+        assert!(!policy.set_priority(9, Priority::Normal(0)));
+        assert!(policy.set_priority(2, Priority::Fifo(1)));
+        assert!(policy.tick(TICK));
+        assert!(!Priority::Normal(20).is_valid());
+        assert!(!Priority::Fifo(0).is_valid());
+    }
+}
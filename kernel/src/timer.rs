@@ -1,5 +1,9 @@
 use crate::prelude::*;
 use crate::term::Terminal;
+
+/// Frequency of the timer interrupt
+pub const HZ: u32 = 100;
+
 pub struct Timer {
     ticks: usize,
     buf: [u8; 80],
@@ -23,12 +27,16 @@ impl Timer {
         write!(s, "{}", self.ticks);
         let f = s.as_str().trim();
 
-        Terminal::global().lock().write_at(f, 0, 79 - f.len());
-
-        if self.ticks > 100 {
-            panic!("timer!!!");
+        // Skip drawing rather than deadlock if the interrupted thread is
+        // printing
+        if let Some(mut term) = Terminal::global().try_lock() {
+            term.write_at(f, 0, 79 - f.len());
         }
     }
 }
 
-interrupt!(timer, _stack, { Timer::global().lock().tick() });
+/// IRQ 0 handler, registered with [`crate::arch::interrupts::irq::register`]
+pub fn tick(_stack: &mut crate::arch::interrupts::InterruptStack) {
+    Timer::global().critical().tick();
+    crate::thread::tick();
+}
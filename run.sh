@@ -2,6 +2,8 @@
 cargo run --bin builder && \
 qemu-system-x86_64 \
 	./build/disk.img \
+	-smp 4 \
 	-monitor stdio \
 	-serial file:serial.txt \
+	-serial tcp::4321,server,nowait \
 	-d cpu_reset
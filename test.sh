@@ -0,0 +1,39 @@
+#!/bin/bash
+# Boot the kernel in QEMU, and check that init runs /bin/musl-hello, a
+# static C program built against musl. Its console output is copied to the
+# first serial port, which is captured in a file. Needs musl-gcc, for the
+# builder to compile the program with.
+TIMEOUT=${TIMEOUT:-60}
+EXPECTED="hello, world from musl-hello"
+
+if ! command -v musl-gcc > /dev/null; then
+	echo "musl-gcc is needed to build /bin/musl-hello"
+	exit 1
+fi
+cargo run --bin builder || exit 1
+
+serial=$(mktemp)
+trap 'rm -f "$serial"' EXIT
+qemu-system-x86_64 \
+	./build/disk.img \
+	-smp 4 \
+	-display none \
+	-serial file:"$serial" \
+	-no-reboot &
+qemu=$!
+
+for _ in $(seq "$TIMEOUT"); do
+	if grep -q "$EXPECTED" "$serial"; then
+		kill $qemu
+		cat "$serial"
+		echo "passed"
+		exit 0
+	fi
+	kill -0 $qemu 2> /dev/null || break
+	sleep 1
+done
+
+kill $qemu 2> /dev/null
+cat "$serial"
+echo "failed: \"$EXPECTED\" was not printed within ${TIMEOUT}s"
+exit 1
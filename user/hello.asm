@@ -0,0 +1,56 @@
+; Run by init. Writes its arguments to the console, separated by spaces,
+; and exits.
+bits 64
+
+SYS_WRITE	equ 1
+SYS_EXIT	equ 60
+STDOUT		equ 1
+
+section .text
+global _start
+_start:
+	mov r12, [rsp]			; argc
+	lea r13, [rsp + 8]		; argv
+
+.next:
+	test r12, r12
+	jz .done
+	mov rdi, [r13]
+	call strlen
+	mov rdx, rax
+	mov rax, SYS_WRITE
+	mov rdi, STDOUT
+	mov rsi, [r13]
+	syscall
+
+	add r13, 8
+	dec r12
+	lea rsi, [rel space]
+	jnz .separate
+	lea rsi, [rel newline]
+.separate:
+	mov rax, SYS_WRITE
+	mov rdi, STDOUT
+	mov rdx, 1
+	syscall
+	jmp .next
+
+.done:
+	mov rax, SYS_EXIT
+	xor rdi, rdi
+	syscall
+
+; Length of the NUL-terminated string at rdi
+strlen:
+	xor rax, rax
+.next:
+	cmp byte [rdi + rax], 0
+	je .done
+	inc rax
+	jmp .next
+.done:
+	ret
+
+section .data
+space:	db " "
+newline: db 10
@@ -0,0 +1,189 @@
+; The first user program. It greets the console by name, through both
+; system call entry points, then starts three children: two that run
+; /bin/hello and /bin/musl-hello, and one that is killed for executing an
+; invalid instruction.
+; From then on it reaps every process that exits, including the orphans it
+; adopts, and reports how each one ended. It never exits.
+bits 64
+
+SYS_WRITE	equ 1
+SYS_NANOSLEEP	equ 35
+SYS_FORK	equ 57
+SYS_EXECVE	equ 59
+SYS_EXIT	equ 60
+SYS_WAIT4	equ 61
+STDOUT		equ 1
+
+section .text
+global _start
+_start:
+	mov r13, [rsp + 8]		; argv[0]
+
+	lea rdi, [rel hello]
+	mov rsi, hello.len
+	call print
+
+	; Write argv[0] through the debugging gate
+	mov rdi, r13
+	call strlen
+	mov rdx, rax
+	mov rax, SYS_WRITE
+	mov rdi, STDOUT
+	mov rsi, r13
+	int 0x80
+
+	lea rdi, [rel newline]
+	mov rsi, 1
+	call print
+
+	lea rdi, [rel hello_path]
+	lea rsi, [rel hello_argv]
+	call run
+	lea rdi, [rel musl_path]
+	lea rsi, [rel musl_argv]
+	call run
+
+	mov rax, SYS_FORK
+	syscall
+	test rax, rax
+	jnz reap
+	ud2
+
+reap:
+	mov rax, SYS_WAIT4
+	mov rdi, -1
+	lea rsi, [rel status]
+	xor rdx, rdx
+	xor r10, r10
+	syscall
+	test rax, rax
+	js idle
+	mov rbx, rax
+
+	lea rdi, [rel reaped]
+	mov rsi, reaped.len
+	call print
+	mov rdi, rbx
+	call print_number
+
+	mov eax, [rel status]
+	test eax, 0x7F
+	jz .exited
+	lea rdi, [rel killed]
+	mov rsi, killed.len
+	call print
+	mov edi, [rel status]
+	and edi, 0x7F
+	jmp .report
+.exited:
+	lea rdi, [rel exited]
+	mov rsi, exited.len
+	call print
+	mov edi, [rel status]
+	shr edi, 8
+	and edi, 0xFF
+.report:
+	call print_number
+	lea rdi, [rel newline]
+	mov rsi, 1
+	call print
+	jmp reap
+
+	; No children for now, but an orphan may be adopted later
+idle:
+	mov rax, SYS_NANOSLEEP
+	lea rdi, [rel second]
+	xor rsi, rsi
+	syscall
+	jmp reap
+
+; Start a child that runs the program at path rdi, with the arguments in
+; the array at rsi
+run:
+	mov r12, rdi
+	mov r14, rsi
+	mov rax, SYS_FORK
+	syscall
+	test rax, rax
+	jz .child
+	ret
+.child:
+	mov rax, SYS_EXECVE
+	mov rdi, r12
+	mov rsi, r14
+	lea rdx, [rel envp]
+	syscall
+	; Only returns if it failed
+	mov rax, SYS_EXIT
+	mov rdi, 127
+	syscall
+
+; Write the rsi bytes at rdi to the console
+print:
+	mov rdx, rsi
+	mov rsi, rdi
+	mov rdi, STDOUT
+	mov rax, SYS_WRITE
+	syscall
+	ret
+
+; Write rdi to the console in decimal
+print_number:
+	mov rax, rdi
+	lea rsi, [rel digits + digits.len]
+	mov rcx, 10
+.digit:
+	xor rdx, rdx
+	div rcx
+	add dl, '0'
+	dec rsi
+	mov [rsi], dl
+	test rax, rax
+	jnz .digit
+	lea rdx, [rel digits + digits.len]
+	sub rdx, rsi
+	mov rax, SYS_WRITE
+	mov rdi, STDOUT
+	syscall
+	ret
+
+; Length of the NUL-terminated string at rdi
+strlen:
+	xor rax, rax
+.next:
+	cmp byte [rdi + rax], 0
+	je .done
+	inc rax
+	jmp .next
+.done:
+	ret
+
+section .data
+hello:	db "hello from "
+.len	equ $ - hello
+reaped:	db "init: process "
+.len	equ $ - reaped
+exited:	db " exited with status "
+.len	equ $ - exited
+killed:	db " was killed by signal "
+.len	equ $ - killed
+newline: db 10
+
+hello_path: db "/bin/hello", 0
+hello_name: db "hello", 0
+hello_arg: db "world", 0
+musl_path: db "/bin/musl-hello", 0
+musl_name: db "musl-hello", 0
+home:	db "HOME=/", 0
+align 8
+hello_argv: dq hello_name, hello_arg, 0
+musl_argv: dq musl_name, 0
+envp:	dq home, 0
+
+; struct timespec of one second
+second:	dq 1, 0
+
+section .bss
+status:	resd 1
+digits:	resb 20
+.len	equ 20
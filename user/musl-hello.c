@@ -0,0 +1,52 @@
+/*
+ * Run by init. A static C program built against musl, checking that the C
+ * library starts up and that stdio, malloc, files and clocks work on the
+ * kernel's Linux system calls.
+ */
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+#include <unistd.h>
+
+int main(int argc, char **argv)
+{
+	/* Large enough for malloc to map it, rather than using the heap */
+	size_t size = 1 << 20;
+	char *big = malloc(size);
+	char *name = malloc(16);
+	if (!big || !name) {
+		perror("malloc");
+		return 1;
+	}
+	memset(big, 0x5A, size);
+	strcpy(name, "world");
+
+	char motd[256];
+	int fd = open("/etc/motd", O_RDONLY);
+	if (fd < 0) {
+		perror("open /etc/motd");
+		return 1;
+	}
+	ssize_t len = read(fd, motd, sizeof(motd) - 1);
+	close(fd);
+	if (len < 0) {
+		perror("read /etc/motd");
+		return 1;
+	}
+	motd[len] = 0;
+
+	struct timespec now;
+	if (clock_gettime(CLOCK_MONOTONIC, &now) < 0) {
+		perror("clock_gettime");
+		return 1;
+	}
+
+	printf("hello, %s from %s, pid %d\n", name, argv[0], getpid());
+	printf("%s", motd);
+	printf("up for %ld.%02lds\n", (long)now.tv_sec, now.tv_nsec / 10000000);
+	free(big);
+	free(name);
+	return 0;
+}